@@ -5,15 +5,22 @@ use simple_error::{bail, require_with, try_with};
 use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::sync::Mutex;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
-use vmsh::kvm::hypervisor::{get_hypervisor, memory::PhysMem};
+use vmsh::attach::get_irq_num;
+use vmsh::kvm::hypervisor::memory::{IovecMem, MemAccess, PhysMem, ProcMem};
+use vmsh::kvm::hypervisor::{get_hypervisor, HypervisorOptions};
 use vmsh::kvm::kvm_ioregionfd::{self, Cmd};
+use vmsh::kvm::mp_state::MpState;
 use vmsh::result::Result;
 use vmsh::tracer::wrap_syscall::KvmRunWrapper;
 
 fn inject(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
 
     print!("check_extensions");
     for _ in 1..100 {
@@ -42,8 +49,209 @@ fn inject(pid: Pid) -> Result<()> {
     Ok(())
 }
 
+/// Number of ioctls `ioctl_throughput` issues per vcpu
+const THROUGHPUT_ROUNDS: u32 = 1000;
+
+fn ioctl_throughput(pid: Pid) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
+    vm.stop()?;
+    let start = Instant::now();
+    for _ in 0..THROUGHPUT_ROUNDS {
+        for cpu in vm.vcpus.iter() {
+            let regs = vm.get_regs(cpu)?;
+            vm.set_regs(cpu, &regs)?;
+        }
+    }
+    let elapsed = start.elapsed();
+    let ioctls = 2 * THROUGHPUT_ROUNDS * vm.vcpus.len() as u32;
+    println!(
+        "{} ioctls in {:?} ({:.0} ioctls/s)",
+        ioctls,
+        elapsed,
+        ioctls as f64 / elapsed.as_secs_f64()
+    );
+    vm.resume()?;
+    Ok(())
+}
+
+/// Number of ioctls `scratch_bench` issues for each variant
+const SCRATCH_BENCH_ROUNDS: u32 = 1000;
+
+/// Compare the cost of an ioctl whose argument lives in memory mapped just for it, like every
+/// ioctl did before the scratch arena, with one using the arena. The first variant maps and
+/// unmaps a `kvm_regs` in the hypervisor around each KVM_GET_REGS.
+fn scratch_bench(pid: Pid) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
+    vm.stop()?;
+    let cpu = &vm.vcpus[0];
+
+    let start = Instant::now();
+    for _ in 0..SCRATCH_BENCH_ROUNDS {
+        let mem = vm.alloc_mem::<kvmb::kvm_regs>()?;
+        drop(mem);
+        vm.get_regs(cpu)?;
+    }
+    let mapped = start.elapsed() / SCRATCH_BENCH_ROUNDS;
+
+    let start = Instant::now();
+    for _ in 0..SCRATCH_BENCH_ROUNDS {
+        vm.get_regs(cpu)?;
+    }
+    let arena = start.elapsed() / SCRATCH_BENCH_ROUNDS;
+
+    println!(
+        "mapped per ioctl: {:?}/ioctl, scratch arena: {:?}/ioctl ({:.1}x)",
+        mapped,
+        arena,
+        mapped.as_secs_f64() / arena.as_secs_f64()
+    );
+    vm.resume()?;
+    Ok(())
+}
+
+/// Number of interrupts `irq_bench` raises for each variant
+const IRQ_BENCH_ROUNDS: u32 = 1000;
+
+/// Compare the cost of raising an interrupt in the guest through an irqfd, as our devices do,
+/// with injecting KVM_IRQ_LINE into the stopped hypervisor, once to raise and once to lower
+/// the line. The irqfd stays registered, so only run this on a throwaway VM.
+fn irq_bench(pid: Pid) -> Result<()> {
+    let mut vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
+    let gsi = get_irq_num(pid)? as u32;
+    vm.stop()?;
+    try_with!(
+        vm.setup_transfer_sockets(),
+        "cannot set up transfer sockets"
+    );
+    let irqfd = try_with!(vm.irqfd(gsi), "cannot register irqfd");
+
+    let start = Instant::now();
+    for _ in 0..IRQ_BENCH_ROUNDS {
+        vm.irq_line(gsi, true)?;
+        vm.irq_line(gsi, false)?;
+    }
+    let manual = start.elapsed() / IRQ_BENCH_ROUNDS;
+
+    let start = Instant::now();
+    for _ in 0..IRQ_BENCH_ROUNDS {
+        try_with!(irqfd.write(1), "cannot signal irqfd");
+    }
+    let eventfd = start.elapsed() / IRQ_BENCH_ROUNDS;
+
+    println!(
+        "KVM_IRQ_LINE: {:?}/interrupt, irqfd: {:?}/interrupt ({:.1}x)",
+        manual,
+        eventfd,
+        manual.as_secs_f64() / eventfd.as_secs_f64()
+    );
+    try_with!(vm.close_transfer_sockets(), "cannot close transfer sockets");
+    vm.resume()?;
+    Ok(())
+}
+
+/// Bytes of guest memory `mem_bench` reads per round and the number of rounds
+const MEM_BENCH_CHUNK: usize = 1024 * 1024;
+const MEM_BENCH_ROUNDS: u32 = 100;
+
+/// Time `rounds` reads of `buf.len()` bytes at `addr` with `mem`.
+fn time_reads(mem: &dyn MemAccess, addr: usize, buf: &mut [u8]) -> Result<Duration> {
+    let start = Instant::now();
+    for _ in 0..MEM_BENCH_ROUNDS {
+        mem.read_slice(addr, buf)?;
+    }
+    Ok(start.elapsed() / MEM_BENCH_ROUNDS)
+}
+
+/// Compare reading guest memory with process_vm_readv(2) to reading it through
+/// /proc/<pid>/mem, the fallback `HostMem` switches to.
+fn mem_bench(pid: Pid) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
+    vm.stop()?;
+    let maps = vm.get_maps()?;
+    let map = require_with!(maps.iter().max_by_key(|m| m.size()), "no guest memory");
+    let mut buf = vec![0u8; MEM_BENCH_CHUNK.min(map.size())];
+
+    let iovec = IovecMem { pid };
+    let proc_mem = try_with!(ProcMem::open(pid), "cannot open /proc/{}/mem", pid);
+    let iovec_time = time_reads(&iovec, map.start, &mut buf)?;
+    let proc_time = time_reads(&proc_mem, map.start, &mut buf)?;
+
+    let mib = buf.len() as f64 / (1024.0 * 1024.0);
+    println!(
+        "process_vm_readv: {:.0} MiB/s, /proc/{}/mem: {:.0} MiB/s ({} bytes per read)",
+        mib / iovec_time.as_secs_f64(),
+        pid,
+        mib / proc_time.as_secs_f64(),
+        buf.len()
+    );
+    vm.resume()?;
+    Ok(())
+}
+
+/// Number of stop/resume cycles `stop_resume` runs
+const STOP_RESUME_ROUNDS: usize = 1000;
+
+/// Rapidly stop and resume the hypervisor and check that the vcpus still make progress.
+fn stop_resume(pid: Pid) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
+    vm.stop()?;
+    let first = vm.get_regs(&vm.vcpus[0])?;
+    vm.resume()?;
+
+    print!("stop_resume");
+    for i in 0..STOP_RESUME_ROUNDS {
+        vm.stop()?;
+        try_with!(vm.get_regs(&vm.vcpus[0]), "cannot get regs in round {}", i);
+        vm.resume()?;
+        if i % 100 == 0 {
+            print!(".");
+        }
+    }
+    println!(" ok");
+
+    // give the guest some time to run after all the interruptions
+    std::thread::sleep(Duration::from_millis(100));
+    vm.stop()?;
+    let last = vm.get_regs(&vm.vcpus[0])?;
+    let state = vm.get_mp_state(&vm.vcpus[0])?;
+    vm.resume()?;
+    // an idle guest may be halted at the same instruction every time we look
+    let unchanged = first.ip() == last.ip() && first.rsp == last.rsp && first.rax == last.rax;
+    if unchanged && state != MpState::Halted {
+        bail!(
+            "vcpu 0 did not make progress and is {}, the guest may be wedged",
+            state
+        );
+    }
+    Ok(())
+}
+
 fn alloc_mem(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
 
     vm.stop()?;
     let mem = try_with!(vm.alloc_mem::<u32>(), "mmap failed");
@@ -59,7 +267,11 @@ fn guest_add_mem(pid: Pid, re_get_slots: bool) -> Result<()> {
     let memslots_a_len;
 
     {
-        let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+        let vm = try_with!(
+            get_hypervisor(pid, HypervisorOptions::default()),
+            "cannot get vms for process {}",
+            pid
+        );
         vm.stop()?;
 
         // count memslots
@@ -92,7 +304,11 @@ fn guest_add_mem(pid: Pid, re_get_slots: bool) -> Result<()> {
     }
 
     // VmMem is out of scope and should thus have removed the memory again.
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     if re_get_slots {
@@ -112,7 +328,11 @@ fn guest_add_mem(pid: Pid, re_get_slots: bool) -> Result<()> {
 fn fd_transfer(pid: Pid) -> Result<()> {
     use std::path::Path;
 
-    let mut vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let mut vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
     try_with!(
         vm.setup_transfer_sockets(),
@@ -143,7 +363,11 @@ fn fd_transfer(pid: Pid) -> Result<()> {
 }
 
 fn cpuid2(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     let cpuid2 = try_with!(vm.get_cpuid2(&vm.vcpus[0]), "cannot get cpuid2");
@@ -158,7 +382,11 @@ fn cpuid2(pid: Pid) -> Result<()> {
 
 /// Some parts of this implementation are still missing.
 fn guest_userfaultfd(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     let vm_mem = vm.vm_add_mem::<u64>(0xd0000000, size_of::<u64>(), true)?;
@@ -178,7 +406,11 @@ fn guest_userfaultfd(pid: Pid) -> Result<()> {
 }
 
 fn guest_ioeventfd(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     let has_cap = try_with!(
@@ -224,7 +456,11 @@ fn guest_ioeventfd(pid: Pid) -> Result<()> {
 }
 
 fn ioregionfd(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     let has_cap = try_with!(
@@ -267,7 +503,11 @@ fn ioregionfd(pid: Pid) -> Result<()> {
 }
 
 fn guest_kvm_exits(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.kvmrun_wrapped(|wrapper_r: &Mutex<Option<KvmRunWrapper>>| {
         let mut wrapper_go = wrapper_r.lock().unwrap();
         let wrapper = wrapper_go.as_mut().unwrap();
@@ -290,7 +530,11 @@ fn guest_kvm_exits(pid: Pid) -> Result<()> {
 }
 
 fn vcpu_maps(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, HypervisorOptions::default()),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     let kvm_run_len = size_of::<kvm_bindings::kvm_run>();
@@ -329,6 +573,11 @@ fn main() {
         .about("Something between integration and unit test to be used by pytest.")
         .subcommand(subtest("alloc_mem"))
         .subcommand(subtest("inject"))
+        .subcommand(subtest("ioctl_throughput"))
+        .subcommand(subtest("scratch_bench"))
+        .subcommand(subtest("mem_bench"))
+        .subcommand(subtest("irq_bench"))
+        .subcommand(subtest("stop_resume"))
         .subcommand(subtest("guest_add_mem"))
         .subcommand(subtest("guest_add_mem_get_maps"))
         .subcommand(subtest("fd_transfer"))
@@ -350,6 +599,11 @@ fn main() {
     let result = match subcommand_name {
         "alloc_mem" => alloc_mem(pid),
         "inject" => inject(pid),
+        "ioctl_throughput" => ioctl_throughput(pid),
+        "scratch_bench" => scratch_bench(pid),
+        "mem_bench" => mem_bench(pid),
+        "irq_bench" => irq_bench(pid),
+        "stop_resume" => stop_resume(pid),
         "cpuid2" => cpuid2(pid),
         "guest_add_mem" => guest_add_mem(pid, false),
         "guest_add_mem_get_maps" => guest_add_mem(pid, true),
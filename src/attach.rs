@@ -1,26 +1,54 @@
-use log::{error, info};
+use kvm_bindings as kvmb;
+use libc::c_int;
+use log::{error, info, warn};
+use nix::sched::CpuSet;
 use nix::unistd::Pid;
-use simple_error::{require_with, try_with};
+use simple_error::{bail, require_with, try_with};
+use std::fmt;
 use std::fs::read_to_string;
 use std::path::PathBuf;
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
 use std::sync::Arc;
 use std::time::Duration;
 
-use crate::devices::use_ioregionfd;
 use crate::devices::DeviceSet;
+use crate::devices::MmioTraceOptions;
+use crate::devices::{use_ioregionfd, use_userspace_ioeventfd};
+use crate::devices::{DriverNotifier, Threads};
+use crate::interrutable_thread::InterrutableThread;
+use crate::kvm::hypervisor::{Hypervisor, HypervisorOptions, HypervisorSummary, MemoryEncryption};
+use crate::kvm::kvm_ioregionfd::KVM_CAP_IOREGIONFD;
 use crate::result::Result;
 use crate::stage1::Stage1;
 use crate::{kvm, signal_handler};
 
 pub struct AttachOptions {
     pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
     pub command: Vec<String>,
     pub backing: PathBuf,
     pub pts: Option<PathBuf>,
+    /// CPUs to pin device threads to. By default they may run on any CPU.
+    pub cpus: Option<CpuSet>,
+    /// Only log every Nth MMIO exit, 1 logs all of them
+    pub mmio_sample: usize,
+    /// Record all MMIO exits to this file
+    pub record_mmio: Option<PathBuf>,
+    /// Log every ioctl the traced hypervisor threads make
+    pub log_ioctls: bool,
+    /// Only trace threads running vcpus instead of all threads of the hypervisor
+    pub vcpu_threads_only: bool,
+    /// Size of the virtqueue of the block device
+    pub queue_size: u16,
+    /// Where stage2 mounts the root of the guest inside the root of `backing`
+    pub data_dir: PathBuf,
+    /// Run the command with the umask and resource limits of the init process of the VM
+    pub inherit_limits: bool,
 }
 
-pub fn get_irq_num(pid: Pid) -> Result<usize> {
+/// Name of the hypervisor process as in /proc/<pid>/comm
+fn vmm_name(pid: Pid) -> Result<String> {
     let mut comm_path = PathBuf::from("/proc");
     comm_path.push(pid.as_raw().to_string());
     comm_path.push("comm");
@@ -29,6 +57,11 @@ pub fn get_irq_num(pid: Pid) -> Result<usize> {
         "failed to read {}",
         comm_path.display()
     );
+    Ok(comm.trim_end().to_string())
+}
+
+pub fn get_irq_num(pid: Pid) -> Result<usize> {
+    let comm = vmm_name(pid)?;
     // dirty hack until we have a better way to find out what IRQs we can use
     if comm.contains("crosvm") {
         Ok(4)
@@ -37,7 +70,239 @@ pub fn get_irq_num(pid: Pid) -> Result<usize> {
     }
 }
 
-pub fn attach(opts: &AttachOptions) -> Result<()> {
+/// What vmsh detected about the hypervisor, logged as a single line when attaching so that
+/// unsupported setups stand out right away.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttachBanner {
+    pub vmm: String,
+    pub pid: Pid,
+    pub arch: &'static str,
+    pub vcpus: usize,
+    /// bytes of guest memory the hypervisor maps
+    pub ram: u64,
+    /// KVM capabilities the selected device backend relies on and whether KVM has them
+    pub caps: Vec<(&'static str, bool)>,
+    pub memory_encryption: Option<MemoryEncryption>,
+}
+
+impl AttachBanner {
+    /// Expects the hypervisor to be stopped, capabilities are queried from within it.
+    pub fn detect(vm: &Hypervisor) -> Result<AttachBanner> {
+        let mut wanted = vec![
+            ("KVM_CAP_USER_MEMORY", kvmb::KVM_CAP_USER_MEMORY),
+            ("KVM_CAP_IRQFD", kvmb::KVM_CAP_IRQFD),
+        ];
+        if !use_userspace_ioeventfd() {
+            wanted.push(("KVM_CAP_IOEVENTFD", kvmb::KVM_CAP_IOEVENTFD));
+        }
+        if use_ioregionfd() {
+            wanted.push(("KVM_CAP_IOREGIONFD", KVM_CAP_IOREGIONFD));
+        }
+        let mut caps = vec![];
+        for (name, cap) in wanted {
+            let present = try_with!(vm.check_extension(cap as c_int), "cannot check {}", name);
+            caps.push((name, present > 0));
+        }
+        let ram = try_with!(vm.get_maps(), "cannot get guest memory mappings")
+            .iter()
+            .map(|m| m.size() as u64)
+            .sum();
+        Ok(AttachBanner {
+            vmm: vmm_name(vm.pid).unwrap_or_else(|_| "unknown".to_string()),
+            pid: vm.pid,
+            arch: std::env::consts::ARCH,
+            vcpus: vm.vcpus.len(),
+            ram,
+            caps,
+            memory_encryption: vm.memory_encryption,
+        })
+    }
+
+    /// Capabilities we need but KVM lacks
+    pub fn missing_caps(&self) -> Vec<&'static str> {
+        self.caps
+            .iter()
+            .filter(|(_, present)| !present)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+impl fmt::Display for AttachBanner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "vmsh {}: {} (pid {}), {}, {} vcpus, {} MiB ram,",
+            env!("CARGO_PKG_VERSION"),
+            self.vmm,
+            self.pid,
+            self.arch,
+            self.vcpus,
+            self.ram >> 20
+        )?;
+        for (name, present) in &self.caps {
+            write!(f, " {}={}", name, if *present { "yes" } else { "no" })?;
+        }
+        match self.memory_encryption {
+            Some(enc) => write!(f, ", memory encrypted with {}", enc),
+            None => write!(f, ", memory not encrypted"),
+        }
+    }
+}
+
+/// Resumes the hypervisor when dropped if it is still stopped. Covers early returns and panics
+/// (i.e. from the `expect`s in the device setup) between `stop` and the final `resume` of an
+/// attach, which would otherwise leave the guest stopped.
+struct ResumeGuard {
+    vm: Arc<Hypervisor>,
+}
+
+fn resume_after_failure(vm: &Hypervisor) {
+    if !vm.is_stopped() {
+        return;
+    }
+    warn!("attach failed, resuming the vm");
+    if let Err(e) = vm.resume() {
+        error!("cannot resume vm, try `vmsh kick {}`: {}", vm.pid, e);
+    }
+}
+
+impl Drop for ResumeGuard {
+    fn drop(&mut self) {
+        resume_after_failure(&self.vm);
+    }
+}
+
+/// A virtio-mmio device vmsh added to the guest
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttachedDevice {
+    pub name: &'static str,
+    /// guest physical base address of its mmio window
+    pub mmio_addr: u64,
+    pub irq: usize,
+}
+
+/// What `Attachment` tears down when it is detached
+struct Running {
+    stage1: Stage1,
+    stage1_thread: InterrutableThread<(), ()>,
+    threads: Threads,
+    driver_notifier: Arc<DriverNotifier>,
+}
+
+impl Running {
+    /// Stop stage1 and our devices, clean up what we allocated in the hypervisor and resume it.
+    fn detach(self, vm: &Hypervisor) -> Result<()> {
+        let Running {
+            stage1,
+            stage1_thread,
+            threads,
+            driver_notifier,
+        } = self;
+        stage1_thread.shutdown();
+        if let Err(e) = stage1_thread.join() {
+            error!("{}", e);
+        };
+        if let Err(e) = driver_notifier.terminate() {
+            error!("failed to stop device: {}", e);
+        }
+        threads.iter().for_each(|t| t.shutdown());
+        let contexts = threads
+            .into_iter()
+            .map(|t| {
+                let (res, ctx) = match t.join() {
+                    Err(e) => (Err(e), None),
+                    Ok((res, ctx)) => (res, ctx),
+                };
+                if let Err(e) = res {
+                    error!("{}", e);
+                }
+                ctx
+            })
+            .collect::<Vec<_>>();
+
+        // MMIO exit handler thread took over pthread control
+        // We need ptrace the process again before we can finish. Without ioregionfd the stop of
+        // `attach` is still outstanding once the handler thread gave the threads back.
+        if !vm.is_stopped() {
+            vm.stop()?;
+        }
+        if !use_ioregionfd() {
+            vm.finish_thread_transfer()?;
+        }
+        // now that we got the tracer back, we can cleanup physical memory and file descriptors
+        drop(stage1);
+        drop(contexts);
+        try_with!(vm.close_transfer_sockets(), "cannot close transfer sockets");
+        vm.resume()?;
+
+        Ok(())
+    }
+}
+
+/// Everything `attach` set up. Stage1 and our devices stay in the guest until `detach` is called
+/// or the attachment is dropped, which detaches as well but can only log errors.
+pub struct Attachment {
+    pub vm: Arc<Hypervisor>,
+    pub devices: Vec<AttachedDevice>,
+    /// pseudo terminal the console device is connected to, if any
+    pub pts: Option<PathBuf>,
+    /// the hypervisor as it was before we added our devices
+    pub summary: HypervisorSummary,
+    receiver: Receiver<()>,
+    /// set once a stop request was received from `receiver`, see `stop_requested`
+    stopping: AtomicBool,
+    /// None once detached
+    running: Option<Running>,
+    _resume_guard: ResumeGuard,
+}
+
+impl Attachment {
+    /// Block until vmsh is asked to stop, i.e. by SIGINT/SIGTERM, or a device or stage1 fails.
+    pub fn wait(&self) {
+        if !self.stopping.load(Ordering::Acquire) {
+            let _ = self.receiver.recv();
+            self.stopping.store(true, Ordering::Release);
+        }
+    }
+
+    /// Whether `wait` would return right away. Does not consume the stop request, so `wait`
+    /// still returns after this returned true.
+    pub fn stop_requested(&self) -> bool {
+        if self.stopping.load(Ordering::Acquire) {
+            return true;
+        }
+        if self.receiver.try_recv().is_ok() {
+            self.stopping.store(true, Ordering::Release);
+            return true;
+        }
+        false
+    }
+
+    /// Stop stage1 and our devices, clean up what we allocated in the hypervisor and resume it.
+    pub fn detach(mut self) -> Result<()> {
+        match self.running.take() {
+            Some(running) => running.detach(&self.vm),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        if let Some(running) = self.running.take() {
+            warn!("attachment dropped without detach, detaching");
+            if let Err(e) = running.detach(&self.vm) {
+                error!("cannot detach from vm: {}", e);
+            }
+        }
+    }
+}
+
+/// Attach our devices and stage1 to the VM of `opts.pid` and start them. Returns once the
+/// devices are ready, the caller decides how long to stay attached and calls
+/// `Attachment::detach`. Returns None if vmsh was asked to stop before the devices were started.
+pub fn attach(opts: &AttachOptions) -> Result<Option<Attachment>> {
     info!("attaching");
 
     let (sender, receiver) = channel();
@@ -45,16 +310,34 @@ pub fn attach(opts: &AttachOptions) -> Result<()> {
     signal_handler::setup(sender.clone());
 
     let mut vm = try_with!(
-        kvm::hypervisor::get_hypervisor(opts.pid),
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.hypervisor),
         "cannot get vms for process {}",
         opts.pid
     );
+    vm.set_vcpu_threads_only(opts.vcpu_threads_only);
     vm.stop()?;
-    try_with!(
-        vm.setup_transfer_sockets(),
-        "failed to setup unix sockets for fd transfer"
-    );
+    match AttachBanner::detect(&vm) {
+        Ok(banner) => {
+            info!("{}", banner);
+            let missing = banner.missing_caps();
+            if !missing.is_empty() {
+                warn!(
+                    "KVM lacks {}, attaching will likely fail",
+                    missing.join(", ")
+                );
+            }
+        }
+        Err(e) => warn!("cannot detect hypervisor capabilities: {}", e),
+    }
+    if let Err(e) = vm.setup_transfer_sockets() {
+        resume_after_failure(&vm);
+        bail!("failed to setup unix sockets for fd transfer: {}", e);
+    }
     let vm = Arc::new(vm);
+    let resume_guard = ResumeGuard {
+        vm: Arc::clone(&vm),
+    };
+    let summary = try_with!(vm.summary(), "cannot summarize hypervisor");
 
     let mut allocator = try_with!(
         kvm::PhysMemAllocator::new(Arc::clone(&vm)),
@@ -69,18 +352,39 @@ pub fn attach(opts: &AttachOptions) -> Result<()> {
             &mut allocator,
             irq_num,
             &opts.backing,
-            opts.pts.clone()
+            opts.pts.clone(),
+            opts.queue_size
         ),
         "cannot create devices"
     );
 
     if receiver.recv_timeout(Duration::from_millis(0)).is_ok() {
-        return Ok(());
+        return Ok(None);
     }
 
     let addrs = devices.mmio_addrs()?;
+    // same order as `DeviceSet::mmio_addrs`
+    let attached = ["block", "console"]
+        .iter()
+        .zip(addrs.iter())
+        .map(|(name, addr)| AttachedDevice {
+            name: *name,
+            mmio_addr: *addr,
+            irq: irq_num,
+        })
+        .collect();
+    // stage2 expects its own options between its path and the command
+    let mut argv = vec![
+        opts.command[0].clone(),
+        "--data-dir".to_string(),
+        opts.data_dir.display().to_string(),
+    ];
+    if !opts.inherit_limits {
+        argv.push("--no-inherit-limits".to_string());
+    }
+    argv.extend_from_slice(&opts.command[1..]);
     let mut stage1 = try_with!(
-        Stage1::new(allocator, &opts.command, irq_num, addrs),
+        Stage1::new(allocator, &argv, irq_num, addrs),
         "failed to initialize stage1"
     );
     let driver_status = require_with!(stage1.driver_status.take(), "no driver status set");
@@ -90,47 +394,68 @@ pub fn attach(opts: &AttachOptions) -> Result<()> {
     );
     let device_status = require_with!(stage1.device_status.take(), "device status is not set");
     let (threads, driver_notifier) = try_with!(
-        devices.start(&vm, device_status, driver_status, sender),
+        devices.start(
+            &vm,
+            device_status,
+            driver_status,
+            opts.cpus,
+            MmioTraceOptions {
+                sample: opts.mmio_sample,
+                record: opts.record_mmio.clone(),
+                log_ioctls: opts.log_ioctls,
+            },
+            sender
+        ),
         "failed to start devices"
     );
 
     info!("blkdev queue ready.");
 
-    // termination wait or vmsh_stop()
-    let _ = receiver.recv();
-    stage1_thread.shutdown();
-    if let Err(e) = stage1_thread.join() {
-        error!("{}", e);
-    };
-    if let Err(e) = driver_notifier.terminate() {
-        error!("failed to stop device: {}", e);
-    }
-    threads.iter().for_each(|t| t.shutdown());
-    let contexts = threads
-        .into_iter()
-        .map(|t| {
-            let (res, ctx) = match t.join() {
-                Err(e) => (Err(e), None),
-                Ok((res, ctx)) => (res, ctx),
-            };
-            if let Err(e) = res {
-                error!("{}", e);
-            }
-            ctx
-        })
-        .collect::<Vec<_>>();
+    Ok(Some(Attachment {
+        vm,
+        devices: attached,
+        pts: opts.pts.clone(),
+        summary,
+        receiver,
+        stopping: AtomicBool::new(false),
+        running: Some(Running {
+            stage1,
+            stage1_thread,
+            threads,
+            driver_notifier,
+        }),
+        _resume_guard: resume_guard,
+    }))
+}
 
-    // MMIO exit handler thread took over pthread control
-    // We need ptrace the process again before we can finish.
-    vm.stop()?;
-    if !use_ioregionfd() {
-        vm.finish_thread_transfer()?;
-    }
-    // now that we got the tracer back, we can cleanup physical memory and file descriptors
-    drop(stage1);
-    drop(contexts);
-    try_with!(vm.close_transfer_sockets(), "cannot close transfer sockets");
-    vm.resume()?;
+#[cfg(test)]
+mod tests {
+    use super::AttachBanner;
+    use crate::kvm::hypervisor::MemoryEncryption;
+    use nix::unistd::Pid;
 
-    Ok(())
+    #[test]
+    fn test_attach_banner() {
+        let mut banner = AttachBanner {
+            vmm: "qemu-system-x86".to_string(),
+            pid: Pid::from_raw(1234),
+            arch: "x86_64",
+            vcpus: 4,
+            ram: 2 << 30,
+            caps: vec![("KVM_CAP_IRQFD", true), ("KVM_CAP_IOEVENTFD", false)],
+            memory_encryption: None,
+        };
+        let line = banner.to_string();
+        assert!(
+            line.ends_with(": qemu-system-x86 (pid 1234), x86_64, 4 vcpus, 2048 MiB ram, KVM_CAP_IRQFD=yes KVM_CAP_IOEVENTFD=no, memory not encrypted"),
+            "{}",
+            line
+        );
+        assert_eq!(banner.missing_caps(), vec!["KVM_CAP_IOEVENTFD"]);
+
+        banner.memory_encryption = Some(MemoryEncryption::Sev);
+        assert!(banner
+            .to_string()
+            .ends_with(", memory encrypted with AMD SEV"));
+    }
 }
@@ -1,14 +1,31 @@
 use log::*;
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Component, PathBuf};
 use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 use clap::{crate_authors, crate_version, Arg, ArgAction, ArgMatches, Command};
+use nix::sched::CpuSet;
 use nix::unistd::Pid;
 
 use vmsh::attach::{self, AttachOptions};
 use vmsh::coredump::CoredumpOptions;
-use vmsh::devices::USE_IOREGIONFD;
-use vmsh::inspect::InspectOptions;
+use vmsh::devices::scripted::{self, ScriptedDeviceOptions};
+use vmsh::devices::virtio::check_queue_size;
+use vmsh::devices::{TRAP_QUEUE_NOTIFY, USE_IOREGIONFD};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use vmsh::inspect::WatchPanicOptions;
+use vmsh::inspect::{
+    AcpiOptions, BacktraceOptions, BootParamsOptions, CmdlineOptions, DescriptorTablesOptions,
+    DiffMapsOptions, ExtractOptions, InjectRegionOptions, InspectOptions, LsmodOptions,
+    LsofOptions, PsOptions, ScanFilter, ScanOptions, TaskStructOffsets, TraceFaultsOptions,
+    TraceRegOptions, UnameOptions, WatchMemOptions,
+};
+use vmsh::interrutable_thread::parse_cpu_list;
+use vmsh::kick::{self, KickOptions};
+use vmsh::kvm::hypervisor::HypervisorOptions;
+use vmsh::snapshot::{self, SnapshotOptions};
+use vmsh::tracer::mmio_record;
 use vmsh::{console, coredump, inspect};
 
 const VM_TYPES: &[&str] = &["process_id", "kubernetes", "vhive", "vhive_fc_vmid"];
@@ -39,6 +56,10 @@ fn vmid_type_arg() -> Arg {
 }
 
 fn parse_vmid_arg(args: &ArgMatches) -> Pid {
+    parse_vmid(args, "id")
+}
+
+fn parse_vmid(args: &ArgMatches, name: &str) -> Pid {
     let mut container_types = vec![];
     if args.contains_id("type") {
         container_types = args
@@ -48,7 +69,9 @@ fn parse_vmid_arg(args: &ArgMatches) -> Pid {
             .collect();
     }
 
-    let container_name = args.get_one::<String>("id").expect("`id` is required"); // safe, because container id is .required
+    let container_name = args
+        .get_one::<String>(name)
+        .unwrap_or_else(|| panic!("`{}` is required", name)); // safe, because container id is .required
     match container_pid::lookup_container_pid(container_name, &container_types) {
         Err(e) => {
             error!("{}", e);
@@ -58,6 +81,197 @@ fn parse_vmid_arg(args: &ArgMatches) -> Pid {
     }
 }
 
+fn cpus_arg() -> Arg {
+    Arg::new("cpus")
+        .long("cpus")
+        .num_args(1)
+        .value_name("CPULIST")
+        .value_parser(|s: &str| parse_cpu_list(s).map_err(|e| e.to_string()))
+        .help("Pin device threads to these CPUs, i.e. 0,2-3")
+}
+
+fn mmio_sample_arg() -> Arg {
+    Arg::new("mmio-sample")
+        .long("mmio-sample")
+        .num_args(1)
+        .value_name("N")
+        .default_value("1")
+        .value_parser(clap::value_parser!(usize))
+        .help(
+            "Only log every Nth MMIO exit (log level trace), exit rates are logged at level debug",
+        )
+}
+
+fn record_mmio_arg() -> Arg {
+    Arg::new("record-mmio")
+        .long("record-mmio")
+        .num_args(1)
+        .value_name("FILE")
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("Record all MMIO exits to FILE, show them with `vmsh mmio-record FILE`")
+}
+
+fn queue_notify_arg() -> Arg {
+    Arg::new("queue-notify")
+        .long("queue-notify")
+        .num_args(1)
+        .value_parser(clap::builder::PossibleValuesParser::new(["ioeventfd", "trap"]))
+        .default_value("ioeventfd")
+        .long_help("How devices learn about new requests of the guest with --mmio wrap_syscall. `ioeventfd` lets KVM signal an eventfd the device polls on, without an exit to the hypervisor. `trap` intercepts writes to the QueueNotify register with ptrace like all other registers, which is slower but works without KVM_CAP_IOEVENTFD. --mmio ioregionfd always traps them.")
+}
+
+fn set_queue_notify(args: &ArgMatches) {
+    TRAP_QUEUE_NOTIFY.store(
+        args.get_one::<String>("queue-notify")
+            .expect("`queue-notify` has a default")
+            == "trap",
+        Ordering::Release,
+    );
+}
+
+fn log_ioctls_arg() -> Arg {
+    Arg::new("log-ioctls")
+        .long("log-ioctls")
+        .action(ArgAction::SetTrue)
+        .help("Log every ioctl the hypervisor makes on the threads vmsh traces, with its decoded request")
+}
+
+fn vcpu_threads_only_arg() -> Arg {
+    Arg::new("vcpu-threads-only")
+        .long("vcpu-threads-only")
+        .action(ArgAction::SetTrue)
+        .help("Only trace threads running vcpus, not i.e. iothreads of the hypervisor")
+}
+
+fn data_dir_arg() -> Arg {
+    Arg::new("data-dir")
+        .long("data-dir")
+        .num_args(1)
+        .value_name("DIR")
+        .default_value("/var/lib/vmsh")
+        .value_parser(parse_data_dir)
+        .help("Directory in the root of the backing file where the root of the VM is mounted, i.e. to run multiple instances side by side")
+}
+
+fn no_inherit_limits_arg() -> Arg {
+    Arg::new("no-inherit-limits")
+        .long("no-inherit-limits")
+        .action(ArgAction::SetTrue)
+        .help("Run the command with the umask and resource limits of stage2 instead of those of the init process of the VM")
+}
+
+fn parse_data_dir(s: &str) -> Result<PathBuf, String> {
+    if !s.starts_with('/') || s.trim_end_matches('/').is_empty() {
+        return Err(format!(
+            "data directory '{}' must be an absolute path other than /",
+            s
+        ));
+    }
+    let path = PathBuf::from(s);
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(format!("data directory '{}' must not contain '..'", s));
+    }
+    Ok(path)
+}
+
+fn queue_size_arg() -> Arg {
+    Arg::new("queue-size")
+        .long("queue-size")
+        .num_args(1)
+        .value_name("N")
+        // vmsh::devices::virtio::QUEUE_MAX_SIZE
+        .default_value("256")
+        .value_parser(parse_queue_size)
+        .help("Number of descriptors in the virtqueue of the block device, a power of two. Larger queues allow more requests in flight, smaller ones use less guest memory")
+}
+
+fn parse_queue_size(s: &str) -> Result<u16, String> {
+    let size = s
+        .parse::<u16>()
+        .map_err(|e| format!("invalid queue size '{}': {}", s, e))?;
+    check_queue_size(size).map_err(|e| e.to_string())?;
+    Ok(size)
+}
+
+/// Parses decimal or 0x-prefixed hexadecimal numbers
+fn parse_number(s: &str) -> Result<usize, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse::<usize>(),
+    }
+    .map_err(|e| format!("invalid number '{}': {}", s, e))
+}
+
+/// Parses a pattern of hex bytes like `deadbeef` or `de ad be ef`
+fn parse_hex_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let digits = s.chars().filter(|c| !c.is_whitespace()).collect::<Vec<_>>();
+    if digits.len() % 2 != 0 {
+        return Err(format!("odd number of hex digits in '{}'", s));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte = pair.iter().collect::<String>();
+            u8::from_str_radix(&byte, 16).map_err(|e| format!("invalid hex byte '{}': {}", byte, e))
+        })
+        .collect()
+}
+
+/// Parses ranges like `0x1000-0x2000`, the end is exclusive
+fn parse_range(s: &str) -> Result<Range<usize>, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("invalid range '{}', expected START-END", s))?;
+    Ok(parse_number(start)?..parse_number(end)?)
+}
+
+/// Parses durations like `100ms`, `2s` or `500us`. Plain numbers are milliseconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, "ms"),
+    };
+    let num = num
+        .parse::<u64>()
+        .map_err(|e| format!("invalid duration '{}': {}", s, e))?;
+    match unit {
+        "us" => Ok(Duration::from_micros(num)),
+        "ms" => Ok(Duration::from_millis(num)),
+        "s" => Ok(Duration::from_secs(num)),
+        _ => Err(format!(
+            "invalid duration '{}': unit must be one of us, ms or s",
+            s
+        )),
+    }
+}
+
+fn phys_arg() -> Arg {
+    Arg::new("phys")
+        .long("phys")
+        .num_args(1)
+        .required(true)
+        .value_name("ADDR")
+        .value_parser(parse_number)
+        .help("Guest physical start address")
+}
+
+fn vcpu_arg() -> Arg {
+    Arg::new("vcpu")
+        .long("vcpu")
+        .num_args(1)
+        .default_value("0")
+        .value_parser(clap::value_parser!(usize))
+        .help("Index of the vcpu to inspect")
+}
+
+fn vmlinux_arg() -> Arg {
+    Arg::new("vmlinux")
+        .long("vmlinux")
+        .num_args(1)
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("Uncompressed kernel image of the guest, used to look up symbols")
+}
+
 fn command_args(index: usize) -> Arg {
     Arg::new("command")
         .help("Command to run in the VM")
@@ -69,6 +283,8 @@ fn command_args(index: usize) -> Arg {
 fn inspect(args: &ArgMatches) {
     let opts = InspectOptions {
         pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        json: args.get_one::<String>("format").map(String::as_str) == Some("json"),
     };
 
     if let Err(err) = inspect::inspect(&opts) {
@@ -77,6 +293,285 @@ fn inspect(args: &ArgMatches) {
     };
 }
 
+fn ps(args: &ArgMatches) {
+    let opts = PsOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        vmlinux: args.get_one::<PathBuf>("vmlinux").cloned(),
+        offsets: args.get_one::<TaskStructOffsets>("task-offsets").cloned(),
+    };
+
+    if let Err(err) = inspect::print_ps(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn lsmod(args: &ArgMatches) {
+    let opts = LsmodOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        vmlinux: args.get_one::<PathBuf>("vmlinux").cloned(),
+    };
+
+    if let Err(err) = inspect::print_lsmod(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn lsof(args: &ArgMatches) {
+    let opts = LsofOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        vmlinux: args.get_one::<PathBuf>("vmlinux").cloned(),
+        guest_pid: *args
+            .get_one::<i32>("guest-pid")
+            .expect("`guest-pid` is required"),
+    };
+
+    if let Err(err) = inspect::print_lsof(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn watch_panic(args: &ArgMatches) {
+    let opts = WatchPanicOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        vmlinux: args.get_one::<PathBuf>("vmlinux").cloned(),
+    };
+
+    if let Err(err) = inspect::print_panic(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn watch_panic(_args: &ArgMatches) {
+    error!("watch-panic relies on hardware breakpoints, which are only supported on x86");
+    std::process::exit(1);
+}
+
+fn backtrace(args: &ArgMatches) {
+    let opts = BacktraceOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        vcpu: *args.get_one::<usize>("vcpu").expect("`vcpu` has a default"),
+        vmlinux: args.get_one::<PathBuf>("vmlinux").cloned(),
+    };
+
+    if let Err(err) = inspect::print_backtrace(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn boot_params(args: &ArgMatches) {
+    let opts = BootParamsOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+    };
+
+    if let Err(err) = inspect::print_boot_params(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn cmdline(args: &ArgMatches) {
+    let opts = CmdlineOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        vmlinux: args.get_one::<PathBuf>("vmlinux").cloned(),
+    };
+
+    if let Err(err) = inspect::print_cmdline(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn uname(args: &ArgMatches) {
+    let opts = UnameOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        vmlinux: args.get_one::<PathBuf>("vmlinux").cloned(),
+    };
+
+    if let Err(err) = inspect::print_uname(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn acpi(args: &ArgMatches) {
+    let opts = AcpiOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+    };
+
+    if let Err(err) = inspect::print_acpi(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn mmio_record(args: &ArgMatches) {
+    let path = args.get_one::<PathBuf>("FILE").expect("`FILE` is required");
+
+    if let Err(err) = mmio_record::print_records(path) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn descriptor_tables(args: &ArgMatches) {
+    let opts = DescriptorTablesOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        vcpu: *args.get_one::<usize>("vcpu").expect("`vcpu` has a default"),
+    };
+
+    if let Err(err) = inspect::print_descriptor_tables(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn diff_maps(args: &ArgMatches) {
+    let opts = DiffMapsOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        other: parse_vmid(args, "other"),
+    };
+
+    match inspect::print_diff_maps(&opts) {
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(2);
+        }
+        // like diff(1), exit with 1 if there are differences
+        Ok(changes) if changes > 0 => std::process::exit(1),
+        Ok(_) => {}
+    };
+}
+
+fn extract(args: &ArgMatches) {
+    let opts = ExtractOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        phys_addr: *args.get_one::<usize>("phys").expect("`phys` is required"),
+        len: *args.get_one::<usize>("len").expect("`len` is required"),
+        path: args
+            .get_one::<PathBuf>("out")
+            .expect("`out` is required")
+            .clone(),
+    };
+
+    if let Err(err) = inspect::extract_region(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn scan(args: &ArgMatches) {
+    let pattern = match args.get_one::<Vec<u8>>("hex") {
+        Some(bytes) => bytes.clone(),
+        None => args
+            .get_one::<String>("string")
+            .expect("either `hex` or `string` is required")
+            .as_bytes()
+            .to_vec(),
+    };
+    let opts = ScanOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        pattern,
+        filter: ScanFilter {
+            writable_only: args.get_flag("writable"),
+            phys_range: args.get_one::<Range<usize>>("phys-range").cloned(),
+            dedup_aliases: args.get_flag("dedup"),
+        },
+        live: args.get_flag("live"),
+    };
+
+    if let Err(err) = inspect::print_scan(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn watch(args: &ArgMatches) {
+    let opts = WatchMemOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        phys_addr: *args.get_one::<usize>("phys").expect("`phys` is required"),
+        len: *args.get_one::<usize>("len").expect("`len` has a default"),
+        interval: *args
+            .get_one::<Duration>("interval")
+            .expect("`interval` has a default"),
+    };
+
+    if let Err(err) = inspect::print_watch_mem(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn trace_faults(args: &ArgMatches) {
+    let opts = TraceFaultsOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        phys_range: args.get_one::<Range<usize>>("phys-range").cloned(),
+        duration: *args
+            .get_one::<Duration>("duration")
+            .expect("`duration` has a default"),
+    };
+
+    if let Err(err) = inspect::print_trace_faults(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn trace_reg(args: &ArgMatches) {
+    let opts = TraceRegOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        phys_addr: *args.get_one::<usize>("phys").expect("`phys` is required") as u64,
+        width: *args
+            .get_one::<usize>("width")
+            .expect("`width` has a default") as u64,
+    };
+
+    if let Err(err) = inspect::print_trace_reg(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn inject_region(args: &ArgMatches) {
+    let opts = InjectRegionOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        phys_addr: *args.get_one::<usize>("phys").expect("`phys` is required"),
+        path: args
+            .get_one::<PathBuf>("file")
+            .expect("`file` is required")
+            .clone(),
+        force: args.get_flag("force"),
+    };
+
+    if let Err(err) = inspect::inject_region_from_file(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
 fn attach_options(args: &ArgMatches) -> AttachOptions {
     let mut command = args
         .get_many::<String>("command")
@@ -89,6 +584,7 @@ fn attach_options(args: &ArgMatches) -> AttachOptions {
 
     AttachOptions {
         pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
         command: command.into_iter().map(Clone::clone).collect::<Vec<_>>(),
         backing: args
             .get_one::<PathBuf>("backing-file")
@@ -97,6 +593,21 @@ fn attach_options(args: &ArgMatches) -> AttachOptions {
         pts: args
             .get_one::<Option<PathBuf>>("pts")
             .map_or_else(|| None, Clone::clone),
+        cpus: args.get_one::<CpuSet>("cpus").cloned(),
+        mmio_sample: *args
+            .get_one::<usize>("mmio-sample")
+            .expect("`mmio-sample` has a default"),
+        record_mmio: args.get_one::<PathBuf>("record-mmio").cloned(),
+        log_ioctls: args.get_flag("log-ioctls"),
+        vcpu_threads_only: args.get_flag("vcpu-threads-only"),
+        queue_size: *args
+            .get_one::<u16>("queue-size")
+            .expect("`queue-size` has a default"),
+        data_dir: args
+            .get_one::<PathBuf>("data-dir")
+            .expect("`data-dir` has a default")
+            .clone(),
+        inherit_limits: !args.get_flag("no-inherit-limits"),
     }
 }
 
@@ -106,8 +617,57 @@ fn attach(args: &ArgMatches) {
         args.get_one::<String>("mmio").expect("`mmio` is required") == "ioregionfd",
         Ordering::Release,
     );
+    set_queue_notify(args);
 
-    if let Err(err) = attach::attach(&opts) {
+    let attachment = match attach::attach(&opts) {
+        Ok(Some(attachment)) => attachment,
+        Ok(None) => return,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    for dev in &attachment.devices {
+        info!(
+            "attached {} device at {:#x} (irq {})",
+            dev.name, dev.mmio_addr, dev.irq
+        );
+    }
+    if let Some(pts) = &attachment.pts {
+        info!("console is connected to {}", pts.display());
+    }
+    // stay attached until we are interrupted or a device fails
+    attachment.wait();
+    if let Err(err) = attachment.detach() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn scripted_device(args: &ArgMatches) {
+    let opts = ScriptedDeviceOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+        config: args
+            .get_one::<PathBuf>("CONFIG")
+            .expect("`CONFIG` is required")
+            .clone(),
+        record: args.get_one::<PathBuf>("record-mmio").cloned(),
+    };
+
+    if let Err(err) = scripted::run_scripted_device(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn kick(args: &ArgMatches) {
+    let opts = KickOptions {
+        pid: parse_vmid_arg(args),
+        hypervisor: hypervisor_options(args),
+    };
+
+    if let Err(err) = kick::kick(&opts) {
         error!("{}", err);
         std::process::exit(1);
     };
@@ -115,13 +675,37 @@ fn attach(args: &ArgMatches) {
 
 fn coredump(args: &ArgMatches) {
     let pid = parse_vmid_arg(args);
-    let path = args
-        .get_one::<PathBuf>("PATH")
-        .map_or_else(|| PathBuf::from(format!("core.{}", pid)), Clone::clone);
+    let compress = args.get_flag("compress");
+    let path = args.get_one::<PathBuf>("PATH").map_or_else(
+        || {
+            let suffix = if compress { ".gz" } else { "" };
+            PathBuf::from(format!("core.{}{}", pid, suffix))
+        },
+        Clone::clone,
+    );
 
-    let opts = CoredumpOptions { pid, path };
+    let opts = CoredumpOptions {
+        pid,
+        hypervisor: hypervisor_options(args),
+        path,
+        compress,
+    };
 
-    if let Err(err) = coredump::generate_coredump(&opts) {
+    // log every 10%, dumping gigabytes of memory takes a while
+    let mut reported = 0;
+    let mut progress = |written: usize, total: usize| {
+        let percent = written * 100 / total.max(1);
+        if percent >= reported + 10 {
+            info!(
+                "wrote {} of {} MiB of guest memory ({}%)",
+                written >> 20,
+                total >> 20,
+                percent
+            );
+            reported = percent;
+        }
+    };
+    if let Err(err) = coredump::generate_coredump(&opts, Some(&mut progress)) {
         error!("{}", err);
         std::process::exit(1);
     };
@@ -129,12 +713,50 @@ fn coredump(args: &ArgMatches) {
 
 fn console(args: &ArgMatches) {
     let opts = attach_options(args);
+    set_queue_notify(args);
     if let Err(err) = console::console(&opts) {
         error!("{}", err);
         std::process::exit(1);
     };
 }
 
+fn snapshot(args: &ArgMatches) {
+    let (action, sub_matches) = args.subcommand().expect("subcommand is required");
+    let opts = SnapshotOptions {
+        pid: parse_vmid_arg(sub_matches),
+        // --vm is global, it might have been passed after the nested subcommand
+        hypervisor: hypervisor_options(sub_matches),
+        dir: sub_matches
+            .get_one::<PathBuf>("DIR")
+            .expect("`DIR` is required")
+            .clone(),
+    };
+    let res = match action {
+        "save" => snapshot::save(&opts),
+        "restore" => snapshot::restore(&opts),
+        _ => unreachable!(),
+    };
+    if let Err(err) = res {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn snapshot_dir_arg() -> Arg {
+    Arg::new("DIR")
+        .help("directory holding the snapshot")
+        .required(true)
+        .value_parser(clap::value_parser!(PathBuf))
+        .index(2)
+}
+
+fn hypervisor_options(args: &ArgMatches) -> HypervisorOptions {
+    HypervisorOptions {
+        vm: args.get_one::<usize>("vm").copied(),
+        borrow_scratch: args.get_flag("borrow-scratch"),
+    }
+}
+
 fn setup_logging(matches: &clap::ArgMatches) {
     if matches.contains_id("verbose") {
         env_logger::Builder::new().parse_filters("debug").init();
@@ -168,13 +790,296 @@ fn cli() -> Command {
              .short('l')
              .num_args(1)
              .help("Finegrained verbosity control. See docs.rs/env_logger. Examples: [error, warn, info, debug, trace]"))
+        .arg(Arg::new("vm")
+             .long("vm")
+             .global(true)
+             .num_args(1)
+             .value_name("INDEX")
+             .value_parser(clap::value_parser!(usize))
+             .help("VM to use if the hypervisor process holds more than one KVM VM. vmsh lists them if this is needed."))
+        .arg(Arg::new("borrow-scratch")
+             .long("borrow-scratch")
+             .global(true)
+             .action(ArgAction::SetTrue)
+             .help("If the hypervisor cannot map memory for ioctl arguments, i.e. because seccomp denies mmap, borrow a page of its stack instead. The content is saved and restored, but the hypervisor's memory is modified while vmsh is attached."))
         .subcommand(
             Command::new("inspect")
             .about("Inspect a virtual machine.")
             .version(crate_version!())
             .author(crate_authors!("\n"))
             .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(
+                Arg::new("format")
+                .long("format")
+                .num_args(1)
+                .value_parser(clap::builder::PossibleValuesParser::new(["text", "json"]))
+                .default_value("text")
+                .help("Output format, json prints pid, vcpus and memory mappings (requires the serde feature)"),
+                ))
+        .subcommand(
+            Command::new("ps")
+            .about("List processes running in a virtual machine.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(vmlinux_arg())
+            .arg(
+                Arg::new("task-offsets")
+                .long("task-offsets")
+                .num_args(1)
+                .value_parser(clap::value_parser!(TaskStructOffsets))
+                .help("Offsets of task_struct members, i.e. tasks=0x3a8,pid=0x4a8,comm=0x6b8,state=0x10"),
+                ))
+        .subcommand(
+            Command::new("lsmod")
+            .about("List kernel modules loaded in a virtual machine.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(vmlinux_arg()))
+        .subcommand(
+            Command::new("lsof")
+            .about("List the open files of a process in a virtual machine.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(
+                Arg::new("guest-pid")
+                .help("Pid of the process inside the guest")
+                .required(true)
+                .value_parser(clap::value_parser!(i32))
+                .index(2))
+            .arg(vmlinux_arg()))
+        .subcommand(
+            Command::new("watch-panic")
+            .about("Wait for the guest kernel to panic and print the panic message and registers.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(vmlinux_arg()))
+        .subcommand(
+            Command::new("backtrace")
+            .about("Print the kernel call chain a vcpu is currently executing.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(vcpu_arg())
+            .arg(vmlinux_arg()))
+        .subcommand(
+            Command::new("boot-params")
+            .about("Decode the boot_params (zero page) including the e820 memory map the guest was booted with.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg()))
+        .subcommand(
+            Command::new("cmdline")
+            .about("Print the command line the guest kernel was booted with.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(vmlinux_arg()))
+        .subcommand(
+            Command::new("uname")
+            .about("Print the version banner of the guest kernel, like /proc/version.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(vmlinux_arg()))
+        .subcommand(
+            Command::new("descriptor-tables")
+            .about("Dump the global and interrupt descriptor tables of a vcpu.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(vcpu_arg()))
+        .subcommand(
+            Command::new("acpi")
+            .about("List the ACPI tables of the guest and decode its MADT.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
             .arg(vmid_type_arg()))
+        .subcommand(
+            Command::new("mmio-record")
+            .about("Print MMIO exits recorded with --record-mmio.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(
+                Arg::new("FILE")
+                .help("Recording to read")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .index(1)
+            ))
+        .subcommand(
+            Command::new("diff-maps")
+            .about("Compare the guest memory layout of two virtual machines.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(
+                Arg::new("other")
+                .help("VM/Hypervisor pid or pod name to compare against")
+                .required(true)
+                .index(2))
+            .arg(vmid_type_arg()))
+        .subcommand(
+            Command::new("extract")
+            .about("Copy a range of guest physical memory to a file.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(phys_arg())
+            .arg(
+                Arg::new("len")
+                .long("len")
+                .num_args(1)
+                .required(true)
+                .value_name("N")
+                .value_parser(parse_number)
+                .help("Number of bytes to copy"))
+            .arg(
+                Arg::new("out")
+                .long("out")
+                .short('o')
+                .num_args(1)
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("File to write the memory to")))
+        .subcommand(
+            Command::new("scan")
+            .about("Print guest physical addresses where a byte pattern occurs in guest memory.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(
+                Arg::new("string")
+                .long("string")
+                .num_args(1)
+                .value_name("TEXT")
+                .required_unless_present("hex")
+                .conflicts_with("hex")
+                .help("Search for the bytes of TEXT"))
+            .arg(
+                Arg::new("hex")
+                .long("hex")
+                .num_args(1)
+                .value_name("BYTES")
+                .value_parser(parse_hex_bytes)
+                .help("Search for hex bytes, i.e. deadbeef"))
+            .arg(
+                Arg::new("writable")
+                .long("writable")
+                .action(ArgAction::SetTrue)
+                .help("Only search memory the hypervisor maps writable, skipping ROMs"))
+            .arg(
+                Arg::new("phys-range")
+                .long("phys-range")
+                .num_args(1)
+                .value_name("START-END")
+                .value_parser(parse_range)
+                .help("Only search guest physical addresses in this range"))
+            .arg(
+                Arg::new("dedup")
+                .long("dedup")
+                .action(ArgAction::SetTrue)
+                .help("Report memory mapped at multiple guest physical addresses only once"))
+            .arg(
+                Arg::new("live")
+                .long("live")
+                .action(ArgAction::SetTrue)
+                .help("Keep the guest running while scanning. It is still stopped once, for the single injected ioctl that looks up its memory layout. Best-effort: memory changing during the scan can hide matches or report stale ones")))
+        .subcommand(
+            Command::new("watch")
+            .about("Print a guest physical memory location whenever it changes.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(phys_arg())
+            .arg(
+                Arg::new("len")
+                .long("len")
+                .num_args(1)
+                .default_value("8")
+                .value_name("N")
+                .value_parser(parse_number)
+                .help("Number of bytes to watch"))
+            .arg(
+                Arg::new("interval")
+                .long("interval")
+                .num_args(1)
+                .default_value("100ms")
+                .value_parser(parse_duration)
+                .help("How often to read the memory, i.e. 100ms or 1s")))
+        .subcommand(
+            Command::new("trace-faults")
+            .about("Print the guest physical pages the guest writes to within a time window. Pages that are only read are not seen.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(
+                Arg::new("phys-range")
+                .long("phys-range")
+                .num_args(1)
+                .value_name("START-END")
+                .value_parser(parse_range)
+                .help("Only report pages in this guest physical range"))
+            .arg(
+                Arg::new("duration")
+                .long("duration")
+                .num_args(1)
+                .default_value("1s")
+                .value_parser(parse_duration)
+                .help("How long to let the guest run while tracing, i.e. 500ms or 10s")))
+        .subcommand(
+            Command::new("trace-reg")
+            .about("Print every guest write to an MMIO register with a timestamp until interrupted.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(phys_arg())
+            .arg(
+                Arg::new("width")
+                .long("width")
+                .num_args(1)
+                .default_value("4")
+                .value_name("N")
+                .value_parser(parse_number)
+                .help("Number of bytes from ADDR to trace, larger values also cover adjacent registers")))
+        .subcommand(
+            Command::new("inject-region")
+            .about("Copy a file into guest physical memory.")
+            .version(crate_version!())
+            .author(crate_authors!("\n"))
+            .arg(vmid_arg(1))
+            .arg(
+                Arg::new("file")
+                .help("File to copy into the guest")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .index(2))
+            .arg(vmid_type_arg())
+            .arg(phys_arg())
+            .arg(
+                Arg::new("force")
+                .long("force")
+                .action(ArgAction::SetTrue)
+                .help("Also write to memory the hypervisor mapped read-only")))
         .subcommand(Command::new("attach")
                     .about("Attach (a block device) to a virtual machine.")
                     .version(crate_version!())
@@ -188,6 +1093,8 @@ fn cli() -> Command {
                         .default_value("/dev/.vmsh")
                         .help("Path where Stage2 is written to in the VM"),
                         )
+                    .arg(data_dir_arg())
+                    .arg(no_inherit_limits_arg())
                     .arg(command_args(2))
                     .arg(
                         Arg::new("backing-file")
@@ -213,7 +1120,38 @@ fn cli() -> Command {
                         .value_parser(clap::value_parser!(PathBuf))
                         .help("Pseudoterminal seat to use for the command run in the VM. Use this when interactivity is required. ")
                         )
+                    .arg(cpus_arg())
+                    .arg(mmio_sample_arg())
+                    .arg(record_mmio_arg())
+                    .arg(log_ioctls_arg())
+                    .arg(queue_notify_arg())
+                    .arg(vcpu_threads_only_arg())
+                    .arg(queue_size_arg())
        )
+        .subcommand(
+            Command::new("scripted-device")
+                    .about("Serve a fake MMIO device described by a config file to the guest until interrupted.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .arg(
+                        Arg::new("CONFIG")
+                        .help("registers of the device and what reads of them return, see src/devices/scripted.rs for the format")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .index(2)
+                    )
+                    .arg(record_mmio_arg())
+        )
+        .subcommand(
+            Command::new("kick")
+                    .about("Resume a virtual machine that a crashed or killed vmsh left stopped.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+        )
         .subcommand(
             Command::new("coredump")
                     .about("Get a coredump of a virtual machine.")
@@ -223,10 +1161,37 @@ fn cli() -> Command {
                     .arg(vmid_type_arg())
                     .arg(
                         Arg::new("PATH")
-                        .help("path to coredump. Defaults to core.${pid}")
+                        .help("path to coredump, - for stdout, i.e. to pipe it into another program. Defaults to core.${pid}")
                         .value_parser(clap::value_parser!(PathBuf))
                         .index(2)
                     )
+                    .arg(
+                        Arg::new("compress")
+                        .long("compress")
+                        .action(ArgAction::SetTrue)
+                        .help("gzip the coredump while writing it, gunzip it before loading it in gdb")
+                    )
+        )
+        .subcommand(
+            Command::new("snapshot")
+                    .about("Save or restore guest memory and vcpu state of a virtual machine.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .subcommand_required(true)
+                    .subcommand(
+                        Command::new("save")
+                        .about("Write guest RAM and the state of all vcpus to a new directory.")
+                        .arg(vmid_arg(1))
+                        .arg(vmid_type_arg())
+                        .arg(snapshot_dir_arg())
+                    )
+                    .subcommand(
+                        Command::new("restore")
+                        .about("Load a snapshot into a virtual machine with the same memory layout and number of vcpus.")
+                        .arg(vmid_arg(1))
+                        .arg(vmid_type_arg())
+                        .arg(snapshot_dir_arg())
+                    )
         )
         .subcommand(
             Command::new("console")
@@ -242,6 +1207,8 @@ fn cli() -> Command {
                         .default_value("/dev/.vmsh")
                         .help("Path where Stage2 is written to in the VM"),
                         )
+                    .arg(data_dir_arg())
+                    .arg(no_inherit_limits_arg())
                     .arg(command_args(2))
                     .arg(
                         Arg::new("backing-file")
@@ -257,6 +1224,13 @@ fn cli() -> Command {
                         .num_args(1)
                         .help("Pseudoterminal seat to use for the command run in the VM. Use this when interactivity is required. ")
                     )
+                    .arg(cpus_arg())
+                    .arg(mmio_sample_arg())
+                    .arg(record_mmio_arg())
+                    .arg(log_ioctls_arg())
+                    .arg(queue_notify_arg())
+                    .arg(vcpu_threads_only_arg())
+                    .arg(queue_size_arg())
         )
 }
 
@@ -265,8 +1239,29 @@ fn main() {
     setup_logging(&matches);
     match matches.subcommand() {
         Some(("inspect", sub_matches)) => inspect(sub_matches),
+        Some(("ps", sub_matches)) => ps(sub_matches),
+        Some(("lsmod", sub_matches)) => lsmod(sub_matches),
+        Some(("lsof", sub_matches)) => lsof(sub_matches),
+        Some(("watch-panic", sub_matches)) => watch_panic(sub_matches),
+        Some(("backtrace", sub_matches)) => backtrace(sub_matches),
+        Some(("boot-params", sub_matches)) => boot_params(sub_matches),
+        Some(("cmdline", sub_matches)) => cmdline(sub_matches),
+        Some(("uname", sub_matches)) => uname(sub_matches),
+        Some(("descriptor-tables", sub_matches)) => descriptor_tables(sub_matches),
+        Some(("acpi", sub_matches)) => acpi(sub_matches),
+        Some(("mmio-record", sub_matches)) => mmio_record(sub_matches),
+        Some(("diff-maps", sub_matches)) => diff_maps(sub_matches),
+        Some(("extract", sub_matches)) => extract(sub_matches),
+        Some(("scan", sub_matches)) => scan(sub_matches),
+        Some(("watch", sub_matches)) => watch(sub_matches),
+        Some(("trace-faults", sub_matches)) => trace_faults(sub_matches),
+        Some(("trace-reg", sub_matches)) => trace_reg(sub_matches),
+        Some(("inject-region", sub_matches)) => inject_region(sub_matches),
         Some(("attach", sub_matches)) => attach(sub_matches),
         Some(("coredump", sub_matches)) => coredump(sub_matches),
+        Some(("kick", sub_matches)) => kick(sub_matches),
+        Some(("scripted-device", sub_matches)) => scripted_device(sub_matches),
+        Some(("snapshot", sub_matches)) => snapshot(sub_matches),
         Some(("console", sub_matches)) => console(sub_matches),
         Some((_, _)) => unreachable!(),
         None => unreachable!(),
@@ -276,8 +1271,13 @@ fn main() {
 #[cfg(test)]
 mod tests {
 
-    use super::VM_TYPES;
+    use super::{
+        cli, parse_cpu_list, parse_data_dir, parse_duration, parse_hex_bytes, parse_queue_size,
+        parse_range, VM_TYPES,
+    };
     use container_pid::AVAILABLE_CONTAINER_TYPES;
+    use std::path::PathBuf;
+    use std::time::Duration;
 
     #[test]
     fn test_container_pid_compat() {
@@ -285,4 +1285,70 @@ mod tests {
             assert!(AVAILABLE_CONTAINER_TYPES.contains(t));
         }
     }
+
+    #[test]
+    fn test_cli() {
+        cli().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("100ms"), Ok(Duration::from_millis(100)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("500us"), Ok(Duration::from_micros(500)));
+        assert_eq!(parse_duration("250"), Ok(Duration::from_millis(250)));
+        assert!(parse_duration("1h").is_err());
+        assert!(parse_duration("ms").is_err());
+    }
+
+    #[test]
+    fn test_parse_scan_args() {
+        assert_eq!(
+            parse_hex_bytes("deadbeef"),
+            Ok(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(parse_hex_bytes("de ad"), Ok(vec![0xde, 0xad]));
+        assert!(parse_hex_bytes("abc").is_err());
+        assert!(parse_hex_bytes("zz").is_err());
+        assert_eq!(parse_range("0x1000-8192"), Ok(0x1000..0x2000));
+        assert!(parse_range("0x1000").is_err());
+    }
+
+    #[test]
+    fn test_parse_queue_size() {
+        assert_eq!(parse_queue_size("1024"), Ok(1024));
+        assert!(parse_queue_size("1000").is_err());
+        assert!(parse_queue_size("65536").is_err());
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        let cpus = parse_cpu_list("0,2-3").expect("cannot parse cpu list");
+        let set = (0..5)
+            .map(|cpu| cpus.is_set(cpu).expect("cpu out of range"))
+            .collect::<Vec<_>>();
+        assert_eq!(set, vec![true, false, true, true, false]);
+
+        let single = parse_cpu_list(" 5 ").expect("cannot parse single cpu");
+        assert!(single.is_set(5).expect("cpu out of range"));
+        assert!(!single.is_set(4).expect("cpu out of range"));
+
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+        assert!(parse_cpu_list("1-").is_err());
+        assert!(parse_cpu_list("0,,1").is_err());
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("100000").is_err());
+    }
+
+    #[test]
+    fn test_parse_data_dir() {
+        assert_eq!(
+            parse_data_dir("/var/lib/vmsh2"),
+            Ok(PathBuf::from("/var/lib/vmsh2"))
+        );
+        assert!(parse_data_dir("var/lib/vmsh").is_err());
+        assert!(parse_data_dir("//").is_err());
+        assert!(parse_data_dir("/var/../..").is_err());
+    }
 }
@@ -0,0 +1,261 @@
+//! Parser for the BPF Type Format (BTF) as found in the `.BTF` section of vmlinux.
+//! See Documentation/bpf/btf.rst in the linux source tree.
+
+use simple_error::{bail, require_with};
+use std::convert::TryInto;
+
+use crate::result::Result;
+
+const BTF_MAGIC: u16 = 0xeb9f;
+
+const BTF_KIND_INT: u32 = 1;
+const BTF_KIND_PTR: u32 = 2;
+const BTF_KIND_ARRAY: u32 = 3;
+const BTF_KIND_STRUCT: u32 = 4;
+const BTF_KIND_UNION: u32 = 5;
+const BTF_KIND_ENUM: u32 = 6;
+const BTF_KIND_FWD: u32 = 7;
+const BTF_KIND_TYPEDEF: u32 = 8;
+const BTF_KIND_VOLATILE: u32 = 9;
+const BTF_KIND_CONST: u32 = 10;
+const BTF_KIND_RESTRICT: u32 = 11;
+const BTF_KIND_FUNC: u32 = 12;
+const BTF_KIND_FUNC_PROTO: u32 = 13;
+const BTF_KIND_VAR: u32 = 14;
+const BTF_KIND_DATASEC: u32 = 15;
+const BTF_KIND_FLOAT: u32 = 16;
+const BTF_KIND_DECL_TAG: u32 = 17;
+const BTF_KIND_TYPE_TAG: u32 = 18;
+const BTF_KIND_ENUM64: u32 = 19;
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name_off: u32,
+    pub type_id: u32,
+    /// offset in bits from the start of the struct
+    pub bit_offset: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Type {
+    pub name_off: u32,
+    pub kind: u32,
+    /// size for structs, unions, ints, enums. Referenced type id for pointers, typedefs, etc.
+    pub size_or_type: u32,
+    /// only set for structs and unions
+    pub members: Vec<Member>,
+}
+
+/// Type information of a kernel. Type ids are indices into `types`, id 0 is `void`.
+pub struct Btf {
+    types: Vec<Type>,
+    strings: Vec<u8>,
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = require_with!(
+            self.data.get(self.pos..self.pos + 4),
+            "BTF type section is truncated at {}",
+            self.pos
+        );
+        self.pos += 4;
+        Ok(u32::from_le_bytes(
+            bytes.try_into().expect("slice has 4 bytes"),
+        ))
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        if self.pos + len > self.data.len() {
+            bail!("BTF type section is truncated at {}", self.pos);
+        }
+        self.pos += len;
+        Ok(())
+    }
+}
+
+fn header_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = require_with!(data.get(offset..offset + 4), "BTF header is truncated");
+    Ok(u32::from_le_bytes(
+        bytes.try_into().expect("slice has 4 bytes"),
+    ))
+}
+
+impl Btf {
+    pub fn parse(data: &[u8]) -> Result<Btf> {
+        if data.len() < 24 {
+            bail!("BTF header is truncated");
+        }
+        let magic = u16::from_le_bytes([data[0], data[1]]);
+        if magic == BTF_MAGIC.swap_bytes() {
+            bail!("big endian BTF is not supported");
+        } else if magic != BTF_MAGIC {
+            bail!("invalid BTF magic {:#x}", magic);
+        }
+        let hdr_len = header_u32(data, 4)? as usize;
+        let type_off = header_u32(data, 8)? as usize;
+        let type_len = header_u32(data, 12)? as usize;
+        let str_off = header_u32(data, 16)? as usize;
+        let str_len = header_u32(data, 20)? as usize;
+
+        let type_data = require_with!(
+            data.get(hdr_len + type_off..hdr_len + type_off + type_len),
+            "BTF type section is out of bounds"
+        );
+        let strings = require_with!(
+            data.get(hdr_len + str_off..hdr_len + str_off + str_len),
+            "BTF string section is out of bounds"
+        );
+
+        // type id 0 is void
+        let mut types = vec![Type {
+            name_off: 0,
+            kind: 0,
+            size_or_type: 0,
+            members: vec![],
+        }];
+        let mut cursor = Cursor {
+            data: type_data,
+            pos: 0,
+        };
+        while cursor.pos < type_data.len() {
+            let name_off = cursor.u32()?;
+            let info = cursor.u32()?;
+            let size_or_type = cursor.u32()?;
+            let vlen = (info & 0xffff) as usize;
+            let kind = (info >> 24) & 0x1f;
+            let kind_flag = info >> 31 != 0;
+            let mut members = vec![];
+            match kind {
+                BTF_KIND_INT | BTF_KIND_VAR | BTF_KIND_DECL_TAG => cursor.skip(4)?,
+                BTF_KIND_ARRAY => cursor.skip(12)?,
+                BTF_KIND_STRUCT | BTF_KIND_UNION => {
+                    for _ in 0..vlen {
+                        let name_off = cursor.u32()?;
+                        let type_id = cursor.u32()?;
+                        let offset = cursor.u32()?;
+                        // with kind_flag set, the upper 8 bits hold the bitfield size
+                        let bit_offset = if kind_flag {
+                            offset & 0xff_ffff
+                        } else {
+                            offset
+                        };
+                        members.push(Member {
+                            name_off,
+                            type_id,
+                            bit_offset,
+                        });
+                    }
+                }
+                BTF_KIND_ENUM | BTF_KIND_FUNC_PROTO => cursor.skip(8 * vlen)?,
+                BTF_KIND_DATASEC | BTF_KIND_ENUM64 => cursor.skip(12 * vlen)?,
+                BTF_KIND_PTR | BTF_KIND_FWD | BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE
+                | BTF_KIND_CONST | BTF_KIND_RESTRICT | BTF_KIND_FUNC | BTF_KIND_FLOAT
+                | BTF_KIND_TYPE_TAG => {}
+                _ => bail!("unknown BTF kind {} for type {}", kind, types.len()),
+            }
+            types.push(Type {
+                name_off,
+                kind,
+                size_or_type,
+                members,
+            });
+        }
+
+        Ok(Btf {
+            types,
+            strings: strings.to_vec(),
+        })
+    }
+
+    pub fn name(&self, name_off: u32) -> &str {
+        let s = self.strings.get(name_off as usize..).unwrap_or(&[]);
+        let len = s.iter().position(|c| *c == 0).unwrap_or(s.len());
+        std::str::from_utf8(&s[..len]).unwrap_or("")
+    }
+
+    pub fn find_struct(&self, name: &str) -> Option<&Type> {
+        self.types
+            .iter()
+            .find(|t| t.kind == BTF_KIND_STRUCT && self.name(t.name_off) == name)
+    }
+
+    /// Bit offset of `member` within the struct or union `ty`, descending into anonymous
+    /// structs and unions.
+    fn member_bit_offset(&self, ty: &Type, member: &str) -> Option<u32> {
+        for m in &ty.members {
+            if m.name_off != 0 {
+                if self.name(m.name_off) == member {
+                    return Some(m.bit_offset);
+                }
+                continue;
+            }
+            let inner = self.types.get(m.type_id as usize)?;
+            if inner.kind == BTF_KIND_STRUCT || inner.kind == BTF_KIND_UNION {
+                if let Some(offset) = self.member_bit_offset(inner, member) {
+                    return Some(m.bit_offset + offset);
+                }
+            }
+        }
+        None
+    }
+
+    /// Byte offset of `member` in `struct <name>`.
+    pub fn offset_of(&self, name: &str, member: &str) -> Result<usize> {
+        let ty = require_with!(self.find_struct(name), "no struct {} in BTF", name);
+        let bits = require_with!(
+            self.member_bit_offset(ty, member),
+            "struct {} has no member {}",
+            name,
+            member
+        );
+        if bits % 8 != 0 {
+            bail!("{}.{} is a bitfield", name, member);
+        }
+        Ok((bits / 8) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Btf;
+
+    fn u32s(v: &[u32]) -> Vec<u8> {
+        v.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_offset_of() {
+        let strings = b"\0int\0task\0pid\0comm\0".to_vec();
+        let types = u32s(&[
+            // [1] int, size 4
+            1, 1 << 24, 4, 32,
+            // [2] anonymous struct { int pid @ 0x20 }
+            0, (4 << 24) | 1, 8, 10, 1, 0x20 * 8,
+            // [3] struct task { int comm @ 0x10; struct { ... } @ 0x100 }
+            5, (4 << 24) | 2, 0x200, 14, 1, 0x10 * 8, 0, 2, 0x100 * 8,
+        ]);
+        let mut data = vec![0x9f, 0xeb, 1, 0];
+        data.extend(u32s(&[
+            24,
+            0,
+            types.len() as u32,
+            types.len() as u32,
+            strings.len() as u32,
+        ]));
+        data.extend(types);
+        data.extend(strings);
+
+        let btf = Btf::parse(&data).expect("cannot parse btf");
+        assert_eq!(btf.offset_of("task", "comm").expect("no comm"), 0x10);
+        assert_eq!(btf.offset_of("task", "pid").expect("no pid"), 0x120);
+        assert!(btf.offset_of("task", "state").is_err());
+        assert!(btf.offset_of("mm_struct", "pgd").is_err());
+    }
+}
@@ -40,9 +40,10 @@ pub fn console(attach: &AttachOptions) -> Result<()> {
     );
     println!("Run the following command in a different terminal");
     let mut attach_cmd = vec![format!(
-        "vmsh attach --pts {} --backing-file {} {} --",
+        "vmsh attach --pts {} --backing-file {} --data-dir {} {} --",
         res.as_path().display(),
         attach.backing.display(),
+        attach.data_dir.display(),
         attach.pid
     )];
     for arg in &attach.command[1..] {
@@ -1,17 +1,17 @@
 use crate::cpu::{FpuRegs, Regs};
 use crate::kvm::hypervisor::VCPU;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use kvm_bindings as kvmb;
 use libc::{off_t, timeval, PT_LOAD, PT_NOTE};
-use nix::sys::{
-    mman::{mmap, MapFlags, ProtFlags},
-    uio::{process_vm_readv, RemoteIoVec},
-};
+use log::debug;
+use nix::sys::mman::{mmap, MapFlags, ProtFlags};
 use nix::unistd::Pid;
-use simple_error::{require_with, try_with};
+use simple_error::{bail, require_with, try_with};
 use std::fs::OpenOptions;
-use std::io::IoSliceMut;
+use std::io::{self, BufWriter};
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{fs::File, io::Write, ptr, slice::from_raw_parts_mut};
 use std::{mem::size_of, os::unix::prelude::AsRawFd};
 
@@ -20,14 +20,23 @@ use crate::elf::{
     Phdr, Shdr, ELFARCH, ELFCLASS, ELFDATA2, ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3, ELF_NGREG,
     ET_CORE, EV_CURRENT, NT_PRPSINFO, NT_PRSTATUS, NT_PRXREG, PF_W, PF_X, SHN_UNDEF,
 };
-use crate::kvm::hypervisor::Hypervisor;
+use crate::kvm;
+use crate::kvm::hypervisor::memory::process_read_slice;
+use crate::kvm::hypervisor::{Hypervisor, HypervisorOptions};
 use crate::page_math::{page_align, page_size};
 use crate::result::Result;
-use crate::{kvm, tracer::proc::Mapping};
+use crate::tracer::proc::{coalesce_mappings, Mapping};
+
+/// Guest memory is copied to coredumps in chunks of this size
+const CHUNK_SIZE: usize = 1 << 20;
 
 pub struct CoredumpOptions {
     pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    /// `-` for stdout
     pub path: PathBuf,
+    /// gzip the coredump while writing it. It needs to be decompressed before gdb can load it.
+    pub compress: bool,
 }
 
 #[repr(C)]
@@ -60,12 +69,27 @@ unsafe fn any_as_bytes<T: Sized>(p: &T) -> &[u8] {
     std::slice::from_raw_parts((p as *const T) as *const u8, size_of::<T>())
 }
 
+/// Fill `dst` with hypervisor memory starting at `addr`
+fn read_chunk(pid: Pid, addr: usize, dst: &mut [u8]) -> Result<()> {
+    try_with!(
+        process_read_slice(pid, addr, dst),
+        "cannot read hypervisor memory"
+    );
+    Ok(())
+}
+
+/// Number of bytes of guest memory in a coredump of `maps`
+fn mappings_size(maps: &[Mapping]) -> usize {
+    maps.iter().map(|m| m.size()).sum()
+}
+
 fn dump_mappings(
     pid: Pid,
     core_file: &mut File,
     core_size: off_t,
     file_offset: off_t,
     maps: &[Mapping],
+    mut progress: Option<&mut dyn FnMut(usize, usize)>,
 ) -> Result<()> {
     let buf_size = core_size - file_offset;
     let buf_size = require_with!(
@@ -86,19 +110,20 @@ fn dump_mappings(
     let raw_buf = try_with!(res, "cannot mmap core file");
     let buf = unsafe { from_raw_parts_mut(raw_buf as *mut u8, buf_size.get()) };
 
-    let mut dst_iovs = vec![IoSliceMut::new(buf)];
-    let src_iovs = maps
-        .iter()
-        .map(|m| RemoteIoVec {
-            base: m.start,
-            len: m.size(),
-        })
-        .collect::<Vec<_>>();
-
-    try_with!(
-        process_vm_readv(pid, dst_iovs.as_mut_slice(), src_iovs.as_slice()),
-        "cannot read hypervisor memory"
-    );
+    let total = mappings_size(maps);
+    let mut written = 0;
+    for m in maps {
+        let mut done = 0;
+        while done < m.size() {
+            let len = (m.size() - done).min(CHUNK_SIZE);
+            read_chunk(pid, m.start + done, &mut buf[written..written + len])?;
+            done += len;
+            written += len;
+            if let Some(progress) = progress.as_mut() {
+                progress(written, total);
+            }
+        }
+    }
     Ok(())
 }
 
@@ -149,7 +174,11 @@ fn pt_load_header(m: &Mapping, offset: Elf_Off) -> Phdr {
     }
 }
 
-fn write_note_section<T: Sized>(core_file: &mut File, ntype: Elf_Word, payload: &T) -> Result<()> {
+fn write_note_section<W: Write, T: Sized>(
+    core_file: &mut W,
+    ntype: Elf_Word,
+    payload: &T,
+) -> Result<()> {
     let hdr = &Nhdr {
         n_namesz: 5,
         n_descsz: size_of::<T>() as Elf_Word,
@@ -171,7 +200,7 @@ fn write_note_section<T: Sized>(core_file: &mut File, ntype: Elf_Word, payload:
 }
 
 #[cfg(target_arch = "x86_64")]
-fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
+fn write_fpu_registers<W: Write>(core_file: &mut W, regs: &FpuRegs) -> Result<()> {
     use crate::elf::NT_PRXFPREG;
     let hdr = &Nhdr {
         n_namesz: 5,
@@ -194,7 +223,7 @@ fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
 }
 
 #[cfg(not(target_arch = "x86_64"))]
-fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
+fn write_fpu_registers<W: Write>(core_file: &mut W, regs: &FpuRegs) -> Result<()> {
     use crate::elf::NT_PRFPREG;
     try_with!(
         write_note_section(
@@ -207,7 +236,7 @@ fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
     Ok(())
 }
 
-fn write_note_sections(core_file: &mut File, vcpus: &[VcpuState]) -> Result<()> {
+fn write_note_sections<W: Write>(core_file: &mut W, vcpus: &[VcpuState]) -> Result<()> {
     try_with!(
         write_note_section(
             core_file,
@@ -289,12 +318,17 @@ pub fn note_size<T>() -> usize {
     size_of::<Nhdr>() + name_size + size_of::<T>()
 }
 
-fn write_corefile(
-    pid: Pid,
-    core_file: &mut File,
-    maps: &[Mapping],
-    vcpus: &[VcpuState],
-) -> Result<()> {
+/// ELF and program headers of a coredump and where the memory of the first mapping starts
+struct CoreLayout {
+    ehdr: Ehdr,
+    headers: Vec<Phdr>,
+    /// offset of the first PT_LOAD payload
+    data_offset: usize,
+    /// size of the whole coredump
+    core_size: usize,
+}
+
+fn core_layout(maps: &[Mapping], vcpus: usize) -> CoreLayout {
     // +1 == PT_NOTE section
     let ehdr = elf_header((maps.len() + 1) as Elf_Half);
 
@@ -302,45 +336,108 @@ fn write_corefile(
     let mut core_size = metadata_size;
 
     let pt_note_size = note_size::<elf_prpsinfo>()
-        + vcpus.len()
-            * (note_size::<core_user>() + note_size::<elf_prstatus>() + note_size::<FpuRegs>());
-    let mut section_headers = vec![pt_note_header(core_size as Elf_Off, pt_note_size as u64)];
+        + vcpus * (note_size::<core_user>() + note_size::<elf_prstatus>() + note_size::<FpuRegs>());
+    let mut headers = vec![pt_note_header(core_size as Elf_Off, pt_note_size as u64)];
     core_size += pt_note_size;
     core_size = page_align(core_size);
+    let data_offset = core_size;
 
     for m in maps {
         let phdr = pt_load_header(m, core_size as Elf_Off);
         core_size += m.size();
-        section_headers.push(phdr);
+        headers.push(phdr);
+    }
+    CoreLayout {
+        ehdr,
+        headers,
+        data_offset,
+        core_size,
     }
+}
 
+/// Write ELF header, program headers and notes
+fn write_metadata<W: Write>(
+    core_file: &mut W,
+    layout: &CoreLayout,
+    vcpus: &[VcpuState],
+) -> Result<()> {
     try_with!(
-        core_file.set_len(core_size as u64),
-        "cannot truncate core file"
-    );
-    try_with!(
-        core_file.write_all(unsafe { any_as_bytes(&ehdr) }),
+        core_file.write_all(unsafe { any_as_bytes(&layout.ehdr) }),
         "cannot write elf header"
     );
-    for header in section_headers {
+    for header in &layout.headers {
         try_with!(
-            core_file.write_all(unsafe { any_as_bytes(&header) }),
+            core_file.write_all(unsafe { any_as_bytes(header) }),
             "cannot write elf header"
         );
     }
-    write_note_sections(core_file, vcpus)?;
+    write_note_sections(core_file, vcpus)
+}
+
+fn write_corefile(
+    pid: Pid,
+    core_file: &mut File,
+    maps: &[Mapping],
+    vcpus: &[VcpuState],
+    progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<()> {
+    let layout = core_layout(maps, vcpus.len());
+
+    try_with!(
+        core_file.set_len(layout.core_size as u64),
+        "cannot truncate core file"
+    );
+    write_metadata(core_file, &layout, vcpus)?;
 
     try_with!(core_file.flush(), "cannot flush core file");
 
     dump_mappings(
         pid,
         core_file,
-        core_size as off_t,
-        page_align(metadata_size + pt_note_size) as off_t,
+        layout.core_size as off_t,
+        layout.data_offset as off_t,
         maps,
+        progress,
     )
 }
 
+/// Like `write_corefile`, but for writers that cannot seek or be mapped, i.e. a compressor.
+/// Guest memory is copied in chunks of `CHUNK_SIZE`, so memory usage does not grow with the
+/// size of the guest.
+fn stream_corefile<W: Write>(
+    pid: Pid,
+    out: &mut W,
+    maps: &[Mapping],
+    vcpus: &[VcpuState],
+    mut progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<()> {
+    let layout = core_layout(maps, vcpus.len());
+    let mut metadata = vec![];
+    write_metadata(&mut metadata, &layout, vcpus)?;
+    // pad up to the first PT_LOAD payload
+    metadata.resize(layout.data_offset, 0);
+    try_with!(out.write_all(&metadata), "cannot write core file");
+
+    let total = mappings_size(maps);
+    let mut written = 0;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    for m in maps {
+        let mut done = 0;
+        while done < m.size() {
+            let len = (m.size() - done).min(CHUNK_SIZE);
+            read_chunk(pid, m.start + done, &mut buf[..len])?;
+            try_with!(out.write_all(&buf[..len]), "cannot write core file");
+            done += len;
+            written += len;
+            if let Some(progress) = progress.as_mut() {
+                progress(written, total);
+            }
+        }
+    }
+    try_with!(out.flush(), "cannot flush core file");
+    Ok(())
+}
+
 const MSR_EFER: u32 = 0xc0000080;
 struct VcpuState {
     regs: Regs,
@@ -372,34 +469,148 @@ impl VcpuState {
     }
 }
 
-#[allow(clippy::print_stdout)]
-pub fn generate_coredump(opts: &CoredumpOptions) -> Result<()> {
-    println!("Write {}", opts.path.display());
-    let mut core_file = try_with!(
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&opts.path),
-        "cannot open core_file: {}",
-        opts.path.display()
-    );
-    let vm = try_with!(
-        kvm::hypervisor::get_hypervisor(opts.pid),
-        "cannot get vms for process {}",
-        opts.pid
-    );
-    vm.stop()?;
+/// Guest memory mappings and vcpu state to put into a coredump. Requires the hypervisor to be
+/// stopped.
+fn coredump_state(vm: &Hypervisor) -> Result<(Vec<Mapping>, Vec<VcpuState>)> {
+    vm.check_memory_readable()?;
     let maps = vm.get_maps()?;
+    let merged = coalesce_mappings(&maps);
+    debug!(
+        "coalesced {} memory mappings into {} program headers",
+        maps.len(),
+        merged.len()
+    );
     let res = vm
         .vcpus
         .iter()
-        .map(|vcpu| VcpuState::new(vcpu, &vm))
+        .map(|vcpu| VcpuState::new(vcpu, vm))
         .collect::<Result<Vec<VcpuState>>>();
     let vcpu_states = try_with!(res, "fail to dump vcpu registers");
+    Ok((merged, vcpu_states))
+}
+
+/// Write a coredump of `vm` to `out` front to back, without seeking, so `out` can be a pipe, a
+/// socket or a compressor. Requires the hypervisor to be stopped. See `generate_coredump` for
+/// `progress`.
+pub fn write_coredump<W: Write>(
+    vm: &Hypervisor,
+    out: &mut W,
+    progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<()> {
+    let (maps, vcpu_states) = coredump_state(vm)?;
     try_with!(
-        write_corefile(opts.pid, &mut core_file, &maps, vcpu_states.as_slice()),
+        stream_corefile(vm.pid, out, &maps, vcpu_states.as_slice(), progress),
         "cannot write core file"
     );
     Ok(())
 }
+
+fn write_compressed_coredump<W: Write>(
+    vm: &Hypervisor,
+    out: W,
+    progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<()> {
+    let mut encoder = GzEncoder::new(out, Compression::fast());
+    write_coredump(vm, &mut encoder, progress)?;
+    let mut out = try_with!(encoder.finish(), "cannot finish compressed core file");
+    try_with!(out.flush(), "cannot flush core file");
+    Ok(())
+}
+
+/// Write a coredump of the vm as described by `opts`. A path of `-` writes it to stdout.
+/// `progress`, if given, is called with the number of bytes of guest memory written so far and the total
+/// amount of guest memory, after every chunk of at most `CHUNK_SIZE` bytes.
+#[allow(clippy::print_stdout)]
+pub fn generate_coredump(
+    opts: &CoredumpOptions,
+    progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<()> {
+    let to_stdout = opts.path == Path::new("-");
+    let core_file = if to_stdout {
+        None
+    } else {
+        println!("Write {}", opts.path.display());
+        Some(try_with!(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(opts.compress)
+                .open(&opts.path),
+            "cannot open core_file: {}",
+            opts.path.display()
+        ))
+    };
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+    match (core_file, opts.compress) {
+        (None, true) => write_compressed_coredump(&vm, BufWriter::new(io::stdout()), progress),
+        (None, false) => write_coredump(&vm, &mut BufWriter::new(io::stdout()), progress),
+        (Some(core_file), true) => {
+            write_compressed_coredump(&vm, BufWriter::new(core_file), progress)
+        }
+        (Some(mut core_file), false) => {
+            let (maps, vcpu_states) = coredump_state(&vm)?;
+            try_with!(
+                write_corefile(
+                    opts.pid,
+                    &mut core_file,
+                    &maps,
+                    vcpu_states.as_slice(),
+                    progress
+                ),
+                "cannot write core file"
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{core_layout, stream_corefile};
+    use crate::tracer::testutils::mapping;
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use nix::unistd::getpid;
+    use std::io::Read;
+
+    #[test]
+    fn test_stream_corefile() {
+        // more than one chunk, read from our own memory
+        let guest = (0..(super::CHUNK_SIZE + 4096))
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let maps = vec![mapping(guest.as_ptr() as usize, 0x10_0000, guest.len())];
+        let mut encoder = GzEncoder::new(vec![], Compression::fast());
+        let mut reports = vec![];
+        stream_corefile(
+            getpid(),
+            &mut encoder,
+            &maps,
+            &[],
+            Some(&mut |written, total| reports.push((written, total))),
+        )
+        .expect("cannot write core file");
+        assert_eq!(
+            reports,
+            vec![(super::CHUNK_SIZE, guest.len()), (guest.len(), guest.len())]
+        );
+        let compressed = encoder.finish().expect("cannot finish compression");
+
+        let mut core = vec![];
+        GzDecoder::new(&compressed[..])
+            .read_to_end(&mut core)
+            .expect("cannot decompress");
+        let layout = core_layout(&maps, 0);
+        assert_eq!(core.len(), layout.core_size);
+        assert_eq!(&core[..4], b"\x7fELF");
+        assert_eq!(layout.headers[1].p_offset as usize, layout.data_offset);
+        assert_eq!(&core[layout.data_offset..], &guest[..]);
+    }
+}
@@ -41,6 +41,16 @@ mod arch {
         pub fn syscall_ret(&self) -> u64 {
             self.regs[0]
         }
+
+        /// Return value at a syscall-exit stop, negative values are `-errno`
+        pub fn syscall_return(&self) -> i64 {
+            self.regs[0] as i64
+        }
+
+        /// Replace the return value at a syscall-exit stop
+        pub fn set_syscall_return(&mut self, ret: i64) {
+            self.regs[0] = ret as u64;
+        }
     }
 
     // $ rasm2  -a arm -b 64 'svc 0'
@@ -138,6 +148,16 @@ mod arch {
             self.rax
         }
 
+        /// Return value at a syscall-exit stop, negative values are `-errno`
+        pub fn syscall_return(&self) -> i64 {
+            self.rax as i64
+        }
+
+        /// Replace the return value at a syscall-exit stop
+        pub fn set_syscall_return(&mut self, ret: i64) {
+            self.rax = ret as u64;
+        }
+
         /// To be used during wrap_syscall.
         /// return (syscall_nr, arg1, ..., arg6)
         pub fn get_syscall_params(&self) -> (u64, u64, u64, u64, u64, u64, u64) {
@@ -162,6 +182,118 @@ mod arch {
     // $ rasm2  -a x86 -b 64 'syscall'
     pub const SYSCALL_TEXT: u64 = 0x050F;
     pub const SYSCALL_SIZE: u64 = 2;
+
+    // Intel SDM Vol. 1, 3.4.3 "EFLAGS Register"
+    const RFLAGS_BITS: &[(u32, &str)] = &[
+        (0, "CF"),
+        (2, "PF"),
+        (4, "AF"),
+        (6, "ZF"),
+        (7, "SF"),
+        (8, "TF"),
+        (9, "IF"),
+        (10, "DF"),
+        (11, "OF"),
+        (14, "NT"),
+        (16, "RF"),
+        (17, "VM"),
+        (18, "AC"),
+        (19, "VIF"),
+        (20, "VIP"),
+        (21, "ID"),
+    ];
+
+    // Intel SDM Vol. 3, 2.5 "Control Registers"
+    const CR0_BITS: &[(u32, &str)] = &[
+        (0, "PE"),
+        (1, "MP"),
+        (2, "EM"),
+        (3, "TS"),
+        (4, "ET"),
+        (5, "NE"),
+        (16, "WP"),
+        (18, "AM"),
+        (29, "NW"),
+        (30, "CD"),
+        (31, "PG"),
+    ];
+
+    const CR4_BITS: &[(u32, &str)] = &[
+        (0, "VME"),
+        (1, "PVI"),
+        (2, "TSD"),
+        (3, "DE"),
+        (4, "PSE"),
+        (5, "PAE"),
+        (6, "MCE"),
+        (7, "PGE"),
+        (8, "PCE"),
+        (9, "OSFXSR"),
+        (10, "OSXMMEXCPT"),
+        (11, "UMIP"),
+        (12, "LA57"),
+        (13, "VMXE"),
+        (14, "SMXE"),
+        (16, "FSGSBASE"),
+        (17, "PCIDE"),
+        (18, "OSXSAVE"),
+        (20, "SMEP"),
+        (21, "SMAP"),
+        (22, "PKE"),
+        (23, "CET"),
+        (24, "PKS"),
+    ];
+
+    // Intel SDM Vol. 4, MSR 0xC0000080
+    const EFER_BITS: &[(u32, &str)] = &[(0, "SCE"), (8, "LME"), (10, "LMA"), (11, "NXE")];
+
+    /// `val` in hex followed by the names of the bits set, i.e. `0x246 [PF ZF IF]`
+    fn decode_bits(val: u64, bits: &[(u32, &str)]) -> String {
+        let names = bits
+            .iter()
+            .filter(|(bit, _)| val & (1 << bit) != 0)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        format!("{:#x} [{}]", val, names.join(" "))
+    }
+
+    /// Flag names of `Regs.eflags`. The I/O privilege level is a two bit field and shown as
+    /// `IOPL=<n>` if not zero.
+    pub fn decode_rflags(rflags: u64) -> String {
+        let mut s = decode_bits(rflags, RFLAGS_BITS);
+        let iopl = (rflags >> 12) & 3;
+        if iopl != 0 {
+            s.insert_str(s.len() - 1, &format!(" IOPL={}", iopl));
+        }
+        s
+    }
+
+    pub fn decode_cr0(cr0: u64) -> String {
+        decode_bits(cr0, CR0_BITS)
+    }
+
+    pub fn decode_cr4(cr4: u64) -> String {
+        decode_bits(cr4, CR4_BITS)
+    }
+
+    pub fn decode_efer(efer: u64) -> String {
+        decode_bits(efer, EFER_BITS)
+    }
 }
 
 pub use arch::*;
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::{decode_cr0, decode_cr4, decode_efer, decode_rflags};
+
+    #[test]
+    fn test_decode_registers() {
+        assert_eq!(decode_rflags(0x246), "0x246 [PF ZF IF]");
+        assert_eq!(decode_rflags(0x3202), "0x3202 [IF IOPL=3]");
+        assert_eq!(decode_rflags(0x2), "0x2 []");
+        assert_eq!(decode_cr0(0x8005_0033), "0x80050033 [PE MP ET NE WP AM PG]");
+        assert_eq!(decode_cr4(0x20), "0x20 [PAE]");
+        assert_eq!(decode_efer(0xd01), "0xd01 [SCE LME LMA NXE]");
+    }
+}
@@ -1,8 +1,10 @@
+use crate::devices::MmioOwner;
 use crate::kvm::hypervisor::ioregionfd::RawIoRegionFd;
 use crate::kvm::kvm_ioregionfd::{ioregionfd_cmd, Cmd};
 use crate::result::Result;
 use crate::tracer::wrap_syscall::{MmioRw, MMIO_RW_DATA_MAX};
 use simple_error::{map_err_with, try_with};
+use std::ops::Range;
 use std::sync::Arc;
 use vm_device::bus::{Bus, BusManager, MmioAddress};
 use vm_device::device_manager::MmioManager;
@@ -60,6 +62,46 @@ impl IoPirate {
         Ok(())
     }
 
+    /// Like `handle_mmio_rw` for an access that straddles the edge of our devices, split into
+    /// `pieces` by `split_mmio`. Our devices serve their pieces. There is nothing behind the
+    /// rest, as devices are placed where neither memory nor devices of the hypervisor are, so
+    /// that part of a read returns zeros and that part of a write is dropped.
+    pub fn handle_split_mmio_rw(
+        &mut self,
+        mmio_rw: &mut MmioRw,
+        pieces: &[(Range<u64>, MmioOwner)],
+    ) -> Result<()> {
+        let mut data = [0u8; MMIO_RW_DATA_MAX];
+        let len = mmio_rw.data().len();
+        if mmio_rw.is_write {
+            data[..len].copy_from_slice(mmio_rw.data());
+        }
+        for (range, owner) in pieces {
+            if *owner == MmioOwner::Foreign {
+                continue;
+            }
+            let offset = (range.start - mmio_rw.addr) as usize;
+            let piece = &mut data[offset..offset + (range.end - range.start) as usize];
+            if mmio_rw.is_write {
+                map_err_with!(
+                    self.mmio_write(MmioAddress(range.start), piece),
+                    "write to mmio device ({:#x}) failed",
+                    range.start
+                )?;
+            } else {
+                map_err_with!(
+                    self.mmio_read(MmioAddress(range.start), piece),
+                    "read from mmio device ({:#x}) failed",
+                    range.start
+                )?;
+            }
+        }
+        if !mmio_rw.is_write {
+            mmio_rw.answer_read(&data[..len])?;
+        }
+        Ok(())
+    }
+
     /// Used with IoRegionFd.
     pub fn handle_ioregion_rw(
         &mut self,
@@ -105,3 +147,105 @@ impl BusManager<MmioAddress> for IoPirate {
         &mut self.mmio_bus
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::IoPirate;
+    use crate::devices::split_mmio;
+    use crate::tracer::testutils::mapping;
+    use crate::tracer::wrap_syscall::MmioRw;
+    use kvm_bindings as kvmb;
+    use nix::unistd::getpid;
+    use std::mem::{size_of, zeroed};
+    use std::ops::Range;
+    use std::sync::{Arc, Mutex};
+    use vm_device::bus::{MmioAddress, MmioRange};
+    use vm_device::device_manager::MmioManager;
+    use vm_device::DeviceMmio;
+
+    const WINDOW: Range<u64> = 0xd000_0000..0xd000_1000;
+
+    /// Reads 0xab, records writes with their offset
+    #[derive(Default)]
+    struct FakeDevice {
+        writes: Mutex<Vec<(u64, Vec<u8>)>>,
+    }
+
+    impl DeviceMmio for FakeDevice {
+        fn mmio_read(&self, _base: MmioAddress, _offset: u64, data: &mut [u8]) {
+            data.fill(0xab);
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, offset: u64, data: &[u8]) {
+            let mut writes = self.writes.lock().expect("cannot lock writes");
+            writes.push((offset, data.to_vec()));
+        }
+    }
+
+    fn pirate(device: &Arc<FakeDevice>) -> IoPirate {
+        let mut pirate = IoPirate::default();
+        let range = MmioRange::new(MmioAddress(WINDOW.start), WINDOW.end - WINDOW.start)
+            .expect("invalid range");
+        pirate
+            .register_mmio(
+                range,
+                Arc::clone(device) as Arc<dyn DeviceMmio + Send + Sync>,
+            )
+            .expect("cannot register device");
+        pirate
+    }
+
+    /// An access of a vcpu whose kvm_run is `run`, in our own process
+    fn mmio_rw(addr: u64, is_write: bool, data: &[u8], run: &mut kvmb::kvm_run) -> MmioRw {
+        let mut raw = kvmb::kvm_run__bindgen_ty_1__bindgen_ty_6 {
+            phys_addr: addr,
+            len: data.len() as u32,
+            is_write: is_write as u8,
+            ..Default::default()
+        };
+        raw.data[..data.len()].copy_from_slice(data);
+        let start = run as *mut kvmb::kvm_run as usize;
+        MmioRw::new(
+            &raw,
+            getpid(),
+            mapping(start, 0, size_of::<kvmb::kvm_run>()),
+        )
+    }
+
+    #[test]
+    fn test_split_read() {
+        let device = Arc::new(FakeDevice::default());
+        let mut pirate = pirate(&device);
+        // Safe because kvm_run is plain data.
+        let mut run: Box<kvmb::kvm_run> = Box::new(unsafe { zeroed() });
+        let addr = WINDOW.end - 2;
+        let mut rw = mmio_rw(addr, false, &[0; 4], &mut run);
+        let pieces = split_mmio(&[WINDOW], addr, 4);
+        pirate
+            .handle_split_mmio_rw(&mut rw, &pieces)
+            .expect("cannot handle read");
+        // nothing behind our window reads as zeros
+        assert_eq!(rw.data(), &[0xab, 0xab, 0, 0]);
+        // Safe because answering a read fills in the mmio exit.
+        let mmio = unsafe { run.__bindgen_anon_1.mmio };
+        assert_eq!(&mmio.data[..4], &[0xab, 0xab, 0, 0]);
+        assert!(device.writes.lock().expect("cannot lock writes").is_empty());
+    }
+
+    #[test]
+    fn test_split_write() {
+        let device = Arc::new(FakeDevice::default());
+        let mut pirate = pirate(&device);
+        // Safe because kvm_run is plain data.
+        let mut run: Box<kvmb::kvm_run> = Box::new(unsafe { zeroed() });
+        let addr = WINDOW.start - 2;
+        let mut rw = mmio_rw(addr, true, &[1, 2, 3, 4], &mut run);
+        let pieces = split_mmio(&[WINDOW], addr, 4);
+        pirate
+            .handle_split_mmio_rw(&mut rw, &pieces)
+            .expect("cannot handle write");
+        // the part before our window is dropped
+        let writes = device.writes.lock().expect("cannot lock writes");
+        assert_eq!(*writes, vec![(0, vec![3, 4])]);
+    }
+}
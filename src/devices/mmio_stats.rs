@@ -0,0 +1,149 @@
+//! Rate limiting and counters for MMIO exit logging. Chatty devices cause thousands of exits per
+//! second, so we only log every Nth exit and periodically summarize the rest.
+
+use log::{debug, info, log_enabled, trace, Level};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::tracer::wrap_syscall::MmioRw;
+
+/// How often exit rates are reported
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of addresses listed in reports
+const TOP_ADDRS: usize = 5;
+
+pub struct MmioStats {
+    /// log every `sample`th exit, 1 logs all of them
+    sample: usize,
+    total: u64,
+    per_addr: HashMap<u64, u64>,
+    window_start: Instant,
+    window_exits: u64,
+}
+
+impl MmioStats {
+    pub fn new(sample: usize, now: Instant) -> MmioStats {
+        MmioStats {
+            sample: sample.max(1),
+            total: 0,
+            per_addr: HashMap::new(),
+            window_start: now,
+            window_exits: 0,
+        }
+    }
+
+    /// Count an exit and return true if it should be logged.
+    pub fn record(&mut self, addr: u64) -> bool {
+        let sampled = self.total % self.sample as u64 == 0;
+        self.total += 1;
+        self.window_exits += 1;
+        *self.per_addr.entry(addr).or_insert(0) += 1;
+        sampled
+    }
+
+    /// Count `mmio_rw` and log it if it is sampled. `intercepted` tells whether the access
+    /// belongs to one of our devices or is passed on to the hypervisor.
+    pub fn log(&mut self, mmio_rw: &MmioRw, intercepted: bool) {
+        if !self.record(mmio_rw.addr) || !log_enabled!(Level::Trace) {
+            return;
+        }
+        if intercepted {
+            trace!("mmio access #{}: {}", self.total, mmio_rw);
+        } else {
+            trace!("ignore addr #{}: {:#x}", self.total, mmio_rw.addr);
+        }
+    }
+
+    /// Exits per second since the last report, or None if the report interval has not passed.
+    pub fn rate(&mut self, now: Instant) -> Option<f64> {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < REPORT_INTERVAL {
+            return None;
+        }
+        let rate = self.window_exits as f64 / elapsed.as_secs_f64();
+        self.window_start = now;
+        self.window_exits = 0;
+        Some(rate)
+    }
+
+    /// Addresses with the most exits, most frequent first.
+    pub fn top_addrs(&self, n: usize) -> Vec<(u64, u64)> {
+        let mut addrs = self
+            .per_addr
+            .iter()
+            .map(|(addr, count)| (*addr, *count))
+            .collect::<Vec<_>>();
+        addrs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        addrs.truncate(n);
+        addrs
+    }
+
+    fn format_top_addrs(&self) -> String {
+        self.top_addrs(TOP_ADDRS)
+            .iter()
+            .map(|(addr, count)| format!("{:#x}: {}", addr, count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Log the exit rate once per `REPORT_INTERVAL`.
+    pub fn report(&mut self, now: Instant) {
+        if !log_enabled!(Level::Debug) {
+            return;
+        }
+        if let Some(rate) = self.rate(now) {
+            debug!(
+                "{:.0} mmio exits/s, {} total, top addresses: {}",
+                rate,
+                self.total,
+                self.format_top_addrs()
+            );
+        }
+    }
+
+    /// Log the totals, i.e. when the device is detached.
+    pub fn summary(&self) {
+        if self.total == 0 {
+            return;
+        }
+        info!(
+            "handled {} mmio exits, top addresses: {}",
+            self.total,
+            self.format_top_addrs()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MmioStats;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_mmio_stats() {
+        let start = Instant::now();
+        let mut stats = MmioStats::new(3, start);
+        let sampled = [
+            0xd000_0000,
+            0xd000_0050,
+            0xd000_0050,
+            0xd000_0050,
+            0xd000_1000,
+        ]
+        .iter()
+        .map(|addr| stats.record(*addr))
+        .collect::<Vec<_>>();
+        assert_eq!(sampled, vec![true, false, false, true, false]);
+        assert_eq!(stats.top_addrs(2), vec![(0xd000_0050, 3), (0xd000_0000, 1)]);
+
+        assert_eq!(stats.rate(start + Duration::from_millis(500)), None);
+        assert_eq!(stats.rate(start + Duration::from_secs(2)), Some(2.5));
+        // the window is reset after each report
+        assert_eq!(stats.rate(start + Duration::from_secs(3)), Some(0.0));
+
+        // 0 is treated like 1
+        let mut all = MmioStats::new(0, start);
+        assert!(all.record(0) && all.record(0));
+    }
+}
@@ -1,4 +1,6 @@
 pub mod mmio;
+pub mod mmio_stats;
+pub mod scripted;
 mod threads;
 pub mod virtio;
 
@@ -7,24 +9,30 @@ use crate::devices::threads::SubscriberEventManager;
 use crate::devices::virtio::block::{self, BlockArgs};
 use crate::devices::virtio::console::{self, ConsoleArgs};
 use crate::devices::virtio::{CommonArgs, MmioConfig};
+use crate::inspect::boot_params::guest_memory_map;
+use crate::kvm::allocator::find_free_range;
 use crate::kvm::hypervisor::ioregionfd::IoRegionFd;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::kvm::PhysMemAllocator;
 use crate::result::Result;
 use crate::tracer::proc::Mapping;
 use libc::pid_t;
+use log::debug;
 use simple_error::{bail, try_with};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
+use virtio_device::VirtioDevice;
+use vm_device::bus::MmioRange;
 use vm_device::device_manager::MmioManager;
 use vm_memory::guest_memory::GuestAddress;
 use vm_memory::mmap::MmapRegion;
-use vm_memory::GuestMemoryRegion;
+use vm_memory::{Bytes, GuestMemoryRegion};
 use vm_memory::{GuestMemoryMmap, GuestRegionMmap};
 
-pub use self::threads::DeviceSet;
+pub use self::threads::{DeviceSet, DriverNotifier, MmioTraceOptions, Threads};
 
 /// Should be initialized by the argument parser.
 pub static USE_IOREGIONFD: AtomicBool = AtomicBool::new(false);
@@ -33,6 +41,18 @@ pub fn use_ioregionfd() -> bool {
     USE_IOREGIONFD.load(Ordering::Relaxed)
 }
 
+/// Should be initialized by the argument parser. With the wrap_syscall backend, do not register
+/// KVM ioeventfds for the QueueNotify register of devices, but intercept writes to it like any
+/// other MMIO access.
+pub static TRAP_QUEUE_NOTIFY: AtomicBool = AtomicBool::new(false);
+
+/// Whether queue notifications reach devices as MMIO writes, forwarded to their
+/// `UserspaceIoEventFd`, instead of through a KVM ioeventfd. Always the case with ioregionfd,
+/// which does not support ioeventfds for the region it serves.
+pub fn use_userspace_ioeventfd() -> bool {
+    use_ioregionfd() || TRAP_QUEUE_NOTIFY.load(Ordering::Relaxed)
+}
+
 pub type Block = block::Block;
 pub type Console = console::Console;
 
@@ -71,21 +91,142 @@ fn convert(pid: pid_t, mappings: &[Mapping]) -> Result<GuestMemoryMmap> {
     ))
 }
 
+/// Device windows at fixed guest physical addresses on x86: IOAPIC, HPET and local APIC
+const FIXED_DEVICE_WINDOWS: &[Range<u64>] = &[
+    0xfec0_0000..0xfec0_1000,
+    0xfed0_0000..0xfed0_0400,
+    0xfee0_0000..0xfef0_0000,
+];
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Make sure that placing a device at `range` does not shadow guest memory in `ram` or one of
+/// the fixed x86 device windows. Guest writes to such a range would end up in our device instead
+/// of memory. Suggests the next free base below the conflict.
+fn check_mmio_range(range: Range<u64>, ram: &[Range<u64>]) -> Result<()> {
+    let busy = ram.iter().chain(FIXED_DEVICE_WINDOWS.iter());
+    let conflict = match busy.clone().find(|r| overlaps(r, &range)) {
+        Some(conflict) => conflict,
+        None => return Ok(()),
+    };
+    let busy = busy.cloned().collect::<Vec<_>>();
+    match find_free_range(&busy, range.end - range.start, 0x1000, conflict.start) {
+        Some(base) => bail!(
+            "mmio range {:#x}-{:#x} overlaps guest memory or device at {:#x}-{:#x}, {:#x} would be free",
+            range.start,
+            range.end,
+            conflict.start,
+            conflict.end,
+            base
+        ),
+        None => bail!(
+            "mmio range {:#x}-{:#x} overlaps guest memory or device at {:#x}-{:#x}",
+            range.start,
+            range.end,
+            conflict.start,
+            conflict.end
+        ),
+    }
+}
+
+/// Guest physical ranges a device must not overlap, see `check_mmio_range`: the guest memory in
+/// `maps` and the memory map of the guest. Expects the hypervisor to be stopped.
+fn guest_ranges(vm: &Hypervisor, maps: &[Mapping]) -> Vec<Range<u64>> {
+    let mut ram = maps
+        .iter()
+        .map(|m| m.phys_addr as u64..m.phys_end() as u64)
+        .collect::<Vec<_>>();
+    // the guest may reserve more than the hypervisor backs, i.e. firmware mmio
+    match guest_memory_map(vm) {
+        Ok(map) => ram.extend(map),
+        Err(e) => debug!("cannot read the memory map of the guest: {}", e),
+    }
+    ram
+}
+
+fn mmio_window(range: &MmioRange) -> Range<u64> {
+    range.base().0..range.last().0 + 1
+}
+
+/// Who serves an MMIO exit, see `classify_mmio`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MmioOwner {
+    /// one of our devices
+    Ours,
+    /// the hypervisor, i.e. one of its own devices or an address nobody backs
+    Foreign,
+}
+
+/// Classify an MMIO exit at guest physical address `addr` by whether it falls into one of the
+/// windows of our devices. Foreign exits have to be passed on to the hypervisor untouched.
+pub fn classify_mmio(windows: &[Range<u64>], addr: u64) -> MmioOwner {
+    if windows.iter().any(|w| w.contains(&addr)) {
+        MmioOwner::Ours
+    } else {
+        MmioOwner::Foreign
+    }
+}
+
+/// Split an MMIO access of `len` bytes at `addr` at the edges of our `windows`. Returns the
+/// pieces in address order with who serves each, a single piece unless the access straddles the
+/// start or end of a window.
+pub fn split_mmio(windows: &[Range<u64>], addr: u64, len: usize) -> Vec<(Range<u64>, MmioOwner)> {
+    let end = addr.saturating_add(len as u64);
+    let mut pieces = vec![];
+    let mut cur = addr;
+    while cur < end {
+        let piece = match windows.iter().find(|w| w.contains(&cur)) {
+            Some(w) => (cur..w.end.min(end), MmioOwner::Ours),
+            None => {
+                let next = windows
+                    .iter()
+                    .map(|w| w.start)
+                    .filter(|start| *start > cur)
+                    .min()
+                    .unwrap_or(end);
+                (cur..next.min(end), MmioOwner::Foreign)
+            }
+        };
+        cur = piece.0.end;
+        pieces.push(piece);
+    }
+    pieces
+}
+
 trait MaybeIoRegionFd {
     fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd>;
 }
 
+/// A device no guest driver has bound to, see `DeviceContext::unprobed_devices`
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnprobedDevice {
+    pub name: &'static str,
+    /// guest physical base address of the mmio window
+    pub addr: u64,
+    pub irq: u32,
+}
+
 pub struct DeviceContext {
     pub blkdev: Arc<Mutex<Block>>,
     pub console: Arc<Mutex<Console>>,
     pub mmio_mgr: Arc<Mutex<IoPirate>>,
-    /// start address of mmio space
-    pub first_mmio_addr: u64,
-    /// start address of mmio space
-    pub last_mmio_addr: u64,
+    /// guest physical MMIO windows of our devices
+    pub mmio_windows: Vec<Range<u64>>,
+    /// guest memory as seen by our devices
+    pub mem: Arc<GuestMemoryMmap>,
 }
 
 impl DeviceContext {
+    pub fn mmio_owner(&self, addr: u64) -> MmioOwner {
+        classify_mmio(&self.mmio_windows, addr)
+    }
+
+    pub fn split_mmio(&self, addr: u64, len: usize) -> Vec<(Range<u64>, MmioOwner)> {
+        split_mmio(&self.mmio_windows, addr, len)
+    }
+
     pub fn mmio_addrs(&self) -> Result<Vec<u64>> {
         Ok(vec![
             try_with!(self.blkdev.lock(), "cannot lock block device")
@@ -100,6 +241,60 @@ impl DeviceContext {
                 .0,
         ])
     }
+
+    /// Devices whose status register the guest never wrote. Stage1 registers our devices as
+    /// virtio-mmio platform devices at runtime and asks modprobe for the virtio_mmio module
+    /// (`trigger_probe` in stage1), after which the driver core probes them right away. An
+    /// unprobed device means the guest needs manual intervention, i.e. installing the
+    /// virtio_mmio module or a kernel with CONFIG_VIRTIO_MMIO.
+    pub fn unprobed_devices(&self) -> Result<Vec<UnprobedDevice>> {
+        let mut devices = vec![];
+        let blkdev = try_with!(self.blkdev.lock(), "cannot lock block device");
+        if blkdev.device_status() == 0 {
+            devices.push(UnprobedDevice {
+                name: "block",
+                addr: blkdev.mmio_cfg.range.base().0,
+                irq: blkdev.mmio_cfg.gsi,
+            });
+        }
+        drop(blkdev);
+        let console = try_with!(self.console.lock(), "cannot lock console device");
+        if console.device_status() == 0 {
+            devices.push(UnprobedDevice {
+                name: "console",
+                addr: console.mmio_cfg.range.base().0,
+                irq: console.mmio_cfg.gsi,
+            });
+        }
+        Ok(devices)
+    }
+
+    /// Copy guest memory at guest physical address `gpa` into `buf`. Uses the memory mappings
+    /// of the devices instead of stopping the hypervisor with ptrace, so it is much faster for
+    /// bulk reads. The guest keeps running while we read though: unless it is stopped, the data
+    /// may change under our feet and `buf` may not be a consistent snapshot.
+    pub fn read_guest(&self, gpa: u64, buf: &mut [u8]) -> Result<()> {
+        try_with!(
+            self.mem.read_slice(buf, GuestAddress(gpa)),
+            "cannot read {} bytes of guest memory at {:#x}",
+            buf.len(),
+            gpa
+        );
+        Ok(())
+    }
+
+    /// Copy `buf` to guest memory at guest physical address `gpa`, see `read_guest`. Writes race
+    /// with a running guest the same way.
+    pub fn write_guest(&self, gpa: u64, buf: &[u8]) -> Result<()> {
+        try_with!(
+            self.mem.write_slice(buf, GuestAddress(gpa)),
+            "cannot write {} bytes of guest memory at {:#x}",
+            buf.len(),
+            gpa
+        );
+        Ok(())
+    }
+
     pub fn new(
         vmm: &Arc<Hypervisor>,
         allocator: &mut PhysMemAllocator,
@@ -107,6 +302,7 @@ impl DeviceContext {
         irq_num: usize,
         backing: &Path,
         pts: Option<PathBuf>,
+        queue_size: u16,
     ) -> Result<DeviceContext> {
         let guest_memory = try_with!(vmm.get_maps(), "cannot get guests memory");
         let mem = Arc::new(try_with!(
@@ -124,8 +320,18 @@ impl DeviceContext {
             gsi: irq_num as u32,
         };
 
-        let first_mmio_addr = console_mmio_cfg.range.base().0;
-        let last_mmio_addr = block_mmio_cfg.range.last().0;
+        let ram = guest_ranges(vmm, &guest_memory);
+        for range in &[&block_mmio_cfg.range, &console_mmio_cfg.range] {
+            try_with!(
+                check_mmio_range(mmio_window(range), &ram),
+                "cannot place device"
+            );
+        }
+
+        let mmio_windows = vec![
+            mmio_window(&block_mmio_cfg.range),
+            mmio_window(&console_mmio_cfg.range),
+        ];
 
         // IoManager replacement:
         let device_manager = Arc::new(Mutex::new(IoPirate::default()));
@@ -146,6 +352,9 @@ impl DeviceContext {
                 read_only: false,
                 root_device: true,
                 advertise_flush: true,
+                queue_size,
+                // the guest driver maps queues to cpus
+                num_queues: vmm.vcpus.len().clamp(1, u16::MAX as usize) as u16,
             };
             match Block::new(args) {
                 Ok(v) => v,
@@ -157,7 +366,7 @@ impl DeviceContext {
             guard.mmio_device(console_mmio_cfg.range.base());
 
             let common = CommonArgs {
-                mem,
+                mem: Arc::clone(&mem),
                 vmm: vmm.clone(),
                 event_mgr,
                 mmio_mgr: guard,
@@ -175,10 +384,93 @@ impl DeviceContext {
             blkdev,
             console,
             mmio_mgr: device_manager,
-            first_mmio_addr,
-            last_mmio_addr,
+            mmio_windows,
+            mem,
         };
 
         Ok(device)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_mmio_range, classify_mmio, split_mmio, MmioOwner};
+
+    #[test]
+    fn test_check_mmio_range() {
+        let ram = vec![0..0x8000_0000, 0x1_0000_0000..0x2_0000_0000];
+        assert!(check_mmio_range(0xd000_0000..0xd000_1000, &ram).is_ok());
+        assert!(check_mmio_range(0xfff_ffff_f000..0x1000_0000_0000, &ram).is_ok());
+
+        let err = check_mmio_range(0x1_ffff_f000..0x2_0000_0000, &ram)
+            .expect_err("overlaps ram")
+            .to_string();
+        assert!(err.contains("0xfffff000 would be free"), "{}", err);
+        // local APIC window
+        let err = check_mmio_range(0xfee0_0000..0xfee0_1000, &ram)
+            .expect_err("overlaps local apic")
+            .to_string();
+        assert!(err.contains("0xfedff000 would be free"), "{}", err);
+        assert!(check_mmio_range(0x1000..0x2000, &ram).is_err());
+    }
+
+    #[test]
+    fn test_classify_mmio() {
+        let windows = vec![0xd000_1000..0xd000_2000, 0xd000_0000..0xd000_1000];
+        assert_eq!(classify_mmio(&windows, 0xd000_0000), MmioOwner::Ours);
+        assert_eq!(classify_mmio(&windows, 0xd000_1ffc), MmioOwner::Ours);
+        // first byte after and last byte before our windows
+        assert_eq!(classify_mmio(&windows, 0xd000_2000), MmioOwner::Foreign);
+        assert_eq!(classify_mmio(&windows, 0xcfff_ffff), MmioOwner::Foreign);
+        // i.e. the hpet of the hypervisor
+        assert_eq!(classify_mmio(&windows, 0xfed0_0000), MmioOwner::Foreign);
+        assert_eq!(classify_mmio(&[], 0xd000_0000), MmioOwner::Foreign);
+    }
+
+    #[test]
+    fn test_split_mmio() {
+        let windows = vec![0xd000_0000..0xd000_1000];
+        assert_eq!(
+            split_mmio(&windows, 0xd000_0ffc, 4),
+            vec![(0xd000_0ffc..0xd000_1000, MmioOwner::Ours)]
+        );
+        // last byte of the window
+        assert_eq!(
+            split_mmio(&windows, 0xd000_0fff, 4),
+            vec![
+                (0xd000_0fff..0xd000_1000, MmioOwner::Ours),
+                (0xd000_1000..0xd000_1003, MmioOwner::Foreign)
+            ]
+        );
+        assert_eq!(
+            split_mmio(&windows, 0xd000_0fff, 8),
+            vec![
+                (0xd000_0fff..0xd000_1000, MmioOwner::Ours),
+                (0xd000_1000..0xd000_1007, MmioOwner::Foreign)
+            ]
+        );
+        // starts before the window
+        assert_eq!(
+            split_mmio(&windows, 0xcfff_fffe, 4),
+            vec![
+                (0xcfff_fffe..0xd000_0000, MmioOwner::Foreign),
+                (0xd000_0000..0xd000_0002, MmioOwner::Ours)
+            ]
+        );
+        assert_eq!(
+            split_mmio(&windows, 0xd000_1000, 8),
+            vec![(0xd000_1000..0xd000_1008, MmioOwner::Foreign)]
+        );
+
+        // crosses from one of our devices into the next
+        let adjacent = vec![0xd000_0000..0xd000_1000, 0xd000_1000..0xd000_2000];
+        assert_eq!(
+            split_mmio(&adjacent, 0xd000_0fff, 8),
+            vec![
+                (0xd000_0fff..0xd000_1000, MmioOwner::Ours),
+                (0xd000_1000..0xd000_1007, MmioOwner::Ours)
+            ]
+        );
+        assert!(split_mmio(&windows, 0xd000_0000, 0).is_empty());
+    }
+}
@@ -0,0 +1,398 @@
+//! An MMIO device whose registers are described by a config file instead of code, to try out a
+//! driver against a fake device without recompiling vmsh.
+//!
+//! The device is served from `KvmRunWrapper` MMIO exits, so it must be placed at guest physical
+//! addresses that are neither RAM nor a device of the hypervisor. Example config:
+//!
+//! ```text
+//! # the guest physical range the device occupies
+//! base 0xd0001000
+//! size 0x100
+//! # offset width kind    values
+//! 0x00      4     const   0x74726976   # reads always return 0x74726976
+//! 0x04      4     seq     0x1 0x2 0x3  # reads return 1, 2, 3, 3, 3, ...
+//! 0x08      4     store   0x0          # reads return the last value written, initially 0
+//! 0x0c      8     counter 0x100        # reads return 0x100, 0x101, ...
+//! ```
+//!
+//! Numbers are hex, `#` starts a comment. Reads of offsets without a register return zeros.
+//! Every write is logged; only `store` registers remember it.
+
+use log::{debug, info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+
+use crate::devices::{check_mmio_range, guest_ranges};
+use crate::kvm::hypervisor::{get_hypervisor, HypervisorOptions};
+use crate::result::Result;
+use crate::signal_handler;
+use crate::tracer::mmio_record::MmioRecorder;
+use crate::tracer::wrap_syscall::{MmioRw, MMIO_RW_DATA_MAX};
+
+pub struct ScriptedDeviceOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub config: PathBuf,
+    /// Record all accesses to the device to this file
+    pub record: Option<PathBuf>,
+}
+
+/// What reads of a register return
+#[derive(Clone, Debug, PartialEq)]
+pub enum Behavior {
+    /// always the same value
+    Const(u64),
+    /// the values in turn, the last one repeats
+    Seq(Vec<u64>),
+    /// the value written last
+    Store(u64),
+    /// the value, which is incremented after every read
+    Counter(u64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Register {
+    /// from the base of the device
+    pub offset: u64,
+    /// in bytes, accesses of other sizes are answered anyway but logged
+    pub width: usize,
+    pub behavior: Behavior,
+    reads: usize,
+}
+
+impl Register {
+    fn read(&mut self) -> u64 {
+        let val = match &mut self.behavior {
+            Behavior::Const(val) | Behavior::Store(val) => *val,
+            Behavior::Seq(vals) => vals[self.reads.min(vals.len() - 1)],
+            Behavior::Counter(val) => {
+                let cur = *val;
+                *val = val.wrapping_add(1);
+                cur
+            }
+        };
+        self.reads += 1;
+        val
+    }
+
+    fn write(&mut self, val: u64) {
+        if let Behavior::Store(stored) = &mut self.behavior {
+            *stored = val;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ScriptedDevice {
+    pub base: u64,
+    pub size: u64,
+    pub registers: Vec<Register>,
+    writes: u64,
+}
+
+fn parse_hex(s: &str) -> Result<u64> {
+    Ok(try_with!(
+        u64::from_str_radix(s.trim_start_matches("0x"), 16),
+        "invalid hex number '{}'",
+        s
+    ))
+}
+
+fn parse_register(fields: &[&str]) -> Result<Register> {
+    if fields.len() < 3 {
+        bail!("expected '<offset> <width> <kind> [values]'");
+    }
+    let offset = parse_hex(fields[0])?;
+    let width = match fields[1] {
+        "1" => 1,
+        "2" => 2,
+        "4" => 4,
+        "8" => 8,
+        w => bail!("width must be 1, 2, 4 or 8, got '{}'", w),
+    };
+    let values = fields[3..]
+        .iter()
+        .map(|v| parse_hex(v))
+        .collect::<Result<Vec<_>>>()?;
+    let single = || -> Result<u64> {
+        match values.as_slice() {
+            [val] => Ok(*val),
+            _ => bail!("'{}' takes exactly one value", fields[2]),
+        }
+    };
+    let behavior = match fields[2] {
+        "const" => Behavior::Const(single()?),
+        "store" => Behavior::Store(single()?),
+        "counter" => Behavior::Counter(single()?),
+        "seq" if values.is_empty() => bail!("'seq' needs at least one value"),
+        "seq" => Behavior::Seq(values.clone()),
+        kind => bail!(
+            "unknown register kind '{}', expected const, seq, store or counter",
+            kind
+        ),
+    };
+    Ok(Register {
+        offset,
+        width,
+        behavior,
+        reads: 0,
+    })
+}
+
+impl ScriptedDevice {
+    pub fn load(path: &Path) -> Result<ScriptedDevice> {
+        let content = try_with!(fs::read_to_string(path), "cannot read {}", path.display());
+        Ok(try_with!(
+            ScriptedDevice::parse(&content),
+            "invalid device config {}",
+            path.display()
+        ))
+    }
+
+    pub fn parse(content: &str) -> Result<ScriptedDevice> {
+        let mut base = None;
+        let mut size = None;
+        let mut registers: Vec<Register> = vec![];
+        for (i, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let res = match fields.as_slice() {
+                [] => continue,
+                ["base", val] => parse_hex(val).map(|v| base = Some(v)),
+                ["size", val] => parse_hex(val).map(|v| size = Some(v)),
+                fields => parse_register(fields).map(|r| registers.push(r)),
+            };
+            try_with!(res, "line {}", i + 1);
+        }
+        let base = require_with!(base, "no 'base' given");
+        let size = require_with!(size, "no 'size' given");
+        if size == 0 || base.checked_add(size).is_none() {
+            bail!("invalid device range {:#x} + {:#x}", base, size);
+        }
+        registers.sort_by_key(|r| r.offset);
+        for pair in registers.windows(2) {
+            if pair[0].offset.saturating_add(pair[0].width as u64) > pair[1].offset {
+                bail!(
+                    "registers at {:#x} and {:#x} overlap",
+                    pair[0].offset,
+                    pair[1].offset
+                );
+            }
+        }
+        if let Some(last) = registers.last() {
+            if last.offset.saturating_add(last.width as u64) > size {
+                bail!(
+                    "register at {:#x} is outside of the device of size {:#x}",
+                    last.offset,
+                    size
+                );
+            }
+        }
+        Ok(ScriptedDevice {
+            base,
+            size,
+            registers,
+            writes: 0,
+        })
+    }
+
+    /// Guest physical addresses of the device
+    pub fn range(&self) -> Range<u64> {
+        self.base..self.base + self.size
+    }
+
+    fn register(&mut self, addr: u64, len: usize) -> Option<&mut Register> {
+        let offset = addr.checked_sub(self.base)?;
+        let reg = self.registers.iter_mut().find(|r| r.offset == offset);
+        match reg {
+            Some(reg) if reg.width != len => {
+                warn!(
+                    "{}-byte access to {}-byte register at {:#x}",
+                    len, reg.width, offset
+                );
+                Some(reg)
+            }
+            Some(reg) => Some(reg),
+            None => {
+                debug!("access to {:#x} hits no register", offset);
+                None
+            }
+        }
+    }
+
+    /// Answer a read of `buf.len()` bytes at guest physical address `addr`.
+    pub fn read(&mut self, addr: u64, buf: &mut [u8]) {
+        let val = self.register(addr, buf.len()).map_or(0, Register::read);
+        let bytes = val.to_le_bytes();
+        let len = buf.len().min(bytes.len());
+        buf.fill(0);
+        buf[..len].copy_from_slice(&bytes[..len]);
+        debug!("read {:#x} -> {:#x}", addr, val);
+    }
+
+    /// Handle a write of `data` to guest physical address `addr`.
+    pub fn write(&mut self, addr: u64, data: &[u8]) {
+        let mut bytes = [0u8; 8];
+        let len = data.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&data[..len]);
+        let val = u64::from_le_bytes(bytes);
+        info!("write {:#x} <- {:#x}", addr, val);
+        self.writes += 1;
+        if let Some(reg) = self.register(addr, data.len()) {
+            reg.write(val);
+        }
+    }
+
+    /// Number of writes to the device so far
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    /// MMIO exit callback, answers reads and handles writes of the vcpu behind `mmio`.
+    pub fn handle_mmio_rw(&mut self, mmio: &mut MmioRw) -> Result<()> {
+        if mmio.is_write {
+            self.write(mmio.addr, mmio.data());
+        } else {
+            let mut data = [0u8; MMIO_RW_DATA_MAX];
+            let slice = &mut data[..mmio.data().len()];
+            self.read(mmio.addr, slice);
+            mmio.answer_read(slice)?;
+        }
+        Ok(())
+    }
+}
+
+/// Serve the device described by `opts.config` to the guest until interrupted by SIGINT/SIGTERM.
+pub fn run_scripted_device(opts: &ScriptedDeviceOptions) -> Result<()> {
+    let mut device = ScriptedDevice::load(&opts.config)?;
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let range = device.range();
+    vm.stop()?;
+    let ram = vm.get_maps().map(|maps| guest_ranges(&vm, &maps));
+    vm.resume()?;
+    let ram = try_with!(ram, "cannot get guest memory mappings");
+    // accesses to memory do not exit to the hypervisor, fixed devices are served by KVM
+    try_with!(check_mmio_range(range.clone(), &ram), "cannot place device");
+    let mut recorder = match &opts.record {
+        Some(path) => Some(MmioRecorder::create(path)?),
+        None => None,
+    };
+
+    let (sender, receiver) = channel();
+    signal_handler::setup(sender);
+
+    info!(
+        "serving {} registers at {:#x}-{:#x}",
+        device.registers.len(),
+        range.start,
+        range.end
+    );
+    vm.kvmrun_wrapped(|wrapper_mo| {
+        let mut wrapper_go = try_with!(wrapper_mo.lock(), "cannot obtain wrapper mutex");
+        let wrapper = require_with!(wrapper_go.as_mut(), "KvmRunWrapper not initialized");
+        wrapper.set_mmio_filter(vec![range.clone()]);
+        wrapper.set_mmio_recorder(recorder.take());
+        let res = loop {
+            // every syscall of the hypervisor returns here, so this is checked regularly
+            if receiver.try_recv().is_ok() {
+                break Ok(());
+            }
+            match wrapper.wait_for_ioctl() {
+                Ok(Some(mut mmio)) => {
+                    if let Err(e) = device.handle_mmio_rw(&mut mmio) {
+                        break Err(e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => break Err(e),
+            }
+        };
+        if let Some(recorder) = wrapper.set_mmio_recorder(None) {
+            let count = recorder.count();
+            match recorder.finish() {
+                Ok(_) => info!("recorded {} mmio exits", count),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        res
+    })?;
+    info!("device saw {} writes", device.writes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Behavior, ScriptedDevice};
+
+    const CONFIG: &str = "
+# fake device
+base 0xd0001000
+size 0x100
+0x00 4 const 0x74726976
+0x04 4 seq 0x1 0x2 0x3 # comment
+0x08 4 store 0x0
+0x0c 8 counter 0x100
+";
+
+    fn read(dev: &mut ScriptedDevice, addr: u64, len: usize) -> u64 {
+        let mut buf = [0u8; 8];
+        dev.read(addr, &mut buf[..len]);
+        u64::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn test_scripted_device() {
+        let mut dev = ScriptedDevice::parse(CONFIG).expect("cannot parse config");
+        assert_eq!(dev.range(), 0xd000_1000..0xd000_1100);
+        assert_eq!(dev.registers[1].behavior, Behavior::Seq(vec![1, 2, 3]));
+
+        assert_eq!(read(&mut dev, 0xd000_1000, 4), 0x7472_6976);
+        assert_eq!(read(&mut dev, 0xd000_1000, 4), 0x7472_6976);
+        // narrower access than the register
+        assert_eq!(read(&mut dev, 0xd000_1000, 1), 0x76);
+
+        let seq = (0..5)
+            .map(|_| read(&mut dev, 0xd000_1004, 4))
+            .collect::<Vec<_>>();
+        assert_eq!(seq, vec![1, 2, 3, 3, 3]);
+
+        assert_eq!(read(&mut dev, 0xd000_1008, 4), 0);
+        dev.write(0xd000_1008, &0x42u32.to_le_bytes());
+        assert_eq!(read(&mut dev, 0xd000_1008, 4), 0x42);
+        // writes to read-only registers are ignored
+        dev.write(0xd000_1000, &0u32.to_le_bytes());
+        assert_eq!(read(&mut dev, 0xd000_1000, 4), 0x7472_6976);
+
+        assert_eq!(read(&mut dev, 0xd000_100c, 8), 0x100);
+        assert_eq!(read(&mut dev, 0xd000_100c, 8), 0x101);
+
+        // no register
+        assert_eq!(read(&mut dev, 0xd000_1080, 4), 0);
+        assert_eq!(dev.writes(), 2);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse =
+            |regs: &str| ScriptedDevice::parse(&format!("base 0x1000\nsize 0x10\n{}", regs));
+        assert!(parse("").is_ok());
+        assert!(ScriptedDevice::parse("size 0x10").is_err());
+        assert!(parse("0x0 3 const 0x0").is_err());
+        assert!(parse("0x0 4 const").is_err());
+        assert!(parse("0x0 4 const 0x1 0x2").is_err());
+        assert!(parse("0x0 4 seq").is_err());
+        assert!(parse("0x0 4 magic 0x1").is_err());
+        // overlapping registers
+        assert!(parse("0x0 4 const 0x0\n0x2 4 const 0x0").is_err());
+        // register beyond the end of the device
+        assert!(parse("0xc 8 const 0x0").is_err());
+    }
+}
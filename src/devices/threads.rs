@@ -1,11 +1,14 @@
 use crate::devices::mmio::IoPirate;
+use crate::devices::mmio_stats::MmioStats;
 use crate::stage1::DeviceStatus;
 use crate::stage1::DriverStatus;
 use event_manager::EventManager;
 use event_manager::MutEventSubscriber;
 use log::debug;
 use log::error;
+use log::warn;
 use log::{info, log_enabled, trace, Level};
+use nix::sched::CpuSet;
 use simple_error::{bail, require_with, simple_error, try_with};
 use stage1_interface::DeviceState;
 use std::path::{Path, PathBuf};
@@ -13,18 +16,23 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
 use virtio_device::{VirtioDevice, WithDriverSelect};
 
 use crate::devices;
-use crate::devices::DeviceContext;
 use crate::devices::MaybeIoRegionFd;
+use crate::devices::{DeviceContext, MmioOwner};
 use crate::interrutable_thread::InterrutableThread;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::kvm::PhysMemAllocator;
 use crate::result::Result;
+use crate::tracer::mmio_record::MmioRecorder;
 use crate::tracer::wrap_syscall::KvmRunWrapper;
 
-const EVENT_LOOP_TIMEOUT_MS: i32 = 1;
+pub(crate) const EVENT_LOOP_TIMEOUT_MS: i32 = 1;
+
+/// How long the guest gets to probe our devices after they have been registered before we warn
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
 
 // Arc<Mutex<>> because the same device (a dyn DevicePio/DeviceMmio from IoManager's
 // perspective, and a dyn MutEventSubscriber from EventManager's) is managed by the 2 entities,
@@ -127,6 +135,7 @@ impl Drop for DriverNotifier {
 fn event_thread(
     mut event_mgr: SubscriberEventManager,
     device_space: &DeviceContext,
+    cpus: Option<CpuSet>,
     err_sender: Sender<()>,
 ) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
     let blkdev = device_space.blkdev.clone();
@@ -136,8 +145,9 @@ fn event_thread(
     };
     log::debug!("event thread started");
 
-    let res = InterrutableThread::spawn(
+    let res = InterrutableThread::spawn_pinned(
         "event-manager",
+        cpus,
         err_sender,
         move |_ctx: &Option<Arc<DeviceContext>>, should_stop: Arc<AtomicBool>| {
             loop {
@@ -164,14 +174,44 @@ fn event_thread(
     Ok(try_with!(res, "failed to spawn event-manager thread"))
 }
 
+/// Services one further queue of the block device, see `Block::take_queue_event_managers`
+fn block_queue_thread(
+    idx: usize,
+    mut event_mgr: SubscriberEventManager,
+    cpus: Option<CpuSet>,
+    err_sender: Sender<()>,
+) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
+    let res = InterrutableThread::spawn_pinned(
+        &format!("blk-queue-{}", idx),
+        cpus,
+        err_sender,
+        move |_ctx: &Option<Arc<DeviceContext>>, should_stop: Arc<AtomicBool>| {
+            while !should_stop.load(Ordering::Relaxed) {
+                if let Err(e) = event_mgr.run_with_timeout(EVENT_LOOP_TIMEOUT_MS) {
+                    log::warn!("Failed to handle events of block queue {}: {:?}", idx, e);
+                }
+            }
+            Ok(())
+        },
+        None,
+    );
+    Ok(try_with!(
+        res,
+        "failed to spawn thread for block queue {}",
+        idx
+    ))
+}
+
 /// Periodically print block device state
 fn blkdev_monitor_thread(
     device: &DeviceContext,
+    cpus: Option<CpuSet>,
     err_sender: Sender<()>,
 ) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
     let blkdev = device.blkdev.clone();
-    let res = InterrutableThread::spawn(
+    let res = InterrutableThread::spawn_pinned(
         "blkdev-monitor",
+        cpus,
         err_sender,
         move |_ctx: &Option<Arc<DeviceContext>>, should_stop: Arc<AtomicBool>| {
             //std::thread::sleep(std::time::Duration::from_millis(10000));
@@ -223,16 +263,75 @@ fn blkdev_monitor_thread(
     Ok(try_with!(res, "failed to spawn blkdev-monitor"))
 }
 
+/// Report devices the guest did not probe within `PROBE_TIMEOUT` after stage1 registered them,
+/// see `DeviceContext::unprobed_devices`.
+fn probe_watch_thread(
+    device: Arc<DeviceContext>,
+    err_sender: Sender<()>,
+) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
+    let res = InterrutableThread::spawn(
+        "probe-watch",
+        err_sender,
+        move |dev: &Option<Arc<DeviceContext>>, should_stop: Arc<AtomicBool>| {
+            let dev = require_with!(dev.as_ref(), "no device passed");
+            let start = Instant::now();
+            while start.elapsed() < PROBE_TIMEOUT {
+                if should_stop.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            let unprobed = dev.unprobed_devices()?;
+            if unprobed.is_empty() {
+                debug!("guest probed all devices");
+                return Ok(());
+            }
+            warn!(
+                "guest did not probe our devices within {}s - does its kernel have virtio-mmio (CONFIG_VIRTIO_MMIO)?",
+                PROBE_TIMEOUT.as_secs()
+            );
+            for d in unprobed {
+                warn!(
+                    "{} device at {:#x} (irq {}) is not probed, manual intervention required: the virtio_mmio module could not be loaded, check `dmesg` in the guest",
+                    d.name, d.addr, d.irq
+                );
+            }
+            Ok(())
+        },
+        Some(device),
+    );
+
+    Ok(try_with!(res, "failed to spawn probe-watch"))
+}
+
+/// How MMIO exits handled by the wrap_syscall backend are logged and recorded
+#[derive(Clone, Debug, Default)]
+pub struct MmioTraceOptions {
+    /// only log every `sample`th exit, 1 logs all of them
+    pub sample: usize,
+    /// record all MMIO exits to this file, see `MmioRecorder`
+    pub record: Option<PathBuf>,
+    /// log all ioctls of the traced hypervisor threads, see `KvmRunWrapper::set_log_ioctls`
+    pub log_ioctls: bool,
+}
+
 /// Traps KVM_MMIO_EXITs with ptrace and forward them as needed to our block and console device driver
 fn handle_mmio_exits(
     wrapper_mo: &Mutex<Option<KvmRunWrapper>>,
     should_stop: &Arc<AtomicBool>,
     ctx: &DeviceContext,
     driver_notifier: &Arc<DriverNotifier>,
+    mmio_trace: &MmioTraceOptions,
 ) -> Result<()> {
     let mut mmio_mgr = try_with!(ctx.mmio_mgr.lock(), "cannot lock mmio manager");
     let mut wrapper_go = try_with!(wrapper_mo.lock(), "cannot obtain wrapper mutex");
     let wrapper_g = require_with!(wrapper_go.as_mut(), "KvmRunWrapper not initialized");
+    if let Some(path) = &mmio_trace.record {
+        let recorder = try_with!(MmioRecorder::create(path), "cannot record mmio exits");
+        info!("recording mmio exits to {}", path.display());
+        wrapper_g.set_mmio_recorder(Some(recorder));
+    }
+    wrapper_g.set_log_ioctls(mmio_trace.log_ioctls);
     try_with!(
         wrapper_g.stop_on_syscall(),
         "failed to wait for vmm exit_mmio"
@@ -240,43 +339,67 @@ fn handle_mmio_exits(
     info!("device ready!");
     driver_notifier.notify(DeviceState::Ready)?;
 
-    loop {
-        let mut kvm_exit = try_with!(
-            wrapper_g.wait_for_ioctl(),
-            "failed to wait for vmm exit_mmio"
-        );
+    let mut stats = MmioStats::new(mmio_trace.sample, Instant::now());
+    let res = loop {
+        let mut kvm_exit = match wrapper_g.wait_for_ioctl() {
+            Ok(kvm_exit) => kvm_exit,
+            Err(e) => break Err(simple_error!("failed to wait for vmm exit_mmio: {}", e)),
+        };
 
         if let Some(mmio_rw) = &mut kvm_exit {
-            if ctx.first_mmio_addr <= mmio_rw.addr && mmio_rw.addr < ctx.last_mmio_addr {
-                // intercept op
-                trace!("mmio access: {:#x}", mmio_rw.addr);
-                try_with!(mmio_mgr.handle_mmio_rw(mmio_rw), "failed to handle MmioRw");
-            } else {
-                // do nothing, just continue to ignore and pass to hv
-                trace!("ignore addr: {:#x}", mmio_rw.addr)
+            let pieces = ctx.split_mmio(mmio_rw.addr, mmio_rw.data().len());
+            let intercepted = pieces.iter().any(|(_, owner)| *owner == MmioOwner::Ours);
+            stats.log(mmio_rw, intercepted);
+            // foreign exits belong to the hypervisor, the vcpu continues as if we were not there
+            if intercepted {
+                let res = if pieces.len() == 1 {
+                    mmio_mgr.handle_mmio_rw(mmio_rw)
+                } else {
+                    debug!(
+                        "mmio access of {} bytes at {:#x} crosses the edge of our devices, only serving the part inside",
+                        mmio_rw.data().len(),
+                        mmio_rw.addr
+                    );
+                    mmio_mgr.handle_split_mmio_rw(mmio_rw, &pieces)
+                };
+                if let Err(e) = res {
+                    break Err(simple_error!("failed to handle MmioRw: {}", e));
+                }
             }
+            stats.report(Instant::now());
         }
 
         if should_stop.load(Ordering::Relaxed) {
-            break;
+            break Ok(());
+        }
+    };
+    stats.summary();
+    if let Some(recorder) = wrapper_g.set_mmio_recorder(None) {
+        let count = recorder.count();
+        match recorder.finish() {
+            Ok(_) => info!("recorded {} mmio exits", count),
+            Err(e) => warn!("{}", e),
         }
     }
-    Ok(())
+    res
 }
 
 /// see handle_mmio_exits
 fn mmio_exit_handler_thread(
     vm: &Arc<Hypervisor>,
     device: Arc<DeviceContext>,
+    cpus: Option<CpuSet>,
     err_sender: Sender<()>,
     driver_notifier: &Arc<DriverNotifier>,
+    mmio_trace: MmioTraceOptions,
 ) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
     let driver_notifier = Arc::clone(driver_notifier);
     let vm = Arc::clone(vm);
     vm.prepare_thread_transfer()?;
 
-    let res = InterrutableThread::spawn(
+    let res = InterrutableThread::spawn_pinned(
         "mmio-exit-handler",
+        cpus,
         err_sender,
         move |dev: &Option<Arc<DeviceContext>>, should_stop: Arc<AtomicBool>| {
             let dev = require_with!(dev.as_ref(), "no device passed");
@@ -290,7 +413,8 @@ fn mmio_exit_handler_thread(
 
             let res = vm.kvmrun_wrapped(|wrapper_mo: &Mutex<Option<KvmRunWrapper>>| {
                 // Signal that our blockdevice driver is ready now
-                let res = handle_mmio_exits(wrapper_mo, &should_stop, dev, &driver_notifier);
+                let res =
+                    handle_mmio_exits(wrapper_mo, &should_stop, dev, &driver_notifier, &mmio_trace);
                 if res.is_err() {
                     // don't shadow error here
                     let _ = driver_notifier.notify(DeviceState::Error);
@@ -311,6 +435,8 @@ fn mmio_exit_handler_thread(
 pub struct DeviceSet {
     context: Arc<DeviceContext>,
     event_manager: SubscriberEventManager,
+    /// one per block queue but the first, which is served by `event_manager`
+    queue_event_managers: Vec<SubscriberEventManager>,
 }
 
 fn ioregion_event_loop(
@@ -354,10 +480,12 @@ fn ioregion_handler_thread(
     devices: Arc<DeviceContext>,
     device: Arc<Mutex<dyn MaybeIoRegionFd + Send>>,
     mmio_mgr: Arc<Mutex<IoPirate>>,
+    cpus: Option<CpuSet>,
     err_sender: Sender<()>,
 ) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
-    let res = InterrutableThread::spawn(
+    let res = InterrutableThread::spawn_pinned(
         "ioregion-handler",
+        cpus,
         err_sender,
         move |_ctx: &Option<Arc<DeviceContext>>, should_stop: Arc<AtomicBool>| {
             info!("ioregion mmio handler started");
@@ -386,6 +514,7 @@ impl DeviceSet {
         irq_num: usize,
         backing_file: &Path,
         pts: Option<PathBuf>,
+        queue_size: u16,
     ) -> Result<DeviceSet> {
         let mut event_manager =
             try_with!(SubscriberEventManager::new(), "cannot create event manager");
@@ -397,13 +526,19 @@ impl DeviceSet {
                 &mut event_manager,
                 irq_num,
                 backing_file,
-                pts
+                pts,
+                queue_size
             ),
             "cannot create device context"
         ));
+        let queue_event_managers = {
+            let mut blkdev = try_with!(context.blkdev.lock(), "cannot lock block device");
+            blkdev.take_queue_event_managers()
+        };
         Ok(DeviceSet {
             context,
             event_manager,
+            queue_event_managers,
         })
     }
 
@@ -412,6 +547,8 @@ impl DeviceSet {
         vm: &Arc<Hypervisor>,
         device_status: DeviceStatus,
         driver_status: DriverStatus,
+        cpus: Option<CpuSet>,
+        mmio_trace: MmioTraceOptions,
         err_sender: Sender<()>,
     ) -> Result<(Threads, Arc<DriverNotifier>)> {
         let driver_notifier = Arc::new(DriverNotifier::new(
@@ -419,14 +556,30 @@ impl DeviceSet {
             driver_status,
             Arc::clone(vm),
         ));
+        let context = Arc::clone(&self.context);
+        let probe_err_sender = err_sender.clone();
         let mut threads = vec![event_thread(
             self.event_manager,
             &self.context,
+            cpus,
             err_sender.clone(),
         )?];
+        // running before the guest activates the device, which registers the queues with them
+        for (idx, event_mgr) in self.queue_event_managers.into_iter().enumerate() {
+            threads.push(block_queue_thread(
+                idx + 1,
+                event_mgr,
+                cpus,
+                err_sender.clone(),
+            )?);
+        }
 
         if log_enabled!(Level::Debug) {
-            threads.push(blkdev_monitor_thread(&self.context, err_sender.clone())?);
+            threads.push(blkdev_monitor_thread(
+                &self.context,
+                cpus,
+                err_sender.clone(),
+            )?);
         }
 
         if devices::use_ioregionfd() {
@@ -442,6 +595,7 @@ impl DeviceSet {
                     self.context.clone(),
                     self.context.blkdev.clone(),
                     self.context.mmio_mgr.clone(),
+                    cpus,
                     err_sender.clone(),
                 ),
                 "cannot spawn block ioregion handler"
@@ -451,6 +605,7 @@ impl DeviceSet {
                     self.context.clone(),
                     self.context.console.clone(),
                     self.context.mmio_mgr.clone(),
+                    cpus,
                     err_sender,
                 ),
                 "cannot spawn console ioregion handler"
@@ -459,12 +614,15 @@ impl DeviceSet {
             threads.push(mmio_exit_handler_thread(
                 vm,
                 self.context,
+                cpus,
                 err_sender,
                 &driver_notifier,
+                mmio_trace,
             )?);
         }
 
         driver_notifier.wait()?;
+        threads.push(probe_watch_thread(context, probe_err_sender)?);
         Ok((threads, driver_notifier))
     }
 }
@@ -5,10 +5,8 @@
 use nix::unistd::Pid;
 use simple_error::SimpleError;
 use std::borrow::{Borrow, BorrowMut};
-use std::fs::OpenOptions;
-use std::io::{Seek, SeekFrom};
+use std::fs::File;
 use std::ops::DerefMut;
-use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use virtio_device::{VirtioDevice, VirtioDeviceType};
 
@@ -23,23 +21,26 @@ use vm_device::{DeviceMmio, MutDeviceMmio};
 use vm_memory::GuestMemoryMmap;
 use vmm_sys_util::eventfd::EventFd;
 
-use crate::devices::use_ioregionfd;
-use crate::devices::virtio::block::inorder_handler::Mmap;
+use crate::devices::threads::SubscriberEventManager;
+use crate::devices::virtio::block::inorder_handler::{Mmap, Storage, Stream};
 use crate::devices::virtio::block::{
-    BLOCK_DEVICE_ID, SECTOR_SHIFT, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO,
+    BLOCK_DEVICE_ID, SECTOR_SHIFT, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO,
 };
 use crate::devices::virtio::features::{
     VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
 };
-use crate::devices::virtio::{IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE};
+use crate::devices::virtio::{check_queue_size, IrqAckHandler, MmioConfig, SingleFdSignalQueue};
 use crate::devices::MaybeIoRegionFd;
+use crate::devices::{use_ioregionfd, use_userspace_ioeventfd};
 use crate::kvm::hypervisor::{
     ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd,
 };
 
 use super::inorder_handler::InOrderQueueHandler;
 use super::queue_handler::QueueHandler;
-use super::{build_config_space, BlockArgs, Error, Result};
+use super::{backing_size, build_config_space, open_backing, BlockArgs, Error, Result};
+
+type Subscriber = Arc<Mutex<dyn MutEventSubscriber + Send>>;
 
 // This Block device can only use the MMIO transport for now, but we plan to reuse large parts of
 // the functionality when we implement virtio PCI as well, for example by having a base generic
@@ -47,22 +48,29 @@ use super::{build_config_space, BlockArgs, Error, Result};
 pub struct Block {
     virtio_cfg: VirtioConfig<Queue>,
     pub mmio_cfg: MmioConfig,
-    endpoint: RemoteEndpoint<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+    /// one per queue, the first one belongs to the shared event manager
+    endpoints: Vec<RemoteEndpoint<Subscriber>>,
+    /// event managers of all queues but the first, see `take_queue_event_managers`
+    queue_event_mgrs: Vec<SubscriberEventManager>,
     pub irq_ack_handler: Arc<Mutex<IrqAckHandler>>,
     irqfd: Arc<EventFd>,
     pub ioregionfd: Option<IoRegionFd>,
-    ioeventfd: Option<IoEvent>,
+    /// one per queue, taken by the queue handlers on activation
+    ioeventfds: Vec<Option<IoEvent>>,
     pub uioefd: UserspaceIoEventFd,
-    /// only used when ioregionfd != None
-    file_path: PathBuf,
+    /// opened once in `new`, the queue handlers get clones of it
+    backing: File,
+    /// None for streams, see `Storage::Stream`
+    disk_size: Option<u64>,
     read_only: bool,
-    sub_id: Option<SubscriberId>,
+    /// subscriber of each activated queue, together with the index of its endpoint
+    sub_ids: Vec<(usize, SubscriberId)>,
     guest_memory: Arc<GuestMemoryMmap>,
     pid: Pid,
 
-    // Before resetting we return the handler to the mmio thread for cleanup
+    // Before resetting we return the handlers to the mmio thread for cleanup
     #[allow(dead_code)]
-    handler: Option<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+    handlers: Vec<Subscriber>,
     // We'll prob need to remember this for state save/restore unless we pass the info from
     // the outside.
     _root_device: bool,
@@ -89,10 +97,26 @@ impl Block {
             device_features |= 1 << VIRTIO_BLK_F_FLUSH;
         }
 
-        // A block device has a single queue.
+        if args.num_queues == 0 {
+            return Err(Error::QueuesNotValid);
+        }
+        let mut backing = open_backing(&args.file_path, args.read_only)?;
+        let disk_size = backing_size(&mut backing)?;
+        // a stream has a single position, queues serviced in parallel would interleave it
+        if disk_size.is_none() && args.num_queues > 1 {
+            log::info!("backing file cannot seek, the block device uses a single queue");
+            args.num_queues = 1;
+        }
+        if args.num_queues > 1 {
+            device_features |= 1 << VIRTIO_BLK_F_MQ;
+        }
+
         let mem = args.common.mem.clone();
-        let queues = vec![Queue::new(QUEUE_MAX_SIZE).map_err(Error::QueueCreation)?];
-        let config_space = build_config_space(&args.file_path)?;
+        check_queue_size(args.queue_size).map_err(Error::Simple)?;
+        let queues = (0..args.num_queues)
+            .map(|_| Queue::new(args.queue_size).map_err(Error::QueueCreation))
+            .collect::<Result<Vec<_>>>()?;
+        let config_space = build_config_space(disk_size.unwrap_or(0), args.num_queues);
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         // Used to send notifications to the driver.
@@ -122,23 +146,45 @@ impl Block {
             );
         }
         let mut uioefd = UserspaceIoEventFd::default();
-        let ioeventfd = IoEvent::register(&args.common.vmm, &mut uioefd, &mmio_cfg, 0)
-            .map_err(Error::Simple)?;
+        let mut ioeventfds = vec![];
+        for queue_idx in 0..args.num_queues {
+            let ioeventfd =
+                IoEvent::register(&args.common.vmm, &mut uioefd, &mmio_cfg, queue_idx as u64)
+                    .map_err(Error::Simple)?;
+            ioeventfds.push(Some(ioeventfd));
+        }
+
+        // Every queue but the first gets an event manager of its own, otherwise a single thread
+        // would serialize all queues again.
+        let mut endpoints = vec![args.common.event_mgr.remote_endpoint()];
+        let mut queue_event_mgrs = vec![];
+        for queue_idx in 1..args.num_queues {
+            let event_mgr = SubscriberEventManager::new().map_err(|e| {
+                Error::Simple(SimpleError::new(format!(
+                    "cannot create event manager for block queue {}: {:?}",
+                    queue_idx, e
+                )))
+            })?;
+            endpoints.push(event_mgr.remote_endpoint());
+            queue_event_mgrs.push(event_mgr);
+        }
 
         let block = Arc::new(Mutex::new(Block {
             virtio_cfg,
             mmio_cfg,
-            endpoint: args.common.event_mgr.remote_endpoint(),
+            endpoints,
+            queue_event_mgrs,
             irq_ack_handler,
             irqfd,
             ioregionfd,
-            ioeventfd: Some(ioeventfd),
+            ioeventfds,
             uioefd,
-            file_path: args.file_path,
+            backing,
+            disk_size,
             read_only: args.read_only,
             pid: args.common.vmm.pid,
-            sub_id: None,
-            handler: None,
+            sub_ids: vec![],
+            handlers: vec![],
             _root_device: args.root_device,
             guest_memory: mem,
         }));
@@ -152,83 +198,137 @@ impl Block {
         Ok(block)
     }
 
-    fn _activate(&mut self) -> Result<()> {
-        if self.virtio_cfg.device_activated {
-            return Err(Error::AlreadyActivated);
-        }
-
-        // We do not support legacy drivers.
-        if self.virtio_cfg.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
-            return Err(Error::BadFeatures(self.virtio_cfg.driver_features));
-        }
+    /// Event managers for all queues but the first. The caller has to run each of them on a
+    /// thread of its own before the device is activated, see `DeviceSet::start`.
+    pub fn take_queue_event_managers(&mut self) -> Vec<SubscriberEventManager> {
+        std::mem::take(&mut self.queue_event_mgrs)
+    }
 
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(!self.read_only)
-            .open(&self.file_path)
-            .map_err(Error::OpenFile)?;
-
-        let disk_size = file.seek(SeekFrom::End(0)).map_err(Error::Seek)?;
-
-        let mmap = match Mmap::new(&file, disk_size as usize) {
-            Ok(m) => m,
-            Err(e) => {
-                return Err(Error::Simple(SimpleError::new(format!(
-                    "cannot mmap disk: {:?}",
-                    e
-                ))))
+    /// Build the handler servicing `queue`. Each queue gets its own file descriptors and mapping
+    /// of the disk, so that handlers do not share state.
+    fn queue_handler(
+        &self,
+        features: u64,
+        queue: Queue,
+        ioeventfd: IoEvent,
+    ) -> Result<QueueHandler> {
+        let file = self.backing.try_clone().map_err(Error::OpenFile)?;
+
+        let (storage, disk, backing, disk_size) = match self.disk_size {
+            // regular files never block on reads and epoll does not support them anyway
+            Some(disk_size) => {
+                let mmap = match Mmap::new(&file, disk_size as usize) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        return Err(Error::Simple(SimpleError::new(format!(
+                            "cannot mmap disk: {:?}",
+                            e
+                        ))))
+                    }
+                };
+                // TODO: Create the backend earlier (as part of `Block::new`)?
+                let disk = StdIoBackend::new(file, features)
+                    .map_err(Error::Backend)?
+                    .with_device_id(*b"vmsh0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
+                (Storage::Mapped(mmap), Some(disk), None, disk_size)
+            }
+            None => {
+                let backing = file.try_clone().map_err(Error::OpenFile)?;
+                (Storage::Stream(Stream::new(file)), None, Some(backing), 0)
             }
         };
 
-        let mut features = self.virtio_cfg.driver_features;
-        if self.read_only {
-            // Not sure if the driver is expected to explicitly acknowledge the `RO` feature,
-            // so adding it explicitly here when present just in case.
-            features |= 1 << VIRTIO_BLK_F_RO;
-        }
-
-        // TODO: Create the backend earlier (as part of `Block::new`)?
-        let disk = StdIoBackend::new(file, features)
-            .map_err(Error::Backend)?
-            .with_device_id(*b"vmsh0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
-
         let driver_notify = SingleFdSignalQueue {
             irqfd: self.irqfd.clone(),
             interrupt_status: self.virtio_cfg.interrupt_status.clone(),
             ack_handler: self.irq_ack_handler.clone(),
         };
 
-        let queue = self.virtio_cfg.queues.remove(0);
         let inner = InOrderQueueHandler {
             pid: self.pid,
             driver_notify,
             queue,
             disk,
             sectors: disk_size >> SECTOR_SHIFT,
-            mmap,
+            storage,
             mem: Arc::clone(&self.guest_memory),
             remote_iovs: vec![],
         };
-        let handler = Arc::new(Mutex::new(QueueHandler {
+        Ok(QueueHandler {
             inner,
-            ioeventfd: match self.ioeventfd.take() {
+            ioeventfd,
+            backing,
+            waiting: false,
+        })
+    }
+
+    fn _activate(&mut self) -> Result<()> {
+        if self.virtio_cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+
+        // We do not support legacy drivers.
+        if self.virtio_cfg.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::BadFeatures(self.virtio_cfg.driver_features));
+        }
+
+        let mut features = self.virtio_cfg.driver_features;
+        if self.read_only {
+            // Not sure if the driver is expected to explicitly acknowledge the `RO` feature,
+            // so adding it explicitly here when present just in case.
+            features |= 1 << VIRTIO_BLK_F_RO;
+        }
+
+        // Without VIRTIO_BLK_F_MQ the driver only sets up the first queue. With it, it may also
+        // use fewer queues than we offer, i.e. if the guest has fewer cpus online.
+        let queues = std::mem::take(&mut self.virtio_cfg.queues);
+        let mut handlers = vec![];
+        for (idx, queue) in queues.into_iter().enumerate() {
+            if !queue.ready() {
+                log::debug!("block queue {} was not set up by the driver", idx);
+                continue;
+            }
+            // the driver may pick a smaller queue than we offer
+            if queue.size() < queue.max_size() {
+                log::info!(
+                    "guest driver shrunk block queue {} to {} of {} descriptors",
+                    idx,
+                    queue.size(),
+                    queue.max_size()
+                );
+            } else {
+                log::debug!("block queue {} size: {}", idx, queue.size());
+            }
+            let ioeventfd = match self.ioeventfds.get_mut(idx).and_then(Option::take) {
                 Some(fd) => fd,
                 None => return Err(Error::Simple(SimpleError::new("ioeventfd not set"))),
-            },
-        }));
-
-        // Register the queue handler with the `EventManager`. We record the `sub_id`
-        // (and/or keep a handler clone) to remove the subscriber when resetting the device
-        let sub_id = self
-            .endpoint
-            .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
-                Ok(mgr.add_subscriber(handler))
-            })
-            .map_err(|e| {
-                log::warn!("{}", e);
-                Error::Endpoint(e)
-            })?;
-        self.sub_id = Some(sub_id);
+            };
+            handlers.push(self.queue_handler(features, queue, ioeventfd)?);
+        }
+        if handlers.is_empty() {
+            return Err(Error::QueuesNotValid);
+        }
+        log::info!("block device uses {} queues", handlers.len());
+
+        // Register each queue handler with the `EventManager` of its queue. We record the
+        // `sub_ids` (and/or keep handler clones) to remove the subscribers when resetting the
+        // device.
+        for (idx, handler) in handlers.into_iter().enumerate() {
+            let handler: Subscriber = Arc::new(Mutex::new(handler));
+            let endpoint = match self.endpoints.get(idx) {
+                Some(endpoint) => endpoint,
+                None => return Err(Error::Simple(SimpleError::new("endpoint not set"))),
+            };
+            let sub_id = endpoint
+                .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
+                    Ok(mgr.add_subscriber(handler))
+                })
+                .map_err(|e| {
+                    log::warn!("{}", e);
+                    Error::Endpoint(e)
+                })?;
+            self.sub_ids.push((idx, sub_id));
+        }
 
         log::debug!("activating device: ok");
         self.virtio_cfg.device_activated = true;
@@ -238,15 +338,14 @@ impl Block {
     fn _reset(&mut self) -> Result<()> {
         // we remove the handler here, since we need to free up the ioeventfd resources
         // in the mmio thread rather the eventmanager thread.
-        if let Some(sub_id) = self.sub_id.take() {
-            let handler = self
-                .endpoint
+        for (idx, sub_id) in std::mem::take(&mut self.sub_ids) {
+            let handler = self.endpoints[idx]
                 .call_blocking(move |mgr| mgr.remove_subscriber(sub_id))
                 .map_err(|e| {
                     log::warn!("{}", e);
                     Error::Endpoint(e)
                 })?;
-            self.handler = Some(handler);
+            self.handlers.push(handler);
         }
         Ok(())
     }
@@ -300,7 +399,7 @@ impl VirtioDeviceActions for Block {
 
 impl VirtioQueueNotifiable for Block {
     fn queue_notify(&mut self, val: u32) {
-        if use_ioregionfd() {
+        if use_userspace_ioeventfd() {
             self.uioefd.queue_notify(val);
             log::trace!("queue_notify {}", val);
         }
@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::fs::File;
-use std::io::{IoSlice, IoSliceMut};
+use std::io::{IoSlice, IoSliceMut, Read, Write};
 use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::{io, result, slice};
@@ -21,6 +21,7 @@ use virtio_blk::stdio_executor::{self, StdIoBackend};
 use virtio_queue::{DescriptorChain, Queue, QueueOwnedT, QueueT};
 use vm_memory::GuestMemoryMmap;
 use vm_memory::{self, Bytes, GuestAddressSpace, GuestMemory, GuestMemoryError};
+use vmm_sys_util::epoll::EventSet;
 
 use crate::devices::virtio::SignalUsedQueue;
 use crate::result::Result;
@@ -81,6 +82,85 @@ impl Drop for Mmap {
     }
 }
 
+/// Where the data of the disk lives
+pub enum Storage {
+    /// The backing file mapped into vmsh. Used for everything that can seek, i.e. regular files
+    /// and block devices.
+    Mapped(Mmap),
+    /// A FIFO, see `Stream`.
+    Stream(Stream),
+}
+
+/// A backing file without a size. Reads and writes have to continue where the last one ended,
+/// starting at sector 0, anything else fails with an IO error. Since FIFOs have no size, the
+/// disk reports a capacity of 0 and accesses are not checked against it.
+///
+/// The file is non-blocking. A read or write that cannot complete stays pending, together with
+/// its data, and no later request is processed until the queue handler sees the file ready in
+/// the direction returned by `InOrderQueueHandler::blocked_on`.
+pub struct Stream {
+    file: File,
+    /// where the next request has to start
+    pos: u64,
+    pending: Option<StreamRequest>,
+}
+
+struct StreamRequest {
+    head_index: u16,
+    request: Request,
+    buf: Vec<u8>,
+    /// bytes of `buf` already transferred
+    done: usize,
+}
+
+impl Stream {
+    pub fn new(file: File) -> Stream {
+        Stream {
+            file,
+            pos: 0,
+            pending: None,
+        }
+    }
+}
+
+/// Read into (`RequestType::In`) or write from `buf[*done..]` until it is done or `file` would
+/// block. Returns false in the latter case.
+fn stream_transfer(
+    file: &mut File,
+    request_type: RequestType,
+    buf: &mut [u8],
+    done: &mut usize,
+) -> io::Result<bool> {
+    while *done < buf.len() {
+        let res = if request_type == RequestType::In {
+            file.read(&mut buf[*done..])
+        } else {
+            file.write(&buf[*done..])
+        };
+        match res {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => *done += n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Request type as defined by the standard, for errors
+fn request_type_nr(request_type: RequestType) -> u32 {
+    match request_type {
+        RequestType::In => 0,
+        RequestType::Out => 1,
+        RequestType::Flush => 4,
+        RequestType::GetDeviceID => 8,
+        RequestType::Discard => 11,
+        RequestType::WriteZeroes => 13,
+        RequestType::Unsupported(nr) => nr,
+    }
+}
+
 // This object is used to process the queue of a block device without making any assumptions
 // about the notification mechanism. We're using a specific backend for now (the `StdIoBackend`
 // object), but the aim is to have a way of working with generic backends and turn this into
@@ -89,9 +169,11 @@ impl Drop for Mmap {
 pub struct InOrderQueueHandler<S: SignalUsedQueue> {
     pub driver_notify: S,
     pub queue: Queue,
-    pub disk: StdIoBackend<File>,
+    /// Serves requests other than reads, writes and flushes. Needs a seekable file, so it is not
+    /// available for streams.
+    pub disk: Option<StdIoBackend<File>>,
     pub sectors: u64,
-    pub mmap: Mmap,
+    pub storage: Storage,
     //pub guest_memory: Arc<Mutex<Option<M>>>,
     pub pid: Pid,
 
@@ -152,6 +234,11 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
             return Err(stdio_executor::Error::InvalidDataLength);
         }
 
+        let ptr = match &self.storage {
+            Storage::Mapped(mmap) => mmap.ptr,
+            Storage::Stream(_) => return self.execute_stream(mem, request),
+        };
+
         match request_type {
             RequestType::In => {
                 self.check_access(total_len / SECTOR_SIZE, request.sector())?;
@@ -162,7 +249,7 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
                 self.prepare_iovs(request)?;
                 let local_iovs = vec![IoSlice::new(unsafe {
                     slice::from_raw_parts(
-                        self.mmap.ptr.add(offset as usize) as *mut u8,
+                        ptr.add(offset as usize) as *mut u8,
                         request.total_data_len() as usize,
                     )
                 })];
@@ -181,7 +268,7 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
                 self.prepare_iovs(request)?;
                 let mut local_iovs = vec![IoSliceMut::new(unsafe {
                     slice::from_raw_parts_mut(
-                        self.mmap.ptr.add(offset as usize) as *mut u8,
+                        ptr.add(offset as usize) as *mut u8,
                         request.total_data_len() as usize,
                     )
                 })];
@@ -200,7 +287,7 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
                 self.check_access(total_len / SECTOR_SIZE, request.sector())?;
                 let res = unsafe {
                     msync(
-                        self.mmap.ptr.add(offset as usize),
+                        ptr.add(offset as usize),
                         total_len as usize,
                         MsFlags::MS_SYNC,
                     )
@@ -209,45 +296,232 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
                     stdio_executor::Error::Flush(io::Error::from_raw_os_error(e as i32))
                 })?
             }
-            _ => return self.disk.execute(mem, request),
+            _ => return self.execute_other(mem, request),
         }
         Ok(bytes_to_mem)
     }
+    /// `execute` for `Storage::Stream`, except for reads and writes, see `start_stream`
+    fn execute_stream(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        request: &Request,
+    ) -> stdio_executor::Result<u32> {
+        // nothing is buffered on our side
+        if request.request_type() == RequestType::Flush {
+            return Ok(0);
+        }
+        self.execute_other(mem, request)
+    }
+
+    /// Check a read or write of a stream and copy the data of a write out of the guest
+    fn prepare_stream(&mut self, request: &Request) -> stdio_executor::Result<Vec<u8>> {
+        let offset = request
+            .sector()
+            .checked_shl(u32::from(SECTOR_SHIFT))
+            .ok_or(stdio_executor::Error::InvalidAccess)?;
+        let total_len = request.total_data_len();
+        if total_len % SECTOR_SIZE != 0 || total_len > u32::MAX as u64 {
+            return Err(stdio_executor::Error::InvalidDataLength);
+        }
+        let pos = match &self.storage {
+            Storage::Stream(stream) => stream.pos,
+            Storage::Mapped(_) => return Err(stdio_executor::Error::InvalidAccess),
+        };
+        if offset != pos {
+            warn!(
+                "stream backed disk can only be accessed sequentially, expected offset {} got {}",
+                pos, offset
+            );
+            return Err(stdio_executor::Error::InvalidAccess);
+        }
+        let mut buf = vec![0; total_len as usize];
+        if request.request_type() == RequestType::Out {
+            self.prepare_iovs(request)?;
+            let read = process_vm_readv(
+                self.pid,
+                &mut [IoSliceMut::new(&mut buf)],
+                self.remote_iovs.as_slice(),
+            )
+            .map_err(|e| {
+                stdio_executor::Error::Write(GuestMemoryError::IOError(
+                    io::Error::from_raw_os_error(e as i32),
+                ))
+            })?;
+            if read != buf.len() {
+                return Err(stdio_executor::Error::Write(GuestMemoryError::IOError(
+                    io::Error::from(io::ErrorKind::UnexpectedEof),
+                )));
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Start a read or write of a stream. Returns false if it is pending, see `Stream`.
+    fn start_stream(&mut self, head_index: u16, request: Request) -> result::Result<bool, Error> {
+        let buf = match self.prepare_stream(&request) {
+            Ok(buf) => buf,
+            Err(e) => {
+                let len = self.write_status(&request, Err(e))?;
+                self.add_used(head_index, len)?;
+                return Ok(true);
+            }
+        };
+        if let Storage::Stream(stream) = &mut self.storage {
+            stream.pending = Some(StreamRequest {
+                head_index,
+                request,
+                buf,
+                done: 0,
+            });
+        }
+        self.resume_stream()
+    }
+
+    /// Continue the pending request of a stream, if any. Returns false if it is still pending.
+    fn resume_stream(&mut self) -> result::Result<bool, Error> {
+        let stream = match &mut self.storage {
+            Storage::Stream(stream) => stream,
+            Storage::Mapped(_) => return Ok(true),
+        };
+        let transferred = match &mut stream.pending {
+            None => return Ok(true),
+            Some(pending) => stream_transfer(
+                &mut stream.file,
+                pending.request.request_type(),
+                &mut pending.buf,
+                &mut pending.done,
+            ),
+        };
+        if let Ok(false) = transferred {
+            return Ok(false);
+        }
+        let pending = match stream.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(true),
+        };
+        let res = match transferred {
+            Ok(_) => {
+                stream.pos += pending.buf.len() as u64;
+                self.finish_stream(&pending)
+            }
+            Err(e) if pending.request.request_type() == RequestType::In => {
+                Err(stdio_executor::Error::Read(GuestMemoryError::IOError(e), 0))
+            }
+            Err(e) => Err(stdio_executor::Error::Write(GuestMemoryError::IOError(e))),
+        };
+        let len = self.write_status(&pending.request, res)?;
+        self.add_used(pending.head_index, len)?;
+        Ok(true)
+    }
+
+    /// Copy the data of a completed stream read into the guest
+    fn finish_stream(&mut self, pending: &StreamRequest) -> stdio_executor::Result<u32> {
+        if pending.request.request_type() != RequestType::In {
+            // like the mapped case, the used length counts bytes written to guest memory
+            return Ok(0);
+        }
+        self.prepare_iovs(&pending.request)?;
+        process_vm_writev(
+            self.pid,
+            &[IoSlice::new(&pending.buf)],
+            self.remote_iovs.as_slice(),
+        )
+        .map(|written| written as u32)
+        .map_err(|e| {
+            stdio_executor::Error::Read(
+                GuestMemoryError::IOError(io::Error::from_raw_os_error(e as i32)),
+                0,
+            )
+        })
+    }
+
+    /// The epoll event a pending stream request waits for, see `Stream`
+    pub fn blocked_on(&self) -> Option<EventSet> {
+        match &self.storage {
+            Storage::Stream(Stream {
+                pending: Some(pending),
+                ..
+            }) => {
+                if pending.request.request_type() == RequestType::In {
+                    Some(EventSet::IN)
+                } else {
+                    Some(EventSet::OUT)
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn execute_other(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        request: &Request,
+    ) -> stdio_executor::Result<u32> {
+        match &mut self.disk {
+            Some(disk) => disk.execute(mem, request),
+            None => Err(stdio_executor::Error::Unsupported(request_type_nr(
+                request.request_type(),
+            ))),
+        }
+    }
+
+    /// Write the status of `request` and return the used length of its chain
+    fn write_status(
+        &self,
+        request: &Request,
+        res: stdio_executor::Result<u32>,
+    ) -> result::Result<u32, Error> {
+        let (status, len) = match res {
+            // TODO: Using `saturating_add` until we consume the recent changes
+            // proposed for the executor upstream.
+            // VIRTIO_BLK_S_OK defined as 0 in the standard.
+            Ok(l) => (0, l.saturating_add(1)),
+            Err(e) => {
+                warn!("failed to execute block request: {:?}", e);
+                // TODO: add `status` or similar method to executor error.
+                if let stdio_executor::Error::Unsupported(_) = e {
+                    // UNSUPP
+                    (2, 1)
+                } else {
+                    // IOERR
+                    (1, 1)
+                }
+            }
+        };
+        self.mem.write_obj(status as u8, request.status_addr())?;
+        Ok(len)
+    }
+
+    fn add_used(&mut self, head_index: u16, len: u32) -> result::Result<(), Error> {
+        self.queue.add_used(self.mem.as_ref(), head_index, len)?;
+
+        if self.queue.needs_notification(self.mem.as_ref())? {
+            log::trace!("notification needed: yes");
+            self.driver_notify.signal_used_queue(0);
+        } else {
+            log::trace!("notification needed: no");
+        }
+        Ok(())
+    }
+
+    /// Returns false if the request of a stream is pending, see `Stream`
     fn process_chain(
         &mut self,
         mut chain: DescriptorChain<&GuestMemoryMmap>,
-    ) -> result::Result<(), Error> {
+    ) -> result::Result<bool, Error> {
         let len;
 
         log::trace!("process_chain");
         match Request::parse(&mut chain) {
             Ok(request) => {
                 log::trace!("request: {:?}", request);
-                let status = match self.execute(chain.memory(), &request) {
-                    Ok(l) => {
-                        // TODO: Using `saturating_add` until we consume the recent changes
-                        // proposed for the executor upstream.
-                        len = l.saturating_add(1);
-                        // VIRTIO_BLK_S_OK defined as 0 in the standard.
-                        0
-                    }
-                    Err(e) => {
-                        warn!("failed to execute block request: {:?}", e);
-                        len = 1;
-                        // TODO: add `status` or similar method to executor error.
-                        if let stdio_executor::Error::Unsupported(_) = e {
-                            // UNSUPP
-                            2
-                        } else {
-                            // IOERR
-                            1
-                        }
-                    }
-                };
-
-                chain
-                    .memory()
-                    .write_obj(status as u8, request.status_addr())?;
+                if let (Storage::Stream(_), RequestType::In | RequestType::Out) =
+                    (&self.storage, request.request_type())
+                {
+                    return self.start_stream(chain.head_index(), request);
+                }
+                let res = self.execute(chain.memory(), &request);
+                len = self.write_status(&request, res)?;
             }
             Err(e) => {
                 len = 0;
@@ -255,18 +529,9 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
             }
         }
 
-        self.queue
-            .add_used(self.mem.as_ref(), chain.head_index(), len)?;
-
-        if self.queue.needs_notification(self.mem.as_ref())? {
-            log::trace!("notification needed: yes");
-            self.driver_notify.signal_used_queue(0);
-        } else {
-            log::trace!("notification needed: no");
-        }
-
+        self.add_used(chain.head_index(), len)?;
         log::trace!("process_chain done");
-        Ok(())
+        Ok(true)
     }
 
     pub fn process_queue(&mut self) -> result::Result<(), Error> {
@@ -277,8 +542,15 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
         loop {
             self.queue.disable_notification(mem.as_ref())?;
 
+            // Requests of a stream complete in order. While one is pending, notifications stay
+            // disabled and the queue handler calls us again once the stream is ready.
+            if !self.resume_stream()? {
+                return Ok(());
+            }
             while let Some(chain) = self.queue.iter(mem.as_ref())?.next() {
-                self.process_chain(chain)?;
+                if !self.process_chain(chain)? {
+                    return Ok(());
+                }
             }
 
             if !self.queue.enable_notification(mem.as_ref())? {
@@ -292,3 +564,66 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
 
 // TODO: Figure out which unit tests make sense to add after implementing a generic backend
 // abstraction for `InOrderHandler`.
+
+#[cfg(test)]
+mod tests {
+    use nix::fcntl::OFlag;
+    use nix::unistd::pipe2;
+    use std::os::unix::io::FromRawFd;
+
+    use super::*;
+
+    fn nonblocking_pipe() -> (File, File) {
+        let (read, write) = pipe2(OFlag::O_NONBLOCK).expect("cannot create pipe");
+        // Safe because we own both fds and nothing else closes them.
+        unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) }
+    }
+
+    #[test]
+    fn test_stream_transfer_read() {
+        let (mut read, mut write) = nonblocking_pipe();
+        let mut buf = [0u8; 1024];
+        let mut done = 0;
+        assert!(!stream_transfer(&mut read, RequestType::In, &mut buf, &mut done).expect("read"));
+
+        write.write_all(&[1u8; 512]).expect("cannot write to pipe");
+        assert!(!stream_transfer(&mut read, RequestType::In, &mut buf, &mut done).expect("read"));
+        assert_eq!(done, 512);
+
+        write.write_all(&[2u8; 512]).expect("cannot write to pipe");
+        assert!(stream_transfer(&mut read, RequestType::In, &mut buf, &mut done).expect("read"));
+        assert_eq!(buf[..512], [1u8; 512]);
+        assert_eq!(buf[512..], [2u8; 512]);
+
+        drop(write);
+        let mut done = 0;
+        assert!(stream_transfer(&mut read, RequestType::In, &mut buf, &mut done).is_err());
+    }
+
+    #[test]
+    fn test_stream_transfer_write() {
+        let (mut read, mut write) = nonblocking_pipe();
+        // more than the default pipe buffer of 64 KiB
+        let mut buf = vec![3u8; 128 * 1024];
+        let mut done = 0;
+        assert!(
+            !stream_transfer(&mut write, RequestType::Out, &mut buf, &mut done).expect("write")
+        );
+        assert!(done > 0 && done < buf.len());
+
+        let mut out = vec![];
+        while out.len() < buf.len() {
+            let mut chunk = [0u8; 4096];
+            match read.read(&mut chunk) {
+                Ok(n) => out.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    stream_transfer(&mut write, RequestType::Out, &mut buf, &mut done)
+                        .expect("write");
+                }
+                Err(e) => panic!("cannot read from pipe: {}", e),
+            }
+        }
+        assert_eq!(done, buf.len());
+        assert_eq!(out, buf);
+    }
+}
@@ -6,11 +6,14 @@ mod device;
 mod inorder_handler;
 mod queue_handler;
 
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 
 use event_manager::Error as EvmgrError;
+use nix::sys::stat::{fstat, SFlag};
 use virtio_blk::stdio_executor;
 use vm_device::bus;
 use vmm_sys_util::errno;
@@ -29,10 +32,39 @@ pub const BLOCK_DEVICE_ID: u32 = 2;
 pub const VIRTIO_BLK_F_RO: u64 = 5;
 // Block device FLUSH feature.
 pub const VIRTIO_BLK_F_FLUSH: u64 = 9;
+// Block device multi-queue feature.
+pub const VIRTIO_BLK_F_MQ: u64 = 12;
+
+// Offset of `num_queues` in `struct virtio_blk_config`.
+const CONFIG_NUM_QUEUES_OFFSET: usize = 34;
 
 // The sector size is 512 bytes (1 << 9).
 const SECTOR_SHIFT: u8 = 9;
 
+/// Open the backing file of a disk. O_NONBLOCK changes nothing for regular files and block
+/// devices, but opening a FIFO without a writer does not hang and its queue handler can wait for
+/// it with epoll, see `inorder_handler::Storage::Stream`.
+fn open_backing<P: AsRef<Path>>(path: P, read_only: bool) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .map_err(Error::OpenFile)
+}
+
+/// Size of the backing file, None if it is a FIFO. Those cannot seek or be mapped and have no
+/// size, the disk reports a capacity of 0 for them.
+fn backing_size(file: &mut File) -> Result<Option<u64>> {
+    let stat = fstat(file.as_raw_fd())
+        .map_err(|e| Error::Simple(SimpleError::new(format!("cannot stat backing file: {}", e))))?;
+    if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFIFO {
+        return Ok(None);
+    }
+    // st_size is 0 for block devices
+    file.seek(SeekFrom::End(0)).map(Some).map_err(Error::Seek)
+}
+
 #[derive(Debug)]
 pub enum Error {
     AlreadyActivated,
@@ -43,7 +75,6 @@ pub enum Error {
     EventFd(io::Error),
     OpenFile(io::Error),
     QueueCreation(virtio_queue::Error),
-    #[allow(dead_code)] // FIXME
     QueuesNotValid,
     #[allow(dead_code)] // FIXME
     RegisterIoevent(errno::Error),
@@ -57,19 +88,19 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 // TODO: Add a helper abstraction to rust-vmm for building the device configuration space.
 // The one we build below for the block device contains the minimally required `capacity` member,
-// but other fields can be present as well depending on the negotiated features.
-fn build_config_space<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
-    // TODO: right now, the file size is computed by the StdioBackend as well. Maybe we should
-    // create the backend as early as possible, and get the size information from there.
-    let file_size = File::open(path)
-        .map_err(Error::OpenFile)?
-        .seek(SeekFrom::End(0))
-        .map_err(Error::Seek)?;
+// and `num_queues` if we offer more than one queue (VIRTIO_BLK_F_MQ). The fields in between
+// belong to features we do not offer and stay zero.
+fn build_config_space(file_size: u64, num_queues: u16) -> Vec<u8> {
     // If the file size is actually not a multiple of sector size, then data at the very end
     // will be ignored.
     let num_sectors = file_size >> SECTOR_SHIFT;
     // This has to be in little endian btw.
-    Ok(num_sectors.to_le_bytes().to_vec())
+    let mut config = num_sectors.to_le_bytes().to_vec();
+    if num_queues > 1 {
+        config.resize(CONFIG_NUM_QUEUES_OFFSET, 0);
+        config.extend_from_slice(&num_queues.to_le_bytes());
+    }
+    config
 }
 
 // Arguments required when building a block device.
@@ -79,17 +110,30 @@ pub struct BlockArgs<'a, B> {
     pub read_only: bool,
     pub root_device: bool,
     pub advertise_flush: bool,
+    /// maximum number of descriptors in the virtqueue, see `check_queue_size`
+    pub queue_size: u16,
+    /// Number of virtqueues, usually one per guest vcpu. More than one enables VIRTIO_BLK_F_MQ.
+    pub num_queues: u16,
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Write;
     use std::mem::size_of;
+    use std::os::unix::io::FromRawFd;
 
+    use nix::sys::stat::Mode;
+    use nix::unistd::mkfifo;
+    use vmm_sys_util::tempdir::TempDir;
     use vmm_sys_util::tempfile::TempFile;
 
     use super::*;
 
+    fn file_size(tmp: &TempFile) -> u64 {
+        let mut file = open_backing(tmp.as_path(), true).unwrap();
+        backing_size(&mut file).unwrap().unwrap()
+    }
+
     #[test]
     fn test_build_config_space() {
         let tmp = TempFile::new().unwrap();
@@ -102,9 +146,9 @@ mod tests {
         }
 
         {
-            let config_space = build_config_space(tmp.as_path()).unwrap();
+            let config_space = build_config_space(file_size(&tmp), 1);
 
-            // The config space is only populated with the `capacity` field for now.
+            // The config space is only populated with the `capacity` field for a single queue.
             assert_eq!(config_space.len(), size_of::<u64>());
             assert_eq!(config_space[..8], num_sectors.to_le_bytes());
         }
@@ -114,9 +158,45 @@ mod tests {
         tmp.as_file().write_all(&[1u8, 2, 3]).unwrap();
 
         {
-            let config_space = build_config_space(tmp.as_path()).unwrap();
+            let config_space = build_config_space(file_size(&tmp), 1);
             // We should get the same value of capacity, as the extra bytes are ignored.
             assert_eq!(config_space[..8], num_sectors.to_le_bytes());
         }
+
+        {
+            let config_space = build_config_space(file_size(&tmp), 4);
+            // `num_queues` follows the fields of features we do not offer.
+            assert_eq!(
+                config_space.len(),
+                CONFIG_NUM_QUEUES_OFFSET + size_of::<u16>()
+            );
+            assert_eq!(config_space[..8], num_sectors.to_le_bytes());
+            assert_eq!(config_space[CONFIG_NUM_QUEUES_OFFSET..], 4u16.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn test_backing_size() {
+        let tmp = TempFile::new().unwrap();
+        tmp.as_file().write_all(&[0u8; 1024]).unwrap();
+        let mut file = File::open(tmp.as_path()).unwrap();
+        assert_eq!(backing_size(&mut file).unwrap(), Some(1024));
+
+        let (read, _write) = nix::unistd::pipe().unwrap();
+        // Safe because we own the fd and nothing else closes it.
+        let mut pipe = unsafe { File::from_raw_fd(read) };
+        assert_eq!(backing_size(&mut pipe).unwrap(), None);
+    }
+    #[test]
+    fn test_open_backing_fifo() {
+        let dir = TempDir::new_with_prefix("/tmp/vmsh-block").unwrap();
+        let path = dir.as_path().join("fifo");
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+
+        // there is no writer, a blocking open would hang here
+        let mut file = open_backing(&path, true).unwrap();
+        assert_eq!(backing_size(&mut file).unwrap(), None);
+        let mut file = open_backing(&path, false).unwrap();
+        assert_eq!(backing_size(&mut file).unwrap(), None);
     }
 }
@@ -3,7 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
-use log::error;
+use log::{error, trace};
+use std::fs::File;
 
 use vmm_sys_util::epoll::EventSet;
 
@@ -12,6 +13,7 @@ use crate::devices::virtio::SingleFdSignalQueue;
 use crate::kvm::hypervisor::ioevent::IoEvent;
 
 const IOEVENT_DATA: u32 = 0;
+const BACKING_DATA: u32 = 1;
 
 // This object simply combines the more generic `InOrderQueueHandler` with a concrete queue
 // signalling implementation based on `EventFd`s, and then also implements `MutEventSubscriber`
@@ -20,27 +22,88 @@ const IOEVENT_DATA: u32 = 0;
 pub(crate) struct QueueHandler {
     pub inner: InOrderQueueHandler<SingleFdSignalQueue>,
     pub ioeventfd: IoEvent,
+    /// Set if the backing file is a stream, i.e. a FIFO, see `Stream`. While a request of it is
+    /// pending, we wait with epoll for the direction it needs instead of blocking the thread.
+    pub backing: Option<File>,
+    /// `backing` is registered with the event loop
+    pub waiting: bool,
+}
+
+impl QueueHandler {
+    fn handle_ioevent(&mut self, ops: &mut EventOps) -> bool {
+        if self.ioeventfd.read().is_err() {
+            error!("ioeventfd read error");
+            return false;
+        }
+        if self.waiting {
+            // the queue is processed once the pending request completes
+            return true;
+        }
+        self.process_queue() && self.wait_for_backing(ops)
+    }
+
+    fn handle_backing_ready(&mut self, events: Events, ops: &mut EventOps) {
+        // level-triggered, stop listening or we would spin until the queue is kicked again
+        if let Err(e) = ops.remove(events) {
+            error!("cannot remove backing file from event loop: {:?}", e);
+        }
+        self.waiting = false;
+        // like in `process`, a broken queue is not serviced any longer
+        if !self.process_queue() || !self.wait_for_backing(ops) {
+            let ioevent = Events::with_data(&self.ioeventfd, IOEVENT_DATA, EventSet::IN);
+            if let Err(e) = ops.remove(ioevent) {
+                error!("cannot remove ioeventfd from event loop: {:?}", e);
+            }
+        }
+    }
+
+    /// Register `backing` for the direction a pending stream request needs, if any
+    fn wait_for_backing(&mut self, ops: &mut EventOps) -> bool {
+        let (event_set, backing) = match (self.inner.blocked_on(), &self.backing) {
+            (Some(event_set), Some(backing)) => (event_set, backing),
+            _ => return true,
+        };
+        trace!("backing file not ready, wait for {:?}", event_set);
+        if let Err(e) = ops.add(Events::with_data(backing, BACKING_DATA, event_set)) {
+            error!("cannot wait for backing file: {:?}", e);
+            return false;
+        }
+        self.waiting = true;
+        true
+    }
+
+    fn process_queue(&mut self) -> bool {
+        if let Err(e) = self.inner.process_queue() {
+            error!("error processing block queue {:?}", e);
+            return false;
+        }
+        true
+    }
 }
 
 impl MutEventSubscriber for QueueHandler {
     fn process(&mut self, events: Events, ops: &mut EventOps) {
-        let mut error = true;
-
         // TODO: Have a look at any potential performance impact caused by these conditionals
         // just to be sure.
-        if events.event_set() != EventSet::IN {
-            error!("unexpected event_set");
-        } else if events.data() != IOEVENT_DATA {
-            error!("unexpected events data {}", events.data());
-        } else if self.ioeventfd.read().is_err() {
-            error!("ioeventfd read error")
-        } else if let Err(e) = self.inner.process_queue() {
-            error!("error processing block queue {:?}", e);
-        } else {
-            error = false;
-        }
+        let ok = match events.data() {
+            IOEVENT_DATA if events.event_set().contains(EventSet::IN) => self.handle_ioevent(ops),
+            BACKING_DATA => {
+                // any event, including EPOLLHUP and EPOLLERR, lets the pending request proceed
+                // or fail. Removes itself from the event loop.
+                self.handle_backing_ready(events, ops);
+                return;
+            }
+            data => {
+                error!(
+                    "unexpected event_set {:?} for events data {}",
+                    events.event_set(),
+                    data
+                );
+                false
+            }
+        };
 
-        if error {
+        if !ok {
             ops.remove(events)
                 .expect("Failed to remove fd from event handling loop");
         }
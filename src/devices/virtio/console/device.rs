@@ -21,7 +21,6 @@ use vm_device::{DeviceMmio, MutDeviceMmio};
 use vm_memory::GuestMemoryMmap;
 use vmm_sys_util::eventfd::EventFd;
 
-use crate::devices::use_ioregionfd;
 use crate::devices::virtio::console::log_handler::LogQueueHandler;
 use crate::devices::virtio::console::VIRTIO_CONSOLE_F_SIZE;
 use crate::devices::virtio::features::{
@@ -29,6 +28,7 @@ use crate::devices::virtio::features::{
 };
 use crate::devices::virtio::{IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE};
 use crate::devices::MaybeIoRegionFd;
+use crate::devices::{use_ioregionfd, use_userspace_ioeventfd};
 use crate::kvm::hypervisor::{
     ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd,
 };
@@ -287,7 +287,7 @@ impl VirtioDeviceActions for Console {
 
 impl VirtioQueueNotifiable for Console {
     fn queue_notify(&mut self, val: u32) {
-        if use_ioregionfd() {
+        if use_userspace_ioeventfd() {
             self.uioefd.queue_notify(val);
             log::trace!("queue_notify {}", val);
         }
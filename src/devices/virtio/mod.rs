@@ -15,6 +15,7 @@ use crate::kvm::hypervisor::{ioeventfd::IoEventFd, Hypervisor};
 use crate::result::Result;
 use event_manager::{EventManager, MutEventSubscriber};
 use log::error;
+use simple_error::bail;
 
 use vm_device::bus::MmioRange;
 use vm_memory::GuestMemoryMmap;
@@ -41,8 +42,24 @@ const VIRTIO_MMIO_INT_VRING: u8 = 0x01;
 // about available queue events.
 const VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET: u64 = 0x50;
 
-// TODO: Make configurable for each device maybe?
-const QUEUE_MAX_SIZE: u16 = 256;
+/// Default size of our virtqueues. The block device can be configured to use a different one,
+/// see `check_queue_size`.
+pub const QUEUE_MAX_SIZE: u16 = 256;
+
+/// Largest size of a split virtqueue allowed by the virtio spec
+pub const VIRTQUEUE_MAX_SIZE: u16 = 32768;
+
+/// Virtqueue sizes must be a power of two of at most `VIRTQUEUE_MAX_SIZE`.
+pub fn check_queue_size(size: u16) -> Result<()> {
+    if !size.is_power_of_two() || size > VIRTQUEUE_MAX_SIZE {
+        bail!(
+            "invalid queue size {}, must be a power of two between 1 and {}",
+            size,
+            VIRTQUEUE_MAX_SIZE
+        );
+    }
+    Ok(())
+}
 
 #[derive(Copy, Clone)]
 pub struct MmioConfig {
@@ -174,3 +191,18 @@ pub fn register_ioeventfd(
 
     Ok(ioeventfd)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::check_queue_size;
+
+    #[test]
+    fn test_check_queue_size() {
+        for size in &[1, 256, 1024, 32768] {
+            check_queue_size(*size).expect("valid queue size");
+        }
+        for size in &[0, 3, 100, 65535] {
+            assert!(check_queue_size(*size).is_err(), "{} is invalid", size);
+        }
+    }
+}
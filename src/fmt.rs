@@ -0,0 +1,82 @@
+//! Helpers to render guest memory for humans.
+
+use std::fmt::Write;
+
+pub struct HexdumpOptions {
+    /// bytes per line
+    pub width: usize,
+    /// insert an extra space every `group` bytes, 0 disables grouping
+    pub group: usize,
+}
+
+impl Default for HexdumpOptions {
+    /// Same layout as `hexdump -C`
+    fn default() -> HexdumpOptions {
+        HexdumpOptions {
+            width: 16,
+            group: 8,
+        }
+    }
+}
+
+/// Render `bytes` in the canonical `offset  hex  |ascii|` layout, one line per 16 bytes.
+/// Offsets start at `addr_base`.
+pub fn hexdump(addr_base: u64, bytes: &[u8]) -> String {
+    hexdump_with(addr_base, bytes, &HexdumpOptions::default())
+}
+
+/// Like `hexdump()` with a custom line width and grouping.
+pub fn hexdump_with(addr_base: u64, bytes: &[u8], opts: &HexdumpOptions) -> String {
+    let width = opts.width.max(1);
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(width).enumerate() {
+        // writing to a String cannot fail
+        let _ = write!(out, "{:08x}  ", addr_base + (i * width) as u64);
+        for col in 0..width {
+            if col != 0 && opts.group != 0 && col % opts.group == 0 {
+                out.push(' ');
+            }
+            match line.get(col) {
+                Some(b) => {
+                    let _ = write!(out, "{:02x} ", b);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str(" |");
+        out.extend(line.iter().map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hexdump, hexdump_with, HexdumpOptions};
+
+    #[test]
+    fn test_hexdump() {
+        let dump = hexdump(0x1000, b"// SPDX-License-Identifier\n");
+        assert_eq!(
+            dump,
+            "00001000  2f 2f 20 53 50 44 58 2d  4c 69 63 65 6e 73 65 2d  |// SPDX-License-|\n\
+             00001010  49 64 65 6e 74 69 66 69  65 72 0a                 |Identifier.|\n"
+        );
+        assert_eq!(hexdump(0, &[]), "");
+    }
+
+    #[test]
+    fn test_hexdump_with() {
+        let opts = HexdumpOptions { width: 4, group: 2 };
+        assert_eq!(
+            hexdump_with(0xd0000000, &[0x01, 0x00, 0x41], &opts),
+            "d0000000  01 00  41     |..A|\n"
+        );
+    }
+}
@@ -3,13 +3,15 @@ use kvm_bindings as kvmb;
 use log::debug;
 use nix::sys::mman::ProtFlags;
 use simple_error::{bail, require_with, try_with};
-use std::cmp::{max, Ordering};
+use std::cmp::{max, min, Ordering};
+use std::mem::{size_of, MaybeUninit};
 use std::ops::Range;
+use std::slice;
 use std::sync::Arc;
 
 use crate::kvm::hypervisor::memory::PhysMem;
 use crate::kvm::hypervisor::Hypervisor;
-use crate::page_math::huge_page_size;
+use crate::page_math::{huge_page_size, page_size};
 use crate::page_table::{
     self, PageTable, PageTableFlags, PageTableIteratorValue, PhysAddr, VirtMem,
 };
@@ -134,6 +136,7 @@ impl GuestMem {
         // To make the design sound we try to allocate memory near the 4 Peta
         // byte limit in the hope that VMs are not getting close to this limit
         // any time soon.
+        hv.check_memory_readable()?;
         let mut mappings = try_with!(hv.get_maps(), "cannot vm memory allocations");
         mappings.sort_by_key(|m| m.phys_addr);
 
@@ -164,6 +167,59 @@ impl GuestMem {
         })
     }
 
+    /// Translate a guest virtual address using the page table of the first vcpu.
+    pub fn translate(&self, hv: &Hypervisor, virt_addr: usize) -> Result<PhysAddr> {
+        page_table::translate(hv, &self.pml4, &self.maps, virt_addr)
+    }
+
+    /// Fill `buf` from guest physical memory starting at `phys_addr`.
+    pub fn read_phys(&self, hv: &Hypervisor, phys_addr: usize, buf: &mut [u8]) -> Result<()> {
+        let (range, host_offset) = require_with!(
+            self.maps.get_range(phys_addr),
+            "physical address {:#x} is not backed by memslot",
+            phys_addr
+        );
+        // memslot ranges are inclusive
+        if phys_addr + buf.len() > range.end + 1 {
+            bail!(
+                "physical read {:#x}-{:#x} crosses memslot boundary",
+                phys_addr,
+                phys_addr + buf.len()
+            );
+        }
+        let addr = PhysAddr {
+            value: phys_addr,
+            host_offset,
+        };
+        hv.read_slice(addr.host_addr(), buf)
+    }
+
+    /// Fill `buf` from guest virtual memory starting at `virt_addr`. The range may span
+    /// multiple, physically discontinuous pages.
+    pub fn read_virt(&self, hv: &Hypervisor, virt_addr: usize, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let addr = virt_addr + done;
+            let len = min(buf.len() - done, page_size() - (addr & (page_size() - 1)));
+            let phys = try_with!(
+                self.translate(hv, addr),
+                "cannot translate virtual address {:#x}",
+                addr
+            );
+            hv.read_slice(phys.host_addr(), &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Read a `T` from guest virtual memory.
+    pub fn read<T: Sized + Copy>(&self, hv: &Hypervisor, virt_addr: usize) -> Result<T> {
+        let mut val = MaybeUninit::<T>::uninit();
+        let buf = unsafe { slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        self.read_virt(hv, virt_addr, buf)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
     pub fn last_memslot_range(&self) -> Option<Range<usize>> {
         self.maps.last_range()
     }
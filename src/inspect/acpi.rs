@@ -0,0 +1,532 @@
+//! Locate and decode the ACPI tables of the guest, see the ACPI specification 6.4, 5.2 "ACPI
+//! System Description Tables".
+
+use log::warn;
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::fmt;
+
+use crate::guest_mem::GuestMem;
+use crate::inspect::bytes::{le_u16, le_u32, le_u64};
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+
+/// Real mode pointer to the extended BIOS data area, stored in the BIOS data area
+const EBDA_POINTER: usize = 0x40e;
+/// The RSDP may be in the first KiB of the EBDA
+const EBDA_SEARCH_LEN: usize = 0x400;
+/// ... or in the BIOS read-only area
+const BIOS_START: usize = 0xe_0000;
+const BIOS_END: usize = 0x10_0000;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Size of the ACPI 1.0 RSDP, which the checksum covers
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+
+const SDT_HEADER_LEN: usize = 36;
+/// Upper bound for table sizes, protects against garbage lengths
+const MAX_TABLE_LEN: usize = 1 << 20;
+
+/// ACPI checksums make all bytes of a structure sum up to zero.
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_end_matches(|c: char| c == '\0' || c == ' ')
+        .to_string()
+}
+
+/// Root System Description Pointer
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rsdp {
+    /// guest physical address the RSDP was found at
+    pub addr: usize,
+    pub oem_id: String,
+    pub revision: u8,
+    pub rsdt_addr: u32,
+    /// only present from ACPI 2.0 on
+    pub xsdt_addr: Option<u64>,
+}
+
+impl Rsdp {
+    fn parse(addr: usize, bytes: &[u8]) -> Option<Rsdp> {
+        if bytes.len() < RSDP_V2_LEN
+            || &bytes[..8] != RSDP_SIGNATURE
+            || !checksum_ok(&bytes[..RSDP_V1_LEN])
+        {
+            return None;
+        }
+        let revision = bytes[15];
+        let xsdt_addr = if revision >= 2 && checksum_ok(&bytes[..RSDP_V2_LEN]) {
+            Some(le_u64(bytes, 24)).filter(|addr| *addr != 0)
+        } else {
+            None
+        };
+        Some(Rsdp {
+            addr,
+            oem_id: ascii(&bytes[9..15]),
+            revision,
+            rsdt_addr: le_u32(bytes, 16),
+            xsdt_addr,
+        })
+    }
+}
+
+/// Search the EBDA and the BIOS area for the RSDP. `read` reads guest physical memory.
+fn find_rsdp<F>(mut read: F) -> Result<Rsdp>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    let mut areas = vec![];
+    let mut ebda = [0u8; 2];
+    if read(EBDA_POINTER, &mut ebda).is_ok() {
+        let start = (u16::from_le_bytes(ebda) as usize) << 4;
+        if start != 0 {
+            areas.push(start..start + EBDA_SEARCH_LEN);
+        }
+    }
+    areas.push(BIOS_START..BIOS_END);
+
+    for area in areas {
+        let mut mem = vec![0u8; area.len()];
+        if let Err(e) = read(area.start, &mut mem) {
+            warn!("cannot read {:#x}-{:#x}: {}", area.start, area.end, e);
+            continue;
+        }
+        // the RSDP is 16 byte aligned
+        for offset in (0..mem.len().saturating_sub(RSDP_V2_LEN - 1)).step_by(16) {
+            if let Some(rsdp) = Rsdp::parse(area.start + offset, &mem[offset..]) {
+                return Ok(rsdp);
+            }
+        }
+    }
+    bail!(
+        "no RSDP found in the EBDA or at {:#x}-{:#x}",
+        BIOS_START,
+        BIOS_END
+    )
+}
+
+/// A system description table, i.e. FACP (FADT) or APIC (MADT)
+#[derive(Clone, Debug)]
+pub struct AcpiTable {
+    pub signature: String,
+    /// guest physical address
+    pub addr: u64,
+    pub revision: u8,
+    pub oem_id: String,
+    pub oem_table_id: String,
+    pub checksum_ok: bool,
+    /// the whole table including the header
+    pub data: Vec<u8>,
+}
+
+impl AcpiTable {
+    fn parse(addr: u64, data: Vec<u8>) -> AcpiTable {
+        AcpiTable {
+            signature: ascii(&data[0..4]),
+            addr,
+            revision: data[8],
+            oem_id: ascii(&data[10..16]),
+            oem_table_id: ascii(&data[16..24]),
+            checksum_ok: checksum_ok(&data),
+            data,
+        }
+    }
+}
+
+/// Read the table at `addr`, using the length from its header.
+fn read_table<F>(read: &mut F, addr: u64) -> Result<AcpiTable>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    let mut header = [0u8; SDT_HEADER_LEN];
+    try_with!(
+        read(addr as usize, &mut header),
+        "cannot read table header at {:#x}",
+        addr
+    );
+    let len = le_u32(&header, 4) as usize;
+    if !(SDT_HEADER_LEN..=MAX_TABLE_LEN).contains(&len) {
+        bail!("table at {:#x} has an invalid length of {}", addr, len);
+    }
+    let mut data = vec![0u8; len];
+    try_with!(
+        read(addr as usize, &mut data),
+        "cannot read table at {:#x}",
+        addr
+    );
+    Ok(AcpiTable::parse(addr, data))
+}
+
+/// Addresses of the tables listed in an RSDT (32-bit entries) or XSDT (64-bit entries).
+fn root_entries(root: &AcpiTable) -> Vec<u64> {
+    let entries = &root.data[SDT_HEADER_LEN..];
+    if root.signature == "XSDT" {
+        entries.chunks_exact(8).map(|e| le_u64(e, 0)).collect()
+    } else {
+        entries
+            .chunks_exact(4)
+            .map(|e| le_u32(e, 0) as u64)
+            .collect()
+    }
+}
+
+/// Entry of the Multiple APIC Description Table
+#[derive(Clone, Debug, PartialEq)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        enabled: bool,
+    },
+    IoApic {
+        id: u8,
+        addr: u32,
+        gsi_base: u32,
+    },
+    InterruptOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    LocalApicNmi {
+        processor_id: u8,
+        flags: u16,
+        lint: u8,
+    },
+    LocalX2Apic {
+        x2apic_id: u32,
+        enabled: bool,
+        processor_uid: u32,
+    },
+    Other {
+        typ: u8,
+        len: u8,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Madt {
+    pub local_apic_addr: u32,
+    /// dual 8259 PICs are installed as well
+    pub pcat_compat: bool,
+    pub entries: Vec<MadtEntry>,
+}
+
+/// Decode the MADT (signature "APIC"), `data` includes the table header.
+pub fn decode_madt(data: &[u8]) -> Result<Madt> {
+    if data.len() < SDT_HEADER_LEN + 8 {
+        bail!("MADT is too short: {} bytes", data.len());
+    }
+    let mut entries = vec![];
+    let mut offset = SDT_HEADER_LEN + 8;
+    while offset + 2 <= data.len() {
+        let (typ, len) = (data[offset], data[offset + 1]);
+        let e = &data[offset..];
+        if len < 2 || offset + len as usize > data.len() {
+            bail!(
+                "MADT entry at offset {} has an invalid length of {}",
+                offset,
+                len
+            );
+        }
+        let entry = match (typ, len) {
+            (0, 8) => MadtEntry::LocalApic {
+                processor_id: e[2],
+                apic_id: e[3],
+                enabled: le_u32(e, 4) & 1 != 0,
+            },
+            (1, 12) => MadtEntry::IoApic {
+                id: e[2],
+                addr: le_u32(e, 4),
+                gsi_base: le_u32(e, 8),
+            },
+            (2, 10) => MadtEntry::InterruptOverride {
+                bus: e[2],
+                source: e[3],
+                gsi: le_u32(e, 4),
+                flags: le_u16(e, 8),
+            },
+            (4, 6) => MadtEntry::LocalApicNmi {
+                processor_id: e[2],
+                flags: le_u16(e, 3),
+                lint: e[5],
+            },
+            (9, 16) => MadtEntry::LocalX2Apic {
+                x2apic_id: le_u32(e, 4),
+                enabled: le_u32(e, 8) & 1 != 0,
+                processor_uid: le_u32(e, 12),
+            },
+            _ => MadtEntry::Other { typ, len },
+        };
+        entries.push(entry);
+        offset += len as usize;
+    }
+    Ok(Madt {
+        local_apic_addr: le_u32(data, SDT_HEADER_LEN),
+        pcat_compat: le_u32(data, SDT_HEADER_LEN + 4) & 1 != 0,
+        entries,
+    })
+}
+
+pub struct Acpi {
+    pub rsdp: Rsdp,
+    /// the RSDT or XSDT
+    pub root: AcpiTable,
+    pub tables: Vec<AcpiTable>,
+    pub madt: Option<Madt>,
+}
+
+/// Find the RSDP, follow the XSDT (or RSDT for ACPI 1.0) and read all tables it lists. The DSDT
+/// is referenced by the FADT rather than the root table and therefore not included.
+fn read_acpi<F>(mut read: F) -> Result<Acpi>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    let rsdp = find_rsdp(&mut read)?;
+    let root_addr = rsdp.xsdt_addr.unwrap_or(rsdp.rsdt_addr as u64);
+    let root = try_with!(read_table(&mut read, root_addr), "cannot read root table");
+    if !root.checksum_ok {
+        warn!(
+            "checksum of {} at {:#x} is wrong",
+            root.signature, root.addr
+        );
+    }
+    let mut tables = vec![];
+    for addr in root_entries(&root) {
+        match read_table(&mut read, addr) {
+            Ok(table) => tables.push(table),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    let madt = match tables.iter().find(|t| t.signature == "APIC") {
+        Some(table) => Some(decode_madt(&table.data)?),
+        None => None,
+    };
+    Ok(Acpi {
+        rsdp,
+        root,
+        tables,
+        madt,
+    })
+}
+
+/// Read the ACPI tables of the guest. Expects the hypervisor to be stopped.
+pub fn acpi(hv: &Hypervisor) -> Result<Acpi> {
+    let mem = GuestMem::new(hv)?;
+    read_acpi(|addr, buf| mem.read_phys(hv, addr, buf))
+}
+
+impl fmt::Display for MadtEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let enabled = |enabled: &bool| if *enabled { "enabled" } else { "disabled" };
+        match self {
+            MadtEntry::LocalApic {
+                processor_id,
+                apic_id,
+                enabled: e,
+            } => write!(
+                f,
+                "local apic: processor {} apic id {} {}",
+                processor_id,
+                apic_id,
+                enabled(e)
+            ),
+            MadtEntry::IoApic { id, addr, gsi_base } => write!(
+                f,
+                "io apic: id {} at {:#x}, gsi base {}",
+                id, addr, gsi_base
+            ),
+            MadtEntry::InterruptOverride {
+                bus,
+                source,
+                gsi,
+                flags,
+            } => write!(
+                f,
+                "interrupt override: bus {} irq {} -> gsi {} flags {:#x}",
+                bus, source, gsi, flags
+            ),
+            MadtEntry::LocalApicNmi {
+                processor_id,
+                flags,
+                lint,
+            } => write!(
+                f,
+                "local apic nmi: processor {:#x} lint{} flags {:#x}",
+                processor_id, lint, flags
+            ),
+            MadtEntry::LocalX2Apic {
+                x2apic_id,
+                enabled: e,
+                processor_uid,
+            } => write!(
+                f,
+                "local x2apic: uid {} x2apic id {} {}",
+                processor_uid,
+                x2apic_id,
+                enabled(e)
+            ),
+            MadtEntry::Other { typ, len } => write!(f, "type {} ({} bytes)", typ, len),
+        }
+    }
+}
+
+impl fmt::Display for Acpi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "RSDP @ {:#x}: revision {}, oem {}",
+            self.rsdp.addr, self.rsdp.revision, self.rsdp.oem_id
+        )?;
+        for table in std::iter::once(&self.root).chain(self.tables.iter()) {
+            write!(
+                f,
+                "{} @ {:#x}: {} bytes, revision {}, oem {} {}",
+                table.signature,
+                table.addr,
+                table.data.len(),
+                table.revision,
+                table.oem_id,
+                table.oem_table_id
+            )?;
+            if !table.checksum_ok {
+                write!(f, " (bad checksum)")?;
+            }
+            writeln!(f)?;
+        }
+        if let Some(madt) = &self.madt {
+            writeln!(
+                f,
+                "MADT: local apic at {:#x}{}",
+                madt.local_apic_addr,
+                if madt.pcat_compat { ", 8259 PICs" } else { "" }
+            )?;
+            for entry in &madt.entries {
+                writeln!(f, "  {}", entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct AcpiOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_acpi(opts: &AcpiOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    print!("{}", acpi(&vm)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_error::simple_error;
+
+    /// Fix up the checksum byte at `offset` so that `bytes` sums up to zero.
+    fn fix_checksum(bytes: &mut [u8], offset: usize) {
+        bytes[offset] = 0;
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes[offset] = 0u8.wrapping_sub(sum);
+    }
+
+    fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; SDT_HEADER_LEN];
+        data[0..4].copy_from_slice(signature);
+        data[4..8].copy_from_slice(&((SDT_HEADER_LEN + body.len()) as u32).to_le_bytes());
+        data[8] = 1;
+        data[10..16].copy_from_slice(b"BOCHS ");
+        data.extend_from_slice(body);
+        fix_checksum(&mut data, 9);
+        data
+    }
+
+    #[test]
+    fn test_read_acpi() {
+        let mut mem = vec![0u8; BIOS_END];
+        // RSDP as placed by seabios in the BIOS area
+        let rsdp = 0xf_5a40;
+        mem[rsdp..rsdp + 8].copy_from_slice(RSDP_SIGNATURE);
+        mem[rsdp + 9..rsdp + 15].copy_from_slice(b"BOCHS ");
+        mem[rsdp + 16..rsdp + 20].copy_from_slice(&0x7_0000u32.to_le_bytes());
+        fix_checksum(&mut mem[rsdp..rsdp + RSDP_V1_LEN], 8);
+
+        let mut madt = vec![];
+        madt.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+        madt.extend_from_slice(&1u32.to_le_bytes());
+        madt.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+        madt.extend_from_slice(&[1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]);
+        madt.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+        let madt = table(b"APIC", &madt);
+        mem[0x7_1000..0x7_1000 + madt.len()].copy_from_slice(&madt);
+        let facp = table(b"FACP", &[0; 8]);
+        mem[0x7_2000..0x7_2000 + facp.len()].copy_from_slice(&facp);
+
+        let mut entries = vec![];
+        entries.extend_from_slice(&0x7_1000u32.to_le_bytes());
+        entries.extend_from_slice(&0x7_2000u32.to_le_bytes());
+        let rsdt = table(b"RSDT", &entries);
+        mem[0x7_0000..0x7_0000 + rsdt.len()].copy_from_slice(&rsdt);
+
+        let acpi = read_acpi(|addr: usize, buf: &mut [u8]| -> Result<()> {
+            let src = mem
+                .get(addr..addr + buf.len())
+                .ok_or_else(|| simple_error!("unmapped {:#x}", addr))?;
+            buf.copy_from_slice(src);
+            Ok(())
+        })
+        .expect("cannot read acpi tables");
+
+        assert_eq!(acpi.rsdp.addr, rsdp);
+        assert_eq!(acpi.rsdp.oem_id, "BOCHS");
+        assert_eq!(acpi.rsdp.xsdt_addr, None);
+        assert_eq!(acpi.root.signature, "RSDT");
+        let signatures = acpi
+            .tables
+            .iter()
+            .map(|t| t.signature.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(signatures, vec!["APIC", "FACP"]);
+        assert!(acpi.tables.iter().all(|t| t.checksum_ok));
+
+        let madt = acpi.madt.expect("no madt");
+        assert_eq!(madt.local_apic_addr, 0xfee0_0000);
+        assert!(madt.pcat_compat);
+        assert_eq!(
+            madt.entries,
+            vec![
+                MadtEntry::LocalApic {
+                    processor_id: 0,
+                    apic_id: 0,
+                    enabled: true,
+                },
+                MadtEntry::IoApic {
+                    id: 0,
+                    addr: 0xfec0_0000,
+                    gsi_base: 0,
+                },
+                MadtEntry::InterruptOverride {
+                    bus: 0,
+                    source: 0,
+                    gsi: 2,
+                    flags: 0,
+                },
+            ]
+        );
+    }
+}
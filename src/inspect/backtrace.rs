@@ -0,0 +1,227 @@
+use log::warn;
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::guest_mem::GuestMem;
+use crate::inspect::open_vmlinux;
+use crate::kernel::{find_kernel, Kernel};
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions, VCPU};
+use crate::result::Result;
+use crate::vmlinux::Vmlinux;
+
+/// Upper bound for the number of frames, protects against cycles in corrupted stacks
+const MAX_FRAMES: usize = 64;
+
+/// Start of the kernel half of the x86_64 address space
+const KERNEL_SPACE_START: usize = 0xffff_8000_0000_0000;
+
+pub struct BacktraceOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub vcpu: usize,
+    pub vmlinux: Option<PathBuf>,
+}
+
+/// Maps addresses back to the closest symbol below them.
+pub struct Symbolizer {
+    /// runtime addresses and names, sorted by address
+    symbols: Vec<(usize, String)>,
+}
+
+impl Symbolizer {
+    /// Symbols exported by the guest kernel plus those of `vmlinux`, limited to the kernel image.
+    pub fn new(kernel: &Kernel, vmlinux: Option<&Vmlinux>) -> Symbolizer {
+        let mut symbols = kernel
+            .symbols
+            .iter()
+            .map(|(name, addr)| (*addr, name.clone()))
+            .collect::<Vec<_>>();
+        if let Some(vmlinux) = vmlinux {
+            symbols.extend(
+                vmlinux
+                    .symbols()
+                    .filter(|(_, addr)| kernel.range.contains(addr))
+                    .map(|(name, addr)| (addr, name.to_owned())),
+            );
+        }
+        Symbolizer::from_symbols(symbols)
+    }
+
+    fn from_symbols(mut symbols: Vec<(usize, String)>) -> Symbolizer {
+        symbols.sort();
+        symbols.dedup_by_key(|(addr, _)| *addr);
+        Symbolizer { symbols }
+    }
+
+    /// Closest symbol at or below `addr` and the offset of `addr` to it
+    pub fn lookup(&self, addr: usize) -> Option<(&str, usize)> {
+        let idx = self.symbols.partition_point(|(a, _)| *a <= addr);
+        let (sym_addr, name) = self.symbols.get(idx.checked_sub(1)?)?;
+        Some((name.as_str(), addr - sym_addr))
+    }
+}
+
+/// A return address on the guest kernel stack
+pub struct Frame {
+    pub addr: usize,
+    /// symbol and offset, if it could be resolved
+    pub symbol: Option<(String, usize)>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.symbol {
+            Some((name, offset)) => write!(f, "{}+{:#x} ({:#x})", name, offset, self.addr),
+            None => write!(f, "{:#x}", self.addr),
+        }
+    }
+}
+
+/// Follow the saved frame pointers starting at `rbp` and return the return addresses found on
+/// the way. Stops at the first frame that does not look like a kernel stack frame: frame
+/// pointers have to point to kernel memory and grow towards the stack top, return addresses have
+/// to point into `text`.
+fn walk_frames<F>(mut read: F, mut rbp: usize, text: &std::ops::Range<usize>) -> Vec<usize>
+where
+    F: FnMut(usize) -> Result<usize>,
+{
+    let mut addrs = vec![];
+    let mut prev = 0;
+    while addrs.len() < MAX_FRAMES {
+        if rbp < KERNEL_SPACE_START || rbp <= prev || rbp % 8 != 0 {
+            break;
+        }
+        // frame layout: [rbp] = caller's rbp, [rbp + 8] = return address
+        let (next, ret) = match (read(rbp), read(rbp + 8)) {
+            (Ok(next), Ok(ret)) => (next, ret),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("stop unwinding at frame {:#x}: {}", rbp, e);
+                break;
+            }
+        };
+        if !text.contains(&ret) {
+            break;
+        }
+        addrs.push(ret);
+        prev = rbp;
+        rbp = next;
+    }
+    addrs
+}
+
+/// Unwind the kernel stack of `vcpu` using frame pointers. This requires a kernel built with
+/// CONFIG_FRAME_POINTER (i.e. not the ORC unwinder); otherwise only the first frame is reliable.
+/// `vmlinux` helps to resolve symbols the kernel does not export. Expects the hypervisor to be
+/// stopped.
+pub fn backtrace(hv: &Hypervisor, vcpu: &VCPU, vmlinux: Option<&Path>) -> Result<Vec<Frame>> {
+    let mem = GuestMem::new(hv)?;
+    let kernel = try_with!(find_kernel(&mem, hv), "could not find kernel");
+    let vmlinux = open_vmlinux(vmlinux, &kernel)?;
+    let symbolizer = Symbolizer::new(&kernel, vmlinux.as_ref());
+
+    let regs = try_with!(
+        hv.get_regs(vcpu),
+        "cannot get registers of vcpu {}",
+        vcpu.idx
+    );
+    let rip = regs.rip as usize;
+    if !kernel.range.contains(&rip) {
+        bail!(
+            "vcpu {} is not executing kernel code (rip={:#x})",
+            vcpu.idx,
+            rip
+        );
+    }
+
+    let mut addrs = vec![rip];
+    addrs.extend(walk_frames(
+        |addr| mem.read::<usize>(hv, addr),
+        regs.rbp as usize,
+        &kernel.range,
+    ));
+    Ok(addrs
+        .into_iter()
+        .map(|addr| Frame {
+            addr,
+            symbol: symbolizer
+                .lookup(addr)
+                .map(|(name, offset)| (name.to_owned(), offset)),
+        })
+        .collect())
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_backtrace(opts: &BacktraceOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    let vcpu = match vm.vcpus.get(opts.vcpu) {
+        Some(vcpu) => vcpu,
+        None => bail!("vm has only {} vcpus", vm.vcpus.len()),
+    };
+    for (i, frame) in backtrace(&vm, vcpu, opts.vmlinux.as_deref())?
+        .iter()
+        .enumerate()
+    {
+        println!("#{:<2} {}", i, frame);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{walk_frames, Symbolizer};
+    use simple_error::simple_error;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_symbolizer() {
+        let symbolizer = Symbolizer::from_symbols(vec![
+            (0xffff_ffff_8100_2000, "schedule".into()),
+            (0xffff_ffff_8100_1000, "do_idle".into()),
+        ]);
+        assert_eq!(
+            symbolizer.lookup(0xffff_ffff_8100_1042),
+            Some(("do_idle", 0x42))
+        );
+        assert_eq!(
+            symbolizer.lookup(0xffff_ffff_8100_2000),
+            Some(("schedule", 0))
+        );
+        assert_eq!(symbolizer.lookup(0xffff_ffff_8100_0fff), None);
+    }
+
+    #[test]
+    fn test_walk_frames() {
+        let text = 0xffff_ffff_8100_0000..0xffff_ffff_8200_0000;
+        let stack = 0xffff_c900_0000_3f00;
+        let mem: HashMap<usize, usize> = vec![
+            (stack, stack + 0x30),
+            (stack + 0x8, 0xffff_ffff_8100_1042),
+            (stack + 0x30, stack + 0x80),
+            (stack + 0x38, 0xffff_ffff_8100_2010),
+            // bottom frame returns to user space
+            (stack + 0x80, 0),
+            (stack + 0x88, 0x7f00_0000_1000),
+        ]
+        .into_iter()
+        .collect();
+        let read = |addr: usize| {
+            mem.get(&addr)
+                .copied()
+                .ok_or_else(|| simple_error!("unmapped {:#x}", addr))
+        };
+        assert_eq!(
+            walk_frames(read, stack, &text),
+            vec![0xffff_ffff_8100_1042, 0xffff_ffff_8100_2010]
+        );
+        // frame pointer into user space
+        assert!(walk_frames(read, 0x7ffe_0000_0000, &text).is_empty());
+    }
+}
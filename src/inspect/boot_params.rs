@@ -0,0 +1,515 @@
+//! Decoder for the x86 boot_params structure ("zero page") the boot loader hands to the kernel,
+//! see Documentation/x86/zero-page.rst and Documentation/x86/boot.rst.
+
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::fmt;
+use std::ops::Range;
+
+use crate::guest_mem::GuestMem;
+use crate::inspect::bytes::{le_u16, le_u32, le_u64};
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+
+/// boot_params (the "zero page") is placed below 1MiB by all hypervisors we know of.
+pub(crate) const BOOT_PARAMS_SCAN_END: usize = 0x10_0000;
+pub(crate) const BOOT_PARAMS_SIZE: usize = 0x1000;
+
+// Offsets within boot_params
+const EXT_RAMDISK_IMAGE: usize = 0x0c0;
+const EXT_RAMDISK_SIZE: usize = 0x0c4;
+pub(crate) const EXT_CMD_LINE_PTR: usize = 0x0c8;
+const EFI_LOADER_SIGNATURE: usize = 0x1c0;
+const EFI_SYSTAB: usize = 0x1c4;
+const EFI_MEMDESC_SIZE: usize = 0x1c8;
+const EFI_MEMDESC_VERSION: usize = 0x1cc;
+const EFI_MEMMAP: usize = 0x1d0;
+const EFI_MEMMAP_SIZE: usize = 0x1d4;
+const EFI_SYSTAB_HI: usize = 0x1d8;
+const EFI_MEMMAP_HI: usize = 0x1dc;
+const E820_ENTRIES: usize = 0x1e8;
+pub(crate) const BOOT_FLAG: usize = 0x1fe;
+pub(crate) const HEADER_MAGIC: usize = 0x202;
+const PROTOCOL_VERSION: usize = 0x206;
+const TYPE_OF_LOADER: usize = 0x210;
+const LOADFLAGS: usize = 0x211;
+const RAMDISK_IMAGE: usize = 0x218;
+const RAMDISK_SIZE: usize = 0x21c;
+pub(crate) const CMD_LINE_PTR: usize = 0x228;
+const SETUP_DATA: usize = 0x250;
+const E820_TABLE: usize = 0x2d0;
+
+const E820_MAX_ENTRIES: usize = 128;
+const E820_ENTRY_SIZE: usize = 20;
+
+/// Header of each element in the setup_data list
+const SETUP_DATA_HEADER_SIZE: usize = 16;
+/// setup_data carrying e820 entries that did not fit into boot_params
+const SETUP_E820_EXT: u32 = 1;
+/// Guard against loops in a corrupted setup_data list
+const MAX_SETUP_DATA: usize = 64;
+
+// Offsets within an EFI memory descriptor (efi_memory_desc_t)
+const EFI_MEMDESC_PHYS_START: usize = 8;
+const EFI_MEMDESC_NUM_PAGES: usize = 24;
+const EFI_PAGE_SIZE: u64 = 0x1000;
+/// Guard against reading garbage for a corrupted efi_memmap_size
+const MAX_EFI_MEMMAP_SIZE: u32 = 1 << 20;
+
+/// `page` starts with a setup header written by a boot loader
+pub(crate) fn has_setup_header(page: &[u8]) -> bool {
+    &page[BOOT_FLAG..BOOT_FLAG + 2] == b"\x55\xaa"
+        && &page[HEADER_MAGIC..HEADER_MAGIC + 4] == b"HdrS"
+}
+
+/// Look for boot_params in the first MiB of guest RAM. Returns its address and contents.
+pub(crate) fn find_boot_params(
+    hv: &Hypervisor,
+    mem: &GuestMem,
+) -> Result<(usize, [u8; BOOT_PARAMS_SIZE])> {
+    let mut page = [0u8; BOOT_PARAMS_SIZE];
+    for addr in (0..BOOT_PARAMS_SCAN_END).step_by(BOOT_PARAMS_SIZE) {
+        if mem.read_phys(hv, addr, &mut page).is_err() {
+            continue;
+        }
+        if has_setup_header(&page) {
+            return Ok((addr, page));
+        }
+    }
+    bail!("no boot_params found below {:#x}", BOOT_PARAMS_SCAN_END)
+}
+
+/// One range of the e820 memory map
+#[derive(Clone, Debug, PartialEq)]
+pub struct E820Entry {
+    pub addr: u64,
+    pub size: u64,
+    pub kind: u32,
+}
+
+impl E820Entry {
+    fn from_bytes(bytes: &[u8]) -> E820Entry {
+        E820Entry {
+            addr: le_u64(bytes, 0),
+            size: le_u64(bytes, 8),
+            kind: le_u32(bytes, 16),
+        }
+    }
+
+    /// Guest physical addresses this entry covers
+    #[must_use]
+    pub fn range(&self) -> Range<u64> {
+        self.addr..self.addr.saturating_add(self.size)
+    }
+
+    #[must_use]
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            1 => "usable",
+            2 => "reserved",
+            3 => "ACPI data",
+            4 => "ACPI NVS",
+            5 => "unusable",
+            7 => "persistent",
+            12 => "persistent (legacy)",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for E820Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[mem {:#018x}-{:#018x}] {}",
+            self.addr,
+            self.addr.saturating_add(self.size).saturating_sub(1),
+            self.kind_name()
+        )
+    }
+}
+
+/// Set by the EFI stub or an EFI boot loader. The kernel builds its memory map from the EFI
+/// memory map in that case, the e820 table may be incomplete or empty.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EfiInfo {
+    /// "EL64" or "EL32"
+    pub loader_signature: String,
+    pub systab: u64,
+    pub memmap: u64,
+    pub memmap_size: u32,
+    pub memdesc_size: u32,
+    pub memdesc_version: u32,
+}
+
+/// Element of the setup_data list
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetupData {
+    pub addr: u64,
+    pub kind: u32,
+    pub len: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootParams {
+    /// guest physical address of the zero page
+    pub addr: usize,
+    /// boot protocol version, i.e. 0x20f for 2.15
+    pub protocol_version: u16,
+    pub loader_type: u8,
+    pub loadflags: u8,
+    pub cmdline_ptr: Option<u64>,
+    /// address and size of the initrd
+    pub initrd: Option<(u64, u64)>,
+    pub e820: Vec<E820Entry>,
+    pub efi: Option<EfiInfo>,
+    pub setup_data: Vec<SetupData>,
+}
+
+/// Decode the boot_params page found at `addr`. Does not follow the setup_data list.
+pub fn decode_boot_params(addr: usize, page: &[u8]) -> Result<BootParams> {
+    if page.len() < BOOT_PARAMS_SIZE {
+        bail!(
+            "boot_params is {:#x} bytes, got {:#x}",
+            BOOT_PARAMS_SIZE,
+            page.len()
+        );
+    }
+    if !has_setup_header(page) {
+        bail!("no valid setup header in boot_params at {:#x}", addr);
+    }
+    let cmdline_ptr =
+        u64::from(le_u32(page, EXT_CMD_LINE_PTR)) << 32 | u64::from(le_u32(page, CMD_LINE_PTR));
+    let initrd_addr =
+        u64::from(le_u32(page, EXT_RAMDISK_IMAGE)) << 32 | u64::from(le_u32(page, RAMDISK_IMAGE));
+    let initrd_size =
+        u64::from(le_u32(page, EXT_RAMDISK_SIZE)) << 32 | u64::from(le_u32(page, RAMDISK_SIZE));
+
+    let nr_e820 = usize::from(page[E820_ENTRIES]).min(E820_MAX_ENTRIES);
+    let e820 = page[E820_TABLE..E820_TABLE + nr_e820 * E820_ENTRY_SIZE]
+        .chunks(E820_ENTRY_SIZE)
+        .map(E820Entry::from_bytes)
+        .collect();
+
+    let signature = &page[EFI_LOADER_SIGNATURE..EFI_LOADER_SIGNATURE + 4];
+    let efi = if signature == b"EL64" || signature == b"EL32" {
+        Some(EfiInfo {
+            loader_signature: String::from_utf8_lossy(signature).into_owned(),
+            systab: u64::from(le_u32(page, EFI_SYSTAB_HI)) << 32
+                | u64::from(le_u32(page, EFI_SYSTAB)),
+            memmap: u64::from(le_u32(page, EFI_MEMMAP_HI)) << 32
+                | u64::from(le_u32(page, EFI_MEMMAP)),
+            memmap_size: le_u32(page, EFI_MEMMAP_SIZE),
+            memdesc_size: le_u32(page, EFI_MEMDESC_SIZE),
+            memdesc_version: le_u32(page, EFI_MEMDESC_VERSION),
+        })
+    } else {
+        None
+    };
+
+    Ok(BootParams {
+        addr,
+        protocol_version: le_u16(page, PROTOCOL_VERSION),
+        loader_type: page[TYPE_OF_LOADER],
+        loadflags: page[LOADFLAGS],
+        cmdline_ptr: if cmdline_ptr == 0 {
+            None
+        } else {
+            Some(cmdline_ptr)
+        },
+        initrd: if initrd_size == 0 {
+            None
+        } else {
+            Some((initrd_addr, initrd_size))
+        },
+        e820,
+        efi,
+        setup_data: vec![],
+    })
+}
+
+/// Walk the setup_data list starting at `head`. Entries of type SETUP_E820_EXT are appended to
+/// the e820 map.
+fn read_setup_data(hv: &Hypervisor, mem: &GuestMem, head: u64, bp: &mut BootParams) -> Result<()> {
+    let mut next = head;
+    while next != 0 {
+        if bp.setup_data.len() >= MAX_SETUP_DATA {
+            bail!(
+                "more than {} setup_data entries, the list is probably corrupted",
+                MAX_SETUP_DATA
+            );
+        }
+        let mut header = [0u8; SETUP_DATA_HEADER_SIZE];
+        try_with!(
+            mem.read_phys(hv, next as usize, &mut header),
+            "cannot read setup_data at {:#x}",
+            next
+        );
+        let data = SetupData {
+            addr: next,
+            kind: le_u32(&header, 8),
+            len: le_u32(&header, 12),
+        };
+        if data.kind == SETUP_E820_EXT {
+            let mut entries = vec![0u8; data.len as usize / E820_ENTRY_SIZE * E820_ENTRY_SIZE];
+            try_with!(
+                mem.read_phys(hv, next as usize + SETUP_DATA_HEADER_SIZE, &mut entries),
+                "cannot read extended e820 entries at {:#x}",
+                next
+            );
+            bp.e820
+                .extend(entries.chunks(E820_ENTRY_SIZE).map(E820Entry::from_bytes));
+        }
+        next = le_u64(&header, 0);
+        bp.setup_data.push(data);
+    }
+    Ok(())
+}
+
+/// Find and decode the boot_params the guest kernel was booted with, including e820 entries
+/// passed via setup_data. Expects the hypervisor to be stopped.
+///
+/// The kernel copies boot_params early during boot and does not keep the original up to date,
+/// so this is how the boot loader described the machine, not necessarily the current state.
+pub fn boot_params(hv: &Hypervisor) -> Result<BootParams> {
+    let mem = GuestMem::new(hv)?;
+    read_boot_params(hv, &mem)
+}
+
+fn read_boot_params(hv: &Hypervisor, mem: &GuestMem) -> Result<BootParams> {
+    let (addr, page) = find_boot_params(hv, mem)?;
+    let mut bp = decode_boot_params(addr, &page)?;
+    read_setup_data(hv, mem, le_u64(&page, SETUP_DATA), &mut bp)?;
+    Ok(bp)
+}
+
+/// Guest physical ranges of an EFI memory map made of `desc_size` byte descriptors
+fn decode_efi_memmap(map: &[u8], desc_size: usize) -> Result<Vec<Range<u64>>> {
+    if desc_size < EFI_MEMDESC_NUM_PAGES + 8 {
+        bail!(
+            "efi memory descriptors of {} bytes are too small",
+            desc_size
+        );
+    }
+    Ok(map
+        .chunks_exact(desc_size)
+        .map(|desc| {
+            let start = le_u64(desc, EFI_MEMDESC_PHYS_START);
+            let len = le_u64(desc, EFI_MEMDESC_NUM_PAGES).saturating_mul(EFI_PAGE_SIZE);
+            start..start.saturating_add(len)
+        })
+        .collect())
+}
+
+/// Guest physical ranges the firmware memory map of the guest describes, whatever for: RAM,
+/// reserved ranges, ACPI tables or firmware MMIO. The guest kernel only considers the holes
+/// between them free for devices. Combines the e820 table with the EFI memory map if the guest
+/// was booted via EFI. Expects the hypervisor to be stopped.
+pub fn guest_memory_map(hv: &Hypervisor) -> Result<Vec<Range<u64>>> {
+    let mem = GuestMem::new(hv)?;
+    let bp = read_boot_params(hv, &mem)?;
+    let mut ranges = bp.e820.iter().map(E820Entry::range).collect::<Vec<_>>();
+    if let Some(efi) = &bp.efi {
+        if efi.memmap_size > MAX_EFI_MEMMAP_SIZE {
+            bail!(
+                "efi memory map at {:#x} claims {} bytes, more than {}",
+                efi.memmap,
+                efi.memmap_size,
+                MAX_EFI_MEMMAP_SIZE
+            );
+        }
+        let mut map = vec![0u8; efi.memmap_size as usize];
+        try_with!(
+            mem.read_phys(hv, efi.memmap as usize, &mut map),
+            "cannot read efi memory map at {:#x}",
+            efi.memmap
+        );
+        ranges.extend(decode_efi_memmap(&map, efi.memdesc_size as usize)?);
+    }
+    if ranges.is_empty() {
+        bail!("the guest has neither an e820 table nor an efi memory map");
+    }
+    Ok(ranges)
+}
+
+impl fmt::Display for BootParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "boot_params at {:#x}, boot protocol {}.{:02}, loader type {:#04x}, loadflags {:#04x}",
+            self.addr,
+            self.protocol_version >> 8,
+            self.protocol_version & 0xff,
+            self.loader_type,
+            self.loadflags
+        )?;
+        match self.cmdline_ptr {
+            Some(ptr) => writeln!(f, "cmdline at {:#x}", ptr)?,
+            None => writeln!(f, "no cmdline")?,
+        }
+        match self.initrd {
+            Some((addr, size)) => writeln!(f, "initrd at {:#x}, {} bytes", addr, size)?,
+            None => writeln!(f, "no initrd")?,
+        }
+        if let Some(efi) = &self.efi {
+            writeln!(
+                f,
+                "efi ({}): systab {:#x}, memmap {:#x} ({} bytes, descriptor size {}, version {})",
+                efi.loader_signature,
+                efi.systab,
+                efi.memmap,
+                efi.memmap_size,
+                efi.memdesc_size,
+                efi.memdesc_version
+            )?;
+        }
+        for data in &self.setup_data {
+            writeln!(
+                f,
+                "setup_data at {:#x}: type {}, {} bytes",
+                data.addr, data.kind, data.len
+            )?;
+        }
+        if self.e820.is_empty() && self.efi.is_some() {
+            writeln!(f, "e820: empty, memory map is passed via efi")?;
+        } else {
+            writeln!(f, "e820: {} entries", self.e820.len())?;
+        }
+        for entry in &self.e820 {
+            writeln!(f, "  {}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct BootParamsOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_boot_params(opts: &BootParamsOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    print!("{}", boot_params(&vm)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn e820_entry(page: &mut [u8], idx: usize, addr: u64, size: u64, kind: u32) {
+        let off = E820_TABLE + idx * E820_ENTRY_SIZE;
+        page[off..off + 8].copy_from_slice(&addr.to_le_bytes());
+        page[off + 8..off + 16].copy_from_slice(&size.to_le_bytes());
+        page[off + 16..off + 20].copy_from_slice(&kind.to_le_bytes());
+    }
+
+    fn zero_page() -> [u8; BOOT_PARAMS_SIZE] {
+        let mut page = [0u8; BOOT_PARAMS_SIZE];
+        page[BOOT_FLAG..BOOT_FLAG + 2].copy_from_slice(b"\x55\xaa");
+        page[HEADER_MAGIC..HEADER_MAGIC + 4].copy_from_slice(b"HdrS");
+        page[PROTOCOL_VERSION..PROTOCOL_VERSION + 2].copy_from_slice(&0x20fu16.to_le_bytes());
+        page
+    }
+
+    #[test]
+    fn test_decode_boot_params() {
+        assert!(decode_boot_params(0x7000, &[0u8; BOOT_PARAMS_SIZE]).is_err());
+
+        let mut page = zero_page();
+        page[TYPE_OF_LOADER] = 0xff;
+        page[CMD_LINE_PTR..CMD_LINE_PTR + 4].copy_from_slice(&0x2_0000u32.to_le_bytes());
+        page[E820_ENTRIES] = 2;
+        e820_entry(&mut page, 0, 0, 0x9fc00, 1);
+        e820_entry(&mut page, 1, 0xf0000, 0x10000, 2);
+
+        let bp = decode_boot_params(0x7000, &page).expect("valid boot_params");
+        assert_eq!(bp.protocol_version, 0x20f);
+        assert_eq!(bp.loader_type, 0xff);
+        assert_eq!(bp.cmdline_ptr, Some(0x2_0000));
+        assert_eq!(bp.initrd, None);
+        assert_eq!(bp.efi, None);
+        assert_eq!(
+            bp.e820,
+            vec![
+                E820Entry {
+                    addr: 0,
+                    size: 0x9fc00,
+                    kind: 1
+                },
+                E820Entry {
+                    addr: 0xf0000,
+                    size: 0x10000,
+                    kind: 2
+                },
+            ]
+        );
+        assert_eq!(
+            bp.e820[0].to_string(),
+            "[mem 0x0000000000000000-0x000000000009fbff] usable"
+        );
+        let out = bp.to_string();
+        assert!(out.starts_with("boot_params at 0x7000, boot protocol 2.15"));
+        assert!(out.contains("e820: 2 entries"));
+    }
+
+    #[test]
+    fn test_decode_efi_boot_params() {
+        let mut page = zero_page();
+        page[EFI_LOADER_SIGNATURE..EFI_LOADER_SIGNATURE + 4].copy_from_slice(b"EL64");
+        page[EFI_SYSTAB..EFI_SYSTAB + 4].copy_from_slice(&0x7f9e_e018u32.to_le_bytes());
+        page[EFI_MEMMAP..EFI_MEMMAP + 4].copy_from_slice(&0x7e0e_2018u32.to_le_bytes());
+        page[EFI_MEMMAP_HI..EFI_MEMMAP_HI + 4].copy_from_slice(&1u32.to_le_bytes());
+        page[EFI_MEMMAP_SIZE..EFI_MEMMAP_SIZE + 4].copy_from_slice(&0x1b90u32.to_le_bytes());
+        page[EFI_MEMDESC_SIZE..EFI_MEMDESC_SIZE + 4].copy_from_slice(&48u32.to_le_bytes());
+        page[EFI_MEMDESC_VERSION..EFI_MEMDESC_VERSION + 4].copy_from_slice(&1u32.to_le_bytes());
+        // more entries than fit into the table
+        page[E820_ENTRIES] = 0xff;
+
+        let bp = decode_boot_params(0x1000, &page).expect("valid boot_params");
+        assert_eq!(bp.e820.len(), E820_MAX_ENTRIES);
+        let efi = bp.efi.expect("booted via efi");
+        assert_eq!(efi.loader_signature, "EL64");
+        assert_eq!(efi.systab, 0x7f9e_e018);
+        assert_eq!(efi.memmap, 0x1_7e0e_2018);
+        assert_eq!(efi.memmap_size, 0x1b90);
+        assert_eq!(efi.memdesc_size, 48);
+    }
+
+    #[test]
+    fn test_decode_efi_memmap() {
+        let desc = |start: u64, pages: u64| {
+            let mut d = vec![0u8; 48];
+            d[EFI_MEMDESC_PHYS_START..EFI_MEMDESC_PHYS_START + 8]
+                .copy_from_slice(&start.to_le_bytes());
+            d[EFI_MEMDESC_NUM_PAGES..EFI_MEMDESC_NUM_PAGES + 8]
+                .copy_from_slice(&pages.to_le_bytes());
+            d
+        };
+        let mut map = desc(0, 0xa0);
+        map.extend(desc(0xffc0_0000, 0x400));
+        // trailing bytes of an incomplete descriptor are ignored
+        map.extend(&[0u8; 8]);
+        assert_eq!(
+            decode_efi_memmap(&map, 48).expect("valid memory map"),
+            vec![0..0xa_0000, 0xffc0_0000..0x1_0000_0000]
+        );
+        assert!(decode_efi_memmap(&map, 16).is_err());
+        assert_eq!(
+            E820Entry {
+                addr: 0x10_0000,
+                size: 0x7ff0_0000,
+                kind: 1
+            }
+            .range(),
+            0x10_0000..0x8000_0000
+        );
+    }
+}
@@ -0,0 +1,20 @@
+//! Little-endian fields of guest structures, i.e. of ACPI tables and boot_params. All of them
+//! panic if `bytes` ends before the field does.
+
+pub(crate) fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    let mut val = [0u8; 2];
+    val.copy_from_slice(&bytes[offset..offset + 2]);
+    u16::from_le_bytes(val)
+}
+
+pub(crate) fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut val = [0u8; 4];
+    val.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(val)
+}
+
+pub(crate) fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut val = [0u8; 8];
+    val.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(val)
+}
@@ -0,0 +1,127 @@
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{require_with, try_with};
+use std::path::{Path, PathBuf};
+
+use crate::guest_mem::GuestMem;
+use crate::inspect::boot_params::{
+    find_boot_params, has_setup_header, CMD_LINE_PTR, EXT_CMD_LINE_PTR,
+};
+use crate::inspect::bytes::le_u32;
+use crate::inspect::{open_vmlinux, read_cstr, symbol};
+use crate::kernel::find_kernel;
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+
+/// The command line is read up to one page. x86 kernels limit it to 2048 bytes by default.
+const MAX_CMDLINE_LEN: usize = 4096;
+
+pub struct CmdlineOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub vmlinux: Option<PathBuf>,
+}
+
+/// Physical address of the command line according to the setup header of a boot_params page.
+fn cmdline_ptr(boot_params: &[u8]) -> Option<usize> {
+    if !has_setup_header(boot_params) {
+        return None;
+    }
+    let ptr = (le_u32(boot_params, EXT_CMD_LINE_PTR) as usize) << 32
+        | le_u32(boot_params, CMD_LINE_PTR) as usize;
+    if ptr == 0 {
+        None
+    } else {
+        Some(ptr)
+    }
+}
+
+/// Look for boot_params in the first MiB of guest RAM and read the command line it points to.
+fn cmdline_from_boot_params(hv: &Hypervisor, mem: &GuestMem) -> Result<(String, bool)> {
+    let (addr, page) = find_boot_params(hv, mem)?;
+    info!("found boot_params at {:#x}", addr);
+    let ptr = require_with!(
+        cmdline_ptr(&page),
+        "boot_params at {:#x} has no command line",
+        addr
+    );
+    read_cstr(
+        |addr, buf| mem.read_phys(hv, addr, buf),
+        ptr,
+        MAX_CMDLINE_LEN,
+    )
+}
+
+/// Read the command line the guest kernel was booted with, like /proc/cmdline. Uses
+/// `saved_command_line` if the symbol can be resolved (with the help of `vmlinux`) and
+/// boot_params in low memory otherwise. Expects the hypervisor to be stopped.
+pub fn kernel_cmdline(hv: &Hypervisor, vmlinux: Option<&Path>) -> Result<String> {
+    let mem = GuestMem::new(hv)?;
+    let saved_command_line = find_kernel(&mem, hv).and_then(|kernel| {
+        let vmlinux = open_vmlinux(vmlinux, &kernel)?;
+        symbol(&kernel, vmlinux.as_ref(), "saved_command_line")
+    });
+
+    let (cmdline, terminated) = match saved_command_line {
+        Ok(addr) => {
+            // saved_command_line is a pointer to a memblock allocation
+            let ptr = try_with!(
+                mem.read::<usize>(hv, addr),
+                "cannot read saved_command_line at {:#x}",
+                addr
+            );
+            read_cstr(
+                |addr, buf| mem.read_virt(hv, addr, buf),
+                ptr,
+                MAX_CMDLINE_LEN,
+            )?
+        }
+        Err(e) => {
+            warn!("{}, falling back to boot_params", e);
+            try_with!(
+                cmdline_from_boot_params(hv, &mem),
+                "cannot read command line from boot_params"
+            )
+        }
+    };
+    if !terminated {
+        warn!(
+            "command line is not terminated within {} bytes, it may be truncated",
+            MAX_CMDLINE_LEN
+        );
+    }
+    Ok(cmdline)
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_cmdline(opts: &CmdlineOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    println!("{}", kernel_cmdline(&vm, opts.vmlinux.as_deref())?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspect::boot_params::{BOOT_FLAG, BOOT_PARAMS_SIZE, HEADER_MAGIC};
+
+    #[test]
+    fn test_cmdline_ptr() {
+        let mut page = [0u8; BOOT_PARAMS_SIZE];
+        assert_eq!(cmdline_ptr(&page), None);
+
+        page[BOOT_FLAG..BOOT_FLAG + 2].copy_from_slice(b"\x55\xaa");
+        page[HEADER_MAGIC..HEADER_MAGIC + 4].copy_from_slice(b"HdrS");
+        page[CMD_LINE_PTR..CMD_LINE_PTR + 4].copy_from_slice(&0x2_0000u32.to_le_bytes());
+        assert_eq!(cmdline_ptr(&page), Some(0x2_0000));
+
+        page[EXT_CMD_LINE_PTR..EXT_CMD_LINE_PTR + 4].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(cmdline_ptr(&page), Some(0x1_0002_0000));
+    }
+}
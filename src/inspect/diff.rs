@@ -0,0 +1,195 @@
+use nix::sys::mman::ProtFlags;
+use nix::unistd::Pid;
+use simple_error::try_with;
+use std::fmt;
+
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+use crate::tracer::proc::Mapping;
+
+pub struct DiffMapsOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub other: Pid,
+}
+
+/// Difference of a guest RAM region between two VMs, keyed by guest physical start address.
+#[derive(Debug, PartialEq)]
+pub enum MapChange {
+    Added(Mapping),
+    Removed(Mapping),
+    Resized { old: Mapping, new: Mapping },
+    Permissions { old: Mapping, new: Mapping },
+}
+
+fn prot_str(prot: ProtFlags) -> String {
+    let flag = |f, c| if prot.contains(f) { c } else { '-' };
+    [
+        flag(ProtFlags::PROT_READ, 'r'),
+        flag(ProtFlags::PROT_WRITE, 'w'),
+        flag(ProtFlags::PROT_EXEC, 'x'),
+    ]
+    .iter()
+    .collect()
+}
+
+fn range_str(m: &Mapping) -> String {
+    format!(
+        "{:#x}-{:#x} ({} KiB)",
+        m.phys_addr,
+        m.phys_end(),
+        m.size() / 1024
+    )
+}
+
+/// One line per change, prefixed with +, - or ~ like a diff.
+impl fmt::Display for MapChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapChange::Added(m) => write!(f, "+ {} {}", range_str(m), prot_str(m.prot_flags)),
+            MapChange::Removed(m) => write!(f, "- {} {}", range_str(m), prot_str(m.prot_flags)),
+            MapChange::Resized { old, new } => write!(
+                f,
+                "~ {:#x} size {} KiB -> {} KiB",
+                old.phys_addr,
+                old.size() / 1024,
+                new.size() / 1024
+            ),
+            MapChange::Permissions { old, new } => write!(
+                f,
+                "~ {:#x} prot {} -> {}",
+                old.phys_addr,
+                prot_str(old.prot_flags),
+                prot_str(new.prot_flags)
+            ),
+        }
+    }
+}
+
+/// Compare two lists of VM mappings as returned by `Hypervisor::get_maps()`. Host addresses are
+/// ignored since they differ between hypervisor processes anyway.
+#[must_use]
+pub fn diff_mappings(a: &[Mapping], b: &[Mapping]) -> Vec<MapChange> {
+    let mut a = a.to_vec();
+    let mut b = b.to_vec();
+    a.sort_by_key(|m| m.phys_addr);
+    b.sort_by_key(|m| m.phys_addr);
+
+    let mut changes = vec![];
+    for old in &a {
+        let new = match b.iter().find(|m| m.phys_addr == old.phys_addr) {
+            Some(new) => new,
+            None => {
+                changes.push(MapChange::Removed(old.clone()));
+                continue;
+            }
+        };
+        if old.size() != new.size() {
+            changes.push(MapChange::Resized {
+                old: old.clone(),
+                new: new.clone(),
+            });
+        }
+        if old.prot_flags != new.prot_flags {
+            changes.push(MapChange::Permissions {
+                old: old.clone(),
+                new: new.clone(),
+            });
+        }
+    }
+    for new in &b {
+        if !a.iter().any(|m| m.phys_addr == new.phys_addr) {
+            changes.push(MapChange::Added(new.clone()));
+        }
+    }
+    changes
+}
+
+/// Compare the guest RAM layout of two VMs.
+pub fn diff_maps(a: &Hypervisor, b: &Hypervisor) -> Result<Vec<MapChange>> {
+    let maps_a = try_with!(a.get_maps(), "cannot get memory of vm {}", a.pid);
+    let maps_b = try_with!(b.get_maps(), "cannot get memory of vm {}", b.pid);
+    Ok(diff_mappings(&maps_a, &maps_b))
+}
+
+/// Print the changes from `opts.pid` to `opts.other` and return how many there are.
+#[allow(clippy::print_stdout)]
+pub fn print_diff_maps(opts: &DiffMapsOptions) -> Result<usize> {
+    let a = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let b = try_with!(
+        get_hypervisor(opts.other, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.other
+    );
+    a.stop()?;
+    b.stop()?;
+    let changes = diff_maps(&a, &b)?;
+    for change in &changes {
+        println!("{}", change);
+    }
+    Ok(changes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_mappings, MapChange};
+    use crate::tracer::proc::Mapping;
+    use crate::tracer::testutils::mapping;
+    use nix::sys::mman::ProtFlags;
+
+    fn ram(phys_addr: usize, size: usize, prot_flags: ProtFlags) -> Mapping {
+        Mapping {
+            prot_flags,
+            ..mapping(0x7f00_0000_0000 + phys_addr, phys_addr, size)
+        }
+    }
+
+    #[test]
+    fn test_diff_mappings() {
+        let rw = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        let a = vec![
+            ram(0, 0x8000_0000, rw),
+            ram(0xfeff_c000, 0x1000, rw),
+            ram(0x1_0000_0000, 0x4000_0000, rw),
+        ];
+        let mut b = vec![
+            ram(0x1_0000_0000, 0x8000_0000, rw),
+            ram(0, 0x8000_0000, ProtFlags::PROT_READ),
+            ram(0xd000_0000, 0x1000, rw),
+        ];
+        // host addresses do not matter
+        b[0].start += 0x1000;
+        b[0].end += 0x1000;
+
+        let changes = diff_mappings(&a, &b);
+        assert_eq!(
+            changes,
+            vec![
+                MapChange::Permissions {
+                    old: a[0].clone(),
+                    new: b[1].clone()
+                },
+                MapChange::Removed(a[1].clone()),
+                MapChange::Resized {
+                    old: a[2].clone(),
+                    new: b[0].clone()
+                },
+                MapChange::Added(b[2].clone()),
+            ]
+        );
+        assert_eq!(
+            changes.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            vec![
+                "~ 0x0 prot rw- -> r--",
+                "- 0xfeffc000-0xfeffd000 (4 KiB) rw-",
+                "~ 0x100000000 size 1048576 KiB -> 2097152 KiB",
+                "+ 0xd0000000-0xd0001000 (4 KiB) rw-",
+            ]
+        );
+        assert!(diff_mappings(&a, &a).is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use kvm_bindings as kvmb;
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::ops::Range;
+use std::thread;
+use std::time::Duration;
+
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::page_math::page_size;
+use crate::result::Result;
+
+pub struct TraceFaultsOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    /// only report pages in this guest physical range
+    pub phys_range: Option<Range<usize>>,
+    /// how long the guest runs while we trace
+    pub duration: Duration,
+}
+
+/// Guest physical addresses of the pages set in the dirty `bitmap` of a memslot starting at
+/// `phys_start`, restricted to pages overlapping `range`.
+fn dirty_pages(bitmap: &[u8], phys_start: usize, range: &Range<usize>) -> Vec<usize> {
+    let page_size = page_size();
+    bitmap
+        .iter()
+        .enumerate()
+        .flat_map(|(i, byte)| {
+            (0..8)
+                .filter(move |bit| byte & (1 << bit) != 0)
+                .map(move |bit| phys_start + (i * 8 + bit) * page_size)
+        })
+        .filter(|addr| *addr < range.end && range.start < addr + page_size)
+        .collect()
+}
+
+/// Guest physical addresses of the pages in `phys_range` the guest writes to within `duration`,
+/// i.e. to estimate its working set.
+///
+/// This uses the dirty page log of KVM, so only writes are seen: pages the guest just reads are
+/// missing. Seeing reads would require clearing the accessed bits of the EPT/NPT, which KVM does
+/// not expose to userspace. Writes of the hypervisor itself, i.e. emulated DMA, bypass KVM and
+/// are missing as well. Expects the hypervisor to be stopped, lets it run for `duration` and
+/// stops it again.
+pub fn trace_faults(
+    hv: &Hypervisor,
+    phys_range: Range<usize>,
+    duration: Duration,
+) -> Result<Vec<usize>> {
+    let slots = hv
+        .get_memslots()?
+        .into_iter()
+        .filter(|s| {
+            s.physical_start() < phys_range.end
+                && phys_range.start < s.physical_start() + s.size()
+                && s.flags() & kvmb::KVM_MEM_READONLY == 0
+        })
+        .collect::<Vec<_>>();
+    if slots.is_empty() {
+        bail!(
+            "no writable guest memory in {:#x}-{:#x}",
+            phys_range.start,
+            phys_range.end
+        );
+    }
+    // Reading the dirty log clears it. If the hypervisor logs dirty pages itself, i.e. during
+    // live migration, it would miss the pages we read, so leave such slots alone.
+    let (logged, slots): (Vec<_>, Vec<_>) = slots
+        .into_iter()
+        .partition(|s| s.flags() & kvmb::KVM_MEM_LOG_DIRTY_PAGES != 0);
+    for slot in &logged {
+        warn!(
+            "the hypervisor logs dirty pages of memslot {} itself, not tracing it",
+            slot.id()
+        );
+    }
+    if slots.is_empty() {
+        bail!(
+            "the hypervisor logs dirty pages of all guest memory in {:#x}-{:#x} itself, i.e. for a migration",
+            phys_range.start,
+            phys_range.end
+        );
+    }
+
+    let mut guards = vec![];
+    for slot in &slots {
+        guards.push(hv.enable_dirty_logging(slot.id())?);
+        // start with a clean log
+        hv.get_dirty_log(slot)?;
+    }
+
+    hv.resume()?;
+    thread::sleep(duration);
+    hv.stop()?;
+
+    let mut pages = vec![];
+    for slot in &slots {
+        let bitmap = hv.get_dirty_log(slot)?;
+        pages.extend(dirty_pages(&bitmap, slot.physical_start(), &phys_range));
+    }
+    for guard in guards {
+        guard.restore()?;
+    }
+    pages.sort_unstable();
+    Ok(pages)
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_trace_faults(opts: &TraceFaultsOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    let range = opts.phys_range.clone().unwrap_or(0..usize::MAX);
+    let res = trace_faults(&vm, range, opts.duration);
+    vm.resume()?;
+    let pages = res?;
+    for page in &pages {
+        println!("{:#x}", page);
+    }
+    info!(
+        "{} pages ({} KiB) written within {:?}",
+        pages.len(),
+        pages.len() * page_size() / 1024,
+        opts.duration
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dirty_pages;
+
+    #[test]
+    fn test_dirty_pages() {
+        // pages 0, 3 and 9
+        let bitmap = [0b0000_1001, 0b0000_0010, 0, 0];
+        let start = 0x10_0000;
+        assert_eq!(
+            dirty_pages(&bitmap, start, &(0..usize::MAX)),
+            vec![0x10_0000, 0x10_3000, 0x10_9000]
+        );
+        assert_eq!(
+            dirty_pages(&bitmap, start, &(0x10_3800..0x10_9000)),
+            vec![0x10_3000]
+        );
+        assert!(dirty_pages(&[0; 8], start, &(0..usize::MAX)).is_empty());
+    }
+}
@@ -0,0 +1,282 @@
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::btf::Btf;
+use crate::guest_mem::GuestMem;
+use crate::inspect::{open_vmlinux, read_cstr, read_u32, read_usize, symbol};
+use crate::kernel::find_kernel;
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+
+/// Upper bound for the module list, protects against cycles in corrupted lists
+const MAX_MODULES: usize = 1 << 16;
+
+/// MODULE_NAME_LEN on 64-bit
+const MODULE_NAME_LEN: usize = 56;
+
+/// MOD_TEXT, MOD_DATA, MOD_RODATA and MOD_RO_AFTER_INIT, the entries of `module.mem` that are
+/// kept after initialization. lsmod(8) shows their total size.
+const CORE_MEM_TYPES: usize = 4;
+
+pub struct LsmodOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub vmlinux: Option<PathBuf>,
+}
+
+/// Offsets of the `struct module` members we need
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleOffsets {
+    pub list: usize,
+    pub name: usize,
+    /// base address of the (first) core memory area
+    pub base: usize,
+    /// size of the (first) core memory area, an unsigned int
+    pub size: usize,
+    /// number of core memory areas, they are `stride` bytes apart
+    pub areas: usize,
+    pub stride: usize,
+}
+
+impl ModuleOffsets {
+    /// The core memory moved from `module_core`/`core_size` to `core_layout` in linux 4.5 and
+    /// was split into `mem[]` in linux 6.4.
+    pub fn from_btf(btf: &Btf) -> Result<ModuleOffsets> {
+        let list = btf.offset_of("module", "list")?;
+        let name = btf.offset_of("module", "name")?;
+        if let Ok(mem) = btf.offset_of("module", "mem") {
+            let stride = require_with!(
+                btf.find_struct("module_memory"),
+                "no struct module_memory in BTF"
+            )
+            .size_or_type as usize;
+            return Ok(ModuleOffsets {
+                list,
+                name,
+                base: mem + btf.offset_of("module_memory", "base")?,
+                size: mem + btf.offset_of("module_memory", "size")?,
+                areas: CORE_MEM_TYPES,
+                stride,
+            });
+        }
+        let (base, size) = match btf.offset_of("module", "core_layout") {
+            Ok(layout) => (
+                layout + btf.offset_of("module_layout", "base")?,
+                layout + btf.offset_of("module_layout", "size")?,
+            ),
+            Err(_) => (
+                btf.offset_of("module", "module_core")?,
+                btf.offset_of("module", "core_size")?,
+            ),
+        };
+        Ok(ModuleOffsets {
+            list,
+            name,
+            base,
+            size,
+            areas: 1,
+            stride: 0,
+        })
+    }
+}
+
+/// A loaded kernel module of the guest
+#[derive(Clone, Debug, PartialEq)]
+pub struct Module {
+    /// guest virtual address of its struct module
+    pub addr: usize,
+    pub name: String,
+    /// start of its code
+    pub base: usize,
+    /// total size of code and data
+    pub size: usize,
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<24} {:>8}  {:#x}", self.name, self.size, self.base)
+    }
+}
+
+fn read_module<F>(read: &mut F, offsets: &ModuleOffsets, addr: usize) -> Result<Module>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    let (name, _) = try_with!(
+        read_cstr(&mut *read, addr + offsets.name, MODULE_NAME_LEN),
+        "cannot read name of module {:#x}",
+        addr
+    );
+    let base = try_with!(
+        read_usize(read, addr + offsets.base),
+        "cannot read base of module {}",
+        name
+    );
+    let mut size = 0;
+    for i in 0..offsets.areas {
+        let area_size = try_with!(
+            read_u32(read, addr + offsets.size + i * offsets.stride),
+            "cannot read size of module {}",
+            name
+        );
+        size += area_size as usize;
+    }
+    Ok(Module {
+        addr,
+        name,
+        base,
+        size,
+    })
+}
+
+/// Follow the `list_head` at `head` and read the modules it links.
+fn walk_modules<F>(mut read: F, head: usize, offsets: &ModuleOffsets) -> Result<Vec<Module>>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    let mut modules = vec![];
+    let mut next = try_with!(read_usize(&mut read, head), "cannot read modules.next");
+    while next != head {
+        if modules.len() > MAX_MODULES {
+            bail!("module list does not end after {} entries", MAX_MODULES);
+        }
+        let module = require_with!(
+            next.checked_sub(offsets.list),
+            "corrupted module list: entry at {:#x} is below the list offset {:#x}",
+            next,
+            offsets.list
+        );
+        modules.push(read_module(&mut read, offsets, module)?);
+        next = try_with!(
+            read_usize(&mut read, next),
+            "cannot read list.next at {:#x}",
+            next
+        );
+    }
+    Ok(modules)
+}
+
+/// Enumerate the modules loaded in the guest by following the `modules` list. The list head is
+/// not exported and the layout of `struct module` comes from BTF, so this requires a `vmlinux`
+/// with BTF. Expects the hypervisor to be stopped.
+pub fn lsmod(hv: &Hypervisor, vmlinux: Option<&Path>) -> Result<Vec<Module>> {
+    let mem = GuestMem::new(hv)?;
+    let kernel = try_with!(find_kernel(&mem, hv), "could not find kernel");
+    let vmlinux = open_vmlinux(vmlinux, &kernel)?;
+    let head = try_with!(
+        symbol(&kernel, vmlinux.as_ref(), "modules"),
+        "cannot find the module list, pass --vmlinux of the guest kernel. Kernels built without CONFIG_MODULES have none"
+    );
+    let btf = require_with!(
+        vmlinux.as_ref().and_then(|v| v.btf.as_ref()),
+        "the layout of struct module is read from BTF, pass --vmlinux of a kernel built with CONFIG_DEBUG_INFO_BTF"
+    );
+    let offsets = try_with!(
+        ModuleOffsets::from_btf(btf),
+        "cannot lookup struct module layout in BTF"
+    );
+    walk_modules(|addr, buf| mem.read_virt(hv, addr, buf), head, &offsets)
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_lsmod(opts: &LsmodOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    let modules = lsmod(&vm, opts.vmlinux.as_deref())?;
+    println!("{:<24} {:>8}  Base", "Module", "Size");
+    for m in &modules {
+        println!("{}", m);
+    }
+    if modules.is_empty() {
+        println!("(no modules loaded)");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{walk_modules, Module, ModuleOffsets};
+    use simple_error::bail;
+
+    #[test]
+    fn test_walk_modules() {
+        let offsets = ModuleOffsets {
+            list: 0x8,
+            name: 0x18,
+            base: 0x60,
+            size: 0x68,
+            areas: 2,
+            stride: 0x10,
+        };
+        let start = 0xffff_ffff_c000_0000;
+        let head = start;
+        let (a, b) = (start + 0x100, start + 0x200);
+        let mut mem = vec![0u8; 0x300];
+        let mut put = |addr: usize, data: &[u8]| {
+            let off = addr - start;
+            mem[off..off + data.len()].copy_from_slice(data);
+        };
+        // head -> a -> b -> head
+        put(head, &(a + offsets.list).to_le_bytes());
+        put(a + offsets.list, &(b + offsets.list).to_le_bytes());
+        put(b + offsets.list, &head.to_le_bytes());
+        put(a + offsets.name, b"virtio_mmio\0");
+        put(a + offsets.base, &0xffff_ffff_c010_0000usize.to_le_bytes());
+        put(a + offsets.size, &0x1000u32.to_le_bytes());
+        put(a + offsets.size + offsets.stride, &0x200u32.to_le_bytes());
+        put(b + offsets.name, b"ext4\0");
+        put(b + offsets.base, &0xffff_ffff_c020_0000usize.to_le_bytes());
+        put(b + offsets.size, &0x4000u32.to_le_bytes());
+
+        let read = |addr: usize, buf: &mut [u8]| {
+            let off = addr.wrapping_sub(start);
+            if off + buf.len() > mem.len() {
+                bail!("unmapped {:#x}", addr);
+            }
+            buf.copy_from_slice(&mem[off..off + buf.len()]);
+            Ok(())
+        };
+        let modules = walk_modules(read, head, &offsets).expect("cannot walk modules");
+        assert_eq!(
+            modules,
+            vec![
+                Module {
+                    addr: a,
+                    name: "virtio_mmio".into(),
+                    base: 0xffff_ffff_c010_0000,
+                    size: 0x1200,
+                },
+                Module {
+                    addr: b,
+                    name: "ext4".into(),
+                    base: 0xffff_ffff_c020_0000,
+                    size: 0x4000,
+                },
+            ]
+        );
+
+        // empty list
+        let empty = |_addr: usize, buf: &mut [u8]| {
+            buf.copy_from_slice(&head.to_le_bytes());
+            Ok(())
+        };
+        assert!(walk_modules(empty, head, &offsets)
+            .expect("cannot walk modules")
+            .is_empty());
+
+        // a corrupted next pointer below the list offset stops the walk
+        let corrupted = |addr: usize, buf: &mut [u8]| {
+            let next: usize = if addr == head { 0x4 } else { head };
+            buf.copy_from_slice(&next.to_le_bytes());
+            Ok(())
+        };
+        assert!(walk_modules(corrupted, head, &offsets).is_err());
+    }
+}
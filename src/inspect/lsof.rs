@@ -0,0 +1,322 @@
+use log::warn;
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::btf::Btf;
+use crate::guest_mem::GuestMem;
+use crate::inspect::ps::{walk_tasks, TaskStructOffsets};
+use crate::inspect::{open_vmlinux, read_cstr, read_u32, read_usize, symbol};
+use crate::kernel::find_kernel;
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+
+/// Upper bound for the fd table, protects against reading garbage for a corrupted table.
+/// The default for fs.nr_open.
+const MAX_FDS: u32 = 1 << 20;
+
+/// Upper bound for the depth of a path, protects against cycles in corrupted dentries
+const MAX_PATH_DEPTH: usize = 256;
+
+/// NAME_MAX plus the terminating null byte
+const MAX_NAME_LEN: usize = 256;
+
+pub struct LsofOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub vmlinux: Option<PathBuf>,
+    /// process in the guest whose files are listed
+    pub guest_pid: i32,
+}
+
+/// Offsets of the members on the way from `task_struct` to the name of an open file
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileOffsets {
+    /// `task_struct.files`
+    pub task_files: usize,
+    /// `files_struct.fdt`
+    pub files_fdt: usize,
+    /// `fdtable.max_fds`, an unsigned int
+    pub fdt_max_fds: usize,
+    /// `fdtable.fd`, an array of `struct file *`
+    pub fdt_fd: usize,
+    /// `file.f_path.dentry`
+    pub file_dentry: usize,
+    /// `file.f_flags`, an unsigned int
+    pub file_flags: usize,
+    /// `file.f_pos`
+    pub file_pos: usize,
+    /// `dentry.d_parent`
+    pub dentry_parent: usize,
+    /// `dentry.d_name.name`
+    pub dentry_name: usize,
+}
+
+impl FileOffsets {
+    pub fn from_btf(btf: &Btf) -> Result<FileOffsets> {
+        Ok(FileOffsets {
+            task_files: btf.offset_of("task_struct", "files")?,
+            files_fdt: btf.offset_of("files_struct", "fdt")?,
+            fdt_max_fds: btf.offset_of("fdtable", "max_fds")?,
+            fdt_fd: btf.offset_of("fdtable", "fd")?,
+            file_dentry: btf.offset_of("file", "f_path")? + btf.offset_of("path", "dentry")?,
+            file_flags: btf.offset_of("file", "f_flags")?,
+            file_pos: btf.offset_of("file", "f_pos")?,
+            dentry_parent: btf.offset_of("dentry", "d_parent")?,
+            dentry_name: btf.offset_of("dentry", "d_name")? + btf.offset_of("qstr", "name")?,
+        })
+    }
+}
+
+/// A file descriptor of a guest process
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpenFile {
+    pub fd: u32,
+    /// guest virtual address of its struct file
+    pub addr: usize,
+    /// open(2) flags
+    pub flags: u32,
+    pub pos: i64,
+    /// Path from the root of the mount the file is on. Files on pseudo filesystems, i.e. pipes
+    /// and sockets, only have a name. None if the dentry could not be read.
+    pub path: Option<String>,
+}
+
+impl fmt::Display for OpenFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>4} {:#018x} {:#08o} {:>10} {}",
+            self.fd,
+            self.addr,
+            self.flags,
+            self.pos,
+            self.path.as_deref().unwrap_or("?")
+        )
+    }
+}
+
+/// Join the names of `dentry` and its parents up to the root of its mount. Mount points are not
+/// crossed, since that requires walking `struct mount` as well.
+fn dentry_path<F>(read: &mut F, offsets: &FileOffsets, dentry: usize) -> Result<String>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    let mut names = vec![];
+    let mut cur = dentry;
+    loop {
+        if names.len() >= MAX_PATH_DEPTH {
+            bail!(
+                "path of dentry {:#x} is deeper than {}",
+                dentry,
+                MAX_PATH_DEPTH
+            );
+        }
+        let name_ptr = read_usize(read, cur + offsets.dentry_name)?;
+        let (name, _) = read_cstr(&mut *read, name_ptr, MAX_NAME_LEN)?;
+        let parent = read_usize(read, cur + offsets.dentry_parent)?;
+        // the root of a mount is its own parent
+        if parent == cur || parent == 0 {
+            if names.is_empty() {
+                return Ok(name);
+            }
+            break;
+        }
+        names.push(name);
+        cur = parent;
+    }
+    names.reverse();
+    Ok(format!("/{}", names.join("/")))
+}
+
+/// Read the fd table of the task_struct at `task`.
+fn read_files<F>(mut read: F, offsets: &FileOffsets, task: usize) -> Result<Vec<OpenFile>>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    let files = read_usize(&mut read, task + offsets.task_files)?;
+    // kernel threads and exiting processes
+    if files == 0 {
+        return Ok(vec![]);
+    }
+    let fdt = read_usize(&mut read, files + offsets.files_fdt)?;
+    let max_fds = read_u32(&mut read, fdt + offsets.fdt_max_fds)?;
+    if max_fds > MAX_FDS {
+        bail!(
+            "fd table at {:#x} claims {} entries, more than {}",
+            fdt,
+            max_fds,
+            MAX_FDS
+        );
+    }
+    let fd_array = read_usize(&mut read, fdt + offsets.fdt_fd)?;
+    let mut open = vec![];
+    for fd in 0..max_fds {
+        let file = read_usize(&mut read, fd_array + fd as usize * 8)?;
+        if file == 0 {
+            continue;
+        }
+        let flags = read_u32(&mut read, file + offsets.file_flags)?;
+        let pos = read_usize(&mut read, file + offsets.file_pos)? as i64;
+        let path = read_usize(&mut read, file + offsets.file_dentry)
+            .and_then(|dentry| dentry_path(&mut read, offsets, dentry));
+        let path = match path {
+            Ok(path) => Some(path),
+            Err(e) => {
+                warn!("cannot read path of fd {}: {}", fd, e);
+                None
+            }
+        };
+        open.push(OpenFile {
+            fd,
+            addr: file,
+            flags,
+            pos,
+            path,
+        });
+    }
+    Ok(open)
+}
+
+/// List the open file descriptors of process `guest_pid` in the guest by walking its fd table.
+/// Requires a `vmlinux` with BTF for the layout of the structs involved. Expects the hypervisor
+/// to be stopped.
+pub fn guest_lsof(
+    hv: &Hypervisor,
+    vmlinux: Option<&Path>,
+    guest_pid: i32,
+) -> Result<Vec<OpenFile>> {
+    let mem = GuestMem::new(hv)?;
+    let kernel = try_with!(find_kernel(&mem, hv), "could not find kernel");
+    let vmlinux = open_vmlinux(vmlinux, &kernel)?;
+    let btf = require_with!(
+        vmlinux.as_ref().and_then(|v| v.btf.as_ref()),
+        "the layout of the fd table is read from BTF, pass --vmlinux of a kernel built with CONFIG_DEBUG_INFO_BTF"
+    );
+    let task_offsets = try_with!(
+        TaskStructOffsets::from_btf(btf),
+        "cannot lookup task_struct layout in BTF"
+    );
+    let offsets = try_with!(
+        FileOffsets::from_btf(btf),
+        "cannot lookup fd table layout in BTF"
+    );
+    let init_task = symbol(&kernel, vmlinux.as_ref(), "init_task")?;
+    let tasks = walk_tasks(hv, &mem, init_task, &task_offsets)?;
+    let task = require_with!(
+        tasks.iter().find(|t| t.pid == guest_pid),
+        "no process with pid {} in the guest",
+        guest_pid
+    );
+    read_files(
+        |addr, buf| mem.read_virt(hv, addr, buf),
+        &offsets,
+        task.addr,
+    )
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_lsof(opts: &LsofOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    let files = guest_lsof(&vm, opts.vmlinux.as_deref(), opts.guest_pid)?;
+    println!(
+        "{:>4} {:<18} {:<8} {:>10} NAME",
+        "FD", "FILE", "FLAGS", "POS"
+    );
+    for file in &files {
+        println!("{}", file);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_files, FileOffsets, OpenFile};
+    use simple_error::bail;
+
+    #[test]
+    fn test_read_files() {
+        let offsets = FileOffsets {
+            task_files: 0x10,
+            files_fdt: 0x20,
+            fdt_max_fds: 0x0,
+            fdt_fd: 0x8,
+            file_dentry: 0x18,
+            file_flags: 0x40,
+            file_pos: 0x48,
+            dentry_parent: 0x18,
+            dentry_name: 0x28,
+        };
+        let start = 0xffff_8880_0000_0000;
+        let task = start;
+        let files = start + 0x100;
+        let fdt = start + 0x200;
+        let fd_array = start + 0x300;
+        let (file0, file2) = (start + 0x400, start + 0x500);
+        let (root, etc, passwd, pipe) =
+            (start + 0x600, start + 0x700, start + 0x800, start + 0x900);
+        let names = start + 0xa00;
+        let mut mem = vec![0u8; 0xb00];
+        let mut put = |addr: usize, data: &[u8]| {
+            let off = addr - start;
+            mem[off..off + data.len()].copy_from_slice(data);
+        };
+        put(task + offsets.task_files, &files.to_le_bytes());
+        put(files + offsets.files_fdt, &fdt.to_le_bytes());
+        put(fdt + offsets.fdt_max_fds, &4u32.to_le_bytes());
+        put(fdt + offsets.fdt_fd, &fd_array.to_le_bytes());
+        // fd 0 and 2 are open
+        put(fd_array, &file0.to_le_bytes());
+        put(fd_array + 16, &file2.to_le_bytes());
+        put(file0 + offsets.file_dentry, &passwd.to_le_bytes());
+        put(file0 + offsets.file_pos, &42usize.to_le_bytes());
+        put(file2 + offsets.file_dentry, &pipe.to_le_bytes());
+        put(file2 + offsets.file_flags, &0o4001u32.to_le_bytes());
+        // /etc/passwd
+        put(names, b"/\0etc\0passwd\0pipe:[1234]\0");
+        put(root + offsets.dentry_name, &names.to_le_bytes());
+        put(root + offsets.dentry_parent, &root.to_le_bytes());
+        put(etc + offsets.dentry_name, &(names + 2).to_le_bytes());
+        put(etc + offsets.dentry_parent, &root.to_le_bytes());
+        put(passwd + offsets.dentry_name, &(names + 6).to_le_bytes());
+        put(passwd + offsets.dentry_parent, &etc.to_le_bytes());
+        put(pipe + offsets.dentry_name, &(names + 13).to_le_bytes());
+        put(pipe + offsets.dentry_parent, &pipe.to_le_bytes());
+
+        let read = |addr: usize, buf: &mut [u8]| {
+            let off = addr.wrapping_sub(start);
+            if off + buf.len() > mem.len() {
+                bail!("unmapped {:#x}", addr);
+            }
+            buf.copy_from_slice(&mem[off..off + buf.len()]);
+            Ok(())
+        };
+        let files = read_files(read, &offsets, task).expect("cannot read files");
+        assert_eq!(
+            files,
+            vec![
+                OpenFile {
+                    fd: 0,
+                    addr: file0,
+                    flags: 0,
+                    pos: 42,
+                    path: Some("/etc/passwd".into()),
+                },
+                OpenFile {
+                    fd: 2,
+                    addr: file2,
+                    flags: 0o4001,
+                    pos: 0,
+                    path: Some("pipe:[1234]".into()),
+                },
+            ]
+        );
+    }
+}
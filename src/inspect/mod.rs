@@ -0,0 +1,301 @@
+//mod device;
+pub mod acpi;
+pub mod backtrace;
+pub mod boot_params;
+mod bytes;
+pub mod cmdline;
+pub mod diff;
+pub mod faults;
+pub mod lsmod;
+pub mod lsof;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod panic;
+pub mod ps;
+pub mod region;
+pub mod regs;
+pub mod scan;
+pub mod tables;
+pub mod trace_reg;
+pub mod uname;
+pub mod watch;
+
+pub use self::acpi::{acpi, print_acpi, Acpi, AcpiOptions, AcpiTable, Madt, MadtEntry};
+pub use self::backtrace::{backtrace, print_backtrace, BacktraceOptions, Frame, Symbolizer};
+pub use self::boot_params::{
+    boot_params, print_boot_params, BootParams, BootParamsOptions, E820Entry, EfiInfo, SetupData,
+};
+pub use self::cmdline::{kernel_cmdline, print_cmdline, CmdlineOptions};
+pub use self::diff::{diff_maps, print_diff_maps, DiffMapsOptions, MapChange};
+pub use self::faults::{print_trace_faults, trace_faults, TraceFaultsOptions};
+pub use self::lsmod::{lsmod, print_lsmod, LsmodOptions, Module, ModuleOffsets};
+pub use self::lsof::{guest_lsof, print_lsof, FileOffsets, LsofOptions, OpenFile};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use self::panic::{print_panic, watch_panic, PanicEvent, WatchPanicOptions};
+pub use self::ps::{print_ps, ps, PsOptions, Task, TaskStructOffsets};
+pub use self::region::{
+    extract, extract_region, inject_region, inject_region_from_file, ExtractOptions,
+    InjectRegionOptions,
+};
+pub use self::regs::{dump_regs, format_regs};
+pub use self::scan::{print_scan, scan, scan_maps, ScanFilter, ScanOptions};
+pub use self::tables::{
+    descriptor_tables, print_descriptor_tables, DescriptorTables, DescriptorTablesOptions,
+};
+pub use self::trace_reg::{print_trace_reg, trace_reg, RegWrite, TraceRegOptions};
+pub use self::uname::{kernel_version, print_uname, UnameOptions};
+pub use self::watch::{print_watch_mem, watch_mem, MemChange, WatchMemOptions};
+
+use crate::guest_mem::GuestMem;
+use crate::kernel::{find_kernel, Kernel};
+use crate::result::Result;
+use crate::vmlinux::Vmlinux;
+use log::*;
+use nix::unistd::Pid;
+use simple_error::{simple_error, try_with};
+use std::path::Path;
+
+use crate::kvm;
+use crate::kvm::hypervisor::HypervisorOptions;
+
+/// Open `path` and relocate its symbols to the running `kernel`.
+pub(crate) fn open_vmlinux(path: Option<&Path>, kernel: &Kernel) -> Result<Option<Vmlinux>> {
+    match path {
+        Some(path) => {
+            let mut v = Vmlinux::open(path)?;
+            v.relocate(kernel)?;
+            Ok(Some(v))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Look up a symbol exported by the guest kernel, falling back to `vmlinux`.
+pub(crate) fn symbol(kernel: &Kernel, vmlinux: Option<&Vmlinux>, name: &str) -> Result<usize> {
+    kernel
+        .symbols
+        .get(name)
+        .copied()
+        .or_else(|| vmlinux.and_then(|v| v.symbol(name)))
+        .ok_or_else(|| simple_error!("cannot find kernel symbol {}", name))
+}
+
+/// Read a NUL terminated string of at most `max_len` bytes at `addr` using `read`, i.e.
+/// `GuestMem::read_virt`. Reads aligned chunks, so we never touch the page after the terminator,
+/// which might not be mapped. Also returns whether the terminator was found.
+pub(crate) fn read_cstr<F>(mut read: F, addr: usize, max_len: usize) -> Result<(String, bool)>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    let mut bytes = vec![];
+    let mut chunk = [0u8; 64];
+    let mut terminated = false;
+    while bytes.len() < max_len {
+        let cur = addr + bytes.len();
+        let len = (chunk.len() - cur % chunk.len()).min(max_len - bytes.len());
+        read(cur, &mut chunk[..len])?;
+        match chunk[..len].iter().position(|c| *c == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&chunk[..end]);
+                terminated = true;
+                break;
+            }
+            None => bytes.extend_from_slice(&chunk[..len]),
+        }
+    }
+    Ok((String::from_utf8_lossy(&bytes).into_owned(), terminated))
+}
+
+/// Read a pointer or `unsigned long` of the guest at `addr` using `read`, see `read_cstr`
+pub(crate) fn read_usize<F>(read: &mut F, addr: usize) -> Result<usize>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    let mut buf = [0u8; 8];
+    read(addr, &mut buf)?;
+    Ok(usize::from_le_bytes(buf))
+}
+
+/// Read an `unsigned int` of the guest at `addr` using `read`, see `read_cstr`
+pub(crate) fn read_u32<F>(read: &mut F, addr: usize) -> Result<u32>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    let mut buf = [0u8; 4];
+    read(addr, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub struct InspectOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    /// print `HypervisorSummary` as json instead of logging details
+    pub json: bool,
+}
+
+#[cfg(feature = "serde")]
+#[allow(clippy::print_stdout)]
+fn print_summary_json(vm: &kvm::hypervisor::Hypervisor) -> Result<()> {
+    let summary = vm.summary()?;
+    let json = try_with!(
+        serde_json::to_string_pretty(&summary),
+        "cannot serialize hypervisor summary"
+    );
+    println!("{}", json);
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn print_summary_json(_vm: &kvm::hypervisor::Hypervisor) -> Result<()> {
+    simple_error::bail!("vmsh was built without the serde feature, json output is not available")
+}
+
+pub fn inspect(opts: &InspectOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    if opts.json {
+        return print_summary_json(&vm);
+    }
+
+    for map in vm.get_maps()? {
+        info!(
+            "vm mem: {:#x} -> {:#x} (physical: {:#x}, flags: {:?} | {:?}) @@ {}",
+            map.start, map.end, map.phys_addr, map.prot_flags, map.map_flags, map.pathname
+        )
+    }
+
+    match vm.address_spaces() {
+        Ok(n) if n > 1 => info!(
+            "kvm supports {} memory address spaces (SMM), mappings are those of the first one",
+            n
+        ),
+        Ok(_) => {}
+        Err(e) => info!("could not query address spaces: {}", e),
+    }
+
+    info!("vcpu maps");
+    for map in vm.get_vcpu_maps()? {
+        info!(
+            "vm cpu mem: {:#x} -> {:#x} (physical: {:#x}, flags: {:?} | {:?}) @@ {}",
+            map.start, map.end, map.phys_addr, map.prot_flags, map.map_flags, map.pathname
+        );
+
+        let map_ptr = map.start as *const kvm_bindings::kvm_run;
+        let kvm_run: kvm_bindings::kvm_run =
+            kvm::hypervisor::memory::process_read(opts.pid, map_ptr as *const libc::c_void)?;
+        info!("kvm_run: exit_reason {}", kvm_run.exit_reason);
+
+        let reason_ptr: *const u32 = unsafe { &((*map_ptr).exit_reason) };
+        let reason: u32 =
+            kvm::hypervisor::memory::process_read(opts.pid, reason_ptr as *const libc::c_void)?;
+        info!("reason ptr = {:?}", reason_ptr);
+        info!("reason = {}", reason);
+    }
+
+    let mem = GuestMem::new(&vm)?;
+
+    match find_kernel(&mem, &vm) {
+        Ok(kernel) => {
+            let sections = &kernel.memory_sections;
+            info!(
+                "found kernel at {:#x}-{:#x} (free space before: {} kib, free space after: {} kib)",
+                kernel.range.start,
+                kernel.range.end,
+                kernel.space_before() / 1024,
+                kernel.space_after() / 1024,
+            );
+            info!("kernel sections:");
+            for m in sections {
+                info!("{:#x} ({}kb, {:?})", m.virt_start, m.len / 1024, m.prot)
+            }
+            info!("{} found kernel symbols", kernel.symbols.len());
+        }
+        Err(e) => info!("could not find kernel: {}", e),
+    }
+
+    match vm.sample_clock() {
+        Ok(c) => info!(
+            "guest clock: {} ns, host monotonic: {} ns (+-{} ns), offset: {} ns, flags: {:#x}",
+            c.guest_ns,
+            c.host_monotonic_ns,
+            c.uncertainty_ns / 2,
+            c.offset_ns(),
+            c.flags
+        ),
+        Err(e) => info!("could not read guest clock: {}", e),
+    }
+    if let Some(vcpu) = vm.vcpus.first() {
+        match vm.sample_tsc(vcpu) {
+            Ok(t) => info!(
+                "guest tsc: {}, host tsc: {} (+-{}), offset: {}, frequency: {}",
+                t.guest_tsc,
+                t.host_tsc,
+                t.uncertainty / 2,
+                t.offset(),
+                t.tsc_khz
+                    .map_or_else(|| "unknown".into(), |khz| format!("{} kHz", khz))
+            ),
+            Err(e) => info!("could not read guest tsc: {}", e),
+        }
+    }
+
+    let pic1 = vm.get_irqchip(0)?;
+    info!("pic1: {:?}", unsafe { pic1.chip.pic });
+    let pic2 = vm.get_irqchip(1)?;
+    info!("pic2: {:?}", unsafe { pic2.chip.pic });
+    let ioapic = vm.get_irqchip(2)?;
+    let ioa = unsafe { ioapic.chip.ioapic };
+    info!(
+        "ioapic: base_address={:x} ioregsel={:x} id={:x} irr={:x}",
+        ioa.base_address, ioa.ioregsel, ioa.id, ioa.irr
+    );
+    match vm.get_irq_routing() {
+        Ok(routes) => {
+            for route in routes.iter().filter(|r| r.in_use()) {
+                info!("{}", route);
+            }
+        }
+        Err(e) => info!("could not read irq routing: {}", e),
+    }
+    if let Err(e) = dump_regs(&vm) {
+        info!("could not read vcpu registers: {}", e);
+    }
+    for vcpu in &vm.vcpus {
+        match vm.get_lapic(vcpu) {
+            Ok(lapic) => info!("vcpu {} lapic:\n{}", vcpu.idx, lapic),
+            Err(e) => info!("could not read lapic of vcpu {}: {}", vcpu.idx, e),
+        }
+    }
+    // this is quite verbose
+    //for (i, field) in ioa.redirtbl.iter().enumerate() {
+    //    info!("ioapic[{}]=bits={:x}: fields={:?}", i, unsafe { field.bits }, unsafe { field.fields });
+    //}
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_cstr;
+
+    #[test]
+    fn test_read_cstr() {
+        let mem = b"\0\0\0panic: oops\0garbage".repeat(8);
+        let read = |addr: usize, buf: &mut [u8]| -> crate::result::Result<()> {
+            buf.copy_from_slice(&mem[addr..addr + buf.len()]);
+            Ok(())
+        };
+        assert_eq!(
+            read_cstr(read, 3, 64).expect("cannot read string"),
+            ("panic: oops".into(), true)
+        );
+        assert_eq!(
+            read_cstr(read, 3, 5).expect("cannot read string"),
+            ("panic".into(), false)
+        );
+    }
+}
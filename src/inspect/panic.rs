@@ -0,0 +1,161 @@
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::path::{Path, PathBuf};
+
+use crate::cpu;
+use crate::guest_mem::GuestMem;
+use crate::inspect::{open_vmlinux, read_cstr, symbol};
+use crate::kernel::find_kernel;
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions, HW_BREAKPOINTS, VCPU};
+use crate::result::Result;
+
+/// Kernel functions entered when the guest crashes. `panic` and `die` take the message as first
+/// argument, `oops_enter` takes none.
+const PANIC_SYMBOLS: &[&str] = &["panic", "die", "oops_enter"];
+
+/// Longest panic message we read from the guest
+const MAX_MESSAGE_LEN: usize = 512;
+
+pub struct WatchPanicOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub vmlinux: Option<PathBuf>,
+}
+
+/// A vcpu that entered one of the `PANIC_SYMBOLS`.
+pub struct PanicEvent {
+    pub symbol: &'static str,
+    pub vcpu: VCPU,
+    pub regs: cpu::Regs,
+    /// format string passed to panic()/die(). Arguments are left in the registers.
+    pub message: Option<String>,
+}
+
+/// Set hardware breakpoints on `panic`, `die` and `oops_enter`, continue the guest and return
+/// once a vcpu hits one of them. Breakpoints are removed before returning, so the guest will
+/// carry on panicking when resumed. Expects the hypervisor to be stopped.
+pub fn watch_panic(hv: &Hypervisor, vmlinux: Option<&Path>) -> Result<PanicEvent> {
+    let mem = GuestMem::new(hv)?;
+    let kernel = try_with!(find_kernel(&mem, hv), "could not find kernel");
+    let vmlinux = open_vmlinux(vmlinux, &kernel)?;
+
+    let mut symbols = vec![];
+    for name in PANIC_SYMBOLS.iter().take(HW_BREAKPOINTS) {
+        match symbol(&kernel, vmlinux.as_ref(), name) {
+            Ok(addr) => {
+                info!("set breakpoint on {} @ {:#x}", name, addr);
+                symbols.push((*name, addr as u64));
+            }
+            Err(e) => warn!("{}, pass --vmlinux to resolve it", e),
+        }
+    }
+    if symbols.is_empty() {
+        bail!("none of {:?} could be resolved", PANIC_SYMBOLS);
+    }
+    let addrs = symbols.iter().map(|(_, addr)| *addr).collect::<Vec<_>>();
+    hv.set_hw_breakpoints(&addrs)?;
+
+    let mut hit = None;
+    let res = hv.kvmrun_wrapped(|wrapper_mutex| {
+        let mut wrapper_guard = try_with!(wrapper_mutex.lock(), "cannot obtain wrapper mutex");
+        let wrapper = require_with!(wrapper_guard.as_mut(), "KvmRunWrapper not initialized");
+        while hit.is_none() {
+            let exit = match wrapper.wait_for_kvm_exit()? {
+                Some(exit) => exit,
+                None => continue,
+            };
+            if let Some(pc) = exit.debug_pc() {
+                // the hypervisor did not set these breakpoints and may not expect the exit
+                exit.hide()?;
+                hit = Some((exit.vcpu, pc));
+            }
+        }
+        Ok(())
+    });
+    try_with!(hv.set_hw_breakpoints(&[]), "cannot remove breakpoints");
+    res?;
+
+    let (vcpu, pc) = require_with!(hit, "no breakpoint was hit");
+    let name = require_with!(
+        symbols.iter().find(|(_, addr)| *addr == pc),
+        "vcpu {} stopped at {:#x}, which is not one of our breakpoints",
+        vcpu.idx,
+        pc
+    )
+    .0;
+    let regs = try_with!(
+        hv.get_regs(&vcpu),
+        "cannot get registers of vcpu {}",
+        vcpu.idx
+    );
+    // first argument according to the x86_64 calling convention
+    let message = match name {
+        "oops_enter" => None,
+        _ => match read_cstr(
+            |addr, buf| mem.read_virt(hv, addr, buf),
+            regs.rdi as usize,
+            MAX_MESSAGE_LEN,
+        ) {
+            Ok((s, _)) => Some(s),
+            Err(e) => {
+                warn!("cannot read message at {:#x}: {}", regs.rdi, e);
+                None
+            }
+        },
+    };
+
+    Ok(PanicEvent {
+        symbol: name,
+        vcpu,
+        regs,
+        message,
+    })
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_panic(opts: &WatchPanicOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    info!("waiting for the guest to panic");
+    let event = watch_panic(&vm, opts.vmlinux.as_deref())?;
+    println!("vcpu {} entered {}", event.vcpu.idx, event.symbol);
+    if let Some(message) = &event.message {
+        println!("message: {}", message.trim_end());
+    }
+    let r = &event.regs;
+    println!(
+        "args: {:#x} {:#x} {:#x} {:#x} {:#x}",
+        r.rsi, r.rdx, r.rcx, r.r8, r.r9
+    );
+    println!(
+        "rip: {:#018x} rsp: {:#018x} rflags: {:#x}",
+        r.rip, r.rsp, r.eflags
+    );
+    println!(
+        "rax: {:#018x} rbx: {:#018x} rcx: {:#018x}",
+        r.rax, r.rbx, r.rcx
+    );
+    println!(
+        "rdx: {:#018x} rsi: {:#018x} rdi: {:#018x}",
+        r.rdx, r.rsi, r.rdi
+    );
+    println!(
+        "rbp: {:#018x} r8:  {:#018x} r9:  {:#018x}",
+        r.rbp, r.r8, r.r9
+    );
+    println!(
+        "r10: {:#018x} r11: {:#018x} r12: {:#018x}",
+        r.r10, r.r11, r.r12
+    );
+    println!(
+        "r13: {:#018x} r14: {:#018x} r15: {:#018x}",
+        r.r13, r.r14, r.r15
+    );
+    Ok(())
+}
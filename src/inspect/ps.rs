@@ -0,0 +1,227 @@
+use log::warn;
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::btf::Btf;
+use crate::guest_mem::GuestMem;
+use crate::inspect::{open_vmlinux, symbol};
+use crate::kernel::find_kernel;
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+
+/// Upper bound for the task list, protects against cycles in corrupted lists
+const MAX_TASKS: usize = 1 << 22;
+
+pub struct PsOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub vmlinux: Option<PathBuf>,
+    pub offsets: Option<TaskStructOffsets>,
+}
+
+/// Offsets of the `task_struct` members we need. The layout depends on kernel version and
+/// configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaskStructOffsets {
+    pub tasks: usize,
+    pub pid: usize,
+    pub comm: usize,
+    pub state: usize,
+}
+
+impl TaskStructOffsets {
+    /// Layout of an x86_64 Linux 5.10 defconfig build. Other builds most likely differ.
+    pub fn hardcoded() -> TaskStructOffsets {
+        warn!("using hardcoded task_struct offsets for linux 5.10, results may be garbage. Pass --vmlinux with BTF or --task-offsets to override");
+        TaskStructOffsets {
+            tasks: 0x3a8,
+            pid: 0x4a8,
+            comm: 0x6b8,
+            state: 0x10,
+        }
+    }
+
+    pub fn from_btf(btf: &Btf) -> Result<TaskStructOffsets> {
+        // renamed to __state in linux 5.14
+        let state = btf
+            .offset_of("task_struct", "__state")
+            .or_else(|_| btf.offset_of("task_struct", "state"))?;
+        Ok(TaskStructOffsets {
+            tasks: btf.offset_of("task_struct", "tasks")?,
+            pid: btf.offset_of("task_struct", "pid")?,
+            comm: btf.offset_of("task_struct", "comm")?,
+            state,
+        })
+    }
+}
+
+/// Parses `tasks=<offset>,pid=<offset>,comm=<offset>,state=<offset>`
+impl FromStr for TaskStructOffsets {
+    type Err = simple_error::SimpleError;
+
+    fn from_str(s: &str) -> Result<TaskStructOffsets> {
+        let (mut tasks, mut pid, mut comm, mut state) = (None, None, None, None);
+        for field in s.split(',') {
+            let (name, value) = require_with!(
+                field.split_once('='),
+                "expected <member>=<offset>, got '{}'",
+                field
+            );
+            let value = value.trim();
+            let offset = try_with!(
+                match value.strip_prefix("0x") {
+                    Some(hex) => usize::from_str_radix(hex, 16),
+                    None => value.parse::<usize>(),
+                },
+                "invalid offset '{}' for {}",
+                value,
+                name
+            );
+            match name.trim() {
+                "tasks" => tasks = Some(offset),
+                "pid" => pid = Some(offset),
+                "comm" => comm = Some(offset),
+                "state" => state = Some(offset),
+                other => bail!("unknown task_struct member {}", other),
+            }
+        }
+        Ok(TaskStructOffsets {
+            tasks: require_with!(tasks, "offset of tasks is missing"),
+            pid: require_with!(pid, "offset of pid is missing"),
+            comm: require_with!(comm, "offset of comm is missing"),
+            state: require_with!(state, "offset of state is missing"),
+        })
+    }
+}
+
+/// A process (or kernel thread) of the guest.
+#[derive(Clone, Debug)]
+pub struct Task {
+    /// guest virtual address of its task_struct
+    pub addr: usize,
+    pub pid: i32,
+    pub comm: String,
+    pub state: u32,
+}
+
+impl Task {
+    /// Single-letter state as shown by ps(1), see `task_state_array` in fs/proc/array.c
+    pub fn state_char(&self) -> char {
+        // TASK_IDLE = TASK_UNINTERRUPTIBLE | TASK_NOLOAD
+        if self.state & 0x402 == 0x402 {
+            return 'I';
+        }
+        match self.state & 0x7f {
+            0 => 'R',
+            s if s & 0x01 != 0 => 'S',
+            s if s & 0x02 != 0 => 'D',
+            s if s & 0x04 != 0 => 'T',
+            s if s & 0x08 != 0 => 't',
+            s if s & 0x10 != 0 => 'X',
+            s if s & 0x20 != 0 => 'Z',
+            _ => 'P',
+        }
+    }
+}
+
+fn read_task(
+    hv: &Hypervisor,
+    mem: &GuestMem,
+    offsets: &TaskStructOffsets,
+    addr: usize,
+) -> Result<Task> {
+    let pid = try_with!(
+        mem.read::<i32>(hv, addr + offsets.pid),
+        "cannot read pid of task {:#x}",
+        addr
+    );
+    let state = try_with!(
+        mem.read::<u32>(hv, addr + offsets.state),
+        "cannot read state of task {:#x}",
+        addr
+    );
+    let comm = try_with!(
+        mem.read::<[u8; 16]>(hv, addr + offsets.comm),
+        "cannot read comm of task {:#x}",
+        addr
+    );
+    let len = comm.iter().position(|c| *c == 0).unwrap_or(comm.len());
+
+    Ok(Task {
+        addr,
+        pid,
+        comm: String::from_utf8_lossy(&comm[..len]).into_owned(),
+        state,
+    })
+}
+
+/// Enumerate guest processes by following the `tasks` list starting at `init_task`.
+/// `vmlinux` is used to resolve symbols the guest kernel does not export and, unless `offsets`
+/// are given, the layout of `task_struct`.
+pub fn ps(
+    hv: &Hypervisor,
+    vmlinux: Option<&Path>,
+    offsets: Option<&TaskStructOffsets>,
+) -> Result<Vec<Task>> {
+    let mem = GuestMem::new(hv)?;
+    let kernel = try_with!(find_kernel(&mem, hv), "could not find kernel");
+    let vmlinux = open_vmlinux(vmlinux, &kernel)?;
+    let init_task = symbol(&kernel, vmlinux.as_ref(), "init_task")?;
+    let btf = vmlinux.as_ref().and_then(|v| v.btf.as_ref());
+    let offsets = match (offsets, btf) {
+        (Some(o), _) => o.clone(),
+        (None, Some(btf)) => try_with!(
+            TaskStructOffsets::from_btf(btf),
+            "cannot lookup task_struct layout in BTF"
+        ),
+        (None, None) => TaskStructOffsets::hardcoded(),
+    };
+    walk_tasks(hv, &mem, init_task, &offsets)
+}
+
+/// Follow the `tasks` list starting at the task_struct at `init_task`.
+pub(crate) fn walk_tasks(
+    hv: &Hypervisor,
+    mem: &GuestMem,
+    init_task: usize,
+    offsets: &TaskStructOffsets,
+) -> Result<Vec<Task>> {
+    let head = init_task + offsets.tasks;
+    let mut tasks = vec![read_task(hv, mem, offsets, init_task)?];
+    let mut next = try_with!(
+        mem.read::<usize>(hv, head),
+        "cannot read init_task.tasks.next"
+    );
+    while next != head {
+        if tasks.len() > MAX_TASKS {
+            bail!("task list does not end after {} entries", MAX_TASKS);
+        }
+        let task = read_task(hv, mem, offsets, next - offsets.tasks)?;
+        tasks.push(task);
+        next = try_with!(
+            mem.read::<usize>(hv, next),
+            "cannot read tasks.next at {:#x}",
+            next
+        );
+    }
+    Ok(tasks)
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_ps(opts: &PsOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    let tasks = ps(&vm, opts.vmlinux.as_deref(), opts.offsets.as_ref())?;
+    println!("{:>7} {} COMMAND", "PID", "S");
+    for t in tasks {
+        println!("{:>7} {} {}", t.pid, t.state_char(), t.comm);
+    }
+    Ok(())
+}
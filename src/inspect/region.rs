@@ -0,0 +1,242 @@
+use log::{info, warn};
+use nix::sys::mman::ProtFlags;
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+use crate::tracer::proc::Mapping;
+
+/// Largest amount of guest memory we copy at once
+const CHUNK_SIZE: usize = 1 << 20;
+
+pub struct ExtractOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub phys_addr: usize,
+    pub len: usize,
+    pub path: PathBuf,
+}
+
+pub struct InjectRegionOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub phys_addr: usize,
+    pub path: PathBuf,
+    /// write to read-only mappings as well
+    pub force: bool,
+}
+
+/// Split the guest physical range `phys_addr..phys_addr + len` into pieces that are each backed
+/// by one mapping. Returns host address and length of each piece.
+pub(crate) fn host_ranges(
+    maps: &[Mapping],
+    phys_addr: usize,
+    len: usize,
+) -> Result<Vec<(usize, usize)>> {
+    let mut ranges = vec![];
+    let end = match phys_addr.checked_add(len) {
+        Some(end) => end,
+        None => bail!("range of {} bytes at {:#x} overflows", len, phys_addr),
+    };
+    let mut cur = phys_addr;
+    while cur < end {
+        let map = match maps
+            .iter()
+            .find(|m| m.phys_addr <= cur && cur < m.phys_end())
+        {
+            Some(map) => map,
+            None => bail!("guest physical address {:#x} is not backed by ram", cur),
+        };
+        let piece = end.min(map.phys_end()) - cur;
+        ranges.push((map.start + (cur - map.phys_addr), piece));
+        cur += piece;
+    }
+    Ok(ranges)
+}
+
+/// Resume the VM after `res` was obtained with the VM stopped. The VM is resumed in any case, an
+/// error in `res` takes precedence over one while resuming, which is only logged then.
+fn resume_after(hv: &Hypervisor, res: Result<()>) -> Result<()> {
+    let resumed = hv.resume();
+    if res.is_err() {
+        if let Err(e) = resumed {
+            warn!("cannot resume vm: {}", e);
+        }
+        return res;
+    }
+    resumed
+}
+
+/// Copy `len` bytes of guest physical memory starting at `phys_addr` to the file at `path`.
+/// The range may span multiple memory slots. Stops the VM while reading.
+pub fn extract(hv: &Hypervisor, phys_addr: usize, len: usize, path: &Path) -> Result<()> {
+    let mut file = try_with!(File::create(path), "cannot create {}", path.display());
+    hv.stop()?;
+    let res = extract_stopped(hv, phys_addr, len, &mut file);
+    resume_after(hv, res)?;
+    info!(
+        "wrote {} bytes from {:#x} to {}",
+        len,
+        phys_addr,
+        path.display()
+    );
+    Ok(())
+}
+
+fn extract_stopped(hv: &Hypervisor, phys_addr: usize, len: usize, file: &mut File) -> Result<()> {
+    let maps = try_with!(hv.get_maps(), "cannot get guest memory mappings");
+    let mut buf = vec![0; CHUNK_SIZE.min(len)];
+    for (host_addr, piece) in host_ranges(&maps, phys_addr, len)? {
+        let mut done = 0;
+        while done < piece {
+            let n = (piece - done).min(buf.len());
+            try_with!(
+                hv.read_slice(host_addr + done, &mut buf[..n]),
+                "cannot read guest memory"
+            );
+            try_with!(file.write_all(&buf[..n]), "cannot write output file");
+            done += n;
+        }
+    }
+    Ok(())
+}
+
+pub fn extract_region(opts: &ExtractOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    extract(&vm, opts.phys_addr, opts.len, &opts.path)
+}
+
+/// Host address to write `len` bytes at `phys_addr` to. The range has to be within a single
+/// mapping, which also has to be writable unless `force` is set.
+fn writable_range(maps: &[Mapping], phys_addr: usize, len: usize, force: bool) -> Result<usize> {
+    let map = match maps
+        .iter()
+        .find(|m| m.phys_addr <= phys_addr && phys_addr < m.phys_end())
+    {
+        Some(map) => map,
+        None => bail!(
+            "guest physical address {:#x} is not backed by ram",
+            phys_addr
+        ),
+    };
+    // phys_addr is within the mapping, so this cannot underflow and len cannot overflow
+    if len > map.phys_end() - phys_addr {
+        bail!(
+            "refusing to write {} bytes at {:#x}, this would cross the end of the mapping at {:#x}",
+            len,
+            phys_addr,
+            map.phys_end()
+        );
+    }
+    if !map.prot_flags.contains(ProtFlags::PROT_WRITE) {
+        if !force {
+            bail!(
+                "mapping {:#x}-{:#x} is read-only, use --force to write anyway",
+                map.phys_addr,
+                map.phys_end()
+            );
+        }
+        warn!(
+            "writing to read-only mapping {:#x}-{:#x}",
+            map.phys_addr,
+            map.phys_end()
+        );
+    }
+    Ok(map.start + (phys_addr - map.phys_addr))
+}
+
+/// Copy the contents of the file at `path` to guest physical memory at `phys_addr`. The range
+/// must not cross a mapping boundary. Read-only mappings are only written with `force`, the host
+/// kernel may still refuse if the hypervisor mapped them read-only. Stops the VM while writing.
+pub fn inject_region(hv: &Hypervisor, phys_addr: usize, path: &Path, force: bool) -> Result<()> {
+    let data = try_with!(fs::read(path), "cannot read {}", path.display());
+    hv.stop()?;
+    let res = inject_stopped(hv, phys_addr, &data, force);
+    resume_after(hv, res)?;
+    info!(
+        "wrote {} bytes from {} to {:#x}",
+        data.len(),
+        path.display(),
+        phys_addr
+    );
+    Ok(())
+}
+
+fn inject_stopped(hv: &Hypervisor, phys_addr: usize, data: &[u8], force: bool) -> Result<()> {
+    let maps = try_with!(hv.get_maps(), "cannot get guest memory mappings");
+    let host_addr = writable_range(&maps, phys_addr, data.len(), force)?;
+    for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        try_with!(
+            hv.write_slice(host_addr + i * CHUNK_SIZE, chunk),
+            "cannot write guest memory"
+        );
+    }
+    Ok(())
+}
+
+pub fn inject_region_from_file(opts: &InjectRegionOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    inject_region(&vm, opts.phys_addr, &opts.path, opts.force)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{host_ranges, writable_range};
+    use crate::tracer::testutils::mapping;
+    use nix::sys::mman::ProtFlags;
+
+    #[test]
+    fn test_host_ranges() {
+        let maps = vec![
+            mapping(0x7f00_0000_0000, 0, 0x10_0000),
+            mapping(0x7e00_0000_0000, 0x10_0000, 0x10_0000),
+        ];
+        assert_eq!(
+            host_ranges(&maps, 0xf_f000, 0x2000).expect("range is backed"),
+            vec![(0x7f00_000f_f000, 0x1000), (0x7e00_0000_0000, 0x1000)]
+        );
+        assert_eq!(
+            host_ranges(&maps, 0x1000, 0x10).expect("range is backed"),
+            vec![(0x7f00_0000_1000, 0x10)]
+        );
+        assert!(host_ranges(&maps, 0x1f_f000, 0x2000).is_err());
+        assert!(host_ranges(&maps, 0, 0).expect("empty range").is_empty());
+        assert!(host_ranges(&maps, 0x1000, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_writable_range() {
+        let mut rom = mapping(0x7d00_0000_0000, 0xfffc_0000, 0x4_0000);
+        rom.prot_flags = ProtFlags::PROT_READ;
+        let maps = vec![
+            mapping(0x7f00_0000_0000, 0, 0x10_0000),
+            mapping(0x7e00_0000_0000, 0x10_0000, 0x10_0000),
+            rom,
+        ];
+        assert_eq!(
+            writable_range(&maps, 0x1000, 0x1000, false).expect("range is writable"),
+            0x7f00_0000_1000
+        );
+        // crosses from the first into the second mapping
+        assert!(writable_range(&maps, 0xf_f000, 0x2000, false).is_err());
+        assert!(writable_range(&maps, 0x20_0000, 1, false).is_err());
+        assert!(writable_range(&maps, 0x1000, usize::MAX, false).is_err());
+        assert!(writable_range(&maps, 0xfffc_0000, 0x10, false).is_err());
+        assert_eq!(
+            writable_range(&maps, 0xfffc_0000, 0x10, true).expect("forced"),
+            0x7d00_0000_0000
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use kvm_bindings as kvmb;
+use log::info;
+use simple_error::try_with;
+use std::fmt::Write;
+
+use crate::cpu::{decode_cr0, decode_cr4, decode_efer, decode_rflags, Regs};
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+
+fn format_segment(out: &mut String, name: &str, seg: &kvmb::kvm_segment) {
+    let _ = writeln!(
+        out,
+        "{:<3} selector={:#06x} base={:#018x} limit={:#010x} type={:#x} dpl={} present={} l={} db={}",
+        name, seg.selector, seg.base, seg.limit, seg.type_, seg.dpl, seg.present, seg.l, seg.db
+    );
+}
+
+/// Human readable register state of the vcpu with index `idx`
+pub fn format_regs(idx: usize, regs: &Regs, sregs: &kvmb::kvm_sregs) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "vcpu {}:", idx);
+    let gprs = [
+        ("rax", regs.rax),
+        ("rbx", regs.rbx),
+        ("rcx", regs.rcx),
+        ("rdx", regs.rdx),
+        ("rsi", regs.rsi),
+        ("rdi", regs.rdi),
+        ("rbp", regs.rbp),
+        ("rsp", regs.rsp),
+        ("r8", regs.r8),
+        ("r9", regs.r9),
+        ("r10", regs.r10),
+        ("r11", regs.r11),
+        ("r12", regs.r12),
+        ("r13", regs.r13),
+        ("r14", regs.r14),
+        ("r15", regs.r15),
+    ];
+    for row in gprs.chunks(4) {
+        let line = row
+            .iter()
+            .map(|(name, val)| format!("{:<3}={:#018x}", name, val))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let _ = writeln!(out, "{}", line);
+    }
+    let _ = writeln!(
+        out,
+        "rip={:#018x} rflags={}",
+        regs.rip,
+        decode_rflags(regs.eflags)
+    );
+    for (name, seg) in [
+        ("cs", &sregs.cs),
+        ("ds", &sregs.ds),
+        ("es", &sregs.es),
+        ("fs", &sregs.fs),
+        ("gs", &sregs.gs),
+        ("ss", &sregs.ss),
+        ("tr", &sregs.tr),
+        ("ldt", &sregs.ldt),
+    ] {
+        format_segment(&mut out, name, seg);
+    }
+    let _ = writeln!(out, "cr0={}", decode_cr0(sregs.cr0));
+    let _ = writeln!(out, "cr2={:#x} cr3={:#x}", sregs.cr2, sregs.cr3);
+    let _ = writeln!(out, "cr4={}", decode_cr4(sregs.cr4));
+    let _ = write!(out, "efer={}", decode_efer(sregs.efer));
+    out
+}
+
+/// Log the mp state as well as general purpose, segment and control registers of every vcpu.
+/// Expects the hypervisor to be stopped.
+pub fn dump_regs(hv: &Hypervisor) -> Result<()> {
+    for vcpu in &hv.vcpus {
+        match hv.get_mp_state(vcpu) {
+            Ok(state) => info!("vcpu {} is {}", vcpu.idx, state),
+            Err(e) => info!("could not read mp state of vcpu {}: {}", vcpu.idx, e),
+        }
+        let regs = try_with!(
+            hv.get_regs(vcpu),
+            "cannot get registers of vcpu {}",
+            vcpu.idx
+        );
+        let sregs = try_with!(
+            hv.get_sregs(vcpu),
+            "cannot get special registers of vcpu {}",
+            vcpu.idx
+        );
+        info!("{}", format_regs(vcpu.idx, &regs, &sregs));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_regs;
+    use crate::cpu::Regs;
+    use kvm_bindings as kvmb;
+
+    #[test]
+    fn test_format_regs() {
+        let regs = Regs {
+            rip: 0xffff_ffff_8100_1042,
+            rax: 0x2a,
+            ..Default::default()
+        };
+        let mut sregs = kvmb::kvm_sregs::default();
+        sregs.cs.selector = 0x10;
+        sregs.cs.l = 1;
+        sregs.cr3 = 0x1000;
+        let out = format_regs(1, &regs, &sregs);
+        assert!(out.starts_with("vcpu 1:\n"));
+        assert!(out.contains("rax=0x000000000000002a"));
+        assert!(out.contains("rip=0xffffffff81001042"));
+        assert!(out.contains("cs  selector=0x0010"));
+        assert!(out.contains("cr3=0x1000"));
+        assert!(out.contains("rflags=0x0 []"));
+    }
+}
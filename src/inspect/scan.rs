@@ -0,0 +1,257 @@
+use log::{debug, info};
+use nix::sys::mman::ProtFlags;
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::ops::Range;
+
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+use crate::tracer::proc::Mapping;
+
+/// Largest amount of guest memory we search at once
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Restricts which guest memory `scan` searches. The default searches all guest RAM.
+#[derive(Clone, Debug, Default)]
+pub struct ScanFilter {
+    /// skip mappings the hypervisor maps read-only, i.e. firmware ROMs
+    pub writable_only: bool,
+    /// only search guest physical addresses in this range
+    pub phys_range: Option<Range<usize>>,
+    /// Hypervisors map some memory at multiple guest physical addresses, i.e. QEMU maps the end
+    /// of the BIOS below 1MiB as well. Skip such aliases if a larger mapping contains the same
+    /// memory, so that it is only reported once.
+    pub dedup_aliases: bool,
+}
+
+pub struct ScanOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub pattern: Vec<u8>,
+    pub filter: ScanFilter,
+    /// Search while the guest keeps running, see `scan_maps`. The guest is still stopped for
+    /// the injected ioctl that looks up its memory layout, see `Hypervisor::get_maps_running`.
+    pub live: bool,
+}
+
+/// Guest physical memory to search, backed by `len` bytes at `host_addr` in the hypervisor
+#[derive(Debug, PartialEq)]
+struct Region {
+    phys_addr: usize,
+    host_addr: usize,
+    len: usize,
+}
+
+fn scan_regions(maps: &[Mapping], filter: &ScanFilter) -> Vec<Region> {
+    let mut regions = vec![];
+    for map in maps {
+        if filter.writable_only && !map.prot_flags.contains(ProtFlags::PROT_WRITE) {
+            continue;
+        }
+        let (start, end) = match &filter.phys_range {
+            Some(range) => (
+                map.phys_addr.max(range.start),
+                map.phys_end().min(range.end),
+            ),
+            None => (map.phys_addr, map.phys_end()),
+        };
+        if start >= end {
+            continue;
+        }
+        regions.push(Region {
+            phys_addr: start,
+            host_addr: map.start + (start - map.phys_addr),
+            len: end - start,
+        });
+    }
+    if filter.dedup_aliases {
+        // larger regions first, so an alias is dropped in favour of the region containing it
+        regions.sort_by(|a, b| b.len.cmp(&a.len));
+        let mut unique: Vec<Region> = vec![];
+        for region in regions {
+            let host = region.host_addr..region.host_addr + region.len;
+            if unique
+                .iter()
+                .any(|u| u.host_addr <= host.start && host.end <= u.host_addr + u.len)
+            {
+                debug!(
+                    "skip {:#x}-{:#x}, it aliases memory we already search",
+                    region.phys_addr,
+                    region.phys_addr + region.len
+                );
+                continue;
+            }
+            unique.push(region);
+        }
+        regions = unique;
+        regions.sort_by_key(|r| r.phys_addr);
+    }
+    regions
+}
+
+/// Offsets of all, possibly overlapping, occurrences of `needle` in `haystack`
+fn find_all(haystack: &[u8], needle: &[u8]) -> Vec<usize> {
+    haystack
+        .windows(needle.len())
+        .enumerate()
+        .filter(|(_, window)| *window == needle)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn scan_region(hv: &Hypervisor, region: &Region, pattern: &[u8]) -> Result<Vec<usize>> {
+    let mut matches = vec![];
+    // read a bit more than a chunk to find matches crossing the end of a chunk
+    let overlap = pattern.len() - 1;
+    let mut buf = vec![0; CHUNK_SIZE.min(region.len) + overlap];
+    let mut offset = 0;
+    while offset < region.len {
+        let n = (region.len - offset).min(CHUNK_SIZE);
+        let read = (n + overlap).min(region.len - offset);
+        try_with!(
+            hv.read_slice(region.host_addr + offset, &mut buf[..read]),
+            "cannot read guest memory at {:#x}",
+            region.phys_addr + offset
+        );
+        matches.extend(
+            find_all(&buf[..read], pattern)
+                .into_iter()
+                .filter(|pos| *pos < n)
+                .map(|pos| region.phys_addr + offset + pos),
+        );
+        offset += n;
+    }
+    Ok(matches)
+}
+
+/// Guest physical addresses where `pattern` occurs in guest RAM, restricted by `filter`.
+/// Expects the hypervisor to be stopped.
+pub fn scan(hv: &Hypervisor, pattern: &[u8], filter: &ScanFilter) -> Result<Vec<usize>> {
+    let maps = try_with!(hv.get_maps(), "cannot get guest memory mappings");
+    scan_maps(hv, &maps, pattern, filter)
+}
+
+/// Like `scan`, but searches the guest memory `maps` of `hv`.
+///
+/// Memory is only read with process_vm_readv(2), so this also works on a running guest. That is
+/// best-effort: pages change while they are read, so matches can be missed or stale, and a
+/// pattern the guest writes concurrently may be found torn. `maps` is not refreshed, so memory
+/// hotplug or remapping during the scan is not noticed.
+pub fn scan_maps(
+    hv: &Hypervisor,
+    maps: &[Mapping],
+    pattern: &[u8],
+    filter: &ScanFilter,
+) -> Result<Vec<usize>> {
+    if pattern.is_empty() {
+        bail!("cannot scan for an empty pattern");
+    }
+    hv.check_memory_readable()?;
+    let regions = scan_regions(maps, filter);
+    info!(
+        "scanning {} MiB of guest memory",
+        regions.iter().map(|r| r.len).sum::<usize>() >> 20
+    );
+    let mut matches = vec![];
+    for region in &regions {
+        matches.extend(scan_region(hv, region, pattern)?);
+    }
+    matches.sort_unstable();
+    Ok(matches)
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_scan(opts: &ScanOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let res = if opts.live {
+        let maps = try_with!(vm.get_maps_running(), "cannot get guest memory mappings");
+        scan_maps(&vm, &maps, &opts.pattern, &opts.filter)
+    } else {
+        vm.stop()?;
+        let res = scan(&vm, &opts.pattern, &opts.filter);
+        vm.resume()?;
+        res
+    };
+    for addr in res? {
+        println!("{:#x}", addr);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer::testutils::mapping;
+
+    #[test]
+    fn test_scan_regions() {
+        let mut bios = mapping(0x7d00_0000_0000, 0xfffc_0000, 0x4_0000);
+        bios.prot_flags = ProtFlags::PROT_READ;
+        // the last 128KiB of the bios, mapped below 1MiB as well
+        let mut bios_alias = mapping(0x7d00_0002_0000, 0xe_0000, 0x2_0000);
+        bios_alias.prot_flags = ProtFlags::PROT_READ;
+        let maps = vec![
+            mapping(0x7f00_0000_0000, 0, 0xa_0000),
+            bios_alias,
+            mapping(0x7f00_0010_0000, 0x10_0000, 0x100_0000),
+            bios,
+        ];
+
+        assert_eq!(scan_regions(&maps, &ScanFilter::default()).len(), 4);
+
+        let writable = ScanFilter {
+            writable_only: true,
+            ..Default::default()
+        };
+        let regions = scan_regions(&maps, &writable);
+        assert_eq!(
+            regions.iter().map(|r| r.phys_addr).collect::<Vec<_>>(),
+            vec![0, 0x10_0000]
+        );
+
+        let range = ScanFilter {
+            phys_range: Some(0x9_f000..0x20_0000),
+            ..Default::default()
+        };
+        assert_eq!(
+            scan_regions(&maps, &range),
+            vec![
+                Region {
+                    phys_addr: 0x9_f000,
+                    host_addr: 0x7f00_0009_f000,
+                    len: 0x1000
+                },
+                Region {
+                    phys_addr: 0xe_0000,
+                    host_addr: 0x7d00_0002_0000,
+                    len: 0x2_0000
+                },
+                Region {
+                    phys_addr: 0x10_0000,
+                    host_addr: 0x7f00_0010_0000,
+                    len: 0x10_0000
+                },
+            ]
+        );
+
+        let dedup = ScanFilter {
+            dedup_aliases: true,
+            ..Default::default()
+        };
+        let regions = scan_regions(&maps, &dedup);
+        assert_eq!(
+            regions.iter().map(|r| r.phys_addr).collect::<Vec<_>>(),
+            vec![0, 0x10_0000, 0xfffc_0000]
+        );
+    }
+
+    #[test]
+    fn test_find_all() {
+        assert_eq!(find_all(b"abababa", b"aba"), vec![0, 2, 4]);
+        assert_eq!(find_all(b"ab", b"abc"), Vec::<usize>::new());
+    }
+}
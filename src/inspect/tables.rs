@@ -0,0 +1,340 @@
+//! Decoder for the global and interrupt descriptor tables, see Intel SDM Vol. 3, 3.4.5 "Segment
+//! Descriptors" and 6.14.1 "64-Bit Mode IDT".
+
+use kvm_bindings as kvmb;
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::fmt;
+
+use crate::guest_mem::GuestMem;
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions, VCPU};
+use crate::result::Result;
+
+const CR0_PG: u64 = 1 << 31;
+const EFER_LMA: u64 = 1 << 10;
+
+pub struct DescriptorTablesOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub vcpu: usize,
+}
+
+/// An entry of the GDT
+#[derive(Clone, Debug, PartialEq)]
+pub struct SegmentDescriptor {
+    /// index in the table, the selector is `index << 3`
+    pub index: usize,
+    pub base: u64,
+    /// limit in bytes, already scaled by the granularity bit
+    pub limit: u32,
+    /// type field, its meaning depends on `system`
+    pub typ: u8,
+    /// system segment (TSS, LDT, gates) rather than code or data
+    pub system: bool,
+    pub dpl: u8,
+    pub present: bool,
+    /// 64-bit code segment
+    pub long: bool,
+    /// 32-bit (rather than 16-bit) default operand size
+    pub db: bool,
+}
+
+/// An entry of the IDT
+#[derive(Clone, Debug, PartialEq)]
+pub struct GateDescriptor {
+    pub vector: usize,
+    pub selector: u16,
+    /// entry point of the handler
+    pub offset: u64,
+    pub typ: u8,
+    pub dpl: u8,
+    pub present: bool,
+    /// interrupt stack table index, 64-bit mode only
+    pub ist: u8,
+}
+
+pub struct DescriptorTables {
+    /// IA-32e mode, which uses 16 byte system descriptors and gates
+    pub long_mode: bool,
+    pub gdt_base: u64,
+    pub gdt: Vec<SegmentDescriptor>,
+    pub idt_base: u64,
+    pub idt: Vec<GateDescriptor>,
+}
+
+fn system_type_name(typ: u8, long_mode: bool) -> &'static str {
+    match (typ, long_mode) {
+        (0x2, _) => "LDT",
+        (0x9, _) => "TSS (available)",
+        (0xb, _) => "TSS (busy)",
+        (0xc, _) => "call gate",
+        (0xe, _) => "interrupt gate",
+        (0xf, _) => "trap gate",
+        (0x1, false) => "16-bit TSS (available)",
+        (0x3, false) => "16-bit TSS (busy)",
+        (0x4, false) => "16-bit call gate",
+        (0x5, false) => "task gate",
+        (0x6, false) => "16-bit interrupt gate",
+        (0x7, false) => "16-bit trap gate",
+        _ => "reserved",
+    }
+}
+
+fn u64_at(bytes: &[u8], idx: usize) -> u64 {
+    let mut val = [0u8; 8];
+    val.copy_from_slice(&bytes[idx * 8..idx * 8 + 8]);
+    u64::from_le_bytes(val)
+}
+
+impl SegmentDescriptor {
+    fn decode(index: usize, desc: u64) -> SegmentDescriptor {
+        let mut limit = ((desc & 0xffff) | ((desc >> 32) & 0xf_0000)) as u32;
+        // granularity: limit is in 4KiB units
+        if desc & (1 << 55) != 0 {
+            limit = (limit << 12) | 0xfff;
+        }
+        SegmentDescriptor {
+            index,
+            base: ((desc >> 16) & 0xff_ffff) | ((desc >> 32) & 0xff00_0000),
+            limit,
+            typ: ((desc >> 40) & 0xf) as u8,
+            system: desc & (1 << 44) == 0,
+            dpl: ((desc >> 45) & 0b11) as u8,
+            present: desc & (1 << 47) != 0,
+            long: desc & (1 << 53) != 0,
+            db: desc & (1 << 54) != 0,
+        }
+    }
+
+    fn type_name(&self, long_mode: bool) -> String {
+        if self.system {
+            return system_type_name(self.typ, long_mode).to_string();
+        }
+        let mut name = String::new();
+        if self.typ & 0x8 != 0 {
+            name.push_str("code");
+            if self.typ & 0x4 != 0 {
+                name.push_str(" conforming");
+            }
+            name.push_str(if self.typ & 0x2 != 0 { " r-x" } else { " --x" });
+            name.push_str(match (self.long, self.db) {
+                (true, _) => " 64-bit",
+                (false, true) => " 32-bit",
+                (false, false) => " 16-bit",
+            });
+        } else {
+            name.push_str("data");
+            if self.typ & 0x4 != 0 {
+                name.push_str(" expand-down");
+            }
+            name.push_str(if self.typ & 0x2 != 0 { " rw-" } else { " r--" });
+        }
+        name
+    }
+}
+
+/// Decode a GDT. In IA-32e mode, LDT and TSS descriptors take up two slots.
+pub fn decode_gdt(bytes: &[u8], long_mode: bool) -> Vec<SegmentDescriptor> {
+    let count = bytes.len() / 8;
+    let mut descs = vec![];
+    let mut i = 0;
+    while i < count {
+        let mut desc = SegmentDescriptor::decode(i, u64_at(bytes, i));
+        let wide = long_mode && desc.system && matches!(desc.typ, 0x2 | 0x9 | 0xb | 0xc);
+        if wide && i + 1 < count {
+            desc.base |= (u64_at(bytes, i + 1) & 0xffff_ffff) << 32;
+            i += 1;
+        }
+        descs.push(desc);
+        i += 1;
+    }
+    descs
+}
+
+/// Decode an IDT, gates are 16 bytes in IA-32e mode and 8 bytes otherwise.
+pub fn decode_idt(bytes: &[u8], long_mode: bool) -> Vec<GateDescriptor> {
+    let size = if long_mode { 16 } else { 8 };
+    (0..bytes.len() / size)
+        .map(|vector| {
+            let lo = u64_at(bytes, vector * size / 8);
+            let hi = if long_mode {
+                u64_at(bytes, vector * 2 + 1) & 0xffff_ffff
+            } else {
+                0
+            };
+            GateDescriptor {
+                vector,
+                selector: ((lo >> 16) & 0xffff) as u16,
+                offset: (lo & 0xffff) | ((lo >> 32) & 0xffff_0000) | (hi << 32),
+                typ: ((lo >> 40) & 0xf) as u8,
+                dpl: ((lo >> 45) & 0b11) as u8,
+                present: lo & (1 << 47) != 0,
+                ist: if long_mode {
+                    ((lo >> 32) & 0b111) as u8
+                } else {
+                    0
+                },
+            }
+        })
+        .collect()
+}
+
+/// Read `limit + 1` bytes of a descriptor table at linear address `base`.
+fn read_table(hv: &Hypervisor, mem: Option<&GuestMem>, base: u64, limit: u16) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; limit as usize + 1];
+    match mem {
+        Some(mem) => mem.read_virt(hv, base as usize, &mut bytes)?,
+        // without paging, linear addresses are physical addresses
+        None => {
+            let maps = hv.get_maps()?;
+            let map = require_with!(
+                maps.iter()
+                    .find(|m| m.phys_addr <= base as usize
+                        && base as usize + bytes.len() <= m.phys_end()),
+                "descriptor table at {:#x} is not backed by guest ram",
+                base
+            );
+            hv.read_slice(map.start + (base as usize - map.phys_addr), &mut bytes)?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Read and decode the GDT and IDT `vcpu` currently uses. Expects the hypervisor to be stopped.
+pub fn descriptor_tables(hv: &Hypervisor, vcpu: &VCPU) -> Result<DescriptorTables> {
+    let sregs: kvmb::kvm_sregs = try_with!(
+        hv.get_sregs(vcpu),
+        "cannot get special registers of vcpu {}",
+        vcpu.idx
+    );
+    let long_mode = sregs.efer & EFER_LMA != 0;
+    let mem = if sregs.cr0 & CR0_PG != 0 {
+        Some(GuestMem::new(hv)?)
+    } else {
+        None
+    };
+    let gdt = try_with!(
+        read_table(hv, mem.as_ref(), sregs.gdt.base, sregs.gdt.limit),
+        "cannot read gdt at {:#x}",
+        sregs.gdt.base
+    );
+    let idt = try_with!(
+        read_table(hv, mem.as_ref(), sregs.idt.base, sregs.idt.limit),
+        "cannot read idt at {:#x}",
+        sregs.idt.base
+    );
+    Ok(DescriptorTables {
+        long_mode,
+        gdt_base: sregs.gdt.base,
+        gdt: decode_gdt(&gdt, long_mode),
+        idt_base: sregs.idt.base,
+        idt: decode_idt(&idt, long_mode),
+    })
+}
+
+/// Lists present entries only.
+impl fmt::Display for DescriptorTables {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "GDT @ {:#x} ({} entries)", self.gdt_base, self.gdt.len())?;
+        for d in self.gdt.iter().filter(|d| d.present) {
+            writeln!(
+                f,
+                "  {:#06x} base={:#018x} limit={:#010x} dpl={} {}",
+                d.index << 3,
+                d.base,
+                d.limit,
+                d.dpl,
+                d.type_name(self.long_mode)
+            )?;
+        }
+        writeln!(f, "IDT @ {:#x} ({} entries)", self.idt_base, self.idt.len())?;
+        for g in self.idt.iter().filter(|g| g.present) {
+            write!(
+                f,
+                "  {:#04x} {:#06x}:{:#018x} dpl={} {}",
+                g.vector,
+                g.selector,
+                g.offset,
+                g.dpl,
+                system_type_name(g.typ, self.long_mode)
+            )?;
+            if g.ist != 0 {
+                write!(f, " ist={}", g.ist)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_descriptor_tables(opts: &DescriptorTablesOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    let vcpu = match vm.vcpus.get(opts.vcpu) {
+        Some(vcpu) => vcpu,
+        None => bail!("vm has only {} vcpus", vm.vcpus.len()),
+    };
+    print!("{}", descriptor_tables(&vm, vcpu)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_gdt() {
+        // null, __KERNEL_CS, __KERNEL_DS and a 64-bit TSS as set up by linux
+        let mut bytes = vec![];
+        for desc in &[
+            0u64,
+            0x00af_9b00_0000_ffff,
+            0x00cf_9300_0000_ffff,
+            0xfe00_8b00_3000_206f,
+            0x0000_0000_ffff_ffff,
+        ] {
+            bytes.extend_from_slice(&desc.to_le_bytes());
+        }
+        let gdt = decode_gdt(&bytes, true);
+        assert_eq!(gdt.len(), 4);
+        assert!(!gdt[0].present);
+        assert!(gdt[1].long && !gdt[1].system);
+        assert_eq!(gdt[1].type_name(true), "code r-x 64-bit");
+        assert_eq!(gdt[2].limit, 0xffff_ffff);
+        assert_eq!(gdt[2].type_name(true), "data rw-");
+        assert_eq!(gdt[3].index, 3);
+        assert_eq!(gdt[3].base, 0xffff_ffff_fe00_3000);
+        assert_eq!(gdt[3].limit, 0x206f);
+        assert_eq!(gdt[3].type_name(true), "TSS (busy)");
+    }
+
+    #[test]
+    fn test_decode_idt() {
+        // interrupt gate to 0xffffffff81a00b50 using IST 2
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&0x81a0_8e02_0010_0b50u64.to_le_bytes());
+        bytes.extend_from_slice(&0xffff_ffffu64.to_le_bytes());
+        let idt = decode_idt(&bytes, true);
+        assert_eq!(
+            idt,
+            vec![GateDescriptor {
+                vector: 0,
+                selector: 0x10,
+                offset: 0xffff_ffff_81a0_0b50,
+                typ: 0xe,
+                dpl: 0,
+                present: true,
+                ist: 2,
+            }]
+        );
+        let idt = decode_idt(&bytes[..8], false);
+        assert_eq!(idt[0].offset, 0x81a0_0b50);
+        assert_eq!(idt[0].ist, 0);
+    }
+}
@@ -0,0 +1,223 @@
+use log::{info, warn};
+use nix::time::{clock_gettime, ClockId};
+use nix::unistd::Pid;
+use simple_error::{require_with, simple_error, try_with};
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+use crate::signal_handler;
+use crate::tracer::wrap_syscall::{Interrupter, MMIO_RW_DATA_MAX};
+
+pub struct TraceRegOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    /// guest physical address of the register
+    pub phys_addr: u64,
+    /// Writes overlapping `width` bytes starting at `phys_addr` are logged, so a width larger
+    /// than the register also covers its neighbours.
+    pub width: u64,
+}
+
+/// A write of the guest to the traced register
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegWrite {
+    /// host CLOCK_MONOTONIC in nanoseconds
+    pub monotonic_ns: u64,
+    pub addr: u64,
+    pub data: Vec<u8>,
+}
+
+impl RegWrite {
+    /// The data as little endian integer, if the access has a size of 1, 2, 4 or 8 bytes.
+    pub fn value(&self) -> Option<u64> {
+        match self.data.len() {
+            1 | 2 | 4 | 8 => {
+                let mut buf = [0u8; 8];
+                buf[..self.data.len()].copy_from_slice(&self.data);
+                Some(u64::from_le_bytes(buf))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RegWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:09} {:#x} <- ",
+            self.monotonic_ns / 1_000_000_000,
+            self.monotonic_ns % 1_000_000_000,
+            self.addr
+        )?;
+        match self.value() {
+            Some(val) => write!(
+                f,
+                "{:#0width$x} ({})",
+                val,
+                val,
+                width = 2 + 2 * self.data.len()
+            ),
+            None => self.data.iter().try_for_each(|b| write!(f, "{:02x}", b)),
+        }
+    }
+}
+
+/// Whether an access of `len` bytes at `addr` touches `reg`
+fn touches(reg: &Range<u64>, addr: u64, len: usize) -> bool {
+    addr < reg.end && reg.start < addr.saturating_add(len as u64)
+}
+
+/// Guest physical range of MMIO exits that may touch `reg`, including accesses starting
+/// up to `MMIO_RW_DATA_MAX - 1` bytes before it.
+fn exit_filter(reg: &Range<u64>) -> Range<u64> {
+    reg.start.saturating_sub(MMIO_RW_DATA_MAX as u64 - 1)..reg.end
+}
+
+/// How often the stop request is passed on to the tracer until it stopped waiting
+const INTERRUPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Call `on_write` for every guest write touching the guest physical range `reg` until `stop`
+/// receives a message. Only sees registers that exit to the hypervisor, i.e. not ioeventfds or
+/// coalesced MMIO. Reads and writes are left to the hypervisor, we only watch.
+pub fn trace_reg<F>(
+    hv: &Hypervisor,
+    reg: Range<u64>,
+    stop: Receiver<()>,
+    mut on_write: F,
+) -> Result<()>
+where
+    F: FnMut(&RegWrite),
+{
+    // An idle guest makes no syscalls we would return from, so the stop request interrupts
+    // the hypervisor threads from a separate thread.
+    let interrupter = Interrupter::new()?;
+    let done = Arc::new(AtomicBool::new(false));
+    let relay = {
+        let interrupter = interrupter.clone();
+        let done = Arc::clone(&done);
+        try_with!(
+            thread::Builder::new()
+                .name("trace-reg-stop".to_string())
+                .spawn(move || {
+                    let mut stopping = false;
+                    while !done.load(Ordering::Acquire) {
+                        stopping |= stop.recv_timeout(INTERRUPT_INTERVAL).is_ok();
+                        if stopping {
+                            if let Err(e) = interrupter.interrupt() {
+                                warn!("{}", e);
+                            }
+                        }
+                    }
+                }),
+            "cannot spawn thread"
+        )
+    };
+
+    let res = hv.kvmrun_wrapped(|wrapper_mo| {
+        let mut wrapper_go = try_with!(wrapper_mo.lock(), "cannot obtain wrapper mutex");
+        let wrapper = require_with!(wrapper_go.as_mut(), "KvmRunWrapper not initialized");
+        wrapper.set_mmio_filter(vec![exit_filter(&reg)]);
+        wrapper.set_interrupter(Some(interrupter.clone()));
+        let res = loop {
+            if interrupter.requested() {
+                break Ok(());
+            }
+            let mmio = match wrapper.wait_for_ioctl() {
+                Ok(Some(mmio)) => mmio,
+                Ok(None) => continue,
+                Err(e) => break Err(e),
+            };
+            if !mmio.is_write || !touches(&reg, mmio.addr, mmio.data().len()) {
+                continue;
+            }
+            let now = match clock_gettime(ClockId::CLOCK_MONOTONIC) {
+                Ok(now) => now,
+                Err(e) => break Err(simple_error!("cannot read host clock: {}", e)),
+            };
+            on_write(&RegWrite {
+                monotonic_ns: now.tv_sec() as u64 * 1_000_000_000 + now.tv_nsec() as u64,
+                addr: mmio.addr,
+                data: mmio.data().to_vec(),
+            });
+        };
+        wrapper.set_interrupter(None);
+        wrapper.set_mmio_filter(vec![]);
+        res
+    });
+
+    // the relay must not wake this thread anymore once we return
+    done.store(true, Ordering::Release);
+    if relay.join().is_err() {
+        warn!("stop relay thread panicked");
+    }
+    res
+}
+
+/// Print every write to the register until interrupted by SIGINT/SIGTERM.
+#[allow(clippy::print_stdout)]
+pub fn print_trace_reg(opts: &TraceRegOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let (sender, receiver) = channel();
+    signal_handler::setup(sender);
+
+    let reg = opts.phys_addr..opts.phys_addr.saturating_add(opts.width);
+    info!("tracing writes to {:#x}-{:#x}", reg.start, reg.end);
+    let mut count = 0;
+    trace_reg(&vm, reg, receiver, |write| {
+        count += 1;
+        println!("{}", write);
+    })?;
+    info!("saw {} writes", count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exit_filter, touches, RegWrite};
+
+    #[test]
+    fn test_touches() {
+        let reg = 0xd000_0050..0xd000_0054;
+        assert!(touches(&reg, 0xd000_0050, 4));
+        assert!(touches(&reg, 0xd000_0053, 1));
+        // 8 byte write to the register before
+        assert!(touches(&reg, 0xd000_004c, 8));
+        assert!(!touches(&reg, 0xd000_004c, 4));
+        assert!(!touches(&reg, 0xd000_0054, 4));
+        assert_eq!(exit_filter(&reg), 0xd000_0049..0xd000_0054);
+        assert_eq!(exit_filter(&(0..4)), 0..4);
+    }
+
+    #[test]
+    fn test_format_reg_write() {
+        let write = RegWrite {
+            monotonic_ns: 12_000_000_042,
+            addr: 0xd000_0050,
+            data: vec![0x2a, 0, 0, 0],
+        };
+        assert_eq!(write.value(), Some(42));
+        assert_eq!(
+            write.to_string(),
+            "12.000000042 0xd0000050 <- 0x0000002a (42)"
+        );
+        let odd = RegWrite {
+            monotonic_ns: 0,
+            addr: 0x1000,
+            data: vec![1, 2, 3],
+        };
+        assert_eq!(odd.value(), None);
+        assert_eq!(odd.to_string(), "0.000000000 0x1000 <- 010203");
+    }
+}
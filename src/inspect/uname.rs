@@ -0,0 +1,118 @@
+use log::{debug, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::path::{Path, PathBuf};
+
+use crate::guest_mem::GuestMem;
+use crate::inspect::scan::{scan, ScanFilter};
+use crate::inspect::{open_vmlinux, read_cstr, symbol};
+use crate::kernel::find_kernel;
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+
+/// linux_banner is formatted as "Linux version %s (%s@%s) (%s) %s\n"
+const BANNER_PREFIX: &[u8] = b"Linux version ";
+
+/// Compiler and build host make the banner longer than one might expect
+const MAX_BANNER_LEN: usize = 512;
+
+/// Without symbols, the banner is searched in the kernel image, which x86 kernels load at 16MiB
+/// by default. KASLR may move it, so search a bit more.
+const LOW_MEM: usize = 1 << 30;
+
+pub struct UnameOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub vmlinux: Option<PathBuf>,
+}
+
+/// Whether `s`, read at an occurrence of `BANNER_PREFIX`, is the banner itself rather than
+/// i.e. a copy in the printk buffer, which is neither NUL nor newline terminated.
+fn is_banner(s: &str, terminated: bool) -> bool {
+    terminated
+        && s.as_bytes().starts_with(BANNER_PREFIX)
+        && s.ends_with('\n')
+        && s[..s.len() - 1]
+            .chars()
+            .all(|c| c.is_ascii() && !c.is_ascii_control())
+}
+
+/// Look for the banner in the first `LOW_MEM` bytes of guest RAM.
+fn banner_from_low_mem(hv: &Hypervisor, mem: &GuestMem) -> Result<String> {
+    let filter = ScanFilter {
+        phys_range: Some(0..LOW_MEM),
+        dedup_aliases: true,
+        ..Default::default()
+    };
+    for addr in scan(hv, BANNER_PREFIX, &filter)? {
+        let (s, terminated) = read_cstr(
+            |addr, buf| mem.read_phys(hv, addr, buf),
+            addr,
+            MAX_BANNER_LEN,
+        )?;
+        if is_banner(&s, terminated) {
+            debug!("found linux_banner at physical address {:#x}", addr);
+            return Ok(s);
+        }
+    }
+    bail!(
+        "cannot find the linux banner in the first {} MiB of guest memory",
+        LOW_MEM >> 20
+    )
+}
+
+/// The version banner of the running guest kernel, like /proc/version. Uses `linux_banner` if
+/// the symbol can be resolved (with the help of `vmlinux`) and searches low memory for the
+/// banner otherwise. Expects the hypervisor to be stopped.
+pub fn kernel_version(hv: &Hypervisor, vmlinux: Option<&Path>) -> Result<String> {
+    let mem = GuestMem::new(hv)?;
+    let linux_banner = find_kernel(&mem, hv).and_then(|kernel| {
+        let vmlinux = open_vmlinux(vmlinux, &kernel)?;
+        symbol(&kernel, vmlinux.as_ref(), "linux_banner")
+    });
+
+    let banner = match linux_banner {
+        Ok(addr) => {
+            let (banner, _) = read_cstr(
+                |addr, buf| mem.read_virt(hv, addr, buf),
+                addr,
+                MAX_BANNER_LEN,
+            )?;
+            banner
+        }
+        Err(e) => {
+            warn!("{}, searching guest memory for the banner", e);
+            try_with!(banner_from_low_mem(hv, &mem), "cannot find kernel version")
+        }
+    };
+    Ok(banner.trim_end().to_string())
+}
+
+#[allow(clippy::print_stdout)]
+pub fn print_uname(opts: &UnameOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+
+    println!("{}", kernel_version(&vm, opts.vmlinux.as_deref())?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_banner;
+
+    #[test]
+    fn test_is_banner() {
+        let banner = "Linux version 5.15.0 (nixbld@localhost) (gcc (GCC) 10.3.0, GNU ld (GNU Binutils) 2.35.2) #1-NixOS SMP Tue Jan 1 00:00:00 UTC 1980\n";
+        assert!(is_banner(banner, true));
+        assert!(!is_banner(banner, false));
+        // printk records carry no newline
+        assert!(!is_banner(banner.trim_end(), true));
+        assert!(!is_banner("Linux version \x01\x02\n", true));
+        assert!(!is_banner("linux_banner\n", true));
+    }
+}
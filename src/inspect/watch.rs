@@ -0,0 +1,155 @@
+use log::info;
+use nix::unistd::Pid;
+use simple_error::try_with;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::inspect::region::host_ranges;
+use crate::interrutable_thread::InterrutableThread;
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions};
+use crate::result::Result;
+use crate::signal_handler;
+
+pub struct WatchMemOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub phys_addr: usize,
+    pub len: usize,
+    pub interval: Duration,
+}
+
+/// A new value of the watched memory
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemChange {
+    /// time since the watch started
+    pub elapsed: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Remembers the last value to drop consecutive identical ones.
+#[derive(Default)]
+struct Dedup {
+    last: Option<Vec<u8>>,
+}
+
+impl Dedup {
+    /// Whether `data` differs from the value passed before. The first value always counts as
+    /// change.
+    fn changed(&mut self, data: &[u8]) -> bool {
+        if self.last.as_deref() == Some(data) {
+            return false;
+        }
+        self.last = Some(data.to_vec());
+        true
+    }
+}
+
+/// Read `len` bytes of guest physical memory at `phys_addr` every `interval` and call
+/// `on_change` whenever they differ from the previous read. The guest keeps running, so values
+/// wider than the guest's own stores may be torn. Runs until the returned thread is shut down.
+pub fn watch_mem<F>(
+    hv: Arc<Hypervisor>,
+    phys_addr: usize,
+    len: usize,
+    interval: Duration,
+    mut on_change: F,
+    err_sender: Sender<()>,
+) -> Result<InterrutableThread<(), ()>>
+where
+    F: FnMut(&MemChange) + Send + 'static,
+{
+    let maps = try_with!(hv.get_maps_running(), "cannot get guest memory mappings");
+    let ranges = host_ranges(&maps, phys_addr, len)?;
+
+    let res = InterrutableThread::spawn(
+        "watch-mem",
+        err_sender,
+        move |_ctx: &(), should_stop: Arc<AtomicBool>| {
+            let start = Instant::now();
+            let mut dedup = Dedup::default();
+            let mut buf = vec![0; len];
+            while !should_stop.load(Ordering::Relaxed) {
+                let mut done = 0;
+                for (host_addr, piece) in &ranges {
+                    try_with!(
+                        hv.read_slice(*host_addr, &mut buf[done..done + piece]),
+                        "cannot read guest memory"
+                    );
+                    done += piece;
+                }
+                if dedup.changed(&buf) {
+                    on_change(&MemChange {
+                        elapsed: start.elapsed(),
+                        data: buf.clone(),
+                    });
+                }
+                std::thread::sleep(interval);
+            }
+            Ok(())
+        },
+        (),
+    );
+    Ok(try_with!(res, "failed to spawn watch-mem thread"))
+}
+
+/// Print the watched memory as hex whenever it changes until interrupted by SIGINT/SIGTERM.
+#[allow(clippy::print_stdout)]
+pub fn print_watch_mem(opts: &WatchMemOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let (sender, receiver) = channel();
+    signal_handler::setup(sender.clone());
+
+    info!(
+        "watching {} bytes at {:#x} every {:?}",
+        opts.len, opts.phys_addr, opts.interval
+    );
+    let phys_addr = opts.phys_addr;
+    let thread = watch_mem(
+        Arc::new(vm),
+        opts.phys_addr,
+        opts.len,
+        opts.interval,
+        move |change| {
+            let hex = change
+                .data
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            println!(
+                "[{:>4}.{:03}] {:#x}: {}",
+                change.elapsed.as_secs(),
+                change.elapsed.subsec_millis(),
+                phys_addr,
+                hex
+            );
+        },
+        sender,
+    )?;
+
+    // interrupted or the thread failed
+    let _ = receiver.recv();
+    thread.shutdown();
+    let (res, _) = thread.join()?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dedup;
+
+    #[test]
+    fn test_dedup() {
+        let mut dedup = Dedup::default();
+        assert!(dedup.changed(&[0, 0]));
+        assert!(!dedup.changed(&[0, 0]));
+        assert!(dedup.changed(&[1, 0]));
+        assert!(!dedup.changed(&[1, 0]));
+        assert!(dedup.changed(&[0, 0]));
+    }
+}
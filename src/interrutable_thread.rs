@@ -1,5 +1,7 @@
-use log::info;
-use simple_error::bail;
+use log::{info, warn};
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
 use std::fmt::Debug;
 use std::io;
 use std::ops::FnOnce;
@@ -42,6 +44,21 @@ where
     /// The thread function will receive an atomic boolean as its first argument
     /// and should stop it's work once it becomes true.
     pub fn spawn<F>(name: &str, err_sender: Sender<()>, func: F, ctx: C) -> io::Result<Self>
+    where
+        F: FnOnce(&C, Arc<AtomicBool>) -> Result<T>,
+        F: Send + 'static,
+    {
+        Self::spawn_pinned(name, None, err_sender, func, ctx)
+    }
+
+    /// Like `spawn()`, but restricts the thread to the CPUs in `cpus` if given.
+    pub fn spawn_pinned<F>(
+        name: &str,
+        cpus: Option<CpuSet>,
+        err_sender: Sender<()>,
+        func: F,
+        ctx: C,
+    ) -> io::Result<Self>
     where
         F: FnOnce(&C, Arc<AtomicBool>) -> Result<T>,
         F: Send + 'static,
@@ -53,6 +70,12 @@ where
         let should_stop2 = Arc::clone(&should_stop);
 
         let handle = builder.spawn(move || {
+            if let Some(cpus) = cpus {
+                // pid 0 is the calling thread
+                if let Err(e) = sched_setaffinity(Pid::from_raw(0), &cpus) {
+                    warn!("cannot pin thread to cpus: {}", e);
+                }
+            }
             let res = func(&ctx, should_stop2);
             if res.is_err() {
                 err_sender
@@ -95,3 +118,23 @@ where
         }
     }
 }
+
+/// Parses a cpu list as used by taskset(1), i.e. `0,2-3`
+pub fn parse_cpu_list(list: &str) -> Result<CpuSet> {
+    let mut cpus = CpuSet::new();
+    for range in list.split(',') {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (range, range),
+        };
+        let start = try_with!(start.trim().parse::<usize>(), "invalid cpu {}", start);
+        let end = try_with!(end.trim().parse::<usize>(), "invalid cpu {}", end);
+        if start > end {
+            bail!("invalid cpu range {}", range);
+        }
+        for cpu in start..=end {
+            try_with!(cpus.set(cpu), "cpu {} is out of range", cpu);
+        }
+    }
+    Ok(cpus)
+}
@@ -5,7 +5,6 @@ use std::collections::HashMap;
 use std::ffi::CStr;
 use std::mem::{self, size_of};
 use std::ops::Range;
-use vm_memory::remote_mem::process_read_bytes;
 
 use crate::guest_mem::{GuestMem, MappedMemory};
 use crate::kvm::hypervisor::Hypervisor;
@@ -318,8 +317,7 @@ pub fn find_kernel(guest_mem: &GuestMem, hv: &Hypervisor) -> Result<Kernel> {
             return None;
         }
         let mut mem = vec![0; s.len];
-        let mem_base = s.phys_start.host_addr() as *const libc::c_void;
-        if let Err(e) = process_read_bytes(hv.pid, &mut mem, mem_base) {
+        if let Err(e) = hv.read_slice(s.phys_start.host_addr(), &mut mem) {
             return Some(Err(SimpleError::new(format!(
                 "failed to read linux kernel from hypervisor memory: {}",
                 e
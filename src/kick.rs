@@ -0,0 +1,105 @@
+use log::{info, warn};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::thread;
+use std::time::Duration;
+
+use crate::kvm::hypervisor::{get_hypervisor, HypervisorOptions};
+use crate::result::Result;
+use crate::tracer::proc::{pid_path, thread_group_leader, thread_status, threads, ThreadStatus};
+
+/// How often we check if the threads are running again after SIGCONT
+const SIGCONT_RETRIES: usize = 100;
+const SIGCONT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct KickOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+}
+
+fn thread_states(pid: Pid) -> Result<Vec<(Pid, ThreadStatus)>> {
+    let mut states = vec![];
+    for tid in threads(pid)? {
+        match thread_status(pid, tid) {
+            Ok(status) => states.push((tid, status)),
+            // thread exited in the meantime
+            Err(_) if !pid_path(pid).join("task").join(tid.to_string()).exists() => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(states)
+}
+
+fn stopped_threads(pid: Pid) -> Result<Vec<Pid>> {
+    Ok(thread_states(pid)?
+        .into_iter()
+        .filter(|(_, status)| status.is_stopped())
+        .map(|(tid, _)| tid)
+        .collect())
+}
+
+/// Resume a hypervisor that a previous vmsh left stopped, i.e. because it crashed or was killed
+/// while the guest was stopped.
+///
+/// We attach to all threads and detach again, which resumes threads in a ptrace stop. If the KVM
+/// fds cannot be found, or threads remain stopped afterwards because they ended up in a group
+/// stop when their tracer died, we send SIGCONT to the process.
+pub fn kick(opts: &KickOptions) -> Result<()> {
+    let pid = try_with!(
+        thread_group_leader(opts.pid),
+        "cannot determine the process of {}",
+        opts.pid
+    );
+    for (tid, status) in thread_states(pid)? {
+        if let Some(tracer) = status.tracer {
+            if pid_path(tracer).exists() {
+                bail!(
+                    "thread {} is still traced by process {}, stop that process first",
+                    tid,
+                    tracer
+                );
+            }
+        }
+    }
+
+    match get_hypervisor(pid, opts.hypervisor) {
+        Ok(vm) => {
+            vm.stop()?;
+            vm.resume()?;
+            info!(
+                "re-attached to {} and resumed it with {} vcpus",
+                pid,
+                vm.vcpus.len()
+            );
+        }
+        Err(e) => warn!("{}, falling back to SIGCONT", e),
+    }
+
+    let mut stopped = stopped_threads(pid)?;
+    if stopped.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "{} threads are still stopped, sending SIGCONT",
+        stopped.len()
+    );
+    try_with!(kill(pid, Signal::SIGCONT), "cannot send SIGCONT to {}", pid);
+    for _ in 0..SIGCONT_RETRIES {
+        thread::sleep(SIGCONT_POLL_INTERVAL);
+        stopped = stopped_threads(pid)?;
+        if stopped.is_empty() {
+            info!("all threads of {} are running again", pid);
+            return Ok(());
+        }
+    }
+    bail!(
+        "threads {} of {} are still stopped after SIGCONT",
+        stopped
+            .iter()
+            .map(|tid| tid.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        pid
+    )
+}
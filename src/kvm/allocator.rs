@@ -1,3 +1,4 @@
+use std::ops::Range;
 use std::sync::Arc;
 
 use crate::{
@@ -9,7 +10,10 @@ use nix::sys::mman::ProtFlags;
 use simple_error::{bail, require_with, try_with};
 use vm_device::bus::{MmioAddress, MmioRange};
 
-use crate::{page_math, result::Result};
+use crate::inspect::boot_params::guest_memory_map;
+use crate::page_math::{self, page_size};
+use crate::result::Result;
+use crate::tracer::proc::Mapping;
 
 use super::hypervisor::{memory::PhysMem, Hypervisor};
 
@@ -17,9 +21,11 @@ pub struct PhysMemAllocator {
     pub hv: Arc<Hypervisor>,
     /// Physical guest memory
     pub guest_mem: GuestMem,
-    /// Physical address where we last allocated memory from.
-    /// After an allocating we substract the allocation size from this value.
-    next_allocation: usize,
+    /// Guest physical ranges we allocated so far. MMIO ranges have no memslot, so
+    /// `Hypervisor::find_free_phys` only knows about them from here.
+    allocated: Vec<Range<u64>>,
+    /// The firmware memory map of the guest, see `guest_memory_map`. Empty if it is unknown.
+    memory_map: Vec<Range<u64>>,
 }
 
 const EXTEND_CPU_INFO_FUNCTION: u32 = 0x80000001;
@@ -44,7 +50,43 @@ fn sme_sev_bits() -> u8 {
     }
 }
 
-fn get_first_allocation(hv: &Arc<Hypervisor>) -> Result<usize> {
+/// Guest physical ranges on x86 that are in use even if the hypervisor has no memslot there:
+/// the first MiB holds the real-mode IVT, BIOS data area, EBDA, VGA memory and the BIOS, and
+/// the 32-bit MMIO gap below 4GiB holds PCI BARs as well as IOAPIC, HPET and local APIC.
+pub const RESERVED_PHYS: &[Range<u64>] = &[0..0x10_0000, 0xc000_0000..0x1_0000_0000];
+
+/// Highest `align`ed base of `len` bytes below `limit` that overlaps none of `busy`.
+pub fn find_free_range(busy: &[Range<u64>], len: u64, align: u64, limit: u64) -> Option<u64> {
+    let align_down = |addr: u64| addr & !(align - 1);
+    let mut base = align_down(limit.checked_sub(len)?);
+    loop {
+        match busy.iter().find(|r| r.start < base + len && base < r.end) {
+            Some(r) => base = align_down(r.start.checked_sub(len)?),
+            None => return Some(base),
+        }
+    }
+}
+
+/// Highest `align`ed base of `len` bytes below `limit` that is neither guest memory in `maps`,
+/// in `RESERVED_PHYS` nor in `avoid`, see `Hypervisor::find_free_phys`.
+pub(crate) fn find_free_phys_in(
+    maps: &[Mapping],
+    avoid: &[Range<u64>],
+    len: u64,
+    align: u64,
+    limit: u64,
+) -> Option<u64> {
+    let mut busy = maps
+        .iter()
+        .map(|m| m.phys_addr as u64..m.phys_end() as u64)
+        .collect::<Vec<_>>();
+    busy.extend_from_slice(RESERVED_PHYS);
+    busy.extend_from_slice(avoid);
+    find_free_range(&busy, len, align, limit)
+}
+
+/// End of the guest physical address space, limited by what both guest and host cpu support.
+pub(crate) fn get_first_allocation(hv: &Hypervisor) -> Result<usize> {
     let host_cpuid = unsafe { core::arch::x86_64::__cpuid(ADDRESS_SIZE_FUNCTION) };
     let vm_cpuid = try_with!(hv.get_cpuid2(&hv.vcpus[0]), "cannot get cpuid2");
     // Get Extended Processor Info and Feature Bits
@@ -113,44 +155,44 @@ impl VirtAlloc {
 
 impl PhysMemAllocator {
     pub fn new(hv: Arc<Hypervisor>) -> Result<Self> {
-        let next_allocation = get_first_allocation(&hv)?;
         let guest_mem = GuestMem::new(&hv)?;
+        // the guest may reserve more than the hypervisor backs, i.e. firmware mmio
+        let memory_map = match guest_memory_map(&hv) {
+            Ok(map) => map,
+            Err(e) => {
+                debug!("cannot read the memory map of the guest: {}", e);
+                vec![]
+            }
+        };
         Ok(Self {
             hv,
             guest_mem,
-            next_allocation,
-            //next_allocation: 0xd0000000 + 0x1000 * 2,
+            allocated: vec![],
+            memory_map,
         })
     }
 
+    /// Place `size` bytes at the highest free guest physical address, see
+    /// `Hypervisor::find_free_phys`.
     fn next_addr(&mut self, size: usize) -> Result<usize> {
-        let start = require_with!(self.next_allocation.checked_sub(size), "out of memory");
-        let last_range = require_with!(
-            self.guest_mem.last_memslot_range(),
-            "vm has no memory assigned"
+        let mut avoid = self.memory_map.clone();
+        avoid.extend_from_slice(&self.allocated);
+        let start = try_with!(
+            self.hv
+                .find_free_phys(size as u64, page_size() as u64, &avoid),
+            "cannot allocate {:#x} bytes of guest physical memory",
+            size
         );
-        let last_alloc = last_range.end;
-        if start < last_alloc {
-            bail!(
-                "cannot allocate memory at {:x}, our allocator conflicts with mapping at {:x}-{:x}. \
-                   This might happen if the last vmsh run did not clean up memory \
-                   correctly or the hypervisor has allocated memory at the very end of \
-                   the physical address space",
-                start, last_range.start, last_range.end
-            );
-        }
-        self.next_allocation = start;
-
-        Ok(start)
+        self.allocated.push(start..start + size as u64);
+        Ok(start as usize)
     }
 
     pub fn phys_alloc(&mut self, size: usize, readonly: bool) -> Result<PhysMem<u8>> {
-        let old_start = self.next_allocation;
         let padded_size = page_math::page_align(size);
         let start = self.next_addr(padded_size)?;
         let res = self.hv.vm_add_mem(start as u64, padded_size, readonly);
         if res.is_err() {
-            self.next_allocation = old_start;
+            self.allocated.pop();
         }
         res
     }
@@ -186,3 +228,58 @@ impl PhysMemAllocator {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{find_free_phys_in, find_free_range, RESERVED_PHYS};
+    use crate::tracer::testutils::mapping;
+
+    #[test]
+    fn test_find_free_range() {
+        let mut busy = vec![0..0x8000_0000, 0x1_0000_0000..0x2_0000_0000];
+        busy.extend_from_slice(RESERVED_PHYS);
+        assert_eq!(
+            find_free_range(&busy, 0x1000, 0x1000, 1 << 40),
+            Some((1 << 40) - 0x1000)
+        );
+        // right below the ram above 4GiB, skipping the 32-bit mmio gap
+        assert_eq!(
+            find_free_range(&busy, 0x1000, 0x1000, 0x1_8000_0000),
+            Some(0xbfff_f000)
+        );
+        assert_eq!(
+            find_free_range(&busy, 0x20_0000, 0x20_0000, 0x1_0000_0000),
+            Some(0xbfe0_0000)
+        );
+        assert_eq!(
+            find_free_range(&busy, 0x4000_0001, 0x1000, 0x1_0000_0000),
+            None
+        );
+        assert_eq!(find_free_range(&busy, 0x1000, 0x1000, 0x800), None);
+    }
+
+    #[test]
+    fn test_find_free_phys_in() {
+        let maps = vec![
+            mapping(0x7f00_0000_0000, 0, 0x8000_0000),
+            mapping(0x7f80_0000_0000, 0x1_0000_0000, 0x1_0000_0000),
+        ];
+        let limit = 1 << 40;
+        assert_eq!(
+            find_free_phys_in(&maps, &[], 0x1000, 0x1000, limit),
+            Some(limit - 0x1000)
+        );
+        // reserved at the end of the address space in the e820 table and our first device
+        let memory_map = 0xfd_0000_0000..limit;
+        let device = 0xfc_ffff_f000..0xfd_0000_0000;
+        assert_eq!(
+            find_free_phys_in(&maps, &[memory_map, device], 0x1000, 0x1000, limit),
+            Some(0xfc_ffff_e000)
+        );
+        // nothing left above the ram, placed in the 2GiB hole below the 32-bit mmio gap
+        assert_eq!(
+            find_free_phys_in(&maps, &[0x2_0000_0000..limit], 0x1000, 0x1000, limit),
+            Some(0xbfff_f000)
+        );
+    }
+}
@@ -4,42 +4,74 @@ use crate::tracer::inject_syscall;
 use kvm_bindings as kvmb;
 use libc::c_int;
 use log::*;
+use nix::errno::Errno;
+use nix::time::{clock_gettime, ClockId};
 use nix::unistd::Pid;
 use simple_error::{bail, require_with, simple_error, try_with};
 use std::ffi::OsStr;
+use std::fmt;
 use std::mem::size_of;
+use std::ops::Range;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::RawFd;
-use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard, TryLockError};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
 use super::ioeventfd::IoEventFd;
 use super::ioregionfd::IoRegionFd;
 use super::memory::*;
+use crate::kvm::allocator::{find_free_phys_in, get_first_allocation};
 use crate::kvm::fd_transfer;
+use crate::kvm::ioapic::IrqRoute;
 use crate::kvm::ioctls;
+use crate::kvm::lapic::Lapic;
+use crate::kvm::memslots::MemSlot;
+use crate::kvm::mp_state::MpState;
 use crate::kvm::tracee::{kvm_msrs, Tracee};
 use crate::page_math::{self, compute_host_offset};
 use crate::result::Result;
-use crate::tracer::proc::{openpid, Mapping, PidHandle};
+use crate::tracer::attach_lock::AttachLock;
+use crate::tracer::proc::{
+    openpid, seccomp_mode, thread_group_leader, Mapping, ProcFiles, SeccompMode,
+};
 use crate::tracer::wrap_syscall::KvmRunWrapper;
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VCPU {
     /// The idx as used in the inode name: anon_inode:kvm-vcpu:0
     pub idx: usize,
     pub fd_num: RawFd,
     /// hypervisor memory where fd_num is mapped to. Must be initialized before use.
     pub vcpu_map: Option<Mapping>,
+    /// Number of VMs created before ours in the same process that have a vcpu with the same idx,
+    /// i.e. how many kvm_run mappings of that name lie above ours. Set by `match_maps`.
+    pub map_rank: usize,
 }
 
 impl VCPU {
-    pub fn match_maps(vcpus: &mut Vec<VCPU>, vcpu_maps: &[Mapping]) {
+    /// Find the kvm_run mapping of each vcpu. With multiple VMs in one process, mappings of
+    /// vcpus with the same index cannot be told apart. As mmap allocates top-down, we assume a VM
+    /// created its mappings below those of the VMs before it. `earlier` holds the vcpu indices of
+    /// each VM created before the one `vcpus` belong to. VMs may have different numbers of vcpus,
+    /// so only those with a vcpu of the same index own a mapping above ours.
+    pub fn match_maps(vcpus: &mut Vec<VCPU>, vcpu_maps: &[Mapping], earlier: &[Vec<usize>]) {
         for vcpu in vcpus {
             let name = format!("{}{}", VCPUFD_INODE_NAME_STARTS_WITH, vcpu.idx);
-            match vcpu_maps.iter().find(|map| map.pathname == name) {
-                Some(map) => vcpu.vcpu_map = Some(map.clone()),
+            let mut candidates = vcpu_maps
+                .iter()
+                .filter(|map| map.pathname == name)
+                .collect::<Vec<_>>();
+            candidates.sort_by_key(|map| std::cmp::Reverse(map.start));
+            let rank = earlier
+                .iter()
+                .filter(|idxs| idxs.contains(&vcpu.idx))
+                .count();
+            vcpu.map_rank = rank;
+            match candidates.get(rank) {
+                Some(map) => vcpu.vcpu_map = Some((*map).clone()),
                 None => warn!(
                     "no mapped memory of vcpu fd {} found called {}",
                     vcpu.fd_num, name
@@ -48,11 +80,141 @@ impl VCPU {
         }
     }
 
+    /// Make sure `vcpu_map` is still mapped according to `current`, the vcpu mappings as they
+    /// are now. If the hypervisor remapped the kvm_run page since we looked last, switch to the
+    /// new mapping. Fails only if the vcpu is not mapped at all anymore.
+    pub fn refresh_map(&mut self, current: &[Mapping]) -> Result<()> {
+        let name = format!("{}{}", VCPUFD_INODE_NAME_STARTS_WITH, self.idx);
+        let mut candidates = current
+            .iter()
+            .filter(|map| map.pathname == name)
+            .collect::<Vec<_>>();
+        if let Some(old) = &self.vcpu_map {
+            if candidates.iter().any(|map| map.start == old.start) {
+                return Ok(());
+            }
+        }
+        // same preference as match_maps(), so we stay with the VM selected by --vm
+        candidates.sort_by_key(|map| std::cmp::Reverse(map.start));
+        let new = require_with!(
+            candidates.get(self.map_rank),
+            "vcpu {} has no kvm_run mapping anymore",
+            self.idx
+        );
+        match &self.vcpu_map {
+            Some(old) => warn!(
+                "kvm_run mapping of vcpu {} moved from {:#x} to {:#x}, using the new one",
+                self.idx, old.start, new.start
+            ),
+            None => warn!(
+                "found kvm_run mapping of vcpu {} at {:#x}",
+                self.idx, new.start
+            ),
+        }
+        self.vcpu_map = Some((*new).clone());
+        Ok(())
+    }
+
     pub fn map(&self) -> Result<&Mapping> {
         self.vcpu_map.as_ref().ok_or_else(|| {
             simple_error!("vcpu_map must be initialized before use (programming error)")
         })
     }
+
+    /// Check that the kvm_run mapping of this vcpu exists, starts at a page boundary and is large
+    /// enough for a `kvm_run`.
+    pub fn check_map(&self) -> Result<&Mapping> {
+        let map = try_with!(self.map(), "vcpu {} has no kvm_run mapping", self.idx);
+        if !page_math::is_page_aligned(map.start) || map.offset != 0 {
+            bail!(
+                "kvm_run mapping of vcpu {} at {:#x} (offset {:#x}) does not start at the beginning of the vcpu fd",
+                self.idx,
+                map.start,
+                map.offset
+            );
+        }
+        if map.size() < size_of::<kvmb::kvm_run>() {
+            bail!(
+                "kvm_run mapping of vcpu {} at {:#x} has only {} bytes, expected at least {}",
+                self.idx,
+                map.start,
+                map.size(),
+                size_of::<kvmb::kvm_run>()
+            );
+        }
+        Ok(map)
+    }
+
+    /// Whether the mapping also contains the ring of coalesced MMIO writes, which KVM places at
+    /// `KVM_COALESCED_MMIO_PAGE_OFFSET` after the kvm_run page.
+    pub fn has_coalesced_mmio_ring(&self) -> bool {
+        self.vcpu_map.as_ref().map_or(false, |map| {
+            map.size() >= (COALESCED_MMIO_PAGE_OFFSET + 1) * page_math::page_size()
+        })
+    }
+}
+
+/// KVM_COALESCED_MMIO_PAGE_OFFSET on x86
+const COALESCED_MMIO_PAGE_OFFSET: usize = 1;
+
+/// Guest kvmclock together with the host's CLOCK_MONOTONIC taken around the same instant.
+#[derive(Debug, Clone, Copy)]
+pub struct ClockSample {
+    /// kvmclock of the guest in nanoseconds
+    pub guest_ns: u64,
+    /// flags returned by KVM_GET_CLOCK, i.e. KVM_CLOCK_TSC_STABLE
+    pub flags: u32,
+    /// CLOCK_MONOTONIC of the host in nanoseconds, midpoint of the time it took to read the guest
+    /// clock
+    pub host_monotonic_ns: u64,
+    /// time between taking the host timestamps before and after reading the guest clock
+    pub uncertainty_ns: u64,
+}
+
+impl ClockSample {
+    /// Add this to a host monotonic timestamp to get the guest time.
+    pub fn offset_ns(&self) -> i64 {
+        self.guest_ns as i64 - self.host_monotonic_ns as i64
+    }
+}
+
+/// Guest TSC of a vcpu together with the host's TSC and CLOCK_MONOTONIC taken around the same
+/// instant.
+#[derive(Debug, Clone, Copy)]
+pub struct TscSample {
+    pub guest_tsc: u64,
+    /// host TSC, midpoint of the time it took to read the guest TSC
+    pub host_tsc: u64,
+    /// host TSC ticks between taking the host timestamps before and after reading the guest TSC
+    pub uncertainty: u64,
+    /// CLOCK_MONOTONIC of the host in nanoseconds at `host_tsc`
+    pub host_monotonic_ns: u64,
+    /// TSC frequency of the guest, None if KVM cannot tell
+    pub tsc_khz: Option<u32>,
+}
+
+impl TscSample {
+    /// Add this to a host TSC value to get the guest TSC. Only holds if the guest TSC is not
+    /// scaled, i.e. runs at the host frequency.
+    pub fn offset(&self) -> i64 {
+        self.guest_tsc.wrapping_sub(self.host_tsc) as i64
+    }
+
+    /// Host CLOCK_MONOTONIC in nanoseconds at which the guest TSC had the value `tsc`, i.e. to
+    /// translate timestamps of guest ftrace events. Requires the TSC frequency.
+    pub fn guest_tsc_to_host_ns(&self, tsc: u64) -> Option<i64> {
+        let khz = i128::from(self.tsc_khz?);
+        let delta_ns = (i128::from(tsc) - i128::from(self.guest_tsc)) * 1_000_000 / khz;
+        Some((i128::from(self.host_monotonic_ns) + delta_ns) as i64)
+    }
+}
+
+fn host_monotonic_ns() -> Result<u64> {
+    let now = try_with!(
+        clock_gettime(ClockId::CLOCK_MONOTONIC),
+        "cannot read host clock"
+    );
+    Ok(now.tv_sec() as u64 * 1_000_000_000 + now.tv_nsec() as u64)
 }
 
 struct TransferContext {
@@ -64,6 +226,42 @@ struct TransferContext {
     cmsg_mem: HvMem<[u8; 64]>,
 }
 
+/// What vmsh knows about a hypervisor, see `Hypervisor::summary`. Serializable with the `serde`
+/// feature for consumption by other tools.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HypervisorSummary {
+    pub pid: i32,
+    pub vm_fd: RawFd,
+    pub vcpus: Vec<VCPU>,
+    /// guest memory mappings in the hypervisor
+    pub mappings: Vec<Mapping>,
+    pub memory_encryption: Option<MemoryEncryption>,
+}
+
+/// Confidential computing technologies that hide guest RAM from the host. The memory stays
+/// mapped in the hypervisor, but reads return ciphertext, or fail for guest_memfd backed memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MemoryEncryption {
+    /// AMD SEV, SEV-ES or SEV-SNP
+    Sev,
+    /// Intel TDX
+    Tdx,
+    /// private guest memory in a guest_memfd, used by SEV-SNP and TDX on newer kernels
+    GuestMemfd,
+}
+
+impl fmt::Display for MemoryEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryEncryption::Sev => write!(f, "AMD SEV"),
+            MemoryEncryption::Tdx => write!(f, "Intel TDX"),
+            MemoryEncryption::GuestMemfd => write!(f, "private memory (guest_memfd)"),
+        }
+    }
+}
+
 /// Owns the tracee to prevent that multiple tracees are created for a Hypervisor. The Hypervisor
 /// is used to handle the lock on `Self.tracee` and is used to instantiate `HvMem` and `VmMem`.
 pub struct Hypervisor {
@@ -71,8 +269,19 @@ pub struct Hypervisor {
     pub vm_fd: RawFd,
     pub vcpus: Vec<VCPU>,
     pub(super) tracee: Arc<RwLock<Tracee>>,
+    /// Backs `read`, `write` and friends. Shared with all other accesses to the memory of `pid`
+    /// until the hypervisor is dropped, see `host_mem`.
+    mem: Arc<HostMem>,
     pub wrapper: Mutex<Option<KvmRunWrapper>>,
     transfer_ctx: Mutex<Option<TransferContext>>,
+    /// see `Hypervisor::set_vcpu_threads_only`
+    vcpu_threads_only: AtomicBool,
+    /// Held until the Hypervisor is dropped, see `AttachLock`
+    _attach_lock: Option<AttachLock>,
+    /// Number of `stop` calls not matched by a `resume` yet, see `Hypervisor::debug_check_stopped`
+    stop_depth: AtomicUsize,
+    /// see `Hypervisor::check_memory_readable`
+    pub memory_encryption: Option<MemoryEncryption>,
 }
 
 impl Hypervisor {
@@ -140,24 +349,75 @@ impl Hypervisor {
         try_with!(self.tracee.write(), "cannot take write lock").adopt()
     }
 
+    /// Undo one `stop`. The hypervisor only runs again once every `stop` is matched by a
+    /// `resume`. No-op if it is not stopped.
     pub fn resume(&self) -> Result<()> {
         let mut tracee = try_with!(
             self.tracee.write(),
             "cannot obtain tracee write lock: poinsoned"
         );
-        let _ = tracee.detach();
-        Ok(())
+        let depth = self.stop_depth.load(Ordering::Acquire);
+        if depth > 1 {
+            self.stop_depth.store(depth - 1, Ordering::Release);
+            return Ok(());
+        }
+        self.stop_depth.store(0, Ordering::Release);
+        match tracee.detach() {
+            Some(proc) => {
+                try_with!(proc.detach(), "cannot resume all threads of the hypervisor");
+                Ok(())
+            }
+            None => Ok(()),
+        }
     }
 
+    /// Stop all threads of the hypervisor. This does not send SIGSTOP: threads are seized and
+    /// interrupted with PTRACE_INTERRUPT, which stops them in a group-stop-like state without
+    /// delivering a signal, so signal handlers of the hypervisor (i.e. QEMU's SIG_IPI used to kick
+    /// vcpus) neither run nor swallow the stop. Vcpus blocked in ioctl(KVM_RUN) are forced out of
+    /// the guest by the interrupt and enter it again on `resume`.
+    ///
+    /// Threads can only have one tracer, so `stop` fails while a `KvmRunWrapper` is active.
+    /// Use `kvmrun_wrapped` instead, which hands over the stopped threads to the wrapper and back.
+    ///
+    /// Calls nest, so a function can stop the hypervisor for itself without resuming it under a
+    /// caller that also stopped it.
     pub fn stop(&self) -> Result<()> {
+        // don't block: the thread holding the lock may be our caller, inside `kvmrun_wrapped`
+        match self.wrapper.try_lock() {
+            Ok(wrapper) if wrapper.is_none() => {}
+            Ok(_) => bail!("cannot stop hypervisor while its vcpus are traced by KvmRunWrapper"),
+            Err(TryLockError::WouldBlock) => {
+                bail!("cannot stop hypervisor while KvmRunWrapper is in use by kvmrun_wrapped")
+            }
+            Err(TryLockError::Poisoned(_)) => bail!("cannot obtain wrapper lock: poisoned"),
+        }
         let mut tracee = try_with!(
             self.tracee.write(),
             "cannot obtain tracee write lock: poinsoned"
         );
         tracee.attach()?;
+        self.stop_depth.fetch_add(1, Ordering::AcqRel);
         Ok(())
     }
 
+    /// Whether the hypervisor was stopped with `stop` and not resumed as often since.
+    pub fn is_stopped(&self) -> bool {
+        self.stop_depth.load(Ordering::Acquire) > 0
+    }
+
+    /// Register access, memory writes and single-stepping race with the guest unless the
+    /// hypervisor is stopped. Debug builds warn if `op` is done on a running guest, release builds
+    /// skip the check.
+    fn debug_check_stopped(&self, op: &str) {
+        if cfg!(debug_assertions) && !self.is_stopped() {
+            warn!(
+                "{} while the guest is running, results may be inconsistent. Call Hypervisor::stop first",
+                op
+            );
+        }
+    }
+
     pub fn tracee_write_guard(&self) -> Result<RwLockWriteGuard<Tracee>> {
         let twg: RwLockWriteGuard<Tracee> = try_with!(
             self.tracee.write(),
@@ -167,6 +427,12 @@ impl Hypervisor {
         Ok(twg)
     }
 
+    /// Only trace vcpu threads in `kvmrun_wrapped` when the hypervisor is not stopped, see
+    /// `KvmRunWrapper::attach_vcpu_threads`.
+    pub fn set_vcpu_threads_only(&self, enable: bool) {
+        self.vcpu_threads_only.store(enable, Ordering::Release);
+    }
+
     /// run code while having full control over ioctl(KVM_RUN).
     /// Guarantees that self.wrapper is Some() during f().
     /// Can be called regardless of de/attached state.
@@ -189,12 +455,20 @@ impl Hypervisor {
                     (true, wrapper)
                 }
                 None => {
-                    let wrapper = KvmRunWrapper::attach(self.pid, &self.vcpus)?;
+                    let wrapper = if self.vcpu_threads_only.load(Ordering::Acquire) {
+                        KvmRunWrapper::attach_vcpu_threads(self.pid, &self.vcpus)?
+                    } else {
+                        KvmRunWrapper::attach(self.pid, &self.vcpus)?
+                    };
                     (false, wrapper)
                 }
             }
         };
 
+        // vcpus run under the wrapper until it is converted back, outstanding stops apply again
+        // after that
+        let stop_depth = self.stop_depth.swap(0, Ordering::AcqRel);
+
         // put wrapper: self.wrapper = Some(wrapper)
         {
             let mut self_wrapper = try_with!(self.wrapper.lock(), "cannot obtain wrapper mutex");
@@ -221,6 +495,7 @@ impl Hypervisor {
                     "cannot re-attach injector after having detached it favour of KvmRunWrapper";
                 let injector = try_with!(inject_syscall::from_tracer(wrapper.into_tracer()?), &err);
                 try_with!(tracee.attach_to(injector), &err);
+                self.stop_depth.store(stop_depth, Ordering::Release);
             }
         }
         try_with!(res, "closure on KvmRunWrapper failed");
@@ -236,6 +511,105 @@ impl Hypervisor {
         tracee.get_maps()
     }
 
+    /// `get_maps` for a hypervisor that is not stopped. Memslots are only visible through an
+    /// injected vm ioctl, so the hypervisor is stopped for the duration of that ioctl and resumed
+    /// right after.
+    pub fn get_maps_running(&self) -> Result<Vec<Mapping>> {
+        self.stop()?;
+        let maps = self.get_maps();
+        self.resume()?;
+        maps
+    }
+
+    /// Find `len` bytes of guest physical address space, aligned to the power of two `align`,
+    /// that are neither backed by memory, reserved for devices (`RESERVED_PHYS`) nor in `avoid`,
+    /// i.e. to place a device or a payload. Callers pass the guest's e820 or EFI memory map from
+    /// `inspect::boot_params::guest_memory_map` in `avoid`. Searches downwards from the end of
+    /// the physical address space. Memory added with `vm_add_mem` is seen, but MMIO ranges are
+    /// not, `PhysMemAllocator` passes those it handed out in `avoid`. Expects the hypervisor to be
+    /// stopped.
+    pub fn find_free_phys(&self, len: u64, align: u64, avoid: &[Range<u64>]) -> Result<u64> {
+        if !align.is_power_of_two() {
+            bail!("alignment {:#x} is not a power of two", align);
+        }
+        let limit = get_first_allocation(self)? as u64;
+        let maps = self.get_maps()?;
+        match find_free_phys_in(&maps, avoid, len, align, limit) {
+            Some(base) => Ok(base),
+            None => bail!(
+                "no free guest physical range of {:#x} bytes below {:#x}",
+                len,
+                limit
+            ),
+        }
+    }
+
+    /// Pid, vcpus and guest memory mappings of the hypervisor
+    pub fn summary(&self) -> Result<HypervisorSummary> {
+        Ok(HypervisorSummary {
+            pid: self.pid.as_raw(),
+            vm_fd: self.vm_fd,
+            vcpus: self.vcpus.clone(),
+            mappings: self.get_maps()?,
+            memory_encryption: self.memory_encryption,
+        })
+    }
+
+    /// Fail if guest RAM is encrypted. Features that interpret guest memory, i.e. coredumps,
+    /// scans and the kernel inspection, would only see ciphertext.
+    pub fn check_memory_readable(&self) -> Result<()> {
+        if let Some(enc) = self.memory_encryption {
+            bail!(
+                "guest memory of hypervisor {} is protected by {}, its contents cannot be read from the host",
+                self.pid,
+                enc
+            );
+        }
+        Ok(())
+    }
+
+    pub fn get_memslots(&self) -> Result<Vec<MemSlot>> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        tracee.get_memslots()
+    }
+
+    /// Fetch and reset the dirty bitmap of `slot`, see `Tracee::get_dirty_log`. Requires dirty
+    /// logging to be enabled with `enable_dirty_logging`.
+    pub fn get_dirty_log(&self, slot: &MemSlot) -> Result<Vec<u8>> {
+        self.debug_check_stopped("dirty log read");
+        // We cannot tell whether the hypervisor enabled manual dirty log protection, only whether
+        // KVM supports it. Clearing a log that KVM_GET_DIRTY_LOG has already reset is harmless.
+        let clear = try_with!(
+            self.check_extension(kvmb::KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2 as c_int),
+            "cannot check for KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2"
+        ) > 0;
+        let mut tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.reset_scratch();
+        tracee.get_dirty_log(slot.id(), slot.size() / page_math::page_size(), clear)
+    }
+
+    /// Turn on dirty page logging for memslot `slot`. The slot flags are restored when the
+    /// returned guard is dropped, even if the hypervisor has been resumed by then.
+    pub fn enable_dirty_logging(&self, slot: u32) -> Result<MemSlotGuard> {
+        let mut tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        let original = tracee.enable_dirty_logging(slot)?;
+        Ok(MemSlotGuard {
+            tracee: Arc::clone(&self.tracee),
+            original,
+            // nothing to restore if dirty logging was enabled already
+            restored: original.flags & kvmb::KVM_MEM_LOG_DIRTY_PAGES != 0,
+        })
+    }
+
     pub fn get_vcpu_maps(&self) -> Result<Vec<Mapping>> {
         let tracee = try_with!(
             self.tracee.read(),
@@ -259,28 +633,23 @@ impl Hypervisor {
         let hv_memslot = self.alloc_mem_padded::<T>(slot_len)?;
         let mut flags = 0;
         flags |= if readonly { kvmb::KVM_MEM_READONLY } else { 0 };
-        let arg = kvmb::kvm_userspace_memory_region {
+        let region = kvmb::kvm_userspace_memory_region {
             slot: self.get_maps()?.len() as u32, // guess a hopfully available slot id
             flags,
             guest_phys_addr: guest_addr, // must be page aligned
             memory_size: slot_len as u64,
             userspace_addr: hv_memslot.ptr as u64,
         };
-        let arg_hv = self.alloc_mem()?;
-        arg_hv.write(&arg)?;
 
-        let tracee = try_with!(
-            self.tracee.read(),
+        let mut tracee = try_with!(
+            self.tracee.write(),
             "cannot obtain tracee write lock: poinsoned"
         );
-        let ret = tracee.vm_ioctl_with_ref(ioctls::KVM_SET_USER_MEMORY_REGION(), &arg_hv)?;
-        if ret != 0 {
-            bail!("ioctl_with_ref failed: {}", ret)
-        }
+        tracee.set_user_memory_region(&region)?;
         let host_offset = compute_host_offset(hv_memslot.ptr, guest_addr as usize);
         Ok(PhysMem {
             mem: hv_memslot,
-            ioctl_arg: arg_hv,
+            region,
             guest_phys_addr: PhysAddr {
                 value: guest_addr as usize,
                 host_offset,
@@ -288,6 +657,28 @@ impl Hypervisor {
         })
     }
 
+    /// Read a `T` from the hypervisor's address space at `addr`.
+    pub fn read<T: Sized + Copy>(&self, addr: usize) -> Result<T> {
+        read_val(self.mem.as_ref(), addr)
+    }
+
+    /// Write `val` to the hypervisor's address space at `addr`.
+    pub fn write<T: Sized + Copy>(&self, addr: usize, val: &T) -> Result<()> {
+        self.debug_check_stopped("memory write");
+        write_val(self.mem.as_ref(), addr, val)
+    }
+
+    /// Fill `buf` from the hypervisor's address space starting at `addr`.
+    pub fn read_slice(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        self.mem.read_slice(addr, buf)
+    }
+
+    /// Write `buf` to the hypervisor's address space starting at `addr`.
+    pub fn write_slice(&self, addr: usize, buf: &[u8]) -> Result<()> {
+        self.debug_check_stopped("memory write");
+        self.mem.write_slice(addr, buf)
+    }
+
     pub fn alloc_mem<T: Copy>(&self) -> Result<HvMem<T>> {
         self.alloc_mem_padded::<T>(size_of::<T>())
     }
@@ -312,10 +703,20 @@ impl Hypervisor {
             ptr: ptr as libc::uintptr_t,
             pid: self.pid,
             tracee: self.tracee.clone(),
+            owned: true,
             phantom: SendPhantom::default(),
         })
     }
 
+    /// Run `f` with the tracee locked and memory for a T from its scratch arena, see
+    /// `HvMem::with_scratch`. Cheaper than `alloc_mem` for short-lived ioctl arguments.
+    fn with_scratch<T: Copy, R>(
+        &self,
+        f: impl FnOnce(&mut Tracee, HvMem<T>) -> Result<R>,
+    ) -> Result<R> {
+        HvMem::with_scratch(&self.tracee, self.pid, f)
+    }
+
     pub fn transfer(&self, fds: &[RawFd]) -> Result<Vec<RawFd>> {
         let tracee = try_with!(
             self.tracee.write(),
@@ -372,32 +773,18 @@ impl Hypervisor {
         info!("irqfd {:?}, interupt gsi/nr {:?}", eventfd.as_raw_fd(), gsi);
         let hv_eventfd = self.transfer(vec![eventfd.as_raw_fd()].as_slice())?[0];
 
-        let irqfd = kvmb::kvm_irqfd {
-            fd: hv_eventfd.as_raw_fd() as u32,
-            gsi,
-            flags: 0,
-            resamplefd: 0,
-            ..Default::default()
-        };
-        let mem = self.alloc_mem()?;
-        mem.write(&irqfd)?;
-        let ret = {
-            let tracee = try_with!(
-                self.tracee.read(),
-                "cannot obtain tracee read lock: poinsoned"
-            );
-            try_with!(
-                tracee.vm_ioctl_with_ref(ioctls::KVM_IRQFD(), &mem),
-                "kvm irqfd ioctl injection failed"
-            )
-        };
-        if ret != 0 {
-            bail!("cannot register KVM_IRQFD via ioctl: {:?}", ret);
-        }
+        self.with_scratch(|tracee, mem| tracee.register_irqfd(&mem, gsi, hv_eventfd))?;
 
         Ok(eventfd)
     }
 
+    /// Raise (`level` true) or lower interrupt line `gsi` by injecting KVM_IRQ_LINE, the manual
+    /// alternative to `irqfd`. Expects the hypervisor to be stopped.
+    pub fn irq_line(&self, gsi: u32, level: bool) -> Result<()> {
+        self.debug_check_stopped("KVM_IRQ_LINE");
+        self.with_scratch(|tracee, mem| tracee.irq_line(&mem, gsi, level))
+    }
+
     pub fn userfaultfd(&self) -> Result<c_int> {
         let tracee = try_with!(
             self.tracee.read(),
@@ -423,8 +810,18 @@ impl Hypervisor {
         tracee.check_extension(cap)
     }
 
+    /// Number of memory address spaces the host's KVM supports (KVM_CAP_MULTI_ADDRESS_SPACE): 2
+    /// on x86 hosts that support system management mode, where the second one is what vcpus see
+    /// while in SMM. This does not tell whether the guest actually uses SMM.
+    pub fn address_spaces(&self) -> Result<usize> {
+        let n = self.check_extension(kvmb::KVM_CAP_MULTI_ADDRESS_SPACE as c_int)?;
+        // 0 if the capability is not supported at all
+        Ok(n.max(1) as usize)
+    }
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_cpuid2(&self, vcpu: &VCPU) -> Result<ioctls::kvm_cpuid2> {
+        // larger than the scratch arena
         let mem = self.alloc_mem()?;
         try_with!(
             mem.write(&ioctls::kvm_cpuid2 {
@@ -452,44 +849,70 @@ impl Hypervisor {
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_irqchip(&self, chip_id: u32) -> Result<kvmb::kvm_irqchip> {
-        let mem = self.alloc_mem()?;
-        let tracee = try_with!(
-            self.tracee.write(),
-            "cannot obtain tracee write lock: poinsoned"
-        );
-        try_with!(
-            mem.write(&kvmb::kvm_irqchip {
-                chip_id,
-                ..Default::default()
-            }),
-            "cannot update kvm_irqchip structure"
-        );
-        tracee.get_irqchip(&mem)
+        self.with_scratch(|tracee, mem| {
+            try_with!(
+                mem.write(&kvmb::kvm_irqchip {
+                    chip_id,
+                    ..Default::default()
+                }),
+                "cannot update kvm_irqchip structure"
+            );
+            tracee.get_irqchip(&mem)
+        })
+    }
+
+    /// Where the GSIs of the in-kernel IOAPIC are routed to, i.e. to check whether a GSI is
+    /// still free before we use it for a device.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_irq_routing(&self) -> Result<Vec<IrqRoute>> {
+        self.with_scratch(|tracee, mem| tracee.get_irq_routing(&mem))
+    }
+
+    pub fn get_clock(&self) -> Result<kvmb::kvm_clock_data> {
+        self.with_scratch(|tracee, mem| tracee.get_clock(&mem))
+    }
+
+    /// Read the guest clock and the host monotonic clock at about the same time. Host timestamps
+    /// are taken right before and after the injected ioctl, so the memory allocation is not
+    /// part of the measurement.
+    pub fn sample_clock(&self) -> Result<ClockSample> {
+        self.with_scratch(|tracee, mem| {
+            let before = host_monotonic_ns()?;
+            let clock = tracee.get_clock(&mem)?;
+            let after = host_monotonic_ns()?;
+            Ok(ClockSample {
+                guest_ns: clock.clock,
+                flags: clock.flags,
+                host_monotonic_ns: before + (after - before) / 2,
+                uncertainty_ns: after - before,
+            })
+        })
     }
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_sregs(&self, vcpu: &VCPU) -> Result<kvmb::kvm_sregs> {
-        let mem = self.alloc_mem()?;
-        let tracee = try_with!(
-            self.tracee.write(),
-            "cannot obtain tracee write lock: poinsoned"
-        );
-        tracee.get_sregs(vcpu, &mem)
+        self.debug_check_stopped("special register read");
+        self.with_scratch(|tracee, mem| tracee.get_sregs(vcpu, &mem))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_sregs(&self, vcpu: &VCPU, sregs: &kvmb::kvm_sregs) -> Result<()> {
+        self.debug_check_stopped("special register write");
+        self.with_scratch(|tracee, mem| {
+            mem.write(sregs)?;
+            tracee.set_sregs(vcpu, &mem)
+        })
     }
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_regs(&self, vcpu: &VCPU) -> Result<cpu::Regs> {
-        let mem = self.alloc_mem()?;
-        let tracee = try_with!(
-            self.tracee.write(),
-            "cannot obtain tracee write lock: poinsoned"
-        );
-        tracee.get_regs(vcpu, &mem)
+        self.debug_check_stopped("register read");
+        self.with_scratch(|tracee, mem| tracee.get_regs(vcpu, &mem))
     }
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn set_regs(&self, vcpu: &VCPU, regs: &cpu::Regs) -> Result<()> {
-        let mem = self.alloc_mem()?;
+        self.debug_check_stopped("register write");
         let regs = kvmb::kvm_regs {
             rax: regs.rax,
             rbx: regs.rbx,
@@ -510,53 +933,286 @@ impl Hypervisor {
             rip: regs.rip,
             rflags: regs.eflags,
         };
-        mem.write(&regs)?;
-        let tracee = try_with!(
-            self.tracee.write(),
-            "cannot obtain tracee write lock: poinsoned"
-        );
-        tracee.set_regs(vcpu, &mem)
+        self.with_scratch(|tracee, mem| {
+            mem.write(&regs)?;
+            tracee.set_regs(vcpu, &mem)
+        })
     }
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_fpu_regs(&self, vcpu: &VCPU) -> Result<cpu::FpuRegs> {
-        let mem = self.alloc_mem()?;
-        let tracee = try_with!(
-            self.tracee.write(),
-            "cannot obtain tracee write lock: poinsoned"
-        );
-        tracee.get_fpu_regs(vcpu, &mem)
+        self.debug_check_stopped("fpu register read");
+        self.with_scratch(|tracee, mem| tracee.get_fpu_regs(vcpu, &mem))
+    }
+
+    /// Unlike `get_fpu_regs`, returns the registers as KVM reports them, i.e. to restore them
+    /// later with `set_fpu`.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_fpu(&self, vcpu: &VCPU) -> Result<kvmb::kvm_fpu> {
+        self.debug_check_stopped("fpu register read");
+        self.with_scratch(|tracee, mem| tracee.get_fpu(vcpu, &mem))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_fpu(&self, vcpu: &VCPU, fpu: &kvmb::kvm_fpu) -> Result<()> {
+        self.debug_check_stopped("fpu register write");
+        self.with_scratch(|tracee, mem| {
+            mem.write(fpu)?;
+            tracee.set_fpu(vcpu, &mem)
+        })
     }
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_msr(&self, vcpu: &VCPU, msr: &kvmb::kvm_msr_entry) -> Result<kvmb::kvm_msr_entry> {
-        let mem = self.alloc_mem()?;
-        try_with!(
-            mem.write(&kvm_msrs {
-                nmsrs: 1,
-                pad: 0,
-                entries: [*msr; 1],
-            }),
-            "cannot obtain tracee write lock: poinsoned"
-        );
-        let tracee = try_with!(
-            self.tracee.write(),
-            "cannot obtain tracee write lock: poinsoned"
-        );
-        tracee.get_msr(vcpu, &mem)
+        self.with_scratch(|tracee, mem| {
+            try_with!(
+                mem.write(&kvm_msrs {
+                    nmsrs: 1,
+                    pad: 0,
+                    entries: [*msr; 1],
+                }),
+                "cannot update kvm_msrs structure"
+            );
+            tracee.get_msr(vcpu, &mem)
+        })
+    }
+
+    /// Read the TSC of `vcpu` and the host's TSC at about the same time, see `sample_clock`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn sample_tsc(&self, vcpu: &VCPU) -> Result<TscSample> {
+        self.with_scratch(|tracee, mem| {
+            let tsc_khz = match tracee.get_tsc_khz(vcpu) {
+                Ok(khz) => Some(khz),
+                Err(e) => {
+                    warn!("cannot get tsc frequency: {}", e);
+                    None
+                }
+            };
+            let monotonic_before = host_monotonic_ns()?;
+            // Safe because rdtsc has no side effects and is available on every x86_64 cpu.
+            let before = unsafe { std::arch::x86_64::_rdtsc() };
+            let guest_tsc = tracee.get_tsc(vcpu, &mem)?;
+            let after = unsafe { std::arch::x86_64::_rdtsc() };
+            let monotonic_after = host_monotonic_ns()?;
+            // The tsc of two cpus is not necessarily synchronized, so if we got migrated in
+            // between, `after` can be smaller than `before`.
+            let uncertainty = after.saturating_sub(before);
+            Ok(TscSample {
+                guest_tsc,
+                host_tsc: before + uncertainty / 2,
+                uncertainty,
+                host_monotonic_ns: monotonic_before + (monotonic_after - monotonic_before) / 2,
+                tsc_khz,
+            })
+        })
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_lapic(&self, vcpu: &VCPU) -> Result<Lapic> {
+        self.with_scratch(|tracee, mem| Ok(Lapic(tracee.get_lapic(vcpu, &mem)?)))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_lapic(&self, vcpu: &VCPU, lapic: &Lapic) -> Result<()> {
+        self.debug_check_stopped("lapic write");
+        self.with_scratch(|tracee, mem| {
+            try_with!(
+                mem.write(&lapic.0),
+                "cannot update kvm_lapic_state structure"
+            );
+            tracee.set_lapic(vcpu, &mem)
+        })
+    }
+
+    /// Whether `vcpu` runs, halted or still waits to be started by the bootstrap processor
+    pub fn get_mp_state(&self, vcpu: &VCPU) -> Result<MpState> {
+        self.debug_check_stopped("mp state read");
+        self.with_scratch(|tracee, mem| {
+            let state = tracee.get_mp_state(vcpu, &mem)?;
+            Ok(MpState::from_raw(state.mp_state))
+        })
+    }
+
+    /// Force `vcpu` into `state`, i.e. `MpState::Runnable` wakes up a halted vcpu without an
+    /// interrupt. The guest does not expect this, so only use it for debugging.
+    pub fn set_mp_state(&self, vcpu: &VCPU, state: MpState) -> Result<()> {
+        self.debug_check_stopped("mp state write");
+        self.with_scratch(|tracee, mem| {
+            try_with!(
+                mem.write(&kvmb::kvm_mp_state {
+                    mp_state: state.raw()
+                }),
+                "cannot update kvm_mp_state structure"
+            );
+            tracee.set_mp_state(vcpu, &mem)
+        })
+    }
+
+    /// Whether `vcpu` can take an external interrupt right now: KVM reported it as ready when
+    /// KVM_RUN last returned and the guest has interrupts enabled.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn interrupt_window_open(&self, vcpu: &VCPU) -> Result<bool> {
+        let map_ptr = vcpu.map()?.start as *const kvmb::kvm_run;
+        let ready_ptr: *const u8 = unsafe { &((*map_ptr).ready_for_interrupt_injection) };
+        let ready: u8 = process_read(self.pid, ready_ptr.cast::<libc::c_void>())?;
+        let regs = self.get_regs(vcpu)?;
+        Ok(ready != 0 && regs.eflags & RFLAGS_IF != 0)
+    }
+
+    /// Inject external interrupt `vector` into `vcpu`. Uses KVM_INTERRUPT for hypervisors with
+    /// a userspace irqchip and falls back to a fixed MSI addressed to the vcpu's local APIC when
+    /// the irqchip is emulated by the kernel.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn inject_irq(&self, vcpu: &VCPU, vector: u8) -> Result<()> {
+        if !self.interrupt_window_open(vcpu)? {
+            bail!(
+                "interrupt window of vcpu {} is closed: the guest has interrupts disabled or another event is pending",
+                vcpu.idx
+            );
+        }
+        let ret = self.with_scratch(|tracee, mem| {
+            mem.write(&kvmb::kvm_interrupt {
+                irq: u32::from(vector),
+            })?;
+            tracee.interrupt(vcpu, &mem)
+        })?;
+        if ret == 0 {
+            return Ok(());
+        }
+        if ret != -libc::ENXIO {
+            bail!("ioctl(KVM_INTERRUPT) failed: {}", Errno::from_i32(-ret));
+        }
+
+        // in-kernel irqchip
+        let apic_id = self.get_lapic(vcpu)?.id();
+        self.with_scratch(|tracee, mem| {
+            mem.write(&kvmb::kvm_msi {
+                // destination in bits 19:12, physical destination mode
+                address_lo: 0xfee0_0000 | (apic_id << 12),
+                // fixed delivery mode, edge triggered
+                data: u32::from(vector),
+                ..Default::default()
+            })?;
+            match tracee.signal_msi(&mem)? {
+                // number of vcpus the interrupt was delivered to
+                n if n > 0 => Ok(()),
+                0 => bail!("guest blocked vector {} on vcpu {}", vector, vcpu.idx),
+                ret => bail!("ioctl(KVM_SIGNAL_MSI) failed: {}", Errno::from_i32(-ret)),
+            }
+        })
     }
+
+    pub fn set_guest_debug(&self, vcpu: &VCPU, dbg: &kvmb::kvm_guest_debug) -> Result<()> {
+        self.debug_check_stopped("setting guest debug flags");
+        self.with_scratch(|tracee, mem| {
+            mem.write(dbg)?;
+            tracee.set_guest_debug(vcpu, &mem)
+        })
+    }
+
+    /// Install hardware breakpoints at the guest virtual addresses `addrs` on all vcpus.
+    /// An empty slice removes all breakpoints. Hits are reported as KVM_EXIT_DEBUG.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_hw_breakpoints(&self, addrs: &[u64]) -> Result<()> {
+        let mut dbg = kvmb::kvm_guest_debug::default();
+        if addrs.len() > HW_BREAKPOINTS {
+            bail!(
+                "only {} hardware breakpoints are available, got {}",
+                HW_BREAKPOINTS,
+                addrs.len()
+            );
+        }
+        if !addrs.is_empty() {
+            dbg.control = kvmb::KVM_GUESTDBG_ENABLE | kvmb::KVM_GUESTDBG_USE_HW_BP;
+        }
+        for (i, addr) in addrs.iter().enumerate() {
+            dbg.arch.debugreg[i] = *addr;
+            // DR7: local enable bit, condition (bits 16+4i) and length (bits 18+4i) 0 = execute
+            dbg.arch.debugreg[7] |= 1 << (i * 2);
+        }
+        for vcpu in &self.vcpus {
+            try_with!(
+                self.set_guest_debug(vcpu, &dbg),
+                "cannot set breakpoints on vcpu {}",
+                vcpu.idx
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Interrupt enable flag
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Number of debug address registers (DR0-DR3)
+pub const HW_BREAKPOINTS: usize = 4;
+
+/// How `get_hypervisor` attaches, set from the command line
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HypervisorOptions {
+    /// Index of the VM to use if a process runs more than one
+    pub vm: Option<usize>,
+    /// Borrow the ioctl scratch memory from the hypervisor's stack if it cannot be mapped, see
+    /// `Tracee::set_borrow_scratch`
+    pub borrow_scratch: bool,
 }
 
 pub const VMFD_INODE_NAME: &str = "anon_inode:kvm-vm";
 pub const VCPUFD_INODE_NAME_STARTS_WITH: &str = "anon_inode:kvm-vcpu:";
 
-fn find_vm_fd(handle: &PidHandle) -> Result<(Vec<RawFd>, Vec<VCPU>)> {
+/// KVM file descriptors of one VM
+#[derive(Debug)]
+pub struct VmFds {
+    pub vm_fd: RawFd,
+    pub vcpus: Vec<VCPU>,
+}
+
+/// Assign vcpus to their VM. KVM has no interface to ask which VM a vcpu fd belongs to, so we
+/// rely on vcpus being created after their VM: each vcpu goes to the VM with the highest fd
+/// number below its own.
+///
+/// Guests with system management mode have a second memory address space, but that is part of
+/// the same vm fd and shares its vcpu fds, so it never shows up as another VM here.
+fn group_vcpus(mut vm_fds: Vec<RawFd>, vcpus: Vec<VCPU>) -> Result<Vec<VmFds>> {
+    vm_fds.sort_unstable();
+    let mut vms = vm_fds
+        .iter()
+        .map(|vm_fd| VmFds {
+            vm_fd: *vm_fd,
+            vcpus: vec![],
+        })
+        .collect::<Vec<_>>();
+    for vcpu in vcpus {
+        let vm = match vms.iter_mut().rev().find(|vm| vm.vm_fd < vcpu.fd_num) {
+            Some(vm) => vm,
+            None => bail!(
+                "vcpu {} (fd {}) was opened before any VM",
+                vcpu.idx,
+                vcpu.fd_num
+            ),
+        };
+        if vm.vcpus.iter().any(|v| v.idx == vcpu.idx) {
+            bail!(
+                "found vcpu {} twice for vm fd {}, cannot tell which vcpus belong to which VM",
+                vcpu.idx,
+                vm.vm_fd
+            );
+        }
+        vm.vcpus.push(vcpu);
+    }
+    for vm in &mut vms {
+        vm.vcpus.sort_by_key(|vcpu| vcpu.idx);
+    }
+    Ok(vms)
+}
+
+fn find_vm_fd(handle: &impl ProcFiles) -> Result<Vec<VmFds>> {
     let mut vm_fds: Vec<RawFd> = vec![];
     let mut vcpu_fds: Vec<VCPU> = vec![];
     let fds = try_with!(
         handle.fds(),
         "cannot lookup file descriptors of process {}",
-        handle.pid
+        handle.pid()
     );
 
     for fd in fds {
@@ -582,30 +1238,214 @@ fn find_vm_fd(handle: &PidHandle) -> Result<(Vec<RawFd>, Vec<VCPU>)> {
                 idx,
                 fd_num: fd.fd_num,
                 vcpu_map: None,
+                map_rank: 0,
             })
         }
     }
-    let old_len = vcpu_fds.len();
-    vcpu_fds.dedup_by_key(|vcpu| vcpu.idx);
-    if old_len != vcpu_fds.len() {
-        bail!("found multiple vcpus with same id, assume multiple VMs in same hypervisor. This is not supported yet")
+
+    group_vcpus(vm_fds, vcpu_fds)
+}
+
+/// Hypervisors we recognize by their comm, which the kernel truncates to 15 characters
+const KNOWN_VMMS: &[&str] = &["qemu", "firecracker", "cloud-hyperviso", "crosvm", "lkvm"];
+
+/// Accelerator QEMU was asked for on its command line, i.e. "tcg" for `-accel tcg` or
+/// `-machine q35,accel=tcg`. Without one, QEMU uses KVM if it can and TCG otherwise.
+fn qemu_accel(cmdline: &[u8]) -> Option<String> {
+    let args = cmdline
+        .split(|c| *c == 0)
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>();
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "-accel" || arg == "--accel" {
+            let next = args.get(i + 1)?;
+            return next.split(',').next().map(str::to_string);
+        }
+        if let Some(accel) = arg.split(',').find_map(|opt| opt.strip_prefix("accel=")) {
+            return Some(accel.to_string());
+        }
+    }
+    None
+}
+
+/// Explain why process `handle` has no KVM VM. Most likely it is a hypervisor emulating the
+/// cpu, i.e. QEMU with TCG, or not a hypervisor at all.
+fn no_vm_error(handle: &impl ProcFiles) -> String {
+    let pid = handle.pid();
+    let comm = handle
+        .read_file("comm")
+        .map(|c| String::from_utf8_lossy(&c).trim_end().to_string())
+        .unwrap_or_default();
+    if !KNOWN_VMMS.iter().any(|vmm| comm.starts_with(vmm)) {
+        return format!(
+            "no KVM-VMs found in process {} ({}), is it a hypervisor?",
+            pid, comm
+        );
+    }
+    let has_kvm = handle
+        .fds()
+        .map(|fds| fds.iter().any(|fd| fd.path.as_os_str() == "/dev/kvm"))
+        .unwrap_or(false);
+    if has_kvm {
+        return format!(
+            "hypervisor {} ({}) has /dev/kvm open but no VM, it may still be starting up",
+            pid, comm
+        );
+    }
+    match handle.read_file("cmdline").ok().and_then(|c| qemu_accel(&c)) {
+        Some(accel) if !accel.starts_with("kvm") => format!(
+            "hypervisor {} ({}) uses the {} accelerator instead of KVM, vmsh requires KVM",
+            pid, comm, accel
+        ),
+        _ => format!(
+            "hypervisor {} ({}) is not using KVM acceleration, i.e. QEMU falls back to TCG if /dev/kvm is not accessible. vmsh requires KVM",
+            pid, comm
+        ),
+    }
+}
+
+/// Detect a confidential VM from the fds and command line of `handle`. KVM does not tell the
+/// type of a VM through its fd, so we look for what the hypervisor needs to set one up:
+/// /dev/sev for SEV, a guest_memfd for private memory, or QEMU's confidential guest objects.
+fn detect_memory_encryption(handle: &impl ProcFiles) -> Option<MemoryEncryption> {
+    let cmdline = handle.read_file("cmdline").unwrap_or_default();
+    // `-object sev-guest,id=sev0`, `qom-type=sev-guest,...` or the json syntax
+    let has_object = |name: &str| {
+        let json = format!("\"{}\"", name);
+        cmdline
+            .split(|c| *c == 0)
+            .map(String::from_utf8_lossy)
+            .any(|arg| {
+                arg.contains(&json)
+                    || arg
+                        .split(',')
+                        .any(|opt| opt.trim_start_matches("qom-type=") == name)
+            })
+    };
+    if has_object("tdx-guest") {
+        return Some(MemoryEncryption::Tdx);
+    }
+    if has_object("sev-guest") || has_object("sev-snp-guest") {
+        return Some(MemoryEncryption::Sev);
+    }
+    let fds = handle.fds().unwrap_or_default();
+    if fds.iter().any(|fd| fd.path.as_os_str() == "/dev/sev") {
+        return Some(MemoryEncryption::Sev);
+    }
+    if fds
+        .iter()
+        .any(|fd| fd.path.as_os_str() == "anon_inode:[kvm-gmem]")
+    {
+        return Some(MemoryEncryption::GuestMemfd);
+    }
+    None
+}
+
+/// Pick the VM to attach to. vmsh attaches to VMs whose KVM fds are held by the target process,
+/// i.e. one level below the host it runs on. A process that created multiple VMs needs
+/// `selected`.
+fn select_vm(pid: Pid, mut vms: Vec<VmFds>, selected: Option<usize>) -> Result<(usize, VmFds)> {
+    if vms.is_empty() {
+        bail!("no KVM-VMs found. If this is qemu, does it enable KVM?");
+    }
+    let selected = match selected {
+        Some(selected) => selected,
+        None => {
+            if vms.len() > 1 {
+                let list = vms
+                    .iter()
+                    .enumerate()
+                    .map(|(i, vm)| {
+                        format!("{}: vm fd {} with {} vcpus", i, vm.vm_fd, vm.vcpus.len())
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                bail!(
+                    "process {} runs {} VMs ({}), select one with --vm <index>",
+                    pid,
+                    vms.len(),
+                    list
+                );
+            }
+            return Ok((0, vms.remove(0)));
+        }
     };
+    if selected >= vms.len() {
+        bail!(
+            "cannot select vm {}, process {} runs only {} VMs",
+            selected,
+            pid,
+            vms.len()
+        );
+    }
+    info!(
+        "using vm {} (fd {}) out of {}",
+        selected,
+        vms[selected].vm_fd,
+        vms.len()
+    );
+    Ok((selected, vms.remove(selected)))
+}
 
-    Ok((vm_fds, vcpu_fds))
+/// Syscalls we inject, i.e. the mmap of the ioctl scratch memory, KVM ioctls or the socket for
+/// fd transfers, run under the seccomp policy of the main thread of the hypervisor, which we
+/// inject through. A filter may make them fail with an unrelated looking error, or kill the
+/// hypervisor, so tell the user up front. Strict mode kills it on the first injected syscall,
+/// so we refuse before injecting anything.
+fn check_seccomp(handle: &impl ProcFiles) -> Result<()> {
+    match seccomp_mode(handle, handle.pid()) {
+        Some(SeccompMode::Filter) => warn!(
+            "hypervisor {} runs under a seccomp filter: syscalls vmsh injects (mmap, ioctl, socket) may be denied or even kill it. \
+             Reads of its memory with process_vm_readv and of /proc do not inject syscalls and keep working",
+            handle.pid()
+        ),
+        Some(SeccompMode::Strict) => bail!(
+            "hypervisor {} runs in seccomp strict mode: any syscall vmsh injects would kill it",
+            handle.pid()
+        ),
+        Some(SeccompMode::Disabled) | None => {}
+    }
+    Ok(())
 }
 
-pub fn get_hypervisor(pid: Pid) -> Result<Hypervisor> {
+pub fn get_hypervisor(pid: Pid, opts: HypervisorOptions) -> Result<Hypervisor> {
+    let tgid = try_with!(
+        thread_group_leader(pid),
+        "cannot determine the process of {}",
+        pid
+    );
+    if tgid != pid {
+        info!(
+            "{} is a thread of process {}, attaching to the process instead",
+            pid, tgid
+        );
+    }
+    let pid = tgid;
+    let attach_lock = AttachLock::acquire(pid)?;
     let handle = try_with!(openpid(pid), "cannot open handle in proc");
+    handle.report_inaccessible();
 
-    let (vm_fds, mut vcpus) = try_with!(find_vm_fd(&handle), "failed to access kvm fds");
-    if vm_fds.is_empty() {
-        bail!("no KVM-VMs found. If this is qemu, does it enable KVM?");
+    let vms = try_with!(find_vm_fd(&handle), "failed to access kvm fds");
+    if vms.is_empty() {
+        bail!("{}", no_vm_error(&handle));
     }
-    if vm_fds.len() > 1 {
-        bail!("multiple VMs found, this is not supported yet.");
+    let vcpu_idxs = vms
+        .iter()
+        .map(|vm| vm.vcpus.iter().map(|vcpu| vcpu.idx).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let (nth, VmFds { vm_fd, mut vcpus }) = select_vm(pid, vms, opts.vm)?;
+    let memory_encryption = detect_memory_encryption(&handle);
+    if let Some(enc) = memory_encryption {
+        warn!(
+            "the guest uses {}, its memory is encrypted: coredumps, scans and kernel inspection are not available",
+            enc
+        );
     }
 
-    let tracee = Hypervisor::attach(pid, vm_fds[0]);
+    check_seccomp(&handle)?;
+
+    let mut tracee = Hypervisor::attach(pid, vm_fd);
+    tracee.set_borrow_scratch(opts.borrow_scratch);
     let vcpu_maps = try_with!(tracee.get_vcpu_maps(), "cannot get vcpufd memory maps");
     if vcpus.is_empty() {
         bail!("found KVM instance but no VCPUs");
@@ -613,13 +1453,492 @@ pub fn get_hypervisor(pid: Pid) -> Result<Hypervisor> {
     if vcpu_maps.is_empty() {
         bail!("found VCPUs but no mappings of their fds");
     }
-    VCPU::match_maps(&mut vcpus, &vcpu_maps);
+    VCPU::match_maps(&mut vcpus, &vcpu_maps, &vcpu_idxs[..nth]);
+    for vcpu in &vcpus {
+        vcpu.check_map()?;
+    }
     Ok(Hypervisor {
         pid,
         tracee: Arc::new(RwLock::new(tracee)),
-        vm_fd: vm_fds[0],
+        mem: host_mem(pid),
+        vm_fd,
         vcpus,
         wrapper: Mutex::new(None),
         transfer_ctx: Mutex::new(None),
+        vcpu_threads_only: AtomicBool::new(false),
+        _attach_lock: Some(attach_lock),
+        stop_depth: AtomicUsize::new(0),
+        memory_encryption,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvm::testutils::{FakeInjector, SpinningChild};
+    use crate::kvm::tracee::SCRATCH_SIZE;
+    use crate::tracer::testutils::mapping;
+    use libc::c_ulong;
+    use nix::unistd::getpid;
+
+    fn fake_hypervisor(pid: Pid) -> Hypervisor {
+        Hypervisor {
+            pid,
+            vm_fd: -1,
+            vcpus: vec![],
+            tracee: Arc::new(RwLock::new(Hypervisor::attach(pid, -1))),
+            mem: host_mem(pid),
+            wrapper: Mutex::new(None),
+            transfer_ctx: Mutex::new(None),
+            vcpu_threads_only: AtomicBool::new(false),
+            _attach_lock: None,
+            // memory of the spinning child is accessed without stopping it, don't warn about it
+            stop_depth: AtomicUsize::new(1),
+            memory_encryption: None,
+        }
+    }
+
+    fn pattern(i: usize) -> u8 {
+        (i % 251) as u8
+    }
+
+    #[test]
+    fn test_stop_while_wrapped() {
+        let hv = fake_hypervisor(getpid());
+        let guard = hv.wrapper.lock().expect("cannot lock wrapper");
+        let err = hv.stop().expect_err("wrapper is busy");
+        assert!(err.to_string().contains("in use"), "{}", err);
+        drop(guard);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let child = SpinningChild::spawn(2 * page_math::page_size(), pattern);
+        let hv = fake_hypervisor(child.pid);
+
+        let val = hv.read::<u64>(child.addr + 8).expect("cannot read u64");
+        let expected = (8..16).map(pattern).collect::<Vec<_>>();
+        assert_eq!(val.to_ne_bytes().to_vec(), expected);
+
+        let mut buf = vec![0u8; child.len];
+        hv.read_slice(child.addr, &mut buf)
+            .expect("cannot read slice");
+        assert!(buf.iter().enumerate().all(|(i, b)| *b == pattern(i)));
+
+        hv.write(child.addr + 4, &0xdead_beefu32)
+            .expect("cannot write u32");
+        assert_eq!(
+            hv.read::<u32>(child.addr + 4).expect("cannot read u32"),
+            0xdead_beef
+        );
+
+        // crosses the page boundary
+        let data = [0xaau8; 64];
+        let addr = child.addr + page_math::page_size() - 32;
+        hv.write_slice(addr, &data).expect("cannot write slice");
+        let mut buf = [0u8; 64];
+        hv.read_slice(addr, &mut buf).expect("cannot read slice");
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_proc_mem() {
+        let child = SpinningChild::spawn(2 * page_math::page_size(), pattern);
+        let mem = ProcMem::open(child.pid).expect("cannot open /proc/pid/mem");
+
+        let mut buf = vec![0u8; child.len];
+        mem.read_slice(child.addr, &mut buf)
+            .expect("cannot read slice");
+        assert!(buf.iter().enumerate().all(|(i, b)| *b == pattern(i)));
+
+        // crosses the page boundary
+        let data = [0xaau8; 64];
+        let addr = child.addr + page_math::page_size() - 32;
+        mem.write_slice(addr, &data).expect("cannot write slice");
+        let mut buf = [0u8; 64];
+        process_read_slice(child.pid, addr, &mut buf).expect("cannot read slice");
+        assert_eq!(buf, data);
+
+        // unmapped and partially mapped ranges fail like with the iovec calls
+        let mut buf = [0u8; 16];
+        assert!(mem.read_slice(0, &mut buf).is_err());
+        let mut buf = vec![0u8; 2 * child.len];
+        assert!(mem.read_slice(child.addr, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_read_out_of_bounds() {
+        let child = SpinningChild::spawn(page_math::page_size(), pattern);
+        let hv = fake_hypervisor(child.pid);
+
+        let mut buf = [0u8; 16];
+        assert!(hv.read_slice(0, &mut buf).is_err());
+        // partially mapped reads are reported as short reads
+        let mut buf = vec![0u8; 2 * child.len];
+        assert!(hv.read_slice(child.addr, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_write_multiple_pages() {
+        let len = 64 * page_math::page_size();
+        let child = SpinningChild::spawn(len, pattern);
+        let hv = fake_hypervisor(child.pid);
+
+        let data = (0..len).map(|i| pattern(i + 1)).collect::<Vec<_>>();
+        hv.write_slice(child.addr, &data)
+            .expect("cannot write slice");
+        let mut buf = vec![0u8; len];
+        hv.read_slice(child.addr, &mut buf)
+            .expect("cannot read slice");
+        assert_eq!(buf, data);
+
+        // partially mapped writes fail once the unmapped part is reached
+        let data = vec![0u8; 2 * len];
+        assert!(hv.write_slice(child.addr, &data).is_err());
+    }
+
+    fn fake_get_regs(_fd: RawFd, request: c_ulong, arg: c_ulong) -> c_int {
+        if request == ioctls::KVM_GET_REGS() {
+            let regs = kvmb::kvm_regs {
+                rax: 42,
+                rip: 0x1000,
+                ..Default::default()
+            };
+            unsafe { std::ptr::write(arg as *mut kvmb::kvm_regs, regs) };
+        }
+        0
+    }
+
+    fn vcpu(idx: usize, fd_num: RawFd) -> VCPU {
+        VCPU {
+            idx,
+            fd_num,
+            vcpu_map: None,
+            map_rank: 0,
+        }
+    }
+
+    #[test]
+    fn test_group_vcpus() {
+        // a VM with two vcpus, then a second one with one vcpu
+        let vcpus = vec![vcpu(1, 13), vcpu(0, 21), vcpu(0, 12)];
+        let vms = group_vcpus(vec![20, 11], vcpus).expect("cannot group vcpus");
+        assert_eq!(vms.len(), 2);
+        assert_eq!(vms[0].vm_fd, 11);
+        assert_eq!(
+            vms[0].vcpus.iter().map(|v| v.fd_num).collect::<Vec<_>>(),
+            vec![12, 13]
+        );
+        assert_eq!(vms[1].vm_fd, 20);
+        assert_eq!(vms[1].vcpus[0].fd_num, 21);
+
+        assert!(group_vcpus(vec![11], vec![vcpu(0, 12), vcpu(0, 13)]).is_err());
+        assert!(group_vcpus(vec![11], vec![vcpu(0, 10)]).is_err());
+    }
+
+    #[test]
+    fn test_find_vm_fd() {
+        use crate::tracer::testutils::FakeProc;
+        let proc = FakeProc::new(Pid::from_raw(42))
+            .fd(0, "/dev/null")
+            .fd(10, "/dev/kvm")
+            .fd(11, "anon_inode:kvm-vm")
+            .fd(13, "anon_inode:kvm-vcpu:1")
+            .broken_fd(14, Errno::ENOENT)
+            .fd(12, "anon_inode:kvm-vcpu:0");
+        let vms = find_vm_fd(&proc).expect("cannot find vm fds");
+        assert_eq!(vms.len(), 1);
+        assert_eq!(vms[0].vm_fd, 11);
+        assert_eq!(
+            vms[0]
+                .vcpus
+                .iter()
+                .map(|v| (v.idx, v.fd_num))
+                .collect::<Vec<_>>(),
+            vec![(0, 12), (1, 13)]
+        );
+
+        let proc = FakeProc::new(Pid::from_raw(42))
+            .fd(11, "anon_inode:kvm-vm")
+            .fd(12, "anon_inode:kvm-vcpu:x");
+        assert!(find_vm_fd(&proc).is_err());
+
+        let proc = FakeProc::new(Pid::from_raw(42)).denied("fd");
+        let err = find_vm_fd(&proc).expect_err("fd is not accessible");
+        assert!(err.to_string().contains("is not accessible"), "{}", err);
+    }
+
+    #[test]
+    fn test_check_seccomp() {
+        use crate::tracer::testutils::FakeProc;
+        let status = |mode: &str| {
+            FakeProc::new(Pid::from_raw(42)).file(
+                "task/42/status",
+                format!("Name:\tqemu-system-x86\nSeccomp:\t{}\n", mode),
+            )
+        };
+        assert!(check_seccomp(&status("0")).is_ok());
+        // injected syscalls may still be allowed by the filter
+        assert!(check_seccomp(&status("2")).is_ok());
+        let err = check_seccomp(&status("1")).expect_err("strict mode kills the hypervisor");
+        assert!(err.to_string().contains("seccomp strict mode"), "{}", err);
+        // kernels without CONFIG_SECCOMP
+        assert!(check_seccomp(&FakeProc::new(Pid::from_raw(42))).is_ok());
+    }
+
+    #[test]
+    fn test_no_vm_error() {
+        use crate::tracer::testutils::FakeProc;
+        let tcg = FakeProc::new(Pid::from_raw(42))
+            .file("comm", "qemu-system-x86\n")
+            .file("cmdline", "qemu-system-x86_64\0-accel\0tcg,thread=multi\0")
+            .fd(0, "/dev/null");
+        let err = no_vm_error(&tcg);
+        assert!(err.contains("uses the tcg accelerator"), "{}", err);
+
+        let fallback = FakeProc::new(Pid::from_raw(42))
+            .file("comm", "qemu-system-x86\n")
+            .file(
+                "cmdline",
+                "qemu-system-x86_64\0-machine\0q35,accel=kvm:tcg\0",
+            );
+        let err = no_vm_error(&fallback);
+        assert!(err.contains("not using KVM acceleration"), "{}", err);
+
+        let starting = FakeProc::new(Pid::from_raw(42))
+            .file("comm", "firecracker\n")
+            .fd(10, "/dev/kvm");
+        let err = no_vm_error(&starting);
+        assert!(err.contains("may still be starting up"), "{}", err);
+
+        let shell = FakeProc::new(Pid::from_raw(42)).file("comm", "bash\n");
+        let err = no_vm_error(&shell);
+        assert!(err.contains("is it a hypervisor?"), "{}", err);
+    }
+
+    #[test]
+    fn test_detect_memory_encryption() {
+        use crate::tracer::testutils::FakeProc;
+        let plain = FakeProc::new(Pid::from_raw(42))
+            .file("cmdline", "qemu-system-x86_64\0-enable-kvm\0")
+            .fd(10, "/dev/kvm");
+        assert_eq!(detect_memory_encryption(&plain), None);
+
+        let sev = FakeProc::new(Pid::from_raw(42)).file(
+            "cmdline",
+            "qemu-system-x86_64\0-object\0sev-guest,id=sev0,cbitpos=47\0",
+        );
+        assert_eq!(detect_memory_encryption(&sev), Some(MemoryEncryption::Sev));
+
+        let tdx = FakeProc::new(Pid::from_raw(42)).file(
+            "cmdline",
+            "qemu-system-x86_64\0-object\0{\"qom-type\":\"tdx-guest\",\"id\":\"tdx\"}\0",
+        );
+        assert_eq!(detect_memory_encryption(&tdx), Some(MemoryEncryption::Tdx));
+
+        let sev_fd = FakeProc::new(Pid::from_raw(42)).fd(12, "/dev/sev");
+        assert_eq!(
+            detect_memory_encryption(&sev_fd),
+            Some(MemoryEncryption::Sev)
+        );
+
+        let gmem = FakeProc::new(Pid::from_raw(42)).fd(13, "anon_inode:[kvm-gmem]");
+        assert_eq!(
+            detect_memory_encryption(&gmem),
+            Some(MemoryEncryption::GuestMemfd)
+        );
+    }
+
+    #[test]
+    fn test_qemu_accel() {
+        assert_eq!(qemu_accel(b"qemu\0-enable-kvm\0"), None);
+        assert_eq!(qemu_accel(b"qemu\0-accel\0hvf\0"), Some("hvf".into()));
+        assert_eq!(
+            qemu_accel(b"qemu\0-machine\0pc,accel=tcg\0"),
+            Some("tcg".into())
+        );
+        assert_eq!(qemu_accel(b"qemu\0-accel"), None);
+    }
+
+    #[test]
+    fn test_match_maps() {
+        let map = |start: usize, pathname: &str| Mapping {
+            pathname: pathname.into(),
+            ..mapping(start, 0, 0x3000)
+        };
+        // vm 0 with one vcpu, then vm 1 with two, mapped top-down
+        let maps = vec![
+            map(0x7f00_0002_0000, "anon_inode:kvm-vcpu:0"),
+            map(0x7f00_0001_0000, "anon_inode:kvm-vcpu:0"),
+            map(0x7f00_0000_0000, "anon_inode:kvm-vcpu:1"),
+        ];
+        let mut vcpus = vec![vcpu(0, 21), vcpu(1, 22)];
+        VCPU::match_maps(&mut vcpus, &maps, &[vec![0]]);
+        assert_eq!(vcpus[0].map_rank, 1);
+        assert_eq!(vcpus[0].map().expect("has map").start, 0x7f00_0001_0000);
+        assert_eq!(vcpus[1].map().expect("has map").start, 0x7f00_0000_0000);
+
+        let mut vcpus = vec![vcpu(0, 12)];
+        VCPU::match_maps(&mut vcpus, &maps, &[]);
+        assert_eq!(vcpus[0].map().expect("has map").start, 0x7f00_0002_0000);
+    }
+
+    #[test]
+    fn test_refresh_map() {
+        let map = |start: usize, pathname: &str| Mapping {
+            pathname: pathname.into(),
+            ..mapping(start, 0, 0x3000)
+        };
+        let mut cpu = vcpu(1, 13);
+        cpu.vcpu_map = Some(map(0x7f00_0000_0000, "anon_inode:kvm-vcpu:1"));
+
+        let unchanged = vec![
+            map(0x7f00_0001_0000, "anon_inode:kvm-vcpu:0"),
+            map(0x7f00_0000_0000, "anon_inode:kvm-vcpu:1"),
+        ];
+        cpu.refresh_map(&unchanged).expect("vcpu is mapped");
+        assert_eq!(cpu.map().expect("has map").start, 0x7f00_0000_0000);
+
+        let moved = vec![
+            map(0x7f00_0001_0000, "anon_inode:kvm-vcpu:0"),
+            map(0x7f00_0002_0000, "anon_inode:kvm-vcpu:1"),
+        ];
+        cpu.refresh_map(&moved).expect("vcpu is mapped");
+        assert_eq!(cpu.map().expect("has map").start, 0x7f00_0002_0000);
+
+        assert!(cpu.refresh_map(&moved[..1]).is_err());
+
+        // vcpu 0 of the second VM stays below the mapping of the first VM
+        let mut cpu = vcpu(0, 21);
+        cpu.map_rank = 1;
+        let both = vec![
+            map(0x7f00_0003_0000, "anon_inode:kvm-vcpu:0"),
+            map(0x7f00_0002_0000, "anon_inode:kvm-vcpu:0"),
+        ];
+        cpu.refresh_map(&both).expect("vcpu is mapped");
+        assert_eq!(cpu.map().expect("has map").start, 0x7f00_0002_0000);
+    }
+
+    #[test]
+    fn test_tsc_sample() {
+        let mut sample = TscSample {
+            guest_tsc: 5_000_000,
+            host_tsc: 9_000_000,
+            uncertainty: 100,
+            host_monotonic_ns: 1_000_000_000,
+            tsc_khz: Some(2_000_000),
+        };
+        assert_eq!(sample.offset(), -4_000_000);
+        // 2 GHz: 2000 ticks are one microsecond
+        assert_eq!(sample.guest_tsc_to_host_ns(5_002_000), Some(1_000_001_000));
+        assert_eq!(sample.guest_tsc_to_host_ns(4_998_000), Some(999_999_000));
+        sample.tsc_khz = None;
+        assert_eq!(sample.guest_tsc_to_host_ns(5_002_000), None);
+    }
+
+    #[test]
+    fn test_fake_ioctl() {
+        let pid = getpid();
+        let tracee = Tracee::new(pid, 7, Some(FakeInjector::new(fake_get_regs)));
+        let vcpu = VCPU {
+            idx: 0,
+            fd_num: 42,
+            vcpu_map: None,
+            map_rank: 0,
+        };
+        let mut regs = kvmb::kvm_regs::default();
+        let mem = HvMem {
+            ptr: &mut regs as *mut kvmb::kvm_regs as libc::uintptr_t,
+            pid,
+            tracee: Arc::new(RwLock::new(Hypervisor::attach(pid, -1))),
+            owned: false,
+            phantom: SendPhantom::default(),
+        };
+
+        let res = tracee.get_regs(&vcpu, &mem).expect("cannot get regs");
+        assert_eq!(res.rax, 42);
+        assert_eq!(res.rip, 0x1000);
+
+        assert_eq!(tracee.check_extension(5).expect("ioctl failed"), 0);
+
+        let calls = tracee
+            .try_get_proc()
+            .expect("not attached")
+            .ioctls
+            .lock()
+            .expect("cannot lock")
+            .clone();
+        assert_eq!(
+            calls,
+            vec![
+                (42, ioctls::KVM_GET_REGS(), mem.ptr as c_ulong),
+                (7, ioctls::KVM_CHECK_EXTENSION(), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scratch_alloc() {
+        let mut tracee = Tracee::new(getpid(), 7, Some(FakeInjector::new(fake_get_regs)));
+        let a = tracee.scratch_alloc(3, 1).expect("cannot allocate");
+        let b = tracee.scratch_alloc(8, 8).expect("cannot allocate");
+        assert_eq!(b - a, 8);
+        assert!(tracee.scratch_alloc(SCRATCH_SIZE, 1).is_err());
+
+        // the arena is reused after a reset
+        tracee.reset_scratch();
+        assert_eq!(tracee.scratch_alloc(8, 8).expect("cannot allocate"), a);
+        assert!(tracee.scratch_alloc(SCRATCH_SIZE - 8, 1).is_ok());
+
+        assert!(tracee.detach().is_some());
+    }
+
+    #[test]
+    fn test_borrow_scratch() {
+        let pid = getpid();
+        let mut tracee = Tracee::new(pid, 7, Some(FakeInjector::without_mmap(fake_get_regs)));
+        assert!(tracee.scratch_alloc(8, 8).is_err());
+        tracee.set_borrow_scratch(true);
+        let ptr = tracee
+            .scratch_alloc(8, 8)
+            .expect("cannot borrow scratch memory");
+        let mut original = [0u8; 8];
+        process_read_slice(pid, ptr, &mut original).expect("cannot read scratch memory");
+        process_write_slice(pid, ptr, &[0xaa; 8]).expect("cannot write scratch memory");
+
+        // the stack content is restored before the hypervisor runs again
+        assert!(tracee.detach().is_some());
+        let mut restored = [0u8; 8];
+        process_read_slice(pid, ptr, &mut restored).expect("cannot read stack");
+        assert_eq!(restored, original);
+    }
+
+    #[test]
+    fn test_select_vm() {
+        let pid = getpid();
+        let vms = || {
+            vec![
+                VmFds {
+                    vm_fd: 5,
+                    vcpus: vec![],
+                },
+                VmFds {
+                    vm_fd: 9,
+                    vcpus: vec![],
+                },
+            ]
+        };
+        assert!(select_vm(pid, vec![], None).is_err());
+        assert!(select_vm(pid, vms(), None).is_err());
+        let (nth, vm) = select_vm(pid, vms(), Some(1)).expect("cannot select vm 1");
+        assert_eq!((nth, vm.vm_fd), (1, 9));
+        assert!(select_vm(pid, vms(), Some(2)).is_err());
+
+        let single = vec![VmFds {
+            vm_fd: 5,
+            vcpus: vec![],
+        }];
+        let (nth, vm) = select_vm(pid, single, None).expect("cannot select the only vm");
+        assert_eq!((nth, vm.vm_fd), (0, 5));
+    }
+}
@@ -1,6 +1,6 @@
-use kvm_bindings as kvmb;
 use log::*;
-use simple_error::{bail, try_with};
+use nix::unistd::Pid;
+use simple_error::try_with;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::RawFd;
 use std::sync::{Arc, RwLock};
@@ -8,7 +8,6 @@ use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
 use super::memory::HvMem;
 use super::Hypervisor;
-use crate::kvm::ioctls;
 use crate::kvm::tracee::Tracee;
 use crate::result::Result;
 use std::ops::Deref;
@@ -16,35 +15,13 @@ use std::ops::Deref;
 pub struct IoEventFd {
     fd: EventFd,
     hv_eventfd: RawFd,
-    hv_mem: HvMem<kvmb::kvm_ioeventfd>,
+    pid: Pid,
     tracee: Arc<RwLock<Tracee>>,
     guest_addr: u64,
     len: u32,
     datamatch: Option<u64>,
 }
 
-fn kvm_ioeventfd(
-    hv_eventfd: RawFd,
-    guest_addr: u64,
-    len: u32,
-    datamatch: Option<u64>,
-) -> kvmb::kvm_ioeventfd {
-    let mut flags = 0;
-    let mut datam = 0;
-    if let Some(data) = datamatch {
-        flags = 1 << kvmb::kvm_ioeventfd_flag_nr_datamatch;
-        datam = data;
-    }
-
-    kvmb::kvm_ioeventfd {
-        len,
-        datamatch: datam,
-        addr: guest_addr,
-        fd: hv_eventfd.as_raw_fd(),
-        flags,
-        ..Default::default()
-    }
-}
 impl IoEventFd {
     pub fn new(
         hv: &Hypervisor,
@@ -59,31 +36,17 @@ impl IoEventFd {
             guest_addr
         );
         let hv_eventfd = hv.transfer(vec![eventfd.as_raw_fd()].as_slice())?[0];
-        let ioeventfd = kvm_ioeventfd(hv_eventfd, guest_addr, len, datamatch);
 
-        let mem = hv.alloc_mem()?;
-        mem.write(&ioeventfd)?;
-
-        let ret = {
-            let tracee = try_with!(
-                hv.tracee.read(),
-                "cannot obtain tracee read lock: poinsoned"
-            );
-            try_with!(
-                tracee.vm_ioctl_with_ref(ioctls::KVM_IOEVENTFD(), &mem),
-                "kvm ioeventfd ioctl injection failed"
-            )
-        };
-        if ret != 0 {
-            bail!("cannot register KVM_IOEVENTFD via ioctl: {:?}", ret);
-        }
+        HvMem::with_scratch(&hv.tracee, hv.pid, |tracee, mem| {
+            tracee.register_ioeventfd(&mem, guest_addr, len, hv_eventfd, datamatch)
+        })?;
 
         Ok(IoEventFd {
             guest_addr,
             len,
             datamatch,
             hv_eventfd,
-            hv_mem: mem,
+            pid: hv.pid,
             fd: eventfd,
             tracee: hv.tracee.clone(),
         })
@@ -92,31 +55,24 @@ impl IoEventFd {
 
 impl Drop for IoEventFd {
     fn drop(&mut self) {
-        let tracee = match self.tracee.read() {
-            Err(e) => {
-                warn!("IoEventfd: Could not aquire lock: {}", e);
-                return;
+        let res = HvMem::with_scratch(&self.tracee, self.pid, |tracee, mem| {
+            if let Err(e) = tracee.unregister_ioeventfd(
+                &mem,
+                self.guest_addr,
+                self.len,
+                self.hv_eventfd,
+                self.datamatch,
+            ) {
+                warn!("IoEventfd: cannot unregister ioeventfd: {}", e)
             }
-            Ok(t) => t,
-        };
-        let mut ioeventfd =
-            kvm_ioeventfd(self.hv_eventfd, self.guest_addr, self.len, self.datamatch);
-        ioeventfd.flags |= 1 << kvmb::kvm_ioeventfd_flag_nr_deassign;
-
-        if let Err(e) = self.hv_mem.write(&ioeventfd) {
-            warn!(
-                "IoEventFd: Could not write to HvMem while dropping IoEventFd: {}",
-                e
-            );
-            return;
-        }
 
-        if let Err(e) = tracee.vm_ioctl_with_ref(ioctls::KVM_IOEVENTFD(), &self.hv_mem) {
-            warn!("IoEventfd: kvm ioeventfd ioctl injection failed: {}", e)
-        }
-
-        if let Err(e) = tracee.close(self.hv_eventfd) {
-            warn!("IoEventfd: failed to close eventfd in hypervisor: {}", e)
+            if let Err(e) = tracee.close(self.hv_eventfd) {
+                warn!("IoEventfd: failed to close eventfd in hypervisor: {}", e)
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            warn!("IoEventfd: cannot unregister ioeventfd: {}", e);
         }
     }
 }
@@ -2,7 +2,7 @@ use log::*;
 use nix::poll::{ppoll, PollFd, PollFlags};
 use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
 use nix::sys::time::TimeSpec;
-use nix::unistd::{close, read, write};
+use nix::unistd::{close, read, write, Pid};
 use simple_error::{bail, try_with};
 use std::mem::size_of;
 use std::mem::MaybeUninit;
@@ -22,7 +22,7 @@ use crate::result::Result;
 /// Implements the KVM IoRegionFd feature.
 pub struct IoRegionFd {
     tracee: Arc<RwLock<Tracee>>,
-    hv_mem: HvMem<kvm_ioregion>,
+    pid: Pid,
     ioregion: kvm_ioregion,
     rfile: RawFd, // our end: we write responses here
     wfile: RawFd, // we read commands from here
@@ -59,26 +59,20 @@ impl IoRegionFd {
         let hv_rf_hv = hv.transfer(vec![rf_hv.as_raw_fd()].as_slice())?[0];
         let hv_wf_hv = hv.transfer(vec![wf_hv.as_raw_fd()].as_slice())?[0];
         let ioregion = kvm_ioregion::new(guest_paddr, len, hv_rf_hv, hv_wf_hv);
-        let mem = hv.alloc_mem()?;
-        mem.write(&ioregion)?;
-
-        let ret = {
-            let tracee = try_with!(
-                hv.tracee.read(),
-                "cannot obtain tracee read lock: poinsoned"
-            );
-            try_with!(
+        let ret = HvMem::with_scratch(&hv.tracee, hv.pid, |tracee, mem| {
+            mem.write(&ioregion)?;
+            Ok(try_with!(
                 tracee.vm_ioctl_with_ref(ioctls::KVM_SET_IOREGION(), &mem),
                 "kvm ioeventfd ioctl injection failed"
-            )
-        };
+            ))
+        })?;
         if ret != 0 {
             bail!("ioregionfd ioctl failed with {}", ret);
         }
 
         Ok(IoRegionFd {
             tracee: hv.tracee.clone(),
-            hv_mem: mem,
+            pid: hv.pid,
             ioregion,
             rfile: rf_dev,
             wfile: wf_dev,
@@ -110,57 +104,52 @@ impl IoRegionFd {
 
 impl Drop for IoRegionFd {
     fn drop(&mut self) {
-        let tracee = match self.tracee.read() {
-            Err(e) => {
-                warn!("IoEventfd: Could not aquire lock: {}", e);
-                return;
-            }
-            Ok(t) => t,
-        };
-
         let mut ioregion = self.ioregion;
         ioregion.rfd = -1;
         ioregion.wfd = -1;
 
-        if let Err(e) = self.hv_mem.write(&ioregion) {
-            warn!(
-                "IoRegionFd: Could not write to HvMem while dropping IoRegionFd: {}",
-                e
+        let res = HvMem::with_scratch(&self.tracee, self.pid, |tracee, mem| {
+            try_with!(
+                mem.write(&ioregion),
+                "Could not write to HvMem while dropping IoRegionFd"
             );
-            return;
-        }
 
-        match tracee.vm_ioctl_with_ref(ioctls::KVM_SET_IOREGION(), &self.hv_mem) {
-            Err(e) => warn!("IoRegionFd: kvm ioregionfd ioctl injection failed: {}", e),
-            Ok(ret) => {
-                if ret != 0 {
-                    warn!("IoRegionFd: kvm ioregionfd remove syscall failed: {}", ret);
+            match tracee.vm_ioctl_with_ref(ioctls::KVM_SET_IOREGION(), &mem) {
+                Err(e) => warn!("IoRegionFd: kvm ioregionfd ioctl injection failed: {}", e),
+                Ok(ret) => {
+                    if ret != 0 {
+                        warn!("IoRegionFd: kvm ioregionfd remove syscall failed: {}", ret);
+                    }
                 }
             }
-        }
 
-        match tracee.close(self.hv_rf_hv) {
-            Err(e) => warn!("IoRegionFd: close injection failed: {}", e),
-            Ok(ret) => {
-                if ret != 0 {
-                    warn!(
-                        "IoRegionFd: failed to close hv_rf_hv in hypervisor: {}",
-                        ret
-                    )
+            match tracee.close(self.hv_rf_hv) {
+                Err(e) => warn!("IoRegionFd: close injection failed: {}", e),
+                Ok(ret) => {
+                    if ret != 0 {
+                        warn!(
+                            "IoRegionFd: failed to close hv_rf_hv in hypervisor: {}",
+                            ret
+                        )
+                    }
                 }
             }
-        }
 
-        match tracee.close(self.hv_wf_hv) {
-            Err(e) => warn!("IoRegionFd: close injection failed: {}", e),
-            Ok(ret) => {
-                if ret != 0 {
-                    warn!(
-                        "IoRegionFd: failed to close hv_wf_hv in hypervisor: {}",
-                        ret
-                    )
+            match tracee.close(self.hv_wf_hv) {
+                Err(e) => warn!("IoRegionFd: close injection failed: {}", e),
+                Ok(ret) => {
+                    if ret != 0 {
+                        warn!(
+                            "IoRegionFd: failed to close hv_wf_hv in hypervisor: {}",
+                            ret
+                        )
+                    }
                 }
             }
+            Ok(())
+        });
+        if let Err(e) = res {
+            warn!("IoRegionFd: cannot remove ioregion: {}", e);
         }
 
         if let Err(e) = close(self.rf_hv) {
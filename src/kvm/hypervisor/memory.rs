@@ -1,24 +1,329 @@
 use crate::page_table::PhysAddr;
 use kvm_bindings as kvmb;
-use libc::c_void;
+use lazy_static::lazy_static;
+use libc::{c_void, off_t};
 use log::*;
+use nix::errno::Errno;
+use nix::sys::uio::{pread, process_vm_readv, process_vm_writev, pwrite, RemoteIoVec};
 use nix::unistd::Pid;
-use simple_error::simple_error;
+use simple_error::{bail, simple_error, try_with};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{IoSlice, IoSliceMut};
 use std::marker::PhantomData;
-use std::mem::size_of;
-use std::sync::{Arc, RwLock};
-use vm_memory::remote_mem;
+use std::mem::{align_of, size_of, MaybeUninit};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
 
-use crate::kvm::ioctls;
-use crate::kvm::tracee::Tracee;
+use crate::kvm::tracee::{Tracee, SCRATCH_SIZE};
 use crate::result::Result;
+use crate::tracer::ptrace::retry_on_eintr;
 
+/// How often `retry_on_esrch` retries and how long it waits in between
+const ESRCH_RETRIES: usize = 5;
+const ESRCH_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+/// Read a `T` from process `pid` at `addr` through its shared `HostMem`, see `host_mem`.
 pub fn process_read<T: Sized + Copy>(pid: Pid, addr: *const c_void) -> Result<T> {
-    remote_mem::process_read(pid, addr).map_err(|e| simple_error!("{}", e))
+    read_val(host_mem(pid).as_ref(), addr as usize)
 }
 
+/// Write `val` to process `pid` at `addr` through its shared `HostMem`, see `host_mem`.
 pub fn process_write<T: Sized + Copy>(pid: Pid, addr: *mut c_void, val: &T) -> Result<()> {
-    remote_mem::process_write(pid, addr, val).map_err(|e| simple_error!("{}", e))
+    write_val(host_mem(pid).as_ref(), addr as usize, val)
+}
+
+/// Read a `T` at `addr` with `mem`. `T` must be plain data that is valid for any bit pattern.
+pub fn read_val<T: Sized + Copy>(mem: &dyn MemAccess, addr: usize) -> Result<T> {
+    let mut val = MaybeUninit::<T>::uninit();
+    // Safe because the slice covers exactly the size_of::<T>() bytes of `val`.
+    let bytes =
+        unsafe { std::slice::from_raw_parts_mut(val.as_mut_ptr().cast::<u8>(), size_of::<T>()) };
+    mem.read_slice(addr, bytes)?;
+    // Safe because the read filled all bytes and T is plain data.
+    Ok(unsafe { val.assume_init() })
+}
+
+/// Write `val` at `addr` with `mem`.
+pub fn write_val<T: Sized + Copy>(mem: &dyn MemAccess, addr: usize, val: &T) -> Result<()> {
+    // Safe because `val` is valid for size_of::<T>() bytes and only read.
+    let bytes =
+        unsafe { std::slice::from_raw_parts((val as *const T).cast::<u8>(), size_of::<T>()) };
+    mem.write_slice(addr, bytes)
+}
+
+/// Repeat `op` with the number of bytes transferred so far until all `len` bytes are
+/// transferred, so that short transfers, i.e. when the range spans many pages, are continued
+/// where they stopped. A transfer that makes no progress fails with EFAULT.
+fn transfer_all<F>(len: usize, mut op: F) -> nix::Result<()>
+where
+    F: FnMut(usize) -> nix::Result<usize>,
+{
+    let mut done = 0;
+    while done < len {
+        match retry_on_eintr(|| op(done))? {
+            0 => return Err(Errno::EFAULT),
+            n => done += n,
+        }
+    }
+    Ok(())
+}
+
+/// Call `f` until it does not fail with ESRCH, at most `ESRCH_RETRIES` more times.
+/// process_vm_readv/writev fail with ESRCH for a moment while the target changes state, i.e.
+/// right after a ptrace stop.
+fn retry_on_esrch<T, F>(mut f: F) -> nix::Result<T>
+where
+    F: FnMut() -> nix::Result<T>,
+{
+    let mut retries = 0;
+    loop {
+        match f() {
+            Err(Errno::ESRCH) if retries < ESRCH_RETRIES => {
+                retries += 1;
+                thread::sleep(ESRCH_RETRY_DELAY);
+            }
+            res => return res,
+        }
+    }
+}
+
+fn vm_readv_all(pid: Pid, addr: usize, buf: &mut [u8]) -> nix::Result<()> {
+    let len = buf.len();
+    transfer_all(len, |done| {
+        let remote_iov = &[RemoteIoVec {
+            base: addr + done,
+            len: len - done,
+        }];
+        retry_on_esrch(|| {
+            process_vm_readv(pid, &mut [IoSliceMut::new(&mut buf[done..])], remote_iov)
+        })
+    })
+}
+
+fn vm_writev_all(pid: Pid, addr: usize, buf: &[u8]) -> nix::Result<()> {
+    transfer_all(buf.len(), |done| {
+        let remote_iov = &[RemoteIoVec {
+            base: addr + done,
+            len: buf.len() - done,
+        }];
+        retry_on_esrch(|| process_vm_writev(pid, &[IoSlice::new(&buf[done..])], remote_iov))
+    })
+}
+
+/// Describe a failed `vm_readv_all`/`vm_writev_all` of `len` bytes at `addr`. ESRCH that
+/// persists after `retry_on_esrch` means the process is gone.
+fn iovec_result(pid: Pid, res: nix::Result<()>, op: &str, len: usize, addr: usize) -> Result<()> {
+    match res {
+        Ok(()) => Ok(()),
+        Err(Errno::ESRCH) => bail!(
+            "cannot {} {} bytes at {:#x}: target process {} disappeared",
+            op,
+            len,
+            addr,
+            pid
+        ),
+        Err(e) => bail!("cannot {} {} bytes at {:#x}: {}", op, len, addr, e),
+    }
+}
+
+/// Fill `buf` with memory of process `pid` starting at `addr` through its shared `HostMem`.
+/// Fails unless all of `buf` could be read.
+pub fn process_read_slice(pid: Pid, addr: usize, buf: &mut [u8]) -> Result<()> {
+    host_mem(pid).read_slice(addr, buf)
+}
+
+/// Write `buf` into the memory of process `pid` starting at `addr`. Like `process_read_slice`,
+/// fails unless all of `buf` could be written.
+pub fn process_write_slice(pid: Pid, addr: usize, buf: &[u8]) -> Result<()> {
+    host_mem(pid).write_slice(addr, buf)
+}
+
+lazy_static! {
+    /// `HostMem` of every process that someone holds one for, i.e. its `Hypervisor`. Entries
+    /// only live as long as that, so a reused pid does not inherit the `HostMem` of a process
+    /// that is gone.
+    static ref HOST_MEMS: Mutex<HashMap<Pid, Weak<HostMem>>> = Mutex::new(HashMap::new());
+}
+
+/// The `HostMem` of process `pid`. All accesses share it while one is alive, so that they fall
+/// back at most once; the `Hypervisor` of `pid` keeps it alive and drops it with itself.
+pub fn host_mem(pid: Pid) -> Arc<HostMem> {
+    let mut mems = HOST_MEMS.lock().unwrap_or_else(PoisonError::into_inner);
+    mems.retain(|_, mem| mem.strong_count() > 0);
+    if let Some(mem) = mems.get(&pid).and_then(Weak::upgrade) {
+        return mem;
+    }
+    let mem = Arc::new(HostMem::new(pid));
+    mems.insert(pid, Arc::downgrade(&mem));
+    mem
+}
+
+/// Reads and writes the memory of another process at its virtual addresses
+pub trait MemAccess: fmt::Debug + Send + Sync {
+    /// Fill `buf` starting at `addr`. Fails unless all of `buf` could be read.
+    fn read_slice(&self, addr: usize, buf: &mut [u8]) -> Result<()>;
+    /// Write `buf` starting at `addr`. Fails unless all of `buf` could be written.
+    fn write_slice(&self, addr: usize, buf: &[u8]) -> Result<()>;
+}
+
+/// Access with process_vm_readv(2) and process_vm_writev(2)
+#[derive(Debug)]
+pub struct IovecMem {
+    pub pid: Pid,
+}
+
+impl MemAccess for IovecMem {
+    fn read_slice(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        let res = vm_readv_all(self.pid, addr, buf);
+        iovec_result(self.pid, res, "read", buf.len(), addr)
+    }
+
+    fn write_slice(&self, addr: usize, buf: &[u8]) -> Result<()> {
+        let res = vm_writev_all(self.pid, addr, buf);
+        iovec_result(self.pid, res, "write", buf.len(), addr)
+    }
+}
+
+/// Access with pread(2) and pwrite(2) on /proc/<pid>/mem, where the file offset is the virtual
+/// address. Requires the same ptrace access mode as the iovec calls, but is not affected by
+/// seccomp filters or kernels that lack them.
+#[derive(Debug)]
+pub struct ProcMem {
+    pid: Pid,
+    file: File,
+}
+
+impl ProcMem {
+    pub fn open(pid: Pid) -> Result<ProcMem> {
+        let path = format!("/proc/{}/mem", pid);
+        let file = try_with!(
+            OpenOptions::new().read(true).write(true).open(&path),
+            "cannot open {}",
+            path
+        );
+        Ok(ProcMem { pid, file })
+    }
+}
+
+impl MemAccess for ProcMem {
+    fn read_slice(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        let len = buf.len();
+        try_with!(
+            transfer_all(len, |done| pread(
+                self.file.as_raw_fd(),
+                &mut buf[done..],
+                (addr + done) as off_t
+            )),
+            "cannot read {} bytes from {:#x} of /proc/{}/mem",
+            len,
+            addr,
+            self.pid
+        );
+        Ok(())
+    }
+
+    fn write_slice(&self, addr: usize, buf: &[u8]) -> Result<()> {
+        try_with!(
+            transfer_all(buf.len(), |done| pwrite(
+                self.file.as_raw_fd(),
+                &buf[done..],
+                (addr + done) as off_t
+            )),
+            "cannot write {} bytes to {:#x} of /proc/{}/mem",
+            buf.len(),
+            addr,
+            self.pid
+        );
+        Ok(())
+    }
+}
+
+/// Uses `IovecMem` and switches to `ProcMem` for good once the iovec calls fail with EPERM or
+/// ENOSYS, i.e. because a sandbox filters them. Other errors, like EFAULT for unmapped memory,
+/// are returned as is.
+#[derive(Debug)]
+pub struct HostMem {
+    iovec: IovecMem,
+    /// opened on the first fallback
+    proc_mem: RwLock<Option<ProcMem>>,
+}
+
+impl HostMem {
+    pub fn new(pid: Pid) -> HostMem {
+        HostMem {
+            iovec: IovecMem { pid },
+            proc_mem: RwLock::new(None),
+        }
+    }
+
+    /// Run `op` on /proc/<pid>/mem if we fell back to it before. Returns None otherwise.
+    fn with_proc_mem<T, F>(&self, op: F) -> Option<Result<T>>
+    where
+        F: FnOnce(&ProcMem) -> Result<T>,
+    {
+        match self.proc_mem.read() {
+            Ok(proc_mem) => proc_mem.as_ref().map(op),
+            Err(_) => Some(Err(simple_error!("cannot obtain proc mem lock: poisoned"))),
+        }
+    }
+
+    /// Switch to /proc/<pid>/mem after the iovec calls failed with `err`.
+    fn fall_back<T, F>(&self, err: Errno, op: F) -> Result<T>
+    where
+        F: FnOnce(&ProcMem) -> Result<T>,
+    {
+        let mut proc_mem = try_with!(
+            self.proc_mem.write(),
+            "cannot obtain proc mem lock: poisoned"
+        );
+        if proc_mem.is_none() {
+            let mem = try_with!(
+                ProcMem::open(self.iovec.pid),
+                "process_vm_readv/writev failed with {} and /proc/{}/mem is not usable either",
+                err,
+                self.iovec.pid
+            );
+            warn!(
+                "process_vm_readv/writev failed with {}, accessing hypervisor memory through /proc/{}/mem instead",
+                err, self.iovec.pid
+            );
+            *proc_mem = Some(mem);
+        }
+        match proc_mem.as_ref() {
+            Some(mem) => op(mem),
+            None => bail!("/proc/{}/mem is not open", self.iovec.pid),
+        }
+    }
+}
+
+fn needs_fallback(err: Errno) -> bool {
+    matches!(err, Errno::EPERM | Errno::ENOSYS)
+}
+
+impl MemAccess for HostMem {
+    fn read_slice(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        if let Some(res) = self.with_proc_mem(|mem| mem.read_slice(addr, buf)) {
+            return res;
+        }
+        match vm_readv_all(self.iovec.pid, addr, buf) {
+            Err(e) if needs_fallback(e) => self.fall_back(e, |mem| mem.read_slice(addr, buf)),
+            res => iovec_result(self.iovec.pid, res, "read", buf.len(), addr),
+        }
+    }
+
+    fn write_slice(&self, addr: usize, buf: &[u8]) -> Result<()> {
+        if let Some(res) = self.with_proc_mem(|mem| mem.write_slice(addr, buf)) {
+            return res;
+        }
+        match vm_writev_all(self.iovec.pid, addr, buf) {
+            Err(e) if needs_fallback(e) => self.fall_back(e, |mem| mem.write_slice(addr, buf)),
+            res => iovec_result(self.iovec.pid, res, "write", buf.len(), addr),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -43,12 +348,17 @@ pub struct HvMem<T: Copy> {
     pub ptr: libc::uintptr_t,
     pub(super) pid: Pid,
     pub(super) tracee: Arc<RwLock<Tracee>>,
+    /// false for memory borrowed from the scratch arena of the tracee, which unmaps it itself
+    pub(super) owned: bool,
     #[allow(dead_code)]
     pub(super) phantom: SendPhantom<T>,
 }
 
 impl<T: Copy> Drop for HvMem<T> {
     fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
         // Useful for debugging
         //warn!("SKIP CLEANUP");
         //return;
@@ -65,7 +375,53 @@ impl<T: Copy> Drop for HvMem<T> {
     }
 }
 
+/// Fails to compile for types that do not fit into the scratch arena, see `HvMem::scratch`
+struct FitsScratch<T>(PhantomData<T>);
+
+impl<T> FitsScratch<T> {
+    const OK: () = assert!(
+        size_of::<T>() <= SCRATCH_SIZE,
+        "type is larger than the scratch arena, use alloc_mem instead"
+    );
+}
+
 impl<T: Copy> HvMem<T> {
+    /// Borrow memory for T from the scratch arena of `tracee`, the locked content of
+    /// `tracee_lock`. Cheaper than mapping memory for short-lived ioctl arguments, but only
+    /// valid while `tracee` stays locked and until the next `Tracee::reset_scratch`.
+    pub(super) fn scratch(
+        tracee_lock: &Arc<RwLock<Tracee>>,
+        tracee: &mut Tracee,
+        pid: Pid,
+    ) -> Result<HvMem<T>> {
+        #[allow(clippy::let_unit_value)]
+        let () = FitsScratch::<T>::OK;
+        let ptr = tracee.scratch_alloc(size_of::<T>(), align_of::<T>())?;
+        Ok(HvMem {
+            ptr,
+            pid,
+            tracee: Arc::clone(tracee_lock),
+            owned: false,
+            phantom: SendPhantom::default(),
+        })
+    }
+
+    /// Lock `tracee_lock`, release earlier scratch allocations and run `f` with memory for T
+    /// from the scratch arena, see `scratch`.
+    pub(super) fn with_scratch<R>(
+        tracee_lock: &Arc<RwLock<Tracee>>,
+        pid: Pid,
+        f: impl FnOnce(&mut Tracee, HvMem<T>) -> Result<R>,
+    ) -> Result<R> {
+        let mut tracee = try_with!(
+            tracee_lock.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.reset_scratch();
+        let mem = HvMem::scratch(tracee_lock, &mut tracee, pid)?;
+        f(&mut tracee, mem)
+    }
+
     pub fn read(&self) -> Result<T> {
         process_read(self.pid, self.ptr as *mut c_void)
     }
@@ -74,11 +430,67 @@ impl<T: Copy> HvMem<T> {
     }
 }
 
+/// Restores the configuration of a memslot, i.e. after `Hypervisor::enable_dirty_logging`,
+/// when dropped. Works regardless of whether the hypervisor is stopped at that point.
+#[derive(Debug)]
+pub struct MemSlotGuard {
+    pub(super) tracee: Arc<RwLock<Tracee>>,
+    pub(super) original: kvmb::kvm_userspace_memory_region,
+    pub(super) restored: bool,
+}
+
+impl MemSlotGuard {
+    /// Memslot this guard restores
+    pub fn slot(&self) -> u32 {
+        self.original.slot
+    }
+
+    /// Restore the memslot now and report errors instead of just logging them.
+    pub fn restore(mut self) -> Result<()> {
+        self.restore_slot()
+    }
+
+    fn restore_slot(&mut self) -> Result<()> {
+        if self.restored {
+            return Ok(());
+        }
+        self.restored = true;
+        let mut tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        // the hypervisor may have been resumed in the meantime
+        let was_attached = tracee.try_get_proc().is_ok();
+        tracee.attach()?;
+        let res = tracee.set_user_memory_region(&self.original);
+        if !was_attached {
+            if let Some(proc) = tracee.detach() {
+                try_with!(proc.detach(), "cannot resume hypervisor");
+            }
+        }
+        try_with!(res, "cannot restore memslot {}", self.original.slot);
+        debug!(
+            "restored flags {:#x} of memslot {}",
+            self.original.flags, self.original.slot
+        );
+        Ok(())
+    }
+}
+
+impl Drop for MemSlotGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.restore_slot() {
+            warn!("{}, the vm may stay slower than before", e);
+        }
+    }
+}
+
 /// Physical Memory attached to a VM. Backed by `PhysMem.mem`.
 #[derive(Debug)]
 pub struct PhysMem<T: Copy> {
     pub mem: HvMem<T>,
-    pub(super) ioctl_arg: HvMem<kvmb::kvm_userspace_memory_region>,
+    /// Memslot registered for `mem`, removed again on drop
+    pub(super) region: kvmb::kvm_userspace_memory_region,
     pub guest_phys_addr: PhysAddr,
 }
 
@@ -88,41 +500,92 @@ impl<T: Copy> Drop for PhysMem<T> {
         //warn!("SKIP CLEANUP");
         //return;
 
-        let tracee = match self.mem.tracee.write() {
+        let mut tracee = match self.mem.tracee.write() {
             Err(e) => {
                 warn!("Could not aquire lock to drop HvMem: {}", e);
                 return;
             }
             Ok(t) => t,
         };
-        let mut ioctl_arg = match self.ioctl_arg.read() {
-            Err(e) => {
-                warn!("Could not read Hypervisor Memory to drop HvMem: {}", e);
-                return;
-            }
-            Ok(t) => t,
+        let region = kvmb::kvm_userspace_memory_region {
+            memory_size: 0, // indicates request for deletion
+            ..self.region
         };
-        ioctl_arg.memory_size = 0; // indicates request for deletion
-        match self.ioctl_arg.write(&ioctl_arg) {
-            Err(e) => {
-                warn!("Could not write to Hypervisor Memory to drop HvMem: {}", e);
-                return;
-            }
-            Ok(t) => t,
-        };
-        let ret =
-            match tracee.vm_ioctl_with_ref(ioctls::KVM_SET_USER_MEMORY_REGION(), &self.ioctl_arg) {
-                Ok(ret) => ret,
-                Err(e) => {
-                    warn!("failed to remove memory from VM: {}", e);
-                    return;
-                }
-            };
-        if ret != 0 {
-            warn!(
-                "ioctl_with_ref to remove memory from VM returned error code: {}",
-                ret
-            )
+        if let Err(e) = tracee.set_user_memory_region(&region) {
+            warn!("failed to remove memory from VM: {}", e);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{host_mem, retry_on_esrch, HostMem, MemAccess, ESRCH_RETRIES, HOST_MEMS};
+    use nix::errno::Errno;
+    use nix::unistd::{getpid, Pid};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_retry_on_esrch() {
+        let mut calls = 0;
+        let res = retry_on_esrch(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(Errno::ESRCH)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(res, Ok(3));
+
+        // the process is gone for good
+        let mut calls = 0;
+        let res = retry_on_esrch::<(), _>(|| {
+            calls += 1;
+            Err(Errno::ESRCH)
+        });
+        assert_eq!(res, Err(Errno::ESRCH));
+        assert_eq!(calls, ESRCH_RETRIES + 1);
+
+        assert_eq!(
+            retry_on_esrch::<(), _>(|| Err(Errno::EFAULT)),
+            Err(Errno::EFAULT)
+        );
+    }
+
+    #[test]
+    fn test_host_mem_fallback() {
+        let mem = HostMem::new(getpid());
+        let src = [1u8, 2, 3, 4];
+        let mut buf = [0u8; 4];
+        assert!(mem.with_proc_mem(|_| Ok(())).is_none());
+        mem.fall_back(Errno::EPERM, |m| {
+            m.read_slice(src.as_ptr() as usize, &mut buf)
+        })
+        .expect("cannot read /proc/self/mem");
+        assert_eq!(buf, src);
+
+        // later accesses go through /proc/self/mem right away
+        assert!(mem.with_proc_mem(|_| Ok(())).is_some());
+        let mut dst = vec![0u8; 4];
+        mem.write_slice(dst.as_mut_ptr() as usize, &src)
+            .expect("cannot write /proc/self/mem");
+        assert_eq!(dst, src);
+        let mut buf = [0u8; 4];
+        mem.read_slice(dst.as_ptr() as usize, &mut buf)
+            .expect("cannot read /proc/self/mem");
+        assert_eq!(buf, src);
+    }
+
+    #[test]
+    fn test_host_mem_shared() {
+        // not processes we access anywhere else in the tests
+        let pid = Pid::from_raw(i32::MAX);
+        let mem = host_mem(pid);
+        assert!(Arc::ptr_eq(&mem, &host_mem(pid)));
+        drop(mem);
+        // the entry goes away with the last owner on the next lookup
+        let _other = host_mem(Pid::from_raw(i32::MAX - 1));
+        let mems = HOST_MEMS.lock().expect("cannot lock host mems");
+        assert!(!mems.contains_key(&pid));
+    }
+}
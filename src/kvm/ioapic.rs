@@ -0,0 +1,142 @@
+//! Decoder for the IOAPIC redirection table as returned by KVM_GET_IRQCHIP, see the 82093AA
+//! IOAPIC datasheet, 3.2.4 "IOREDTBL[23:0]—I/O Redirection Table Registers".
+//!
+//! KVM has no ioctl to read back the GSI routing table set with KVM_SET_GSI_ROUTING. With the
+//! default routing GSI n is IOAPIC pin n, so the redirection table is what decides where an
+//! interrupt on a GSI ends up.
+
+use kvm_bindings as kvmb;
+use std::fmt;
+
+const VECTOR_MASK: u64 = 0xff;
+const DELIVERY_MODE_SHIFT: u64 = 8;
+const DEST_MODE_LOGICAL: u64 = 1 << 11;
+const POLARITY_LOW: u64 = 1 << 13;
+const TRIGGER_LEVEL: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+const DEST_SHIFT: u64 = 56;
+
+/// Where an interrupt raised on `gsi` is delivered to
+#[derive(Clone, Debug, PartialEq)]
+pub struct IrqRoute {
+    pub gsi: u32,
+    pub vector: u8,
+    /// fixed, lowest priority, SMI, NMI, INIT or ExtINT
+    pub delivery_mode: u8,
+    /// `dest` is a logical rather than a physical APIC id
+    pub logical_dest: bool,
+    pub active_low: bool,
+    pub level_triggered: bool,
+    pub masked: bool,
+    pub dest: u8,
+}
+
+impl IrqRoute {
+    #[must_use]
+    pub fn from_entry(gsi: u32, entry: u64) -> IrqRoute {
+        IrqRoute {
+            gsi,
+            vector: (entry & VECTOR_MASK) as u8,
+            delivery_mode: ((entry >> DELIVERY_MODE_SHIFT) & 0b111) as u8,
+            logical_dest: entry & DEST_MODE_LOGICAL != 0,
+            active_low: entry & POLARITY_LOW != 0,
+            level_triggered: entry & TRIGGER_LEVEL != 0,
+            masked: entry & MASKED != 0,
+            dest: (entry >> DEST_SHIFT) as u8,
+        }
+    }
+
+    /// A guest driver has set up this pin. Unused pins stay masked, which is also the reset
+    /// state.
+    #[must_use]
+    pub fn in_use(&self) -> bool {
+        !self.masked
+    }
+
+    fn delivery_mode_name(&self) -> &'static str {
+        match self.delivery_mode {
+            0 => "fixed",
+            1 => "lowest-priority",
+            2 => "smi",
+            4 => "nmi",
+            5 => "init",
+            7 => "extint",
+            _ => "reserved",
+        }
+    }
+}
+
+impl fmt::Display for IrqRoute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "gsi {:<2} -> vector={:#04x} {} dest={} ({}) {} {}{}",
+            self.gsi,
+            self.vector,
+            self.delivery_mode_name(),
+            self.dest,
+            if self.logical_dest {
+                "logical"
+            } else {
+                "physical"
+            },
+            if self.level_triggered {
+                "level"
+            } else {
+                "edge"
+            },
+            if self.active_low { "low" } else { "high" },
+            if self.masked { " masked" } else { "" }
+        )
+    }
+}
+
+/// All pins of the IOAPIC in `state`
+#[must_use]
+pub fn ioapic_routes(state: &kvmb::kvm_ioapic_state) -> Vec<IrqRoute> {
+    state
+        .redirtbl
+        .iter()
+        .enumerate()
+        // Safe because every bit pattern is a valid u64.
+        .map(|(gsi, entry)| IrqRoute::from_entry(gsi as u32, unsafe { entry.bits }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_ioapic() {
+        let mut state = kvmb::kvm_ioapic_state::default();
+        for entry in state.redirtbl.iter_mut() {
+            entry.bits = MASKED;
+        }
+        // serial port: vector 0x24 to apic 1, level triggered, active low
+        state.redirtbl[4].bits = (1 << DEST_SHIFT) | TRIGGER_LEVEL | POLARITY_LOW | 0x24;
+
+        let routes = ioapic_routes(&state);
+        assert_eq!(routes.len(), 24);
+        let used = routes.iter().filter(|r| r.in_use()).collect::<Vec<_>>();
+        assert_eq!(used.len(), 1);
+        assert_eq!(
+            *used[0],
+            IrqRoute {
+                gsi: 4,
+                vector: 0x24,
+                delivery_mode: 0,
+                logical_dest: false,
+                active_low: true,
+                level_triggered: true,
+                masked: false,
+                dest: 1,
+            }
+        );
+        assert_eq!(
+            used[0].to_string(),
+            "gsi 4  -> vector=0x24 fixed dest=1 (physical) level low"
+        );
+        assert!(routes[5].to_string().ends_with(" masked"));
+    }
+}
@@ -176,7 +176,7 @@ macro_rules! ioctl_iowr_nr {
 // Define IOC_* constants in a module so that we can allow missing docs on it.
 // There is not much value in documenting these as it is code generated from
 // kernel definitions.
-use std::os::raw::c_uint;
+use std::os::raw::{c_uint, c_ulong};
 
 const _IOC_NRBITS: c_uint = 8;
 const _IOC_TYPEBITS: c_uint = 8;
@@ -210,6 +210,12 @@ ioctl_iow_nr!(KVM_IOEVENTFD, KVMIO, 0x79, kvmb::kvm_ioeventfd);
 // Available with KVM_CAP_IRQFD
 ioctl_iow_nr!(KVM_IRQFD, KVMIO, 0x76, kvmb::kvm_irqfd);
 
+// Available with KVM_CAP_ADJUST_CLOCK
+ioctl_ior_nr!(KVM_GET_CLOCK, KVMIO, 0x7c, kvmb::kvm_clock_data);
+
+// Available with KVM_CAP_SIGNAL_MSI
+ioctl_iow_nr!(KVM_SIGNAL_MSI, KVMIO, 0xa5, kvmb::kvm_msi);
+
 // Available with KVM_CAP_USER_MEMORY
 ioctl_iow_nr!(
     KVM_SET_USER_MEMORY_REGION,
@@ -244,6 +250,13 @@ ioctl_iow_nr!(KVM_SET_REGS, KVMIO, 0x82, kvmb::kvm_regs);
     target_arch = "powerpc64"
 ))]
 ioctl_ior_nr!(KVM_GET_SREGS, KVMIO, 0x83, kvmb::kvm_sregs);
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64"
+))]
+ioctl_iow_nr!(KVM_SET_SREGS, KVMIO, 0x84, kvmb::kvm_sregs);
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 ioctl_ior_nr!(KVM_GET_FPU, KVMIO, 0x8c, kvmb::kvm_fpu);
@@ -252,6 +265,17 @@ ioctl_iow_nr!(KVM_SET_FPU, KVMIO, 0x8d, kvmb::kvm_fpu);
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 ioctl_iowr_nr!(KVM_GET_MSRS, KVMIO, 0x88, kvmb::kvm_msrs);
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_INTERRUPT, KVMIO, 0x86, kvmb::kvm_interrupt);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_LAPIC, KVMIO, 0x8e, kvmb::kvm_lapic_state);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_LAPIC, KVMIO, 0x8f, kvmb::kvm_lapic_state);
+// Available with KVM_CAP_SET_GUEST_DEBUG
+ioctl_iow_nr!(KVM_SET_GUEST_DEBUG, KVMIO, 0x9b, kvmb::kvm_guest_debug);
+// Available with KVM_CAP_GET_TSC_KHZ
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_io_nr!(KVM_GET_TSC_KHZ, KVMIO, 0xa3);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 
 /// according to arch/x86/include/asm/kvm_host.h
 pub const KVM_MAX_CPUID_ENTRIES: usize = 256;
@@ -266,5 +290,165 @@ pub struct kvm_cpuid2 {
 }
 ioctl_iowr_nr!(KVM_GET_CPUID2, KVMIO, 0x91, kvmb::kvm_cpuid2);
 
+ioctl_iow_nr!(KVM_IRQ_LINE, KVMIO, 0x61, kvmb::kvm_irq_level);
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 ioctl_iowr_nr!(KVM_GET_IRQCHIP, KVMIO, 0x62, kvmb::kvm_irqchip);
+
+ioctl_iow_nr!(KVM_GET_DIRTY_LOG, KVMIO, 0x42, kvmb::kvm_dirty_log);
+ioctl_iowr_nr!(KVM_CLEAR_DIRTY_LOG, KVMIO, 0xc0, kvmb::kvm_clear_dirty_log);
+ioctl_ior_nr!(KVM_GET_MP_STATE, KVMIO, 0x98, kvmb::kvm_mp_state);
+ioctl_iow_nr!(KVM_SET_MP_STATE, KVMIO, 0x99, kvmb::kvm_mp_state);
+
+// Not issued by vmsh, only defined so `ioctl_name` can decode them when tracing the hypervisor.
+ioctl_io_nr!(KVM_GET_API_VERSION, KVMIO, 0x00);
+ioctl_io_nr!(KVM_CREATE_VM, KVMIO, 0x01);
+ioctl_io_nr!(KVM_GET_VCPU_MMAP_SIZE, KVMIO, 0x04);
+ioctl_io_nr!(KVM_CREATE_VCPU, KVMIO, 0x41);
+ioctl_io_nr!(KVM_CREATE_IRQCHIP, KVMIO, 0x60);
+ioctl_iowr_nr!(KVM_IRQ_LINE_STATUS, KVMIO, 0x67, kvmb::kvm_irq_level);
+ioctl_iow_nr!(KVM_SET_GSI_ROUTING, KVMIO, 0x6a, kvmb::kvm_irq_routing);
+ioctl_iow_nr!(KVM_SET_CLOCK, KVMIO, 0x7b, kvmb::kvm_clock_data);
+ioctl_iow_nr!(KVM_SET_SIGNAL_MASK, KVMIO, 0x8b, kvmb::kvm_signal_mask);
+ioctl_iow_nr!(KVM_ENABLE_CAP, KVMIO, 0xa3, kvmb::kvm_enable_cap);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iowr_nr!(KVM_GET_SUPPORTED_CPUID, KVMIO, 0x05, kvmb::kvm_cpuid2);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_io_nr!(KVM_SET_TSS_ADDR, KVMIO, 0x47);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_IDENTITY_MAP_ADDR, KVMIO, 0x48, u64);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_SET_IRQCHIP, KVMIO, 0x63, kvmb::kvm_irqchip);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_CREATE_PIT2, KVMIO, 0x77, kvmb::kvm_pit_config);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_MSRS, KVMIO, 0x89, kvmb::kvm_msrs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_CPUID2, KVMIO, 0x90, kvmb::kvm_cpuid2);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_VCPU_EVENTS, KVMIO, 0x9f, kvmb::kvm_vcpu_events);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_VCPU_EVENTS, KVMIO, 0xa0, kvmb::kvm_vcpu_events);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_DEBUGREGS, KVMIO, 0xa1, kvmb::kvm_debugregs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_DEBUGREGS, KVMIO, 0xa2, kvmb::kvm_debugregs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_io_nr!(KVM_SET_TSC_KHZ, KVMIO, 0xa2);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_XSAVE, KVMIO, 0xa4, kvmb::kvm_xsave);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_XSAVE, KVMIO, 0xa5, kvmb::kvm_xsave);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_XCRS, KVMIO, 0xa6, kvmb::kvm_xcrs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_XCRS, KVMIO, 0xa7, kvmb::kvm_xcrs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_io_nr!(KVM_KVMCLOCK_CTRL, KVMIO, 0xad);
+
+/// Name of the KVM ioctl `request`, if it is one we know.
+pub fn ioctl_name(request: c_ulong) -> Option<&'static str> {
+    let mut names = vec![
+        (KVM_GET_API_VERSION(), "KVM_GET_API_VERSION"),
+        (KVM_CREATE_VM(), "KVM_CREATE_VM"),
+        (KVM_CHECK_EXTENSION(), "KVM_CHECK_EXTENSION"),
+        (KVM_GET_VCPU_MMAP_SIZE(), "KVM_GET_VCPU_MMAP_SIZE"),
+        (KVM_CREATE_VCPU(), "KVM_CREATE_VCPU"),
+        (KVM_GET_DIRTY_LOG(), "KVM_GET_DIRTY_LOG"),
+        (KVM_CLEAR_DIRTY_LOG(), "KVM_CLEAR_DIRTY_LOG"),
+        (KVM_SET_USER_MEMORY_REGION(), "KVM_SET_USER_MEMORY_REGION"),
+        (KVM_SET_IOREGION(), "KVM_SET_IOREGION"),
+        (KVM_CREATE_IRQCHIP(), "KVM_CREATE_IRQCHIP"),
+        (KVM_IRQ_LINE(), "KVM_IRQ_LINE"),
+        (KVM_IRQ_LINE_STATUS(), "KVM_IRQ_LINE_STATUS"),
+        (KVM_SET_GSI_ROUTING(), "KVM_SET_GSI_ROUTING"),
+        (KVM_IRQFD(), "KVM_IRQFD"),
+        (KVM_IOEVENTFD(), "KVM_IOEVENTFD"),
+        (KVM_SET_CLOCK(), "KVM_SET_CLOCK"),
+        (KVM_GET_CLOCK(), "KVM_GET_CLOCK"),
+        (KVM_RUN(), "KVM_RUN"),
+        (KVM_SET_SIGNAL_MASK(), "KVM_SET_SIGNAL_MASK"),
+        (KVM_GET_MP_STATE(), "KVM_GET_MP_STATE"),
+        (KVM_SET_MP_STATE(), "KVM_SET_MP_STATE"),
+        (KVM_SET_GUEST_DEBUG(), "KVM_SET_GUEST_DEBUG"),
+        (KVM_ENABLE_CAP(), "KVM_ENABLE_CAP"),
+        (KVM_SIGNAL_MSI(), "KVM_SIGNAL_MSI"),
+    ];
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    names.extend_from_slice(&[
+        (KVM_GET_REGS(), "KVM_GET_REGS"),
+        (KVM_SET_REGS(), "KVM_SET_REGS"),
+    ]);
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    names.extend_from_slice(&[
+        (KVM_GET_SUPPORTED_CPUID(), "KVM_GET_SUPPORTED_CPUID"),
+        (KVM_SET_TSS_ADDR(), "KVM_SET_TSS_ADDR"),
+        (KVM_SET_IDENTITY_MAP_ADDR(), "KVM_SET_IDENTITY_MAP_ADDR"),
+        (KVM_GET_IRQCHIP(), "KVM_GET_IRQCHIP"),
+        (KVM_SET_IRQCHIP(), "KVM_SET_IRQCHIP"),
+        (KVM_CREATE_PIT2(), "KVM_CREATE_PIT2"),
+        (KVM_GET_SREGS(), "KVM_GET_SREGS"),
+        (KVM_SET_SREGS(), "KVM_SET_SREGS"),
+        (KVM_INTERRUPT(), "KVM_INTERRUPT"),
+        (KVM_GET_MSRS(), "KVM_GET_MSRS"),
+        (KVM_SET_MSRS(), "KVM_SET_MSRS"),
+        (KVM_GET_FPU(), "KVM_GET_FPU"),
+        (KVM_SET_FPU(), "KVM_SET_FPU"),
+        (KVM_GET_LAPIC(), "KVM_GET_LAPIC"),
+        (KVM_SET_LAPIC(), "KVM_SET_LAPIC"),
+        (KVM_SET_CPUID2(), "KVM_SET_CPUID2"),
+        (KVM_GET_CPUID2(), "KVM_GET_CPUID2"),
+        (KVM_GET_VCPU_EVENTS(), "KVM_GET_VCPU_EVENTS"),
+        (KVM_SET_VCPU_EVENTS(), "KVM_SET_VCPU_EVENTS"),
+        (KVM_GET_DEBUGREGS(), "KVM_GET_DEBUGREGS"),
+        (KVM_SET_DEBUGREGS(), "KVM_SET_DEBUGREGS"),
+        (KVM_SET_TSC_KHZ(), "KVM_SET_TSC_KHZ"),
+        (KVM_GET_TSC_KHZ(), "KVM_GET_TSC_KHZ"),
+        (KVM_GET_XSAVE(), "KVM_GET_XSAVE"),
+        (KVM_SET_XSAVE(), "KVM_SET_XSAVE"),
+        (KVM_GET_XCRS(), "KVM_GET_XCRS"),
+        (KVM_SET_XCRS(), "KVM_SET_XCRS"),
+        (KVM_KVMCLOCK_CTRL(), "KVM_KVMCLOCK_CTRL"),
+    ]);
+    names
+        .into_iter()
+        .find(|(nr, _)| *nr == request)
+        .map(|(_, name)| name)
+}
+
+/// `ioctl_name` of `request`, or its direction, type, number and size in the notation of the
+/// `_IOC` macros if we do not know it, i.e. `_IOW(0xae, 0x7, 16)`.
+pub fn format_request(request: c_ulong) -> String {
+    if let Some(name) = ioctl_name(request) {
+        return name.to_string();
+    }
+    let request = request as c_uint;
+    let dir = match (request >> _IOC_DIRSHIFT) & _IOC_DIRMASK {
+        _IOC_NONE => "_IO",
+        _IOC_WRITE => "_IOW",
+        _IOC_READ => "_IOR",
+        _ => "_IOWR",
+    };
+    format!(
+        "{}({:#x}, {:#x}, {})",
+        dir,
+        (request >> _IOC_TYPESHIFT) & _IOC_TYPEMASK,
+        (request >> _IOC_NRSHIFT) & _IOC_NRMASK,
+        (request >> _IOC_SIZESHIFT) & _IOC_SIZEMASK
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_request, ioctl_name, KVM_IRQ_LINE, KVM_RUN};
+
+    #[test]
+    fn test_ioctl_name() {
+        assert_eq!(ioctl_name(0xae80), Some("KVM_RUN"));
+        assert_eq!(ioctl_name(KVM_RUN()), Some("KVM_RUN"));
+        assert_eq!(format_request(KVM_IRQ_LINE()), "KVM_IRQ_LINE");
+        // TCGETS is not a KVM ioctl
+        assert_eq!(ioctl_name(0x5401), None);
+        assert_eq!(format_request(0x5401), "_IO(0x54, 0x1, 0)");
+        assert_eq!(format_request(0x4010_aeff), "_IOW(0xae, 0xff, 16)");
+    }
+}
@@ -0,0 +1,181 @@
+//! Decoder for the local APIC register page as returned by KVM_GET_LAPIC.
+//! Offsets are from the Intel SDM Vol. 3, Table 10-1 "Local APIC Register Address Map".
+
+use kvm_bindings as kvmb;
+use std::fmt;
+
+pub const APIC_ID: usize = 0x20;
+pub const APIC_VERSION: usize = 0x30;
+pub const APIC_TPR: usize = 0x80;
+pub const APIC_PPR: usize = 0xa0;
+pub const APIC_SVR: usize = 0xf0;
+pub const APIC_ISR: usize = 0x100;
+pub const APIC_TMR: usize = 0x180;
+pub const APIC_IRR: usize = 0x200;
+pub const APIC_ESR: usize = 0x280;
+pub const APIC_ICR_LOW: usize = 0x300;
+pub const APIC_ICR_HIGH: usize = 0x310;
+pub const APIC_LVT_TIMER: usize = 0x320;
+pub const APIC_LVT_LINT0: usize = 0x350;
+pub const APIC_LVT_LINT1: usize = 0x360;
+pub const APIC_LVT_ERROR: usize = 0x370;
+pub const APIC_TIMER_INITIAL_COUNT: usize = 0x380;
+pub const APIC_TIMER_CURRENT_COUNT: usize = 0x390;
+pub const APIC_TIMER_DIVIDE: usize = 0x3e0;
+
+/// LVT entries with this bit set do not deliver interrupts
+const LVT_MASKED: u32 = 1 << 16;
+
+#[derive(Clone, Copy)]
+pub struct Lapic(pub kvmb::kvm_lapic_state);
+
+impl Lapic {
+    /// 32-bit register at `offset` of the register page
+    #[must_use]
+    pub fn reg(&self, offset: usize) -> u32 {
+        let r = &self.0.regs[offset..offset + 4];
+        u32::from_le_bytes([r[0] as u8, r[1] as u8, r[2] as u8, r[3] as u8])
+    }
+
+    pub fn set_reg(&mut self, offset: usize, val: u32) {
+        for (i, b) in val.to_le_bytes().iter().enumerate() {
+            self.0.regs[offset + i] = *b as _;
+        }
+    }
+
+    /// Vectors set in one of the 256-bit registers ISR, TMR or IRR. They are spread over eight
+    /// 32-bit registers, 16 bytes apart.
+    fn vectors(&self, base: usize) -> Vec<u8> {
+        (0..256)
+            .filter(|v| self.reg(base + (v / 32) * 0x10) & (1 << (v % 32)) != 0)
+            .map(|v| v as u8)
+            .collect()
+    }
+
+    /// Interrupts currently being serviced
+    #[must_use]
+    pub fn isr(&self) -> Vec<u8> {
+        self.vectors(APIC_ISR)
+    }
+
+    /// Interrupts accepted but not yet delivered to the cpu
+    #[must_use]
+    pub fn irr(&self) -> Vec<u8> {
+        self.vectors(APIC_IRR)
+    }
+
+    /// Level-triggered interrupts
+    #[must_use]
+    pub fn tmr(&self) -> Vec<u8> {
+        self.vectors(APIC_TMR)
+    }
+
+    #[must_use]
+    pub fn id(&self) -> u32 {
+        self.reg(APIC_ID) >> 24
+    }
+
+    /// Software enable bit of the spurious interrupt vector register
+    #[must_use]
+    pub fn enabled(&self) -> bool {
+        self.reg(APIC_SVR) & (1 << 8) != 0
+    }
+
+    fn timer_mode(&self) -> &'static str {
+        match (self.reg(APIC_LVT_TIMER) >> 17) & 0b11 {
+            0 => "one-shot",
+            1 => "periodic",
+            2 => "tsc-deadline",
+            _ => "reserved",
+        }
+    }
+
+    fn timer_divisor(&self) -> u32 {
+        let d = self.reg(APIC_TIMER_DIVIDE);
+        // bits 0, 1 and 3 encode a power of two, 0b111 means divide by 1
+        match (d & 0b11) | ((d >> 1) & 0b100) {
+            0b111 => 1,
+            shift => 2 << shift,
+        }
+    }
+}
+
+fn lvt(f: &mut fmt::Formatter, name: &str, val: u32) -> fmt::Result {
+    writeln!(
+        f,
+        "{:<7} vector={:#04x}{}",
+        name,
+        val & 0xff,
+        if val & LVT_MASKED != 0 { " masked" } else { "" }
+    )
+}
+
+/// Multi-line summary of the registers relevant for interrupt debugging
+impl fmt::Display for Lapic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "id={} version={:#x} {} tpr={:#x} ppr={:#x} esr={:#x}",
+            self.id(),
+            self.reg(APIC_VERSION) & 0xff,
+            if self.enabled() {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            self.reg(APIC_TPR),
+            self.reg(APIC_PPR),
+            self.reg(APIC_ESR),
+        )?;
+        writeln!(f, "isr     {:x?}", self.isr())?;
+        writeln!(f, "irr     {:x?}", self.irr())?;
+        writeln!(f, "tmr     {:x?}", self.tmr())?;
+        writeln!(
+            f,
+            "icr     {:#010x}:{:#010x}",
+            self.reg(APIC_ICR_HIGH),
+            self.reg(APIC_ICR_LOW)
+        )?;
+        lvt(f, "timer", self.reg(APIC_LVT_TIMER))?;
+        writeln!(
+            f,
+            "        {} initial={} current={} divisor={}",
+            self.timer_mode(),
+            self.reg(APIC_TIMER_INITIAL_COUNT),
+            self.reg(APIC_TIMER_CURRENT_COUNT),
+            self.timer_divisor()
+        )?;
+        lvt(f, "lint0", self.reg(APIC_LVT_LINT0))?;
+        lvt(f, "lint1", self.reg(APIC_LVT_LINT1))?;
+        lvt(f, "error", self.reg(APIC_LVT_ERROR))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_lapic() {
+        let mut lapic = Lapic(kvmb::kvm_lapic_state::default());
+        lapic.set_reg(APIC_ID, 3 << 24);
+        lapic.set_reg(APIC_SVR, 0x1ff);
+        // vector 0x30 in the second ISR register, 0xec in the last IRR register
+        lapic.set_reg(APIC_ISR + 0x10, 1 << 16);
+        lapic.set_reg(APIC_IRR + 0x70, 1 << 12);
+        lapic.set_reg(APIC_LVT_TIMER, (1 << 17) | LVT_MASKED | 0xec);
+        lapic.set_reg(APIC_TIMER_DIVIDE, 0b1011);
+
+        assert_eq!(lapic.id(), 3);
+        assert!(lapic.enabled());
+        assert_eq!(lapic.isr(), vec![0x30]);
+        assert_eq!(lapic.irr(), vec![0xec]);
+        assert!(lapic.tmr().is_empty());
+        assert_eq!(lapic.timer_mode(), "periodic");
+        assert_eq!(lapic.timer_divisor(), 1);
+
+        let out = lapic.to_string();
+        assert!(out.contains("isr     [30]"), "{}", out);
+        assert!(out.contains("timer   vector=0xec masked"), "{}", out);
+    }
+}
@@ -1,14 +1,16 @@
 use bcc::perf_event::PerfMapBuilder;
 use bcc::{BPFBuilder, Kprobe, BPF};
 use core::slice::from_raw_parts as make_slice;
+use kvm_bindings as kvmb;
 use libc::{c_ulong, size_t};
-use log::warn;
+use log::{debug, warn};
 use nix::sys::utsname::uname;
 use nix::unistd::Pid;
 use simple_error::bail;
 use simple_error::require_with;
 use simple_error::simple_error;
 use simple_error::try_with;
+use std::ops::Range;
 use std::sync::mpsc::channel;
 use std::time::Duration;
 use std::{fmt, ptr};
@@ -16,7 +18,7 @@ use std::{fmt, ptr};
 use crate::kvm::hypervisor;
 use crate::result::Result;
 use crate::tracer::proc::openpid;
-use crate::tracer::proc::{self, Mapping};
+use crate::tracer::proc::{self, Mapping, ProcFiles};
 use crate::{kvm::tracee::Tracee, page_math::page_size};
 
 #[derive(Clone, Debug)]
@@ -25,6 +27,8 @@ pub struct MemSlot {
     base_gfn: u64,
     npages: c_ulong,
     userspace_addr: c_ulong,
+    flags: u32,
+    id: u32,
 }
 
 impl MemSlot {
@@ -43,18 +47,41 @@ impl MemSlot {
     pub fn physical_start(&self) -> usize {
         (self.base_gfn as usize) * page_size()
     }
+
+    /// Slot number as passed to KVM_SET_USER_MEMORY_REGION
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// KVM_MEM_* flags
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// The ioctl argument that would (re-)create this slot as it is
+    pub fn region(&self) -> kvmb::kvm_userspace_memory_region {
+        kvmb::kvm_userspace_memory_region {
+            slot: self.id,
+            flags: self.flags,
+            guest_phys_addr: self.physical_start() as u64,
+            memory_size: self.size() as u64,
+            userspace_addr: self.userspace_addr as u64,
+        }
+    }
 }
 
 impl fmt::Display for MemSlot {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "MemSlot {{ start={:#x}, end={:#x}, size={:#x}, physical_start={:#x}, physical_end = {:#x} }}",
+            "MemSlot {{ id={}, start={:#x}, end={:#x}, size={:#x}, physical_start={:#x}, physical_end = {:#x}, flags={:#x} }}",
+            self.id,
             self.start(),
             self.end(),
             self.size(),
             self.physical_start(),
             self.physical_start() + self.size(),
+            self.flags,
         )
     }
 }
@@ -66,6 +93,8 @@ struct memslot {
     gfn_t base_gfn;
     unsigned long npages;
     unsigned long userspace_addr;
+    u32 flags;
+    u32 id;
 };
 
 // KVM_MEM_SLOTS_NUM became to big to handle it in ebpf
@@ -120,6 +149,8 @@ void kvm_vm_ioctl(struct pt_regs *ctx, struct file *filp) {
         out_slot->base_gfn = slot->base_gfn;
         out_slot->npages = slot->npages;
         out_slot->userspace_addr = slot->userspace_addr;
+        out_slot->flags = slot->flags;
+        out_slot->id = slot->id;
         out->used_slots++;
 
         struct rb_node* left_child = node->rb_left;
@@ -143,6 +174,8 @@ void kvm_vm_ioctl(struct pt_regs *ctx, struct file *filp) {
       out_slot->base_gfn = in_slot->base_gfn;
       out_slot->npages = in_slot->npages;
       out_slot->userspace_addr = in_slot->userspace_addr;
+      out_slot->flags = in_slot->flags;
+      out_slot->id = in_slot->id;
     }
 #endif
 
@@ -186,7 +219,9 @@ pub fn fetch_mappings(pid: Pid) -> Result<Vec<Mapping>> {
     Ok(mappings)
 }
 
-pub fn get_maps(tracee: &Tracee) -> Result<Vec<Mapping>> {
+/// Memslots of the first address space as seen by the kernel. Slots of the SMM address space
+/// are not included.
+pub fn get_memslots(tracee: &Tracee) -> Result<Vec<MemSlot>> {
     let mut module = bpf_prog(tracee.pid())?;
     try_with!(
         Kprobe::new()
@@ -210,7 +245,17 @@ pub fn get_maps(tracee: &Tracee) -> Result<Vec<Mapping>> {
         })
     });
     let mut perf_map = try_with!(builder.build(), "could not install perf event handler");
-    try_with!(tracee.check_extension(0), "cannot query kvm extensions");
+    // any vm ioctl triggers the kprobe, this one also tells us whether there is more to see
+    let address_spaces = try_with!(
+        tracee.check_extension(kvmb::KVM_CAP_MULTI_ADDRESS_SPACE as i32),
+        "cannot query kvm extensions"
+    );
+    if address_spaces > 1 {
+        debug!(
+            "kvm supports {} address spaces (i.e. for SMM), only reading memslots of the first one",
+            address_spaces
+        );
+    }
 
     perf_map.poll(0);
     let memslots = try_with!(
@@ -223,11 +268,39 @@ pub fn get_maps(tracee: &Tracee) -> Result<Vec<Mapping>> {
 We might miss physical memory allocations."
         );
     }
+    Ok(memslots)
+}
+
+/// Compare the hypervisor address range `slot` of a memslot with `mapping`, the entry of
+/// /proc/<pid>/maps that contains its start. Mappings covering several memslots are normal,
+/// i.e. qemu registers RAM below and above 4G as separate slots of one mapping. A slot that
+/// extends past its mapping means the maps layout was split behind KVM's back, i.e. by
+/// transparent hugepages or mprotect, and the flags of `mapping` may not apply to all of it.
+fn slot_mapping_mismatch(slot: &Range<usize>, mapping: &Mapping) -> Option<String> {
+    if slot.end <= mapping.end {
+        return None;
+    }
+    Some(format!(
+        "memslot at {:#x}-{:#x} spans more than the mapping {:#x}-{:#x} in /proc/<pid>/maps",
+        slot.start, slot.end, mapping.start, mapping.end
+    ))
+}
+
+/// Guest memory mappings. KVM's memslots are authoritative for the layout, host and physical
+/// addresses; /proc/<pid>/maps only contributes flags and pathnames.
+pub fn get_maps(tracee: &Tracee) -> Result<Vec<Mapping>> {
+    let memslots = get_memslots(tracee)?;
     let mappings = fetch_mappings(tracee.pid())?;
     memslots
         .iter()
         .map(|slot| match proc::find_mapping(&mappings, slot.start()) {
             Some(mut m) => {
+                if let Some(mismatch) = slot_mapping_mismatch(&(slot.start()..slot.end()), &m) {
+                    warn!(
+                        "{}, unusual memory layout (transparent hugepages?), using the memslot layout",
+                        mismatch
+                    );
+                }
                 m.start = slot.start();
                 m.end = slot.end();
                 m.phys_addr = slot.physical_start();
@@ -242,9 +315,34 @@ We might miss physical memory allocations."
         .collect()
 }
 
-/// ordered list of the hypervisor memory mapped to [vcpu0fd, vcpu1fd, ...]
+/// Mappings of vcpu fds, sorted by vcpu index. A mapping outlives the fd it was created from,
+/// so mappings of vcpus that are no longer open (i.e. of a VM that was shut down) are dropped.
 pub fn get_vcpu_maps(pid: Pid) -> Result<Vec<Mapping>> {
-    let mappings = fetch_mappings(pid)?;
+    let handle = try_with!(openpid(pid), "cannot open handle in proc");
+    find_vcpu_maps(&handle)
+}
+
+fn find_vcpu_maps(handle: &impl ProcFiles) -> Result<Vec<Mapping>> {
+    let mappings = try_with!(handle.maps(), "cannot read process maps");
+    let open_vcpus = match handle.fds() {
+        Ok(fds) => Some(
+            fds.iter()
+                .filter_map(|fd| fd.path.to_str())
+                .filter(|name| name.starts_with(hypervisor::VCPUFD_INODE_NAME_STARTS_WITH))
+                .map(String::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => {
+            warn!("cannot check which vcpu mappings belong to open fds: {}", e);
+            None
+        }
+    };
+    select_vcpu_maps(mappings, open_vcpus.as_deref())
+}
+
+/// Mappings of vcpu fds whose name is in `open_vcpus` (all of them if None), sorted by vcpu
+/// index.
+fn select_vcpu_maps(mappings: Vec<Mapping>, open_vcpus: Option<&[String]>) -> Result<Vec<Mapping>> {
     let vcpu_maps = mappings.into_iter().filter(|m| {
         m.pathname
             .starts_with(hypervisor::VCPUFD_INODE_NAME_STARTS_WITH)
@@ -253,6 +351,15 @@ pub fn get_vcpu_maps(pid: Pid) -> Result<Vec<Mapping>> {
     // we need a for loop, because we can not return errors from within a .sort() lambda.
     let mut taged_maps = vec![]; // (vcpunr, vcpu_map)
     for vcpu_map in vcpu_maps {
+        if let Some(open) = open_vcpus {
+            if !open.contains(&vcpu_map.pathname) {
+                debug!(
+                    "ignore mapping {:#x}-{:#x} of closed {}",
+                    vcpu_map.start, vcpu_map.end, vcpu_map.pathname
+                );
+                continue;
+            }
+        }
         let ao: Option<&str> = vcpu_map
             .pathname
             .strip_prefix(hypervisor::VCPUFD_INODE_NAME_STARTS_WITH);
@@ -270,7 +377,88 @@ pub fn get_vcpu_maps(pid: Pid) -> Result<Vec<Mapping>> {
         taged_maps.push((ai, vcpu_map));
     }
 
-    taged_maps.sort_unstable_by_key(|(i, _map)| *i);
+    // stable, so maps of vcpus with the same index in different VMs stay in address order
+    taged_maps.sort_by_key(|(i, _map)| *i);
     let sorted_maps = taged_maps.into_iter().map(|(_i, map)| map).collect();
     Ok(sorted_maps)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{find_vcpu_maps, select_vcpu_maps, slot_mapping_mismatch};
+    use crate::tracer::proc::Mapping;
+    use crate::tracer::testutils::{mapping, FakeProc};
+    use nix::errno::Errno;
+    use nix::unistd::Pid;
+
+    fn vcpu_map(start: usize, pathname: &str) -> Mapping {
+        Mapping {
+            pathname: pathname.into(),
+            ..mapping(start, 0, 0x3000)
+        }
+    }
+
+    #[test]
+    fn test_select_vcpu_maps() {
+        let maps = vec![
+            vcpu_map(0x7f00_0000_0000, "/usr/lib/libc.so.6"),
+            vcpu_map(0x7f00_0001_0000, "anon_inode:kvm-vcpu:1"),
+            vcpu_map(0x7f00_0002_0000, "anon_inode:kvm-vcpu:0"),
+            // left over from a vcpu whose fd was closed
+            vcpu_map(0x7f00_0003_0000, "anon_inode:kvm-vcpu:2"),
+        ];
+        let open = vec![
+            "anon_inode:kvm-vcpu:0".to_string(),
+            "anon_inode:kvm-vcpu:1".to_string(),
+        ];
+        let selected = select_vcpu_maps(maps.clone(), Some(&open)).expect("valid maps");
+        assert_eq!(
+            selected.iter().map(|m| m.start).collect::<Vec<_>>(),
+            vec![0x7f00_0002_0000, 0x7f00_0001_0000]
+        );
+        assert_eq!(select_vcpu_maps(maps, None).expect("valid maps").len(), 3);
+    }
+
+    #[test]
+    fn test_find_vcpu_maps() {
+        let maps = "7f0000010000-7f0000013000 rw-s 00000000 00:0e 10296                      anon_inode:kvm-vcpu:1
+7f0000020000-7f0000023000 rw-s 00000000 00:0e 10296                      anon_inode:kvm-vcpu:0
+7f0000030000-7f0000033000 rw-s 00000000 00:0e 10296                      anon_inode:kvm-vcpu:2
+";
+        let proc = FakeProc::new(Pid::from_raw(42))
+            .file("maps", maps)
+            .fd(10, "anon_inode:kvm-vcpu:0")
+            .broken_fd(11, Errno::ENOENT)
+            .fd(12, "anon_inode:kvm-vcpu:1");
+        let selected = find_vcpu_maps(&proc).expect("valid maps");
+        assert_eq!(
+            selected.iter().map(|m| m.start).collect::<Vec<_>>(),
+            vec![0x7f00_0002_0000, 0x7f00_0001_0000]
+        );
+
+        // without access to the fds all mappings are used
+        let proc = FakeProc::new(Pid::from_raw(42))
+            .file("maps", maps)
+            .denied("fd");
+        assert_eq!(find_vcpu_maps(&proc).expect("valid maps").len(), 3);
+
+        let proc = FakeProc::new(Pid::from_raw(42)).file("maps", "7f0000010000 rw-s\n");
+        assert!(find_vcpu_maps(&proc).is_err());
+    }
+
+    #[test]
+    fn test_slot_mapping_mismatch() {
+        let ram = vcpu_map(0x7f00_0000_0000, "");
+        // two slots in one mapping
+        assert_eq!(
+            slot_mapping_mismatch(&(0x7f00_0000_0000..0x7f00_0000_1000), &ram),
+            None
+        );
+        assert_eq!(
+            slot_mapping_mismatch(&(0x7f00_0000_1000..0x7f00_0000_3000), &ram),
+            None
+        );
+        // mapping was split
+        assert!(slot_mapping_mismatch(&(0x7f00_0000_0000..0x7f00_0000_4000), &ram).is_some());
+    }
+}
@@ -1,8 +1,13 @@
 pub mod allocator;
 pub mod fd_transfer;
 pub mod hypervisor;
+pub mod ioapic;
 pub mod ioctls;
 pub mod kvm_ioregionfd;
+pub mod lapic;
 pub mod memslots;
+pub mod mp_state;
+#[cfg(test)]
+pub mod testutils;
 pub mod tracee;
 pub use self::allocator::PhysMemAllocator;
@@ -0,0 +1,87 @@
+//! Multiprocessing state of a vcpu as returned by KVM_GET_MP_STATE, see
+//! Documentation/virt/kvm/api.rst in the linux source.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MpState {
+    /// executing or ready to execute
+    Runnable,
+    /// an application processor that has not been started yet
+    Uninitialized,
+    /// waiting for the startup IPI after an INIT
+    InitReceived,
+    /// executed hlt and waits for an interrupt
+    Halted,
+    /// received the startup IPI
+    SipiReceived,
+    /// s390 only
+    Stopped,
+    /// s390 only
+    CheckStop,
+    /// s390 only
+    Operating,
+    /// s390 only
+    Load,
+    /// a state this version does not know about
+    Unknown(u32),
+}
+
+const STATES: [(MpState, u32, &str); 9] = [
+    (MpState::Runnable, 0, "RUNNABLE"),
+    (MpState::Uninitialized, 1, "UNINITIALIZED"),
+    (MpState::InitReceived, 2, "INIT_RECEIVED"),
+    (MpState::Halted, 3, "HALTED"),
+    (MpState::SipiReceived, 4, "SIPI_RECEIVED"),
+    (MpState::Stopped, 5, "STOPPED"),
+    (MpState::CheckStop, 6, "CHECK_STOP"),
+    (MpState::Operating, 7, "OPERATING"),
+    (MpState::Load, 8, "LOAD"),
+];
+
+impl MpState {
+    /// Decode `kvm_mp_state.mp_state`
+    #[must_use]
+    pub fn from_raw(raw: u32) -> MpState {
+        STATES
+            .iter()
+            .find(|(_, r, _)| *r == raw)
+            .map_or(MpState::Unknown(raw), |(state, _, _)| *state)
+    }
+
+    /// Value for `kvm_mp_state.mp_state`
+    #[must_use]
+    pub fn raw(self) -> u32 {
+        match self {
+            MpState::Unknown(raw) => raw,
+            state => STATES
+                .iter()
+                .find(|(s, _, _)| *s == state)
+                .map_or(0, |(_, raw, _)| *raw),
+        }
+    }
+}
+
+impl fmt::Display for MpState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match STATES.iter().find(|(s, _, _)| s == self) {
+            Some((_, _, name)) => write!(f, "{}", name),
+            None => write!(f, "UNKNOWN({})", self.raw()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MpState;
+
+    #[test]
+    fn test_mp_state() {
+        assert_eq!(MpState::from_raw(3), MpState::Halted);
+        assert_eq!(MpState::Halted.to_string(), "HALTED");
+        assert_eq!(MpState::SipiReceived.raw(), 4);
+        assert_eq!(MpState::from_raw(42), MpState::Unknown(42));
+        assert_eq!(MpState::Unknown(42).raw(), 42);
+        assert_eq!(MpState::Unknown(42).to_string(), "UNKNOWN(42)");
+    }
+}
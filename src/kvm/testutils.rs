@@ -0,0 +1,126 @@
+//! Helpers to exercise the kvm module without a running KVM VM.
+
+use libc::{c_int, c_ulong, c_void, off_t, size_t};
+use nix::errno::Errno;
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::{fork, ForkResult, Pid};
+use simple_error::bail;
+use std::num::NonZeroUsize;
+use std::os::unix::prelude::RawFd;
+use std::ptr;
+use std::sync::Mutex;
+
+use crate::result::Result;
+use crate::tracer::inject_syscall::Injector;
+
+/// A forked child that spins with a region of `len` bytes mapped at `addr`. The region is
+/// initialized with `fill` before fork, so its content in the child is known.
+pub struct SpinningChild {
+    pub pid: Pid,
+    pub addr: usize,
+    pub len: usize,
+}
+
+impl SpinningChild {
+    pub fn spawn(len: usize, fill: impl Fn(usize) -> u8) -> SpinningChild {
+        let region = unsafe {
+            mmap(
+                None,
+                NonZeroUsize::new(len).expect("region must not be empty"),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+            .expect("cannot mmap region")
+        } as *mut u8;
+        for i in 0..len {
+            unsafe { ptr::write(region.add(i), fill(i)) };
+        }
+        match unsafe { fork() }.expect("cannot fork") {
+            ForkResult::Child => loop {
+                unsafe { libc::pause() };
+            },
+            ForkResult::Parent { child } => SpinningChild {
+                pid: child,
+                addr: region as usize,
+                len,
+            },
+        }
+    }
+}
+
+impl Drop for SpinningChild {
+    fn drop(&mut self) {
+        let _ = kill(self.pid, Signal::SIGKILL);
+        let _ = waitpid(self.pid, None);
+        let _ = unsafe { munmap(self.addr as *mut c_void, self.len) };
+    }
+}
+
+/// Handles an ioctl on behalf of the fake hypervisor. Arguments point into our own address space.
+pub type IoctlHandler = fn(fd: RawFd, request: c_ulong, arg: c_ulong) -> c_int;
+
+/// An `Injector` that records ioctls instead of injecting them. Memory is allocated in the
+/// calling process, so `HvMem` backed by it can be used with `nix::unistd::getpid()`.
+#[derive(Debug)]
+pub struct FakeInjector {
+    pub ioctls: Mutex<Vec<(RawFd, c_ulong, c_ulong)>>,
+    handler: IoctlHandler,
+    /// fail mmap like a hypervisor whose seccomp filter denies it
+    deny_mmap: bool,
+}
+
+impl FakeInjector {
+    pub fn new(handler: IoctlHandler) -> FakeInjector {
+        FakeInjector {
+            ioctls: Mutex::new(vec![]),
+            handler,
+            deny_mmap: false,
+        }
+    }
+
+    /// Like `new`, but mmap fails with EPERM
+    pub fn without_mmap(handler: IoctlHandler) -> FakeInjector {
+        FakeInjector {
+            deny_mmap: true,
+            ..FakeInjector::new(handler)
+        }
+    }
+}
+
+impl Injector for FakeInjector {
+    fn ioctl(&self, fd: RawFd, request: c_ulong, arg: c_ulong) -> Result<c_int> {
+        self.ioctls
+            .lock()
+            .expect("cannot lock ioctl log")
+            .push((fd, request, arg));
+        Ok((self.handler)(fd, request, arg))
+    }
+
+    fn mmap(
+        &self,
+        addr: *mut c_void,
+        length: size_t,
+        prot: c_int,
+        flags: c_int,
+        fd: RawFd,
+        offset: off_t,
+    ) -> Result<*mut c_void> {
+        if self.deny_mmap {
+            bail!("mmap failed: {}", Errno::EPERM);
+        }
+        Ok(unsafe { libc::mmap(addr, length, prot, flags, fd, offset) })
+    }
+
+    fn munmap(&self, addr: *mut c_void, length: size_t) -> Result<()> {
+        unsafe { libc::munmap(addr, length) };
+        Ok(())
+    }
+
+    fn close(&self, fd: RawFd) -> Result<c_int> {
+        Ok(unsafe { libc::close(fd) })
+    }
+}
@@ -1,20 +1,24 @@
 use crate::cpu;
 use kvm_bindings as kvmb;
 use libc::{c_int, c_ulong, c_void};
+use log::warn;
+use nix::errno::Errno;
 use nix::unistd::Pid;
-use simple_error::{bail, try_with};
-use std::mem::MaybeUninit;
+use simple_error::{bail, require_with, try_with};
+use std::mem::{align_of, size_of, MaybeUninit};
 use std::os::unix::prelude::RawFd;
 use std::ptr;
 
 use super::ioctls;
-use crate::kvm::hypervisor::{memory::HvMem, VCPU};
+use crate::kvm::hypervisor::memory::{process_read_slice, process_write_slice, HvMem};
+use crate::kvm::hypervisor::VCPU;
+use crate::kvm::ioapic::{ioapic_routes, IrqRoute};
 use crate::kvm::ioctls::KVM_CHECK_EXTENSION;
-use crate::kvm::memslots::{get_maps, get_vcpu_maps};
+use crate::kvm::memslots::{get_maps, get_memslots, get_vcpu_maps, MemSlot};
 use crate::result::Result;
 use crate::tracer::inject_syscall;
-use crate::tracer::inject_syscall::Process as Injectee;
-use crate::tracer::proc::Mapping;
+use crate::tracer::inject_syscall::{Injector, Process as Injectee};
+use crate::tracer::proc::{openpid, Mapping, ProcFiles};
 
 /// In theory this is dynamic however for for simplicity we limit it to 1 entry to not have to rewrite our vm allocation stack
 #[repr(C)]
@@ -26,10 +30,27 @@ pub struct kvm_msrs {
     pub entries: [kvmb::kvm_msr_entry; 1],
 }
 
+/// Time stamp counter, see Intel SDM Vol. 4, Table 2-2
+const MSR_IA32_TSC: u32 = 0x10;
+
+/// Size of the per-session scratch memory used to marshal ioctl arguments
+pub const SCRATCH_SIZE: usize = 4096;
+
+/// Memory in the hypervisor for ioctl arguments. Mapped once per attach instead of once per
+/// ioctl and handed out by bumping `used`, which is reset before each ioctl.
+#[derive(Debug)]
+struct ScratchArena {
+    ptr: usize,
+    used: usize,
+    /// Original content if the arena is borrowed from an existing mapping rather than mapped
+    /// by us. Written back on `detach`.
+    saved: Option<Vec<u8>>,
+}
+
 /// This is a handle with abstractions for the syscall injector. Its primary goal is to be an interface for the
 /// destructors of `HvMem` and `VmMem` to be able to (de-)allocate memory.
 #[derive(Debug)]
-pub struct Tracee {
+pub struct Tracee<I = Injectee> {
     pid: Pid,
     vm_fd: RawFd,
     /// The Process which is traced and injected into is blocked for the lifetime of Injectee.
@@ -38,7 +59,29 @@ pub struct Tracee {
     /// the programmer should always assure that the tracee it attached, before running
     /// other functions.
     /// This hold especially true for the destructor of for example `VmMem`.
-    proc: Option<Injectee>,
+    proc: Option<I>,
+    /// Scratch memory of the current attach session, see `Tracee::scratch_alloc`
+    scratch: Option<ScratchArena>,
+    /// see `Tracee::set_borrow_scratch`
+    borrow_scratch: bool,
+}
+
+fn kvm_ioeventfd(addr: u64, len: u32, fd: RawFd, datamatch: Option<u64>) -> kvmb::kvm_ioeventfd {
+    let mut flags = 0;
+    let mut datam = 0;
+    if let Some(data) = datamatch {
+        flags = 1 << kvmb::kvm_ioeventfd_flag_nr_datamatch;
+        datam = data;
+    }
+
+    kvmb::kvm_ioeventfd {
+        len,
+        datamatch: datam,
+        addr,
+        fd,
+        flags,
+        ..Default::default()
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -49,65 +92,128 @@ pub type socklen_t = usize;
 #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
 pub type socklen_t = libc::socklen_t;
 
-impl Tracee {
-    pub fn new(pid: Pid, vm_fd: RawFd, proc: Option<Injectee>) -> Tracee {
-        Tracee { pid, vm_fd, proc }
-    }
-
-    /// see Process#adopt
-    pub fn adopt(&mut self) -> Result<()> {
-        let proc = self.try_get_proc_mut()?;
-        proc.adopt()
+impl<I: Injector> Tracee<I> {
+    pub fn new(pid: Pid, vm_fd: RawFd, proc: Option<I>) -> Tracee<I> {
+        Tracee {
+            pid,
+            vm_fd,
+            proc,
+            scratch: None,
+            borrow_scratch: false,
+        }
     }
 
-    /// see Process#disown
-    pub fn disown(&mut self) -> Result<()> {
-        let proc = self.try_get_proc_mut()?;
-        proc.disown()
+    /// If the scratch memory cannot be mapped, i.e. because a seccomp filter denies mmap, borrow
+    /// it from the hypervisor's stack instead, see `Tracee::borrow_scratch`. Off by default
+    /// since it overwrites memory the hypervisor owns.
+    pub fn set_borrow_scratch(&mut self, borrow: bool) {
+        self.borrow_scratch = borrow;
     }
 
-    /// Attach to pid. The target `proc` will be stopped until `Self.detach` or the end of the
-    /// lifetime of self.
-    pub fn attach(&mut self) -> Result<()> {
-        if self.proc.is_none() {
-            self.proc = Some(try_with!(
-                inject_syscall::attach(self.pid),
-                "cannot attach to hypervisor"
-            ));
+    pub fn detach(&mut self) -> Option<I> {
+        if let Some(scratch) = self.scratch.take() {
+            match scratch.saved {
+                Some(saved) => {
+                    if let Err(e) = process_write_slice(self.pid, scratch.ptr, &saved) {
+                        warn!(
+                            "cannot restore hypervisor memory borrowed as scratch: {}",
+                            e
+                        );
+                    }
+                }
+                None => {
+                    if let Err(e) = self.munmap(scratch.ptr as *mut c_void, SCRATCH_SIZE) {
+                        warn!("cannot unmap scratch memory from hypervisor: {}", e);
+                    }
+                }
+            }
         }
-        Ok(())
+        self.proc.take()
     }
 
-    /// See attach()
-    pub fn attach_to(&mut self, injector: Injectee) -> Result<()> {
-        let inj_pid = injector.main_thread().tid;
-        if self.pid != inj_pid {
+    /// Allocate `size` bytes aligned to `align` from the scratch memory of this attach session.
+    /// The memory is mapped on first use, or borrowed if mmap fails and `set_borrow_scratch` is set,
+    /// and released on `detach`. Allocations are only valid until the next `reset_scratch`.
+    pub fn scratch_alloc(&mut self, size: usize, align: usize) -> Result<usize> {
+        if self.scratch.is_none() {
+            self.scratch = Some(self.map_scratch()?);
+        }
+        let scratch = require_with!(self.scratch.as_mut(), "scratch memory not mapped");
+        let offset = (scratch.used + align - 1) / align * align;
+        if offset + size > SCRATCH_SIZE {
             bail!(
-                "cannot attach Tracee {} using a tracer on {}",
-                self.pid,
-                inj_pid
+                "cannot allocate {} bytes of scratch memory, {} of {} bytes are in use",
+                size,
+                scratch.used,
+                SCRATCH_SIZE
             );
         }
-        if self.proc.is_some() {
-            bail!("cannot attach tracee because it is already attach to something else");
+        scratch.used = offset + size;
+        Ok(scratch.ptr + offset)
+    }
+
+    fn map_scratch(&self) -> Result<ScratchArena> {
+        let err = match self.mmap(SCRATCH_SIZE) {
+            Ok(ptr) => {
+                return Ok(ScratchArena {
+                    ptr: ptr as usize,
+                    used: 0,
+                    saved: None,
+                })
+            }
+            Err(e) => e,
+        };
+        if !self.borrow_scratch {
+            return Err(err);
         }
+        warn!(
+            "cannot map scratch memory in the hypervisor ({}), borrowing it from its stack",
+            err
+        );
+        self.borrow_scratch()
+    }
 
-        self.proc = Some(injector);
-        Ok(())
+    /// Use the lowest `SCRATCH_SIZE` bytes of the main thread's stack mapping as scratch memory
+    /// without injecting mmap. The stack grows down, so this is the part furthest from the
+    /// frames in use. All threads are stopped while we are attached and the content is written
+    /// back on `detach`, before the hypervisor runs again.
+    fn borrow_scratch(&self) -> Result<ScratchArena> {
+        let handle = try_with!(openpid(self.pid), "cannot open handle in proc");
+        let maps = try_with!(handle.maps(), "cannot read mappings of the hypervisor");
+        let stack = require_with!(
+            maps.iter()
+                .find(|m| m.pathname == "[stack]" && m.size() >= SCRATCH_SIZE),
+            "hypervisor {} has no stack to borrow scratch memory from",
+            self.pid
+        );
+        let mut saved = vec![0; SCRATCH_SIZE];
+        try_with!(
+            process_read_slice(self.pid, stack.start, &mut saved),
+            "cannot save hypervisor stack at {:#x}",
+            stack.start
+        );
+        Ok(ScratchArena {
+            ptr: stack.start,
+            used: 0,
+            saved: Some(saved),
+        })
     }
 
-    pub fn detach(&mut self) -> Option<Injectee> {
-        self.proc.take()
+    /// Release all scratch allocations, i.e. before marshalling the arguments of the next ioctl.
+    pub fn reset_scratch(&mut self) {
+        if let Some(scratch) = self.scratch.as_mut() {
+            scratch.used = 0;
+        }
     }
 
-    pub fn try_get_proc(&self) -> Result<&Injectee> {
+    pub fn try_get_proc(&self) -> Result<&I> {
         match &self.proc {
             None => bail!("programming error: tracee is not attached."),
             Some(proc) => Ok(proc),
         }
     }
 
-    fn try_get_proc_mut(&mut self) -> Result<&mut Injectee> {
+    fn try_get_proc_mut(&mut self) -> Result<&mut I> {
         match &mut self.proc {
             None => bail!("programming error: tracee is not attached."),
             Some(proc) => Ok(proc),
@@ -128,6 +234,9 @@ impl Tracee {
     /// * `req`: a device-dependent request code.
     /// * `arg`: an immutable reference passed to ioctl.
     ///
+    /// Fails instead of blocking forever if the hypervisor does not return from the ioctl in
+    /// time, see `inject_syscall::Process::set_syscall_timeout`.
+    ///
     /// # Safety
     ///
     /// The caller should ensure to pass a valid file descriptor and have the
@@ -141,6 +250,22 @@ impl Tracee {
         self.vm_ioctl(request, arg.ptr as c_ulong)
     }
 
+    /// Run an ioctl whose argument is passed by value rather than through hypervisor memory.
+    ///
+    /// KVM vm ioctls fall into two groups:
+    ///
+    /// * value arguments: `KVM_CHECK_EXTENSION` (the capability), `KVM_CREATE_VCPU` (the vcpu
+    ///   id), `KVM_SET_TSS_ADDR` (a guest physical address) and `KVM_CREATE_IRQCHIP` (unused, 0).
+    ///   Use this function for them, it needs no scratch memory.
+    /// * pointer arguments: everything transferring a struct, i.e. `KVM_SET_USER_MEMORY_REGION`,
+    ///   `KVM_IOEVENTFD`, `KVM_IRQFD`, `KVM_SIGNAL_MSI` or `KVM_GET_DIRTY_LOG`. The struct has to
+    ///   live in the hypervisor, use `vm_ioctl_with_ref` for them.
+    ///
+    /// Returns the raw ioctl result, negative values are errnos.
+    pub fn vm_ioctl_with_val(&self, request: c_ulong, arg: c_ulong) -> Result<c_int> {
+        self.vm_ioctl(request, arg)
+    }
+
     fn vcpu_ioctl(&self, vcpu: &VCPU, request: c_ulong, arg: c_ulong) -> Result<c_int> {
         let proc = self.try_get_proc()?;
         proc.ioctl(vcpu.fd_num, request, arg)
@@ -166,59 +291,6 @@ impl Tracee {
         proc.mmap(addr, length, prot, flags, fd, offset)
     }
 
-    /// Guarantees not to allocate or follow pointers. Pure pointer calculus.
-    /// You are free to try to convince the compiler that this is constant. In theory it is.
-    ///
-    /// # Safety
-    ///
-    /// This is pointer calculus.
-    #[allow(non_snake_case)]
-    pub unsafe fn CMSG_SPACE(length: libc::c_uint) -> libc::c_uint {
-        libc::CMSG_SPACE(length)
-    }
-
-    /// Guarantees not to allocate or follow pointers. Pure pointer calculus.
-    ///
-    /// # Safety
-    ///
-    /// This is pointer calculus.
-    #[allow(non_snake_case)]
-    pub unsafe fn __CMSG_FIRSTHDR(
-        msg_control: *mut libc::c_void,
-        msg_controllen: socklen_t,
-    ) -> *mut libc::cmsghdr {
-        let mut msg_hdr = MaybeUninit::<libc::msghdr>::zeroed();
-        let p = msg_hdr.as_mut_ptr();
-        (*p).msg_name = std::ptr::null_mut::<libc::c_void>();
-        (*p).msg_namelen = 0;
-        (*p).msg_iov = std::ptr::null_mut::<libc::iovec>();
-        (*p).msg_iovlen = 0;
-        (*p).msg_control = msg_control;
-        (*p).msg_controllen = msg_controllen;
-        (*p).msg_flags = 0;
-        libc::CMSG_FIRSTHDR(p as *const libc::msghdr)
-    }
-
-    /// Guarantees not to allocate or follow pointers. Pure pointer calculus.
-    ///
-    /// # Safety
-    ///
-    /// This is pointer calculus.
-    #[allow(non_snake_case)]
-    pub unsafe fn CMSG_LEN(length: libc::c_uint) -> libc::c_uint {
-        libc::CMSG_LEN(length)
-    }
-
-    /// Guarantees not to allocate or follow pointers. Pure pointer calculus.
-    ///
-    /// # Safety
-    ///
-    /// This is pointer calculus.
-    #[allow(non_snake_case)]
-    pub unsafe fn CMSG_DATA(cmsg: *const libc::cmsghdr) -> *mut libc::c_uchar {
-        libc::CMSG_DATA(cmsg)
-    }
-
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_cpuid2(
         &self,
@@ -247,6 +319,48 @@ impl Tracee {
         Ok(irqchip)
     }
 
+    /// Read the IOAPIC redirection table, see `ioapic`. Fails if the hypervisor emulates the
+    /// irqchip in userspace, there is nothing to read from KVM then.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_irq_routing(&self, irqchip: &HvMem<kvmb::kvm_irqchip>) -> Result<Vec<IrqRoute>> {
+        use crate::kvm::ioctls::KVM_GET_IRQCHIP;
+
+        try_with!(
+            irqchip.write(&kvmb::kvm_irqchip {
+                chip_id: kvmb::KVM_IRQCHIP_IOAPIC,
+                ..Default::default()
+            }),
+            "cannot update kvm_irqchip structure"
+        );
+        let ret = try_with!(
+            self.vm_ioctl(KVM_GET_IRQCHIP(), irqchip.ptr as c_ulong),
+            "vm_ioctl failed"
+        );
+        if ret == -libc::ENXIO {
+            bail!("vm has no in-kernel irqchip, the hypervisor routes interrupts in userspace");
+        } else if ret < 0 {
+            bail!("ioctl(KVM_GET_IRQCHIP) failed: {}", Errno::from_i32(-ret));
+        }
+        let irqchip = try_with!(irqchip.read(), "cannot read irqchip");
+        // Safe because we asked for the ioapic.
+        Ok(ioapic_routes(unsafe { &irqchip.chip.ioapic }))
+    }
+
+    /// Get the current kvmclock of the guest in nanoseconds
+    pub fn get_clock(&self, clock: &HvMem<kvmb::kvm_clock_data>) -> Result<kvmb::kvm_clock_data> {
+        use crate::kvm::ioctls::KVM_GET_CLOCK;
+
+        let ret = try_with!(
+            self.vm_ioctl(KVM_GET_CLOCK(), clock.ptr as c_ulong),
+            "vm_ioctl failed"
+        );
+        if ret < 0 {
+            bail!("ioctl(KVM_GET_CLOCK) failed: {}", Errno::from_i32(-ret));
+        }
+        let clock = try_with!(clock.read(), "cannot read clock");
+        Ok(clock)
+    }
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_sregs(
         &self,
@@ -262,6 +376,17 @@ impl Tracee {
         Ok(sregs)
     }
 
+    /// Set special registers of VCPU
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_sregs(&self, vcpu: &VCPU, sregs: &HvMem<kvmb::kvm_sregs>) -> Result<()> {
+        use crate::kvm::ioctls::KVM_SET_SREGS;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_SET_SREGS(), sregs.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        Ok(())
+    }
+
     /// Set general-purpose pointer registers of VCPU
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn set_regs(&self, vcpu: &VCPU, regs: &HvMem<kvmb::kvm_regs>) -> Result<()> {
@@ -313,6 +438,29 @@ impl Tracee {
         })
     }
 
+    /// Get floating pointer registers of VCPU in the layout of KVM
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_fpu(&self, vcpu: &VCPU, fpu: &HvMem<kvmb::kvm_fpu>) -> Result<kvmb::kvm_fpu> {
+        use crate::kvm::ioctls::KVM_GET_FPU;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_FPU(), fpu.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        let fpu = try_with!(fpu.read(), "cannot read fpu registers");
+        Ok(fpu)
+    }
+
+    /// Set floating pointer registers of VCPU
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_fpu(&self, vcpu: &VCPU, fpu: &HvMem<kvmb::kvm_fpu>) -> Result<()> {
+        use crate::kvm::ioctls::KVM_SET_FPU;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_SET_FPU(), fpu.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        Ok(())
+    }
+
     /// Get floating pointer registers of VCPU
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_fpu_regs(&self, vcpu: &VCPU, regs: &HvMem<kvmb::kvm_fpu>) -> Result<cpu::FpuRegs> {
@@ -358,6 +506,240 @@ impl Tracee {
         Ok(msrs.entries[0])
     }
 
+    /// Read the time stamp counter of VCPU, as the guest sees it
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_tsc(&self, vcpu: &VCPU, msrs: &HvMem<kvm_msrs>) -> Result<u64> {
+        use crate::kvm::ioctls::KVM_GET_MSRS;
+        try_with!(
+            msrs.write(&kvm_msrs {
+                nmsrs: 1,
+                pad: 0,
+                entries: [kvmb::kvm_msr_entry {
+                    index: MSR_IA32_TSC,
+                    ..Default::default()
+                }],
+            }),
+            "cannot update kvm_msrs structure"
+        );
+        let read = try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_MSRS(), msrs.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        if read != 1 {
+            bail!("cannot read MSR_IA32_TSC of vcpu {}", vcpu.idx);
+        }
+        let msrs = try_with!(msrs.read(), "cannot read registers");
+        Ok(msrs.entries[0].data)
+    }
+
+    /// TSC frequency of VCPU in kHz
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_tsc_khz(&self, vcpu: &VCPU) -> Result<u32> {
+        use crate::kvm::ioctls::KVM_GET_TSC_KHZ;
+        if self.check_extension(kvmb::KVM_CAP_GET_TSC_KHZ as c_int)? <= 0 {
+            bail!("kvm does not support KVM_CAP_GET_TSC_KHZ");
+        }
+        let khz = try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_TSC_KHZ(), 0),
+            "vcpu_ioctl failed"
+        );
+        if khz < 0 {
+            bail!("ioctl(KVM_GET_TSC_KHZ) failed: {}", Errno::from_i32(-khz));
+        } else if khz == 0 {
+            bail!("kvm does not know the TSC frequency of vcpu {}", vcpu.idx);
+        }
+        Ok(khz as u32)
+    }
+
+    /// Get the local APIC register page of VCPU
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_lapic(
+        &self,
+        vcpu: &VCPU,
+        lapic: &HvMem<kvmb::kvm_lapic_state>,
+    ) -> Result<kvmb::kvm_lapic_state> {
+        use crate::kvm::ioctls::KVM_GET_LAPIC;
+        let ret = try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_LAPIC(), lapic.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        if ret < 0 {
+            bail!("ioctl(KVM_GET_LAPIC) failed: {}", Errno::from_i32(-ret));
+        }
+        let lapic = try_with!(lapic.read(), "cannot read lapic state");
+        Ok(lapic)
+    }
+
+    /// Set the local APIC register page of VCPU
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_lapic(&self, vcpu: &VCPU, lapic: &HvMem<kvmb::kvm_lapic_state>) -> Result<()> {
+        use crate::kvm::ioctls::KVM_SET_LAPIC;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_SET_LAPIC(), lapic.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        Ok(())
+    }
+
+    /// Get the multiprocessing state of VCPU
+    pub fn get_mp_state(
+        &self,
+        vcpu: &VCPU,
+        state: &HvMem<kvmb::kvm_mp_state>,
+    ) -> Result<kvmb::kvm_mp_state> {
+        use crate::kvm::ioctls::KVM_GET_MP_STATE;
+        let ret = try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_MP_STATE(), state.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        if ret < 0 {
+            bail!("ioctl(KVM_GET_MP_STATE) failed: {}", Errno::from_i32(-ret));
+        }
+        let state = try_with!(state.read(), "cannot read mp state");
+        Ok(state)
+    }
+
+    /// Set the multiprocessing state of VCPU
+    pub fn set_mp_state(&self, vcpu: &VCPU, state: &HvMem<kvmb::kvm_mp_state>) -> Result<()> {
+        use crate::kvm::ioctls::KVM_SET_MP_STATE;
+        let ret = try_with!(
+            self.vcpu_ioctl(vcpu, KVM_SET_MP_STATE(), state.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        if ret < 0 {
+            bail!("ioctl(KVM_SET_MP_STATE) failed: {}", Errno::from_i32(-ret));
+        }
+        Ok(())
+    }
+
+    /// Queue an external interrupt on VCPU. Only works without an in-kernel irqchip.
+    /// Returns the raw ioctl result.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn interrupt(&self, vcpu: &VCPU, irq: &HvMem<kvmb::kvm_interrupt>) -> Result<c_int> {
+        use crate::kvm::ioctls::KVM_INTERRUPT;
+        self.vcpu_ioctl(vcpu, KVM_INTERRUPT(), irq.ptr as c_ulong)
+    }
+
+    /// Inject a message signaled interrupt. Returns the raw ioctl result.
+    pub fn signal_msi(&self, msi: &HvMem<kvmb::kvm_msi>) -> Result<c_int> {
+        use crate::kvm::ioctls::KVM_SIGNAL_MSI;
+        self.vm_ioctl(KVM_SIGNAL_MSI(), msi.ptr as c_ulong)
+    }
+
+    /// Enable or disable guest debugging (i.e. hardware breakpoints) of VCPU
+    pub fn set_guest_debug(&self, vcpu: &VCPU, dbg: &HvMem<kvmb::kvm_guest_debug>) -> Result<()> {
+        use crate::kvm::ioctls::KVM_SET_GUEST_DEBUG;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_SET_GUEST_DEBUG(), dbg.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        Ok(())
+    }
+
+    /// Make guest writes of `len` bytes to the guest physical address `addr` signal the
+    /// hypervisor's eventfd `fd` in the kernel instead of exiting to userspace. `len` 0 matches
+    /// writes of any size. With `datamatch` only writes of this value signal the eventfd.
+    /// `ioeventfd` is hypervisor memory for the ioctl argument.
+    pub fn register_ioeventfd(
+        &self,
+        ioeventfd: &HvMem<kvmb::kvm_ioeventfd>,
+        addr: u64,
+        len: u32,
+        fd: RawFd,
+        datamatch: Option<u64>,
+    ) -> Result<()> {
+        let arg = kvm_ioeventfd(addr, len, fd, datamatch);
+        self.ioeventfd_ioctl(ioeventfd, &arg)
+    }
+
+    /// Undo `register_ioeventfd`. The arguments have to match the ones of the registration.
+    pub fn unregister_ioeventfd(
+        &self,
+        ioeventfd: &HvMem<kvmb::kvm_ioeventfd>,
+        addr: u64,
+        len: u32,
+        fd: RawFd,
+        datamatch: Option<u64>,
+    ) -> Result<()> {
+        let mut arg = kvm_ioeventfd(addr, len, fd, datamatch);
+        arg.flags |= 1 << kvmb::kvm_ioeventfd_flag_nr_deassign;
+        self.ioeventfd_ioctl(ioeventfd, &arg)
+    }
+
+    fn ioeventfd_ioctl(
+        &self,
+        ioeventfd: &HvMem<kvmb::kvm_ioeventfd>,
+        arg: &kvmb::kvm_ioeventfd,
+    ) -> Result<()> {
+        ioeventfd.write(arg)?;
+        let ret = try_with!(
+            self.vm_ioctl_with_ref(ioctls::KVM_IOEVENTFD(), ioeventfd),
+            "kvm ioeventfd ioctl injection failed"
+        );
+        if ret != 0 {
+            bail!(
+                "KVM_IOEVENTFD for {:#x} failed: {}",
+                arg.addr,
+                Errno::from_i32(-ret)
+            );
+        }
+        Ok(())
+    }
+
+    /// Make the kernel raise the interrupt `gsi` in the guest whenever the hypervisor's eventfd
+    /// `fd` is signaled, without an exit to the hypervisor or vmsh. `irqfd` is hypervisor memory
+    /// for the ioctl argument.
+    pub fn register_irqfd(
+        &self,
+        irqfd: &HvMem<kvmb::kvm_irqfd>,
+        gsi: u32,
+        fd: RawFd,
+    ) -> Result<()> {
+        irqfd.write(&kvmb::kvm_irqfd {
+            fd: fd as u32,
+            gsi,
+            ..Default::default()
+        })?;
+        let ret = try_with!(
+            self.vm_ioctl_with_ref(ioctls::KVM_IRQFD(), irqfd),
+            "kvm irqfd ioctl injection failed"
+        );
+        if ret != 0 {
+            bail!(
+                "KVM_IRQFD for gsi {} failed: {}",
+                gsi,
+                Errno::from_i32(-ret)
+            );
+        }
+        Ok(())
+    }
+
+    /// Set the level of interrupt line `gsi` with KVM_IRQ_LINE, i.e. to inject an interrupt
+    /// without an irqfd. `irq_level` is hypervisor memory for the ioctl argument.
+    pub fn irq_line(
+        &self,
+        irq_level: &HvMem<kvmb::kvm_irq_level>,
+        gsi: u32,
+        level: bool,
+    ) -> Result<()> {
+        irq_level.write(&kvmb::kvm_irq_level {
+            __bindgen_anon_1: kvmb::kvm_irq_level__bindgen_ty_1 { irq: gsi },
+            level: level as u32,
+        })?;
+        let ret = try_with!(
+            self.vm_ioctl_with_ref(ioctls::KVM_IRQ_LINE(), irq_level),
+            "kvm irq line ioctl injection failed"
+        );
+        if ret != 0 {
+            bail!(
+                "KVM_IRQ_LINE for gsi {} failed: {}",
+                gsi,
+                Errno::from_i32(-ret)
+            );
+        }
+        Ok(())
+    }
+
     /// Unmap memory in the process
     ///
     /// length in bytes.
@@ -372,17 +754,238 @@ impl Tracee {
     }
 
     pub fn check_extension(&self, cap: c_int) -> Result<c_int> {
-        self.vm_ioctl(KVM_CHECK_EXTENSION(), cap as c_ulong)
+        self.vm_ioctl_with_val(KVM_CHECK_EXTENSION(), cap as c_ulong)
     }
 
     pub fn pid(&self) -> Pid {
         self.pid
     }
+}
+
+impl Tracee {
+    /// see Process#adopt
+    pub fn adopt(&mut self) -> Result<()> {
+        let proc = self.try_get_proc_mut()?;
+        proc.adopt()
+    }
+
+    /// see Process#disown
+    pub fn disown(&mut self) -> Result<()> {
+        let proc = self.try_get_proc_mut()?;
+        proc.disown()
+    }
+
+    /// Attach to pid. The target `proc` will be stopped until `Self.detach` or the end of the
+    /// lifetime of self.
+    pub fn attach(&mut self) -> Result<()> {
+        if self.proc.is_none() {
+            self.proc = Some(try_with!(
+                inject_syscall::attach(self.pid),
+                "cannot attach to hypervisor"
+            ));
+        }
+        Ok(())
+    }
+
+    /// See attach()
+    pub fn attach_to(&mut self, injector: Injectee) -> Result<()> {
+        let inj_pid = injector.main_thread().tid;
+        if self.pid != inj_pid {
+            bail!(
+                "cannot attach Tracee {} using a tracer on {}",
+                self.pid,
+                inj_pid
+            );
+        }
+        if self.proc.is_some() {
+            bail!("cannot attach tracee because it is already attach to something else");
+        }
+
+        self.proc = Some(injector);
+        Ok(())
+    }
+
+    /// Guarantees not to allocate or follow pointers. Pure pointer calculus.
+    /// You are free to try to convince the compiler that this is constant. In theory it is.
+    ///
+    /// # Safety
+    ///
+    /// This is pointer calculus.
+    #[allow(non_snake_case)]
+    pub unsafe fn CMSG_SPACE(length: libc::c_uint) -> libc::c_uint {
+        libc::CMSG_SPACE(length)
+    }
+
+    /// Guarantees not to allocate or follow pointers. Pure pointer calculus.
+    ///
+    /// # Safety
+    ///
+    /// This is pointer calculus.
+    #[allow(non_snake_case)]
+    pub unsafe fn __CMSG_FIRSTHDR(
+        msg_control: *mut libc::c_void,
+        msg_controllen: socklen_t,
+    ) -> *mut libc::cmsghdr {
+        let mut msg_hdr = MaybeUninit::<libc::msghdr>::zeroed();
+        let p = msg_hdr.as_mut_ptr();
+        (*p).msg_name = std::ptr::null_mut::<libc::c_void>();
+        (*p).msg_namelen = 0;
+        (*p).msg_iov = std::ptr::null_mut::<libc::iovec>();
+        (*p).msg_iovlen = 0;
+        (*p).msg_control = msg_control;
+        (*p).msg_controllen = msg_controllen;
+        (*p).msg_flags = 0;
+        libc::CMSG_FIRSTHDR(p as *const libc::msghdr)
+    }
+
+    /// Guarantees not to allocate or follow pointers. Pure pointer calculus.
+    ///
+    /// # Safety
+    ///
+    /// This is pointer calculus.
+    #[allow(non_snake_case)]
+    pub unsafe fn CMSG_LEN(length: libc::c_uint) -> libc::c_uint {
+        libc::CMSG_LEN(length)
+    }
+
+    /// Guarantees not to allocate or follow pointers. Pure pointer calculus.
+    ///
+    /// # Safety
+    ///
+    /// This is pointer calculus.
+    #[allow(non_snake_case)]
+    pub unsafe fn CMSG_DATA(cmsg: *const libc::cmsghdr) -> *mut libc::c_uchar {
+        libc::CMSG_DATA(cmsg)
+    }
 
     pub fn get_maps(&self) -> Result<Vec<Mapping>> {
         get_maps(self)
     }
 
+    pub fn get_memslots(&self) -> Result<Vec<MemSlot>> {
+        get_memslots(self)
+    }
+
+    /// Set KVM_MEM_LOG_DIRTY_PAGES on memslot `slot`. The slot is re-registered with
+    /// KVM_SET_USER_MEMORY_REGION using its current configuration, so only the flags change.
+    /// Returns the previous configuration, which has to be restored before detaching: dirty
+    /// logging slows down the guest and the hypervisor does not expect it.
+    pub fn enable_dirty_logging(&mut self, slot: u32) -> Result<kvmb::kvm_userspace_memory_region> {
+        let memslots = try_with!(self.get_memslots(), "cannot read memslots");
+        let old = require_with!(
+            memslots.iter().find(|s| s.id() == slot),
+            "memslot {} does not exist",
+            slot
+        )
+        .region();
+        if old.flags & kvmb::KVM_MEM_LOG_DIRTY_PAGES != 0 {
+            return Ok(old);
+        }
+        warn!(
+            "enabling dirty page logging on memslot {}, this changes how the vm runs until it is disabled again",
+            slot
+        );
+        let mut new = old;
+        new.flags |= kvmb::KVM_MEM_LOG_DIRTY_PAGES;
+        self.set_user_memory_region(&new)?;
+        Ok(old)
+    }
+
+    /// (Re-)register a memslot, or remove it if `memory_size` is 0. Unlike most methods, this one
+    /// places the ioctl argument in the scratch memory itself, so it can be used while holding
+    /// the tracee lock.
+    pub fn set_user_memory_region(
+        &mut self,
+        region: &kvmb::kvm_userspace_memory_region,
+    ) -> Result<()> {
+        use crate::kvm::hypervisor::memory::process_write;
+        self.reset_scratch();
+        let ptr = self.scratch_alloc(
+            size_of::<kvmb::kvm_userspace_memory_region>(),
+            align_of::<kvmb::kvm_userspace_memory_region>(),
+        )?;
+        let res = process_write(self.pid, ptr as *mut c_void, region)
+            .and_then(|_| self.vm_ioctl(ioctls::KVM_SET_USER_MEMORY_REGION(), ptr as c_ulong));
+        let ret = try_with!(res, "cannot set memory region of slot {}", region.slot);
+        if ret != 0 {
+            bail!(
+                "cannot set memory region of slot {}: {}",
+                region.slot,
+                nix::errno::Errno::from_i32(-ret)
+            );
+        }
+        Ok(())
+    }
+
+    /// Fetch and reset the dirty bitmap of memslot `slot` with `npages` pages. Bit n is set if
+    /// page n of the slot was written since dirty logging was enabled or since the last call.
+    /// If the hypervisor enabled KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2, KVM_GET_DIRTY_LOG leaves the
+    /// log as it is, so with `clear` the pages read are reset with KVM_CLEAR_DIRTY_LOG as well.
+    /// The ioctl argument and the bitmap are allocated in the scratch arena if they fit, i.e.
+    /// for slots of up to about 127 MiB with 4 KiB pages, and mapped just for this call otherwise.
+    pub fn get_dirty_log(&mut self, slot: u32, npages: usize, clear: bool) -> Result<Vec<u8>> {
+        use crate::kvm::hypervisor::memory::{process_read_slice, process_write};
+        // KVM copies the bitmap in 64-bit words
+        let bitmap_len = (npages + 63) / 64 * 8;
+        // room for the argument of either ioctl
+        let arg_len = std::mem::size_of::<kvmb::kvm_clear_dirty_log>()
+            .max(std::mem::size_of::<kvmb::kvm_dirty_log>());
+        let len = arg_len + bitmap_len;
+        let mapped = len > SCRATCH_SIZE;
+        let ptr = if mapped {
+            self.mmap(len)?
+        } else {
+            self.scratch_alloc(len, align_of::<kvmb::kvm_clear_dirty_log>())? as *mut c_void
+        };
+        let bitmap_ptr = ptr as usize + arg_len;
+        let arg = kvmb::kvm_dirty_log {
+            slot,
+            padding1: 0,
+            __bindgen_anon_1: kvmb::kvm_dirty_log__bindgen_ty_1 {
+                dirty_bitmap: bitmap_ptr as *mut c_void,
+            },
+        };
+        let mut bitmap = vec![0u8; bitmap_len];
+        let res = process_write(self.pid, ptr, &arg)
+            .and_then(|_| self.vm_ioctl(ioctls::KVM_GET_DIRTY_LOG(), ptr as c_ulong))
+            .and_then(|ret| {
+                if ret != 0 {
+                    bail!("{}", nix::errno::Errno::from_i32(-ret));
+                }
+                process_read_slice(self.pid, bitmap_ptr, &mut bitmap)
+            })
+            .and_then(|_| {
+                if !clear || bitmap.iter().all(|b| *b == 0) {
+                    return Ok(());
+                }
+                // clears exactly the pages set in the bitmap KVM just copied to `bitmap_ptr`
+                let arg = kvmb::kvm_clear_dirty_log {
+                    slot,
+                    num_pages: npages as u32,
+                    first_page: 0,
+                    __bindgen_anon_1: kvmb::kvm_clear_dirty_log__bindgen_ty_1 {
+                        dirty_bitmap: bitmap_ptr as *mut c_void,
+                    },
+                };
+                process_write(self.pid, ptr, &arg)?;
+                let ret = self.vm_ioctl(ioctls::KVM_CLEAR_DIRTY_LOG(), ptr as c_ulong)?;
+                if ret != 0 {
+                    bail!(
+                        "ioctl(KVM_CLEAR_DIRTY_LOG) failed: {}",
+                        nix::errno::Errno::from_i32(-ret)
+                    );
+                }
+                Ok(())
+            });
+        if mapped {
+            if let Err(e) = self.munmap(ptr, len) {
+                warn!("failed to unmap memory from process: {}", e);
+            }
+        }
+        try_with!(res, "cannot get dirty log of memslot {}", slot);
+        Ok(bitmap)
+    }
+
     pub fn get_vcpu_maps(&self) -> Result<Vec<Mapping>> {
         get_vcpu_maps(self.pid)
     }
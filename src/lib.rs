@@ -1,3 +1,11 @@
+//! Library behind the `vmsh` binary.
+//!
+//! Every command is an entry point taking a plain options struct, i.e. `attach::attach` with
+//! `AttachOptions`, `inspect::inspect` with `InspectOptions` or `coredump::generate_coredump`
+//! with `CoredumpOptions`. Command line parsing lives in `src/bin/vmsh.rs` only, which needs the
+//! default `cli` feature, so the library can be embedded without clap with
+//! `default-features = false`.
+
 #![deny(clippy::print_stdout, clippy::print_stderr, clippy::unwrap_used)]
 // TODO: more checks
 //#![warn(
@@ -12,21 +20,26 @@
 //)]
 
 pub mod attach;
+pub mod btf;
 pub mod console;
 pub mod coredump;
 pub mod cpu;
 pub mod debug;
 pub mod devices;
 pub mod elf;
+pub mod fmt;
 pub mod guest_mem;
 pub mod inspect;
 pub mod interrutable_thread;
 pub mod kernel;
+pub mod kick;
 pub mod kvm;
 pub mod loader;
 pub mod page_math;
 pub mod page_table;
 pub mod result;
 pub mod signal_handler;
+pub mod snapshot;
 pub mod stage1;
 pub mod tracer;
+pub mod vmlinux;
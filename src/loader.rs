@@ -1,5 +1,4 @@
 use std::collections::HashMap;
-use std::io::IoSlice;
 use std::mem::{size_of, size_of_val};
 use std::ptr;
 
@@ -10,7 +9,6 @@ use elfloader::{
 };
 use log::{debug, error, warn};
 use nix::sys::mman::ProtFlags;
-use nix::sys::uio::{process_vm_writev, RemoteIoVec};
 use simple_error::{bail, require_with, try_with};
 use stage1_interface::{DeviceState, Stage1Args};
 use xmas_elf::sections::{SectionData, SHN_UNDEF};
@@ -19,6 +17,7 @@ use xmas_elf::symbol_table::{Binding, DynEntry64};
 use crate::guest_mem::MappedMemory;
 use crate::kernel::{Kernel, LINUX_KERNEL_KASLR_RANGE};
 use crate::kvm::allocator::VirtAlloc;
+use crate::kvm::hypervisor::memory::process_write_slice;
 use crate::kvm::PhysMemAllocator;
 use crate::page_math::{page_align, page_start};
 use crate::page_table::VirtMem;
@@ -130,27 +129,15 @@ impl<'a> Loader<'a> {
     }
 
     fn upload_binary(&self) -> Result<()> {
-        let mut local_iovec = vec![];
-        let mut remote_iovec = vec![];
-        let mut len = 0;
         for l in self.loadables.iter() {
-            local_iovec.push(IoSlice::new(&l.content));
-            len += l.content.len();
-            remote_iovec.push(RemoteIoVec {
-                base: l.mapping.phys_start.host_addr() + l.virt_offset,
-                len: l.content.len(),
-            });
-        }
-        let written = try_with!(
-            process_vm_writev(
-                self.allocator.hv.pid,
-                local_iovec.as_slice(),
-                remote_iovec.as_slice()
-            ),
-            "cannot write to process"
-        );
-        if written != len {
-            bail!("short write, expected {}, written: {}", len, written);
+            try_with!(
+                process_write_slice(
+                    self.allocator.hv.pid,
+                    l.mapping.phys_start.host_addr() + l.virt_offset,
+                    &l.content
+                ),
+                "cannot write to process"
+            );
         }
         Ok(())
     }
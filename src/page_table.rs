@@ -2,20 +2,20 @@ use std::borrow::Borrow;
 use std::cell::RefCell;
 use std::cmp::max;
 use std::collections::HashMap;
-use std::io::IoSlice;
 use std::mem::{size_of, size_of_val};
 use std::ops::Range;
 use std::rc::Rc;
 use std::sync::Arc;
 
 use crate::guest_mem::{MappedMemory, PhysHostMap};
-use crate::kvm::hypervisor::{memory::process_read, memory::PhysMem, Hypervisor};
+use crate::kvm::hypervisor::{
+    memory::process_read, memory::process_write_slice, memory::PhysMem, Hypervisor,
+};
 use crate::page_math::{is_page_aligned, page_align, page_size};
 use crate::result::Result;
 use bitflags::bitflags;
 use log::{error, info};
 use nix::sys::mman::ProtFlags;
-use nix::sys::uio::{process_vm_writev, RemoteIoVec};
 use simple_error::{bail, require_with, try_with};
 use vm_memory::remote_mem::any_as_bytes;
 
@@ -203,6 +203,39 @@ fn get_index(virt: u64, level: u8) -> u64 {
     virt >> get_shift(level) & 0x1FF
 }
 
+/// Translate the guest virtual address `virt_addr` to a guest physical address by walking the
+/// page table rooted at `pml4`.
+pub fn translate(
+    hv: &Hypervisor,
+    pml4: &PhysAddr,
+    phys_host_map: &PhysHostMap,
+    virt_addr: usize,
+) -> Result<PhysAddr> {
+    let mut pt = PageTable::read(hv, pml4, 0, 0)?;
+    for level in 0..LEVEL_COUNT as u8 {
+        let entry = pt.entries[get_index(virt_addr as u64, level) as usize];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            bail!("virtual address {:#x} is not mapped", virt_addr);
+        }
+        if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let page_mask = (1 << get_shift(level)) - 1;
+            let phys_addr = (entry.addr() as usize & !page_mask) | (virt_addr & page_mask);
+            let host_offset = require_with!(
+                phys_host_map.get(phys_addr),
+                "physical address {:#x} is not backed by memslot",
+                phys_addr
+            );
+            return Ok(PhysAddr {
+                value: phys_addr,
+                host_offset,
+            });
+        }
+        let next_phys_addr = pt.phys_addr(entry, phys_host_map)?;
+        pt = PageTable::read(hv, &next_phys_addr, 0, level + 1)?;
+    }
+    unreachable!("the last page table level always maps a page")
+}
+
 pub fn table_align(pages: usize) -> usize {
     (pages + (ENTRY_COUNT - 1)) & !(ENTRY_COUNT - 1)
 }
@@ -356,24 +389,13 @@ fn get_start_idx(virt_addr: usize, idx: usize, level: u8) -> usize {
 }
 
 fn commit_page_tables(hv: &Hypervisor, tables: &[PageTable]) -> Result<()> {
-    let mut local_iovec = vec![];
-    let mut remote_iovec = vec![];
     for t in tables {
         let t = t.borrow();
         let bytes = unsafe { any_as_bytes(&t.entries) };
-        local_iovec.push(IoSlice::new(bytes));
-        remote_iovec.push(RemoteIoVec {
-            base: t.phys_addr.host_addr(),
-            len: page_size(),
-        });
-    }
-    let written = try_with!(
-        process_vm_writev(hv.pid, local_iovec.as_slice(), remote_iovec.as_slice()),
-        "cannot write to process"
-    );
-    let expected = remote_iovec.len() * page_size();
-    if written != expected {
-        bail!("short write, expected {}, written: {}", expected, written);
+        try_with!(
+            process_write_slice(hv.pid, t.phys_addr.host_addr(), bytes),
+            "cannot write page table"
+        );
     }
     Ok(())
 }
@@ -0,0 +1,469 @@
+//! Save guest RAM and vcpu state of a running VM to a directory and write it back later.
+//!
+//! A snapshot directory contains:
+//!
+//! * `layout`: a text file describing the snapshot, see `SnapshotLayout`.
+//! * `memory`: the content of all writable guest memory regions, concatenated in the order
+//!   of the `mem` lines of `layout`.
+//! * `vcpu<N>`: the state of vcpu N: its general purpose registers as `cpu::Regs`, followed by
+//!   the raw KVM structures `kvm_sregs`, `kvm_fpu` and `kvm_lapic_state`. Their sizes are
+//!   recorded in `layout`.
+//!
+//! This is not a migration: MSRs, XSAVE state, pending events and the state of emulated devices
+//! live in KVM or the hypervisor and are not saved. Restoring is meant for the same VM, or one
+//! started with the same configuration, i.e. to roll back a guest after an experiment.
+
+use kvm_bindings as kvmb;
+use log::info;
+use nix::sys::mman::ProtFlags;
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+
+use crate::cpu::Regs;
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor, HypervisorOptions, VCPU};
+use crate::kvm::lapic::Lapic;
+use crate::result::Result;
+use crate::tracer::proc::Mapping;
+
+/// Guest memory is copied in chunks of this size
+const CHUNK_SIZE: usize = 1 << 20;
+
+const MAGIC: &str = "vmsh-snapshot";
+const VERSION: u32 = 1;
+
+pub struct SnapshotOptions {
+    pub pid: Pid,
+    pub hypervisor: HypervisorOptions,
+    pub dir: PathBuf,
+}
+
+/// Guest physical memory region saved in a snapshot
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotRegion {
+    pub phys_addr: usize,
+    pub size: usize,
+}
+
+/// Content of the `layout` file:
+///
+/// ```text
+/// vmsh-snapshot 1
+/// vcpus 2
+/// vcpu-state <size of cpu::Regs> <size of kvm_sregs> <size of kvm_fpu> <size of kvm_lapic_state>
+/// mem <guest physical address> <size>
+/// ...
+/// ```
+///
+/// Addresses and sizes of `mem` lines are hexadecimal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotLayout {
+    pub vcpus: usize,
+    pub vcpu_state: [usize; 4],
+    pub regions: Vec<SnapshotRegion>,
+}
+
+fn vcpu_state_sizes() -> [usize; 4] {
+    [
+        size_of::<Regs>(),
+        size_of::<kvmb::kvm_sregs>(),
+        size_of::<kvmb::kvm_fpu>(),
+        size_of::<kvmb::kvm_lapic_state>(),
+    ]
+}
+
+impl fmt::Display for SnapshotLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} {}", MAGIC, VERSION)?;
+        writeln!(f, "vcpus {}", self.vcpus)?;
+        let [regs, sregs, fpu, lapic] = self.vcpu_state;
+        writeln!(f, "vcpu-state {} {} {} {}", regs, sregs, fpu, lapic)?;
+        for region in &self.regions {
+            writeln!(f, "mem {:#x} {:#x}", region.phys_addr, region.size)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex(s: &str) -> Result<usize> {
+    Ok(try_with!(
+        usize::from_str_radix(s.trim_start_matches("0x"), 16),
+        "invalid hex number '{}'",
+        s
+    ))
+}
+
+impl SnapshotLayout {
+    /// Layout of the writable guest memory in `maps` and `vcpus` vcpus of this vmsh build
+    pub fn new(maps: &[Mapping], vcpus: usize) -> SnapshotLayout {
+        let mut regions = maps
+            .iter()
+            .filter(|m| m.prot_flags.contains(ProtFlags::PROT_WRITE))
+            .map(|m| SnapshotRegion {
+                phys_addr: m.phys_addr,
+                size: m.size(),
+            })
+            .collect::<Vec<_>>();
+        regions.sort_by_key(|r| r.phys_addr);
+        SnapshotLayout {
+            vcpus,
+            vcpu_state: vcpu_state_sizes(),
+            regions,
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<SnapshotLayout> {
+        let mut lines = content.lines();
+        let header = require_with!(lines.next(), "layout is empty");
+        match header.split_once(' ') {
+            Some((MAGIC, version)) if version == VERSION.to_string() => {}
+            Some((MAGIC, version)) => bail!(
+                "unsupported snapshot version {}, expected {}",
+                version,
+                VERSION
+            ),
+            _ => bail!("not a vmsh snapshot: '{}'", header),
+        }
+        let mut vcpus = None;
+        let mut vcpu_state = None;
+        let mut regions = vec![];
+        for line in lines {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields.as_slice() {
+                ["vcpus", n] => {
+                    vcpus = Some(try_with!(n.parse::<usize>(), "invalid vcpu count '{}'", n))
+                }
+                ["vcpu-state", sizes @ ..] if sizes.len() == 4 => {
+                    let mut parsed = [0; 4];
+                    for (i, size) in sizes.iter().enumerate() {
+                        parsed[i] = try_with!(size.parse::<usize>(), "invalid size '{}'", size);
+                    }
+                    vcpu_state = Some(parsed);
+                }
+                ["mem", phys_addr, size] => regions.push(SnapshotRegion {
+                    phys_addr: parse_hex(phys_addr)?,
+                    size: parse_hex(size)?,
+                }),
+                [] => {}
+                _ => bail!("invalid line in layout: '{}'", line),
+            }
+        }
+        Ok(SnapshotLayout {
+            vcpus: require_with!(vcpus, "layout has no vcpus line"),
+            vcpu_state: require_with!(vcpu_state, "layout has no vcpu-state line"),
+            regions,
+        })
+    }
+
+    /// Fails unless a snapshot with this layout can be restored into a VM with layout `vm`
+    pub fn check_compatible(&self, vm: &SnapshotLayout) -> Result<()> {
+        if self.vcpu_state != vm.vcpu_state {
+            bail!(
+                "snapshot was taken by a vmsh with different kvm structures ({:?} != {:?})",
+                self.vcpu_state,
+                vm.vcpu_state
+            );
+        }
+        if self.vcpus != vm.vcpus {
+            bail!(
+                "snapshot has {} vcpus, but the vm has {}",
+                self.vcpus,
+                vm.vcpus
+            );
+        }
+        if self.regions != vm.regions {
+            let describe = |regions: &[SnapshotRegion]| {
+                regions
+                    .iter()
+                    .map(|r| format!("{:#x}-{:#x}", r.phys_addr, r.phys_addr + r.size))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            bail!(
+                "guest memory layout differs, snapshot: [{}], vm: [{}]",
+                describe(&self.regions),
+                describe(&vm.regions)
+            );
+        }
+        Ok(())
+    }
+
+    fn memory_size(&self) -> usize {
+        self.regions.iter().map(|r| r.size).sum()
+    }
+}
+
+fn as_bytes<T: Copy>(v: &T) -> &[u8] {
+    // Safe because the slice covers exactly the `size_of::<T>()` bytes of `v` and borrows it, so
+    // it cannot outlive it. `T` is a plain KVM structure without padding we would leak.
+    unsafe { slice::from_raw_parts((v as *const T) as *const u8, size_of::<T>()) }
+}
+
+/// `T` must be a plain KVM structure, valid for any bit pattern.
+fn from_bytes<T: Copy>(buf: &[u8]) -> T {
+    assert_eq!(buf.len(), size_of::<T>());
+    // Safe because `buf` holds `size_of::<T>()` bytes, the read does not need alignment and
+    // every bit pattern is a valid `T`.
+    unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) }
+}
+
+fn host_addr(maps: &[Mapping], region: &SnapshotRegion) -> Result<usize> {
+    let map = require_with!(
+        maps.iter()
+            .find(|m| m.phys_addr == region.phys_addr && m.size() == region.size),
+        "no mapping for guest memory at {:#x}",
+        region.phys_addr
+    );
+    Ok(map.start)
+}
+
+fn save_vcpu(hv: &Hypervisor, vcpu: &VCPU, path: &Path) -> Result<()> {
+    let regs = hv.get_regs(vcpu)?;
+    let sregs = hv.get_sregs(vcpu)?;
+    let fpu = hv.get_fpu(vcpu)?;
+    let lapic = hv.get_lapic(vcpu)?;
+    let mut state = vec![];
+    state.extend_from_slice(as_bytes(&regs));
+    state.extend_from_slice(as_bytes(&sregs));
+    state.extend_from_slice(as_bytes(&fpu));
+    state.extend_from_slice(as_bytes(&lapic.0));
+    try_with!(fs::write(path, state), "cannot write {}", path.display());
+    Ok(())
+}
+
+fn read_vcpu(path: &Path, sizes: &[usize; 4]) -> Result<Vec<u8>> {
+    let state = try_with!(fs::read(path), "cannot read {}", path.display());
+    if state.len() != sizes.iter().sum::<usize>() {
+        bail!(
+            "{} has {} bytes, expected {}",
+            path.display(),
+            state.len(),
+            sizes.iter().sum::<usize>()
+        );
+    }
+    Ok(state)
+}
+
+/// `state` as returned by `read_vcpu`
+fn restore_vcpu(hv: &Hypervisor, vcpu: &VCPU, state: &[u8], sizes: &[usize; 4]) -> Result<()> {
+    let (regs, rest) = state.split_at(sizes[0]);
+    let (sregs, rest) = rest.split_at(sizes[1]);
+    let (fpu, lapic) = rest.split_at(sizes[2]);
+    // sregs first: they switch modes, which general purpose registers depend on
+    hv.set_sregs(vcpu, &from_bytes::<kvmb::kvm_sregs>(sregs))?;
+    hv.set_regs(vcpu, &from_bytes::<Regs>(regs))?;
+    hv.set_fpu(vcpu, &from_bytes::<kvmb::kvm_fpu>(fpu))?;
+    hv.set_lapic(vcpu, &Lapic(from_bytes::<kvmb::kvm_lapic_state>(lapic)))?;
+    Ok(())
+}
+
+fn vcpu_path(dir: &Path, vcpu: &VCPU) -> PathBuf {
+    dir.join(format!("vcpu{}", vcpu.idx))
+}
+
+/// Write a snapshot of `hv` to `dir`, which is created if needed. Expects the hypervisor to be
+/// stopped.
+pub fn save_snapshot(hv: &Hypervisor, dir: &Path) -> Result<SnapshotLayout> {
+    try_with!(fs::create_dir_all(dir), "cannot create {}", dir.display());
+    let maps = try_with!(hv.get_maps(), "cannot get guest memory mappings");
+    let layout = SnapshotLayout::new(&maps, hv.vcpus.len());
+
+    let path = dir.join("memory");
+    let file = try_with!(File::create(&path), "cannot create {}", path.display());
+    let mut out = BufWriter::new(file);
+    let mut buf = vec![0; CHUNK_SIZE];
+    for region in &layout.regions {
+        let start = host_addr(&maps, region)?;
+        let mut offset = 0;
+        while offset < region.size {
+            let chunk = &mut buf[..(region.size - offset).min(CHUNK_SIZE)];
+            try_with!(
+                hv.read_slice(start + offset, chunk),
+                "cannot read guest memory at {:#x}",
+                region.phys_addr + offset
+            );
+            try_with!(out.write_all(chunk), "cannot write {}", path.display());
+            offset += chunk.len();
+        }
+    }
+    try_with!(out.flush(), "cannot write {}", path.display());
+
+    for vcpu in &hv.vcpus {
+        save_vcpu(hv, vcpu, &vcpu_path(dir, vcpu))?;
+    }
+
+    // written last, a snapshot without layout is incomplete
+    let path = dir.join("layout");
+    try_with!(
+        fs::write(&path, layout.to_string()),
+        "cannot write {}",
+        path.display()
+    );
+    Ok(layout)
+}
+
+/// Write the snapshot in `dir` back into `hv`. Fails without touching the VM if the snapshot
+/// does not match its memory layout or vcpus, or any of its files is missing or has the wrong
+/// size. Expects the hypervisor to be stopped.
+pub fn restore_snapshot(hv: &Hypervisor, dir: &Path) -> Result<SnapshotLayout> {
+    let path = dir.join("layout");
+    let content = try_with!(fs::read_to_string(&path), "cannot read {}", path.display());
+    let layout = try_with!(
+        SnapshotLayout::parse(&content),
+        "cannot parse {}",
+        path.display()
+    );
+    let maps = try_with!(hv.get_maps(), "cannot get guest memory mappings");
+    layout.check_compatible(&SnapshotLayout::new(&maps, hv.vcpus.len()))?;
+
+    let path = dir.join("memory");
+    let file = try_with!(File::open(&path), "cannot open {}", path.display());
+    let len = try_with!(file.metadata(), "cannot stat {}", path.display()).len();
+    if len != layout.memory_size() as u64 {
+        bail!(
+            "{} has {} bytes, expected {}",
+            path.display(),
+            len,
+            layout.memory_size()
+        );
+    }
+    // everything that can be checked is checked before the first write, so a broken snapshot
+    // does not leave the VM half restored
+    let mut vcpu_states = vec![];
+    for vcpu in &hv.vcpus {
+        let path = vcpu_path(dir, vcpu);
+        if !path.exists() {
+            bail!("snapshot has no state for vcpu {}", vcpu.idx);
+        }
+        vcpu_states.push(read_vcpu(&path, &layout.vcpu_state)?);
+    }
+    let starts = layout
+        .regions
+        .iter()
+        .map(|region| host_addr(&maps, region))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut input = BufReader::new(file);
+    let mut buf = vec![0; CHUNK_SIZE];
+    for (region, start) in layout.regions.iter().zip(starts) {
+        let mut offset = 0;
+        while offset < region.size {
+            let chunk = &mut buf[..(region.size - offset).min(CHUNK_SIZE)];
+            try_with!(input.read_exact(chunk), "cannot read {}", path.display());
+            try_with!(
+                hv.write_slice(start + offset, chunk),
+                "cannot write guest memory at {:#x}",
+                region.phys_addr + offset
+            );
+            offset += chunk.len();
+        }
+    }
+
+    for (vcpu, state) in hv.vcpus.iter().zip(&vcpu_states) {
+        try_with!(
+            restore_vcpu(hv, vcpu, state, &layout.vcpu_state),
+            "cannot restore vcpu {}",
+            vcpu.idx
+        );
+    }
+    Ok(layout)
+}
+
+pub fn save(opts: &SnapshotOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+    let res = save_snapshot(&vm, &opts.dir);
+    vm.resume()?;
+    let layout = res?;
+    info!(
+        "saved {} MiB of guest memory and {} vcpus to {}",
+        layout.memory_size() >> 20,
+        layout.vcpus,
+        opts.dir.display()
+    );
+    Ok(())
+}
+
+pub fn restore(opts: &SnapshotOptions) -> Result<()> {
+    let vm = try_with!(
+        get_hypervisor(opts.pid, opts.hypervisor),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+    let res = restore_snapshot(&vm, &opts.dir);
+    vm.resume()?;
+    let layout = res?;
+    info!(
+        "restored {} MiB of guest memory and {} vcpus from {}",
+        layout.memory_size() >> 20,
+        layout.vcpus,
+        opts.dir.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer::testutils::mapping;
+
+    fn map(phys_addr: usize, size: usize, prot_flags: ProtFlags) -> Mapping {
+        Mapping {
+            prot_flags,
+            ..mapping(0x7f00_0000_0000 + phys_addr, phys_addr, size)
+        }
+    }
+
+    #[test]
+    fn test_snapshot_layout() {
+        let rw = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        let maps = vec![
+            map(0x10_0000, 0x7ff0_0000, rw),
+            // the bios is read-only and not saved
+            map(0xfffc_0000, 0x4_0000, ProtFlags::PROT_READ),
+            map(0, 0xa_0000, rw),
+        ];
+        let layout = SnapshotLayout::new(&maps, 2);
+        assert_eq!(
+            layout.regions,
+            vec![
+                SnapshotRegion {
+                    phys_addr: 0,
+                    size: 0xa_0000
+                },
+                SnapshotRegion {
+                    phys_addr: 0x10_0000,
+                    size: 0x7ff0_0000
+                },
+            ]
+        );
+        let parsed = SnapshotLayout::parse(&layout.to_string()).expect("valid layout");
+        assert_eq!(parsed, layout);
+        parsed
+            .check_compatible(&layout)
+            .expect("same layout is compatible");
+
+        let smaller = SnapshotLayout::new(&maps[..2], 2);
+        assert!(layout.check_compatible(&smaller).is_err());
+        let fewer_vcpus = SnapshotLayout::new(&maps, 1);
+        assert!(layout.check_compatible(&fewer_vcpus).is_err());
+
+        assert!(SnapshotLayout::parse("vmsh-snapshot 2\nvcpus 1\n").is_err());
+        assert!(SnapshotLayout::parse("core dump\n").is_err());
+        assert!(SnapshotLayout::parse("vmsh-snapshot 1\nvcpu-state 1 2 3 4\n").is_err());
+        assert!(
+            SnapshotLayout::parse("vmsh-snapshot 1\nvcpus 1\nvcpu-state 1 2 3 4\nmem 0\n").is_err()
+        );
+    }
+}
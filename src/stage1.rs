@@ -1,6 +1,6 @@
 use crate::cpu::Regs;
 use libc::c_void;
-use log::{debug, info};
+use log::{debug, info, warn};
 /// This module loads kernel code into the VM that we want to attach to.
 use simple_error::bail;
 use simple_error::try_with;
@@ -61,6 +61,9 @@ impl Stage1 {
         mmio_ranges: Vec<u64>,
     ) -> Result<Stage1> {
         let kernel = find_kernel(&allocator.guest_mem, &allocator.hv)?;
+        if !kernel.symbols.contains_key("register_virtio_device") {
+            warn!("guest kernel does not export register_virtio_device: virtio is either disabled or built as a module, our devices may not get probed");
+        }
 
         let mut regs = try_with!(
             allocator.hv.get_regs(&allocator.hv.vcpus[0]),
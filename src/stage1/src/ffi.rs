@@ -184,6 +184,10 @@ pub unsafe fn kernel_write_4_13(
     ) as ssize_t
 }
 
+/// Signature of `__request_module`, which is looked up at runtime because kernels without
+/// CONFIG_MODULES do not have it
+pub type request_module_fn = unsafe extern "C" fn(wait: bool, fmt: *const c_char, ...) -> c_int;
+
 extern "C" {
     pub fn platform_device_register_full(
         pdevinfo: *const platform_device_info,
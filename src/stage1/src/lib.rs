@@ -391,6 +391,22 @@ fn usleep_range(min: c_ulong, max: c_ulong) {
     }
 }
 
+/// Make the guest probe the devices we registered. The driver core binds them as soon as the
+/// virtio-mmio driver is registered, but if it is built as a module, nothing loads it for
+/// devices added at runtime. modprobe treats built-in and loaded modules as success.
+unsafe fn trigger_probe() {
+    let sym = ffi::__symbol_get(c_str!("__request_module").as_ptr() as *const c_char);
+    if sym.is_null() {
+        printkln!("stage1: kernel cannot load modules, not loading virtio_mmio");
+        return;
+    }
+    let request_module = core::mem::transmute::<*mut c_void, ffi::request_module_fn>(sym);
+    let res = request_module(true, c_str!("virtio_mmio").as_ptr() as *const c_char);
+    if res != 0 {
+        printkln!("stage1: failed to load virtio_mmio: %d", res);
+    }
+}
+
 // cannot put this onto the stack without stackoverflows?
 static mut DEVICES: [Option<PlatformDevice>; MAX_DEVICES] = [None, None, None];
 
@@ -431,6 +447,7 @@ unsafe fn run_stage2() -> Result<(), ()> {
             }
         };
     }
+    trigger_probe();
 
     // we never delete this file, however deleting files is complex and requires accessing
     // internal structs that might change.
@@ -1,15 +1,18 @@
+use nix::sys::resource::{getrlimit, setrlimit};
+use nix::sys::stat::{umask, Mode};
 use nix::{self, unistd};
-use simple_error::try_with;
+use simple_error::{bail, try_with};
 use std::collections::HashMap;
 use std::env;
 use std::ffi::OsString;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader};
 use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::CommandExt;
 use std::process::Child;
 use std::process::Command;
 
-use crate::procfs;
+use crate::procfs::{self, Limit};
 use crate::result::Result;
 
 pub struct Cmd {
@@ -17,30 +20,31 @@ pub struct Cmd {
     command: String,
     arguments: Vec<String>,
     home: Option<OsString>,
+    umask: Option<Mode>,
+    limits: Vec<Limit>,
 }
 
+/// Environment variables of `pid`, empty if it has no environ file.
 fn read_environment(pid: unistd::Pid) -> Result<HashMap<OsString, OsString>> {
     let path = procfs::get_path().join(pid.to_string()).join("environ");
-    let f = try_with!(File::open(&path), "failed to open {}", path.display());
+    let f = match File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => bail!("failed to open {}: {}", path.display(), e),
+    };
     let reader = BufReader::new(f);
-    let res: HashMap<OsString, OsString> = reader
-        .split(b'\0')
-        .filter_map(|var| {
-            let var = match var {
-                Ok(var) => var,
-                Err(_) => return None,
-            };
-
-            let tuple: Vec<&[u8]> = var.splitn(2, |b| *b == b'=').collect();
-            if tuple.len() != 2 {
-                return None;
-            }
-            Some((
-                OsString::from_vec(Vec::from(tuple[0])),
-                OsString::from_vec(Vec::from(tuple[1])),
-            ))
-        })
-        .collect();
+    let mut res = HashMap::new();
+    for var in reader.split(b'\0') {
+        let var = try_with!(var, "failed to read {}", path.display());
+        let tuple: Vec<&[u8]> = var.splitn(2, |b| *b == b'=').collect();
+        if tuple.len() != 2 {
+            continue;
+        }
+        res.insert(
+            OsString::from_vec(Vec::from(tuple[0])),
+            OsString::from_vec(Vec::from(tuple[1])),
+        );
+    }
     Ok(res)
 }
 
@@ -59,17 +63,35 @@ impl Cmd {
 
         let command = command.unwrap_or_else(|| String::from("sh"));
 
-        let variables = try_with!(
-            read_environment(pid),
-            "could not inherit environment variables of container"
-        );
+        // /proc/<pid>/environ may be restricted, i.e. by hidepid. The command still
+        // works without the container's variables, PATH and HOME are set in `spawn`.
+        let variables = match read_environment(pid) {
+            Ok(variables) => variables,
+            Err(e) => {
+                eprintln!(
+                    "could not inherit environment variables of container, continuing without: {}",
+                    e
+                );
+                HashMap::new()
+            }
+        };
         Ok(Cmd {
             command,
             arguments,
             home,
             environment: variables,
+            umask: None,
+            limits: vec![],
         })
     }
+
+    /// Run the command with the umask and resource limits of the target process instead of ours,
+    /// so it behaves like a process started in the container.
+    pub fn inherit_limits(&mut self, umask: Option<Mode>, limits: Vec<Limit>) {
+        self.umask = umask;
+        self.limits = limits;
+    }
+
     pub fn spawn(mut self) -> Result<Child> {
         let default_path =
             OsString::from("/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin");
@@ -82,10 +104,43 @@ impl Cmd {
             self.environment.insert(OsString::from("HOME"), path);
         }
 
-        let child = Command::new(&self.command)
-            .args(&self.arguments)
-            .envs(self.environment)
-            .spawn();
+        // Raising a hard limit needs CAP_SYS_RESOURCE, which we may have dropped already. In this
+        // case we fall back to our hard limit, looked up here since pre_exec must not allocate.
+        let limits = self
+            .limits
+            .iter()
+            .map(|limit| {
+                let fallback = getrlimit(limit.resource)
+                    .map(|(_, hard)| hard)
+                    .unwrap_or(limit.hard);
+                (*limit, fallback)
+            })
+            .collect::<Vec<_>>();
+        let mask = self.umask;
+
+        let mut command = Command::new(&self.command);
+        command.args(&self.arguments).envs(self.environment);
+        if mask.is_some() || !limits.is_empty() {
+            // Safe because umask and setrlimit are async-signal-safe.
+            unsafe {
+                command.pre_exec(move || {
+                    if let Some(mask) = mask {
+                        umask(mask);
+                    }
+                    for (limit, fallback) in &limits {
+                        if setrlimit(limit.resource, limit.soft, limit.hard).is_err() {
+                            let _ = setrlimit(
+                                limit.resource,
+                                limit.soft.min(*fallback),
+                                limit.hard.min(*fallback),
+                            );
+                        }
+                    }
+                    Ok(())
+                })
+            };
+        }
+        let child = command.spawn();
         Ok(try_with!(
             child,
             "failed to spawn {} {}",
@@ -6,7 +6,7 @@ use simple_error::{bail, try_with};
 use std::ffi::OsString;
 use std::fs;
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::{env, io};
 use user_namespace::IdMap;
@@ -27,6 +27,7 @@ mod mount_context;
 mod mountns;
 mod namespace;
 mod procfs;
+mod reaper;
 mod result;
 mod sys_ext;
 mod user_namespace;
@@ -36,6 +37,12 @@ struct Options {
     command: Option<String>,
     args: Vec<String>,
     home: Option<OsString>,
+    /// reap orphaned processes of the command, like an init process would
+    subreaper: bool,
+    /// run the command with the umask and resource limits of the target process
+    inherit_limits: bool,
+    /// where the root of the target is visible while the command runs in our root
+    data_dir: PathBuf,
 }
 
 fn cleanup_vmsh_exe() {
@@ -143,6 +150,22 @@ fn run_stage2(opts: &Options) -> Result<()> {
         "failed to get status of target process"
     );
 
+    // read before we switch to the namespaces of the target, its /proc may be restricted
+    let limits = if opts.inherit_limits {
+        match procfs::limits(opts.target_pid) {
+            Ok(limits) => Some(limits),
+            Err(e) => {
+                eprintln!(
+                    "could not inherit resource limits of target process, continuing with ours: {}",
+                    e
+                );
+                Some(vec![])
+            }
+        }
+    } else {
+        None
+    };
+
     let metadata = try_with!(
         fs::metadata(procfs::get_path().join(opts.target_pid.to_string())),
         "failed to container uid/gid"
@@ -206,7 +229,7 @@ fn run_stage2(opts: &Options) -> Result<()> {
 
     try_with!(mount_namespace.apply(), "failed to apply mount namespace");
 
-    let mount_ns = mountns::setup(&dev, mount_namespace, &mount_label)?;
+    let mount_ns = mountns::setup(&dev, mount_namespace, &mount_label, &opts.data_dir)?;
     let dropped_groups = if supported_namespaces.contains(namespace::USER.name) {
         unistd::setgroups(&[]).is_ok()
     } else {
@@ -236,25 +259,51 @@ fn run_stage2(opts: &Options) -> Result<()> {
         try_with!(profile.inherit_profile(), "failed to inherit lsm profile");
     }
 
-    let cmd = Cmd::new(
+    let mut cmd = Cmd::new(
         opts.command.clone(),
         opts.args.clone(),
         opts.target_pid,
         opts.home.clone(),
     )?;
+    if let Some(limits) = limits {
+        cmd.inherit_limits(process_status.umask, limits);
+    }
+
+    if opts.subreaper {
+        reaper::set_child_subreaper()?;
+    }
 
     let mut child = cmd.spawn()?;
     // now that we have our child, we can drop temporary mount points
 
     drop(mount_ns);
-    let status = try_with!(child.wait(), "failed to wait for child process");
-    eprintln!("process finished with {}", status);
+    if opts.subreaper {
+        let status = reaper::wait_reaping(Pid::from_raw(child.id() as i32))?;
+        eprintln!("process finished with {:?}", status);
+    } else {
+        let status = try_with!(child.wait(), "failed to wait for child process");
+        eprintln!("process finished with {}", status);
+    }
     Ok(())
 }
 
 fn main() {
     kmsg_log("[stage2] start\n");
-    let args = env::args().collect::<Vec<_>>();
+    let mut args = env::args().collect::<Vec<_>>();
+    // options from vmsh come before the command
+    let mut data_dir = PathBuf::from(mountns::DEFAULT_DATA_DIR);
+    let mut inherit_limits = true;
+    loop {
+        if args.len() > 2 && args[1] == "--data-dir" {
+            data_dir = PathBuf::from(args.remove(2));
+            args.remove(1);
+        } else if args.len() > 1 && args[1] == "--no-inherit-limits" {
+            inherit_limits = false;
+            args.remove(1);
+        } else {
+            break;
+        }
+    }
     let command = if args.len() > 2 {
         Some(args[1].clone())
     } else {
@@ -266,6 +315,9 @@ fn main() {
         target_pid: Pid::from_raw(1),
         args: args[2..].to_vec(),
         home: None,
+        subreaper: true,
+        inherit_limits,
+        data_dir,
     };
     if let Err(e) = run_stage2(&opts) {
         // print to both allocated pty and kmsg
@@ -1,18 +1,19 @@
 use ioutils::tmp;
 use nix::sched::CloneFlags;
+use nix::sys::statvfs::{statvfs, FsFlags};
 use nix::sys::wait::waitpid;
 use nix::sys::wait::WaitStatus;
 use nix::unistd::fork;
 use nix::{mount, sched, unistd};
 use nix::{mount::MsFlags, unistd::getpid};
-use simple_error::try_with;
 use simple_error::SimpleError;
+use simple_error::{bail, try_with};
 use std::fs::File;
-use std::fs::{metadata, remove_dir};
+use std::fs::{metadata, remove_dir, symlink_metadata};
 use std::fs::{set_permissions, Permissions};
 use std::io;
 use std::os::unix::prelude::*;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
 use crate::block::BlockDevice;
 use crate::dir::mkdir_p;
@@ -39,7 +40,8 @@ const MOUNTS: &[&str] = &[
     "proc",
 ];
 
-const VMSH_MOUNT_POINT: &str = "var/lib/vmsh";
+/// Where the root of the container is moved to in our root, unless vmsh passes `--data-dir`
+pub const DEFAULT_DATA_DIR: &str = "/var/lib/vmsh";
 
 impl MountNamespace {
     fn new(old_namespace: namespace::Namespace) -> Result<MountNamespace> {
@@ -134,11 +136,11 @@ impl Drop for MountNamespace {
 
 const NONE: Option<&'static [u8]> = None;
 
-pub fn setup_bindmounts(mounts: &[&str]) -> Result<()> {
+pub fn setup_bindmounts(mounts: &[&str], data_dir: &Path) -> Result<()> {
     for m in mounts {
         let mountpoint_buf = PathBuf::from("/").join(m);
         let mountpoint = mountpoint_buf.as_path();
-        let source_buf = PathBuf::from("/var/lib/vmsh").join(m);
+        let source_buf = data_dir.join(m);
         let source = source_buf.as_path();
 
         let source_stat = match metadata(source) {
@@ -203,11 +205,56 @@ pub fn setup_bindmounts(mounts: &[&str]) -> Result<()> {
     Ok(())
 }
 
+/// Make sure `data_dir` can be created below `root` before anything is created: every part of it
+/// that already exists has to be a directory, not i.e. a symlink pointing out of `root`, and the
+/// file system has to be writable. We run as root, so access(2) would grant write access
+/// regardless of permissions.
+fn check_data_dir(root: &Path, data_dir: &Path) -> Result<()> {
+    // `dir` is `path` as seen from the root of the backing file, for messages
+    let mut path = root.to_path_buf();
+    let mut dir = PathBuf::from("/");
+    for component in data_dir.components().skip(1) {
+        path.push(component);
+        dir.push(component);
+        match symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => bail!("{} in the backing file is not a directory", dir.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => bail!("cannot stat {}: {}", dir.display(), e),
+        }
+    }
+    let stat = try_with!(statvfs(root), "cannot stat file system of the backing file");
+    if stat.flags().contains(FsFlags::ST_RDONLY) {
+        bail!(
+            "cannot create data directory {}: the backing file is mounted read-only",
+            data_dir.display()
+        );
+    }
+    Ok(())
+}
+
 pub fn setup(
     device: &BlockDevice,
     container_namespace: namespace::Namespace,
     mount_label: &Option<String>,
+    data_dir: &Path,
 ) -> Result<MountNamespace> {
+    let relative_data_dir = match data_dir.strip_prefix("/") {
+        Ok(dir) if dir.parent().is_some() => dir,
+        _ => bail!(
+            "data directory {} must be an absolute path other than /",
+            data_dir.display()
+        ),
+    };
+    if relative_data_dir
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        bail!(
+            "data directory {} must not contain '.' or '..'",
+            data_dir.display()
+        );
+    }
     let ns = MountNamespace::new(container_namespace)?;
 
     try_with!(
@@ -235,11 +282,12 @@ pub fn setup(
 
     device.mount(ns.mountpoint.as_path(), mount_label)?;
 
-    let vmsh_mount_point = &ns.mountpoint.join(VMSH_MOUNT_POINT);
+    let vmsh_mount_point = &ns.mountpoint.join(relative_data_dir);
+    check_data_dir(&ns.mountpoint, data_dir)?;
     try_with!(
         mkdir_p(&vmsh_mount_point),
-        "cannot create container mountpoint /{}",
-        VMSH_MOUNT_POINT
+        "cannot create container mountpoint {}",
+        data_dir.display()
     );
     let flags = MsFlags::MS_REC | MsFlags::MS_MOVE;
     try_with!(
@@ -279,7 +327,10 @@ pub fn setup(
         try_with!(mkdir_p(p), "cannot create directory {}", p);
     }
 
-    try_with!(setup_bindmounts(MOUNTS), "failed to setup bind mounts");
+    try_with!(
+        setup_bindmounts(MOUNTS, data_dir),
+        "failed to setup bind mounts"
+    );
 
     Ok(ns)
 }
@@ -1,12 +1,14 @@
 use libc::pid_t;
+use nix::sys::resource::{rlim_t, Resource};
+use nix::sys::stat::Mode;
 use nix::unistd::Pid;
-use simple_error::{try_with, SimpleError};
+use simple_error::{bail, try_with, SimpleError};
 use std::env;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::result::Result;
 
@@ -22,6 +24,8 @@ pub struct ProcStatus {
     pub local_pid: Pid,
     pub inherited_capabilities: u64,
     pub effective_capabilities: u64,
+    /// Only reported by Linux 4.7 and newer
+    pub umask: Option<Mode>,
 }
 
 pub fn status(target_pid: Pid) -> Result<ProcStatus> {
@@ -31,6 +35,7 @@ pub fn status(target_pid: Pid) -> Result<ProcStatus> {
     let mut ns_pid: Option<Pid> = None;
     let mut inherited_caps: Option<u64> = None;
     let mut effective_caps: Option<u64> = None;
+    let mut umask: Option<Mode> = None;
 
     let reader = BufReader::new(file);
     for line in reader.lines() {
@@ -64,6 +69,10 @@ pub fn status(target_pid: Pid) -> Result<ProcStatus> {
                 );
                 effective_caps = Some(cap);
             }
+        } else if columns[0] == "Umask:" {
+            if let Some(umask_string) = columns.last() {
+                umask = Some(parse_umask(umask_string)?);
+            }
         }
     }
 
@@ -94,5 +103,159 @@ pub fn status(target_pid: Pid) -> Result<ProcStatus> {
             }),
             ""
         ),
+        umask,
+    })
+}
+
+fn parse_umask(value: &str) -> Result<Mode> {
+    let mode = try_with!(
+        u32::from_str_radix(value, 8),
+        "read invalid umask from proc: '{}'",
+        value
+    );
+    Ok(Mode::from_bits_truncate(mode))
+}
+
+/// A resource limit as listed in `/proc/<pid>/limits`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub resource: Resource,
+    pub soft: rlim_t,
+    pub hard: rlim_t,
+}
+
+fn limit_resource(name: &str) -> Option<Resource> {
+    Some(match name {
+        "Max cpu time" => Resource::RLIMIT_CPU,
+        "Max file size" => Resource::RLIMIT_FSIZE,
+        "Max data size" => Resource::RLIMIT_DATA,
+        "Max stack size" => Resource::RLIMIT_STACK,
+        "Max core file size" => Resource::RLIMIT_CORE,
+        "Max resident set" => Resource::RLIMIT_RSS,
+        "Max processes" => Resource::RLIMIT_NPROC,
+        "Max open files" => Resource::RLIMIT_NOFILE,
+        "Max locked memory" => Resource::RLIMIT_MEMLOCK,
+        "Max address space" => Resource::RLIMIT_AS,
+        "Max file locks" => Resource::RLIMIT_LOCKS,
+        "Max pending signals" => Resource::RLIMIT_SIGPENDING,
+        "Max msgqueue size" => Resource::RLIMIT_MSGQUEUE,
+        "Max nice priority" => Resource::RLIMIT_NICE,
+        "Max realtime priority" => Resource::RLIMIT_RTPRIO,
+        "Max realtime timeout" => Resource::RLIMIT_RTTIME,
+        _ => return None,
     })
 }
+
+fn parse_limit_value(value: &str) -> Result<rlim_t> {
+    if value == "unlimited" {
+        return Ok(libc::RLIM_INFINITY);
+    }
+    Ok(try_with!(
+        value.parse::<rlim_t>(),
+        "read invalid limit from proc: '{}'",
+        value
+    ))
+}
+
+/// Width of the name column in `/proc/<pid>/limits`
+const LIMIT_NAME_WIDTH: usize = 26;
+
+pub fn limits(target_pid: Pid) -> Result<Vec<Limit>> {
+    let path = get_path().join(target_pid.to_string()).join("limits");
+    let file = try_with!(File::open(&path), "failed to open {}", path.display());
+    parse_limits(BufReader::new(file), &path)
+}
+
+fn parse_limits<R: BufRead>(reader: R, path: &Path) -> Result<Vec<Limit>> {
+    let mut limits = vec![];
+    // the first line is the header
+    for line in reader.lines().skip(1) {
+        let line = try_with!(line, "could not read {}", path.display());
+        if line.len() < LIMIT_NAME_WIDTH {
+            bail!("invalid line in {}: '{}'", path.display(), line);
+        }
+        let (name, values) = line.split_at(LIMIT_NAME_WIDTH);
+        let resource = match limit_resource(name.trim()) {
+            Some(resource) => resource,
+            // limits added by newer kernels
+            None => continue,
+        };
+        let mut values = values.split_whitespace();
+        let (soft, hard) = match (values.next(), values.next()) {
+            (Some(soft), Some(hard)) => (parse_limit_value(soft)?, parse_limit_value(hard)?),
+            _ => bail!("invalid line in {}: '{}'", path.display(), line),
+        };
+        limits.push(Limit {
+            resource,
+            soft,
+            hard,
+        });
+    }
+    Ok(limits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{limits, parse_limits, parse_umask, Limit};
+    use nix::sys::resource::Resource;
+    use nix::sys::stat::Mode;
+    use nix::unistd::getpid;
+    use std::path::Path;
+
+    const LIMITS: &str = "\
+Limit                     Soft Limit           Hard Limit           Units     
+Max cpu time              unlimited            unlimited            seconds   
+Max open files            1024                 524288               files     
+Max core file size        0                    unlimited            bytes     
+Max future limit          1                    2                    things    
+";
+
+    #[test]
+    fn test_parse_limits() {
+        let limits =
+            parse_limits(LIMITS.as_bytes(), Path::new("limits")).expect("cannot parse limits");
+        assert_eq!(
+            limits,
+            vec![
+                Limit {
+                    resource: Resource::RLIMIT_CPU,
+                    soft: libc::RLIM_INFINITY,
+                    hard: libc::RLIM_INFINITY,
+                },
+                Limit {
+                    resource: Resource::RLIMIT_NOFILE,
+                    soft: 1024,
+                    hard: 524288,
+                },
+                Limit {
+                    resource: Resource::RLIMIT_CORE,
+                    soft: 0,
+                    hard: libc::RLIM_INFINITY,
+                },
+            ]
+        );
+
+        let truncated = "header\nMax open files\n";
+        assert!(parse_limits(truncated.as_bytes(), Path::new("limits")).is_err());
+        let garbage = "header\nMax open files            many                 524288\n";
+        assert!(parse_limits(garbage.as_bytes(), Path::new("limits")).is_err());
+    }
+
+    #[test]
+    fn test_own_limits() {
+        let limits = limits(getpid()).expect("cannot read own limits");
+        assert!(limits
+            .iter()
+            .any(|limit| limit.resource == Resource::RLIMIT_NOFILE));
+    }
+
+    #[test]
+    fn test_parse_umask() {
+        assert_eq!(
+            parse_umask("0022").expect("valid umask"),
+            Mode::S_IWGRP | Mode::S_IWOTH
+        );
+        assert_eq!(parse_umask("0000").expect("valid umask"), Mode::empty());
+        assert!(parse_umask("0099").is_err());
+    }
+}
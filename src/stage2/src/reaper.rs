@@ -0,0 +1,44 @@
+use nix::errno::Errno;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+
+use crate::result::Result;
+use crate::sys_ext::prctl;
+
+/// Make orphaned descendants, i.e. jobs the shell backgrounded before it exited, our children
+/// so we can reap them. Must be called before spawning the command. Descendants in a different
+/// PID namespace are reaped by the init process of that namespace instead.
+pub fn set_child_subreaper() -> Result<()> {
+    try_with!(
+        prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0),
+        "prctl(PR_SET_CHILD_SUBREAPER) failed"
+    );
+    Ok(())
+}
+
+/// Reap all children until `child` exits and return its exit status. Afterwards reap those
+/// orphans that have exited already, without waiting for the ones still running.
+pub fn wait_reaping(child: Pid) -> Result<WaitStatus> {
+    let status = loop {
+        match waitpid(None, None) {
+            Ok(status @ WaitStatus::Exited(pid, _))
+            | Ok(status @ WaitStatus::Signaled(pid, _, _))
+                if pid == child =>
+            {
+                break status;
+            }
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(Errno::ECHILD) => bail!("child {} disappeared", child),
+            Err(e) => bail!("failed to wait for child process: {}", e),
+        }
+    };
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break,
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => bail!("failed to reap orphaned processes: {}", e),
+        }
+    }
+    Ok(status)
+}
@@ -0,0 +1,106 @@
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::{Pid, Uid};
+use simple_error::{bail, try_with};
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::result::Result;
+
+/// Advisory lock that marks a process as attached by vmsh. ptrace only allows one tracer, so a
+/// second vmsh would fail half-way through attaching and could leave the hypervisor stopped.
+///
+/// The lock is a flock on a file keyed by pid. The kernel drops it when vmsh exits, even if it
+/// crashes, so a leftover lock file is harmless. We never delete the file: another vmsh might
+/// already have it open and would end up locking an unlinked inode.
+///
+/// As root the lock files live in `LOCK_DIR`, which only root can write to. Otherwise they go to
+/// the temporary directory, where another user could plant a file or symlink first. Lock files
+/// are therefore opened without following symlinks and must be regular files owned by us.
+#[derive(Debug)]
+pub struct AttachLock {
+    pid: Pid,
+    _file: File,
+}
+
+const LOCK_DIR: &str = "/run/vmsh";
+
+fn lock_dir() -> Result<PathBuf> {
+    if !Uid::effective().is_root() {
+        return Ok(std::env::temp_dir());
+    }
+    match DirBuilder::new().mode(0o700).create(LOCK_DIR) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => bail!("cannot create {}: {}", LOCK_DIR, e),
+    }
+    let meta = try_with!(
+        Path::new(LOCK_DIR).symlink_metadata(),
+        "cannot stat {}",
+        LOCK_DIR
+    );
+    if !meta.is_dir() || meta.uid() != 0 {
+        bail!("{} is not a directory owned by root", LOCK_DIR);
+    }
+    Ok(PathBuf::from(LOCK_DIR))
+}
+
+impl AttachLock {
+    /// Fails right away if another vmsh, or another `Hypervisor` in this process, holds the
+    /// lock for `pid`.
+    pub fn acquire(pid: Pid) -> Result<AttachLock> {
+        let path = lock_dir()?.join(format!("vmsh-attach-{}.lock", pid));
+        let file = try_with!(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .mode(0o600)
+                .custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC)
+                .open(&path),
+            "cannot open lock file {}",
+            path.display()
+        );
+        let meta = try_with!(file.metadata(), "cannot stat {}", path.display());
+        if !meta.is_file() || meta.uid() != Uid::effective().as_raw() {
+            bail!(
+                "lock file {} is not a regular file owned by us",
+                path.display()
+            );
+        }
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => bail!("another vmsh is already attached to pid {}", pid),
+            Err(e) => bail!("cannot lock {}: {}", path.display(), e),
+        }
+        Ok(AttachLock { pid, _file: file })
+    }
+
+    #[must_use]
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AttachLock;
+    use nix::unistd::getpid;
+
+    #[test]
+    fn test_attach_lock() {
+        let pid = getpid();
+        let lock = AttachLock::acquire(pid).expect("first lock succeeds");
+        assert_eq!(lock.pid(), pid);
+        let err = AttachLock::acquire(pid).expect_err("second lock fails");
+        assert_eq!(
+            err.to_string(),
+            format!("another vmsh is already attached to pid {}", pid)
+        );
+        drop(lock);
+        AttachLock::acquire(pid).expect("lock is released on drop");
+    }
+}
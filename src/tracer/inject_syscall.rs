@@ -1,11 +1,13 @@
 use libc::{c_int, c_long, c_ulong, c_void, off_t, pid_t, size_t, ssize_t, SYS_munmap};
 use libc::{SYS_getpid, SYS_ioctl, SYS_mmap};
-use log::debug;
-use nix::sys::wait::{waitpid, WaitStatus};
+use log::{debug, warn};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use simple_error::{bail, try_with};
 use std::os::unix::prelude::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::{current, ThreadId};
+use std::time::{Duration, Instant};
 
 use super::ptrace::attach_seize;
 use crate::cpu::{self, Regs};
@@ -13,6 +15,30 @@ use crate::kvm::hypervisor::VCPU;
 use crate::result::Result;
 use crate::tracer::{ptrace, Tracer};
 
+/// How long an injected syscall may take before we give up on the target thread
+pub const DEFAULT_SYSCALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of times we poll for a syscall stop without sleeping. Injected syscalls are usually
+/// done within microseconds, sleeping right away would slow down every ioctl.
+const SPIN_POLLS: usize = 1000;
+
+/// The syscalls that `kvm::tracee::Tracee` injects into the hypervisor. Implemented by `Process`,
+/// abstracted so that tests can substitute a fake hypervisor for ioctls.
+pub trait Injector {
+    fn ioctl(&self, fd: RawFd, request: c_ulong, arg: c_ulong) -> Result<c_int>;
+    fn mmap(
+        &self,
+        addr: *mut c_void,
+        length: size_t,
+        prot: c_int,
+        flags: c_int,
+        fd: RawFd,
+        offset: off_t,
+    ) -> Result<*mut c_void>;
+    fn munmap(&self, addr: *mut c_void, length: libc::size_t) -> Result<()>;
+    fn close(&self, fd: RawFd) -> Result<c_int>;
+}
+
 #[derive(Debug)]
 pub struct Process {
     process_idx: usize,
@@ -21,6 +47,10 @@ pub struct Process {
     /// Must never be None during operation. Only deinit() (called by drop) may take() this.
     threads: Option<Vec<ptrace::Thread>>,
     owner: Option<ThreadId>,
+    /// see `Process::set_syscall_timeout`
+    syscall_timeout: Duration,
+    /// Set if an injected syscall could not be aborted, see `Process::abort_syscall`
+    poisoned: AtomicBool,
 }
 
 /// save and overwrite main thread state
@@ -73,6 +103,8 @@ pub fn from_tracer(t: Tracer) -> Result<Process> {
         saved_text,
         threads: Some(t.threads),
         owner: t.owner,
+        syscall_timeout: DEFAULT_SYSCALL_TIMEOUT,
+        poisoned: AtomicBool::new(false),
     })
 }
 
@@ -97,6 +129,8 @@ pub fn attach(pid: Pid) -> Result<Process> {
         saved_text,
         threads: Some(threads),
         owner: Some(current().id()),
+        syscall_timeout: DEFAULT_SYSCALL_TIMEOUT,
+        poisoned: AtomicBool::new(false),
     })
 }
 
@@ -163,6 +197,13 @@ impl Process {
             .tid
     }
 
+    /// Fail injected syscalls that did not return within `timeout` instead of blocking forever,
+    /// i.e. because the thread is stuck in uninterruptible sleep. On a timeout the syscall is
+    /// aborted, see `abort_syscall`.
+    pub fn set_syscall_timeout(&mut self, timeout: Duration) {
+        self.syscall_timeout = timeout;
+    }
+
     fn check_owner(&self) -> Result<()> {
         if let Some(tracer) = self.owner {
             if current().id() != tracer {
@@ -206,12 +247,18 @@ impl Process {
             bail!("thread is already disowned");
         }
         let threads = deinit(self).expect("process was deinited before it was dropped!");
-        for thread in &threads {
-            thread.detach()?;
-        }
+        let res = ptrace::detach_all_threads(&threads);
         self.threads = Some(threads);
         self.owner = None;
-        Ok(())
+        res
+    }
+
+    /// Restore the main thread and let all threads continue. Unlike dropping the process, this
+    /// reports threads that could not be released, after having tried all of them.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn detach(mut self) -> Result<()> {
+        let threads = deinit(&mut self).expect("process was deinited before it was dropped!");
+        ptrace::detach_all_threads(&threads)
     }
 
     pub fn ioctl(&self, fd: RawFd, request: c_ulong, arg: c_ulong) -> Result<c_int> {
@@ -332,10 +379,10 @@ impl Process {
         self.syscall(&args).map(|v| v as c_int)
     }
 
-    fn wait_for_syscall(&self) -> Result<()> {
+    fn wait_for_syscall(&self, deadline: Instant) -> Result<()> {
         loop {
-            try_with!(self.main_thread().syscall(), "ptrace_syscall() failed");
-            let status = try_with!(waitpid(self.main_thread().tid, None), "waitpid failed");
+            try_with!(self.main_thread().syscall(None), "ptrace_syscall() failed");
+            let status = self.wait_until(deadline)?;
 
             match status {
                 WaitStatus::PtraceSyscall(_) => return Ok(()),
@@ -345,17 +392,85 @@ impl Process {
         }
     }
 
+    /// waitpid() for the main thread, but give up at `deadline`
+    fn wait_until(&self, deadline: Instant) -> Result<WaitStatus> {
+        let tid = self.main_thread().tid;
+        let mut polls = 0;
+        loop {
+            let status = try_with!(waitpid(tid, Some(WaitPidFlag::WNOHANG)), "waitpid failed");
+            if status != WaitStatus::StillAlive {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "thread {} did not finish the injected syscall within {:?}, it may be stuck in uninterruptible sleep",
+                    tid,
+                    self.syscall_timeout
+                );
+            }
+            if polls < SPIN_POLLS {
+                polls += 1;
+                std::thread::yield_now();
+            } else {
+                std::thread::sleep(Duration::from_micros(100));
+            }
+        }
+    }
+
+    /// Interrupt the main thread after an injected syscall timed out and restore the registers
+    /// it had before we injected anything, so it does not run on with our syscall's arguments.
+    /// If the thread does not stop either, i.e. it is in uninterruptible sleep, we cannot put it
+    /// back into a known state and poison the process: all further syscalls fail.
+    fn abort_syscall(&self) {
+        let thread = self.main_thread();
+        let deadline = Instant::now() + self.syscall_timeout;
+        let stopped = thread
+            .interrupt()
+            .and_then(|_| self.wait_until(deadline))
+            .map(|status| !matches!(status, WaitStatus::Exited(..) | WaitStatus::Signaled(..)));
+        match stopped {
+            Ok(true) => match thread.setregs(&self.saved_regs) {
+                Ok(()) => {
+                    debug!("aborted injected syscall in thread {}", thread.tid);
+                    return;
+                }
+                Err(e) => warn!("cannot restore registers of thread {}: {}", thread.tid, e),
+            },
+            Ok(false) => warn!("thread {} exited during an injected syscall", thread.tid),
+            Err(e) => warn!(
+                "cannot abort injected syscall in thread {}: {}",
+                thread.tid, e
+            ),
+        }
+        self.poisoned.store(true, Ordering::Release);
+    }
+
     fn syscall(&self, regs: &Regs) -> Result<isize> {
         self.check_owner()?;
+        if self.poisoned.load(Ordering::Acquire) {
+            bail!(
+                "an earlier syscall injected into thread {} could not be aborted, cannot inject more",
+                self.main_thread().tid
+            );
+        }
+        let deadline = Instant::now() + self.syscall_timeout;
         try_with!(
             self.main_thread().setregs(regs),
             "cannot set system call args"
         );
         // FIXME: on arm we would need PTRACE_SET_SYSCALL
         // stops before syscall
-        try_with!(self.wait_for_syscall(), "failed to trap before syscall");
+        let res = self.wait_for_syscall(deadline);
+        if res.is_err() {
+            self.abort_syscall();
+        }
+        try_with!(res, "failed to trap before syscall");
         // traps after syscall
-        try_with!(self.wait_for_syscall(), "failed to trap after syscall");
+        let res = self.wait_for_syscall(deadline);
+        if res.is_err() {
+            self.abort_syscall();
+        }
+        try_with!(res, "failed to trap after syscall");
         let result_regs = try_with!(self.main_thread().getregs(), "cannot syscall results");
         assert!(self.saved_regs.ip() == result_regs.ip() - cpu::SYSCALL_SIZE);
         Ok(result_regs.syscall_ret() as isize)
@@ -369,6 +484,32 @@ impl Process {
     }
 }
 
+impl Injector for Process {
+    fn ioctl(&self, fd: RawFd, request: c_ulong, arg: c_ulong) -> Result<c_int> {
+        Process::ioctl(self, fd, request, arg)
+    }
+
+    fn mmap(
+        &self,
+        addr: *mut c_void,
+        length: size_t,
+        prot: c_int,
+        flags: c_int,
+        fd: RawFd,
+        offset: off_t,
+    ) -> Result<*mut c_void> {
+        Process::mmap(self, addr, length, prot, flags, fd, offset)
+    }
+
+    fn munmap(&self, addr: *mut c_void, length: libc::size_t) -> Result<()> {
+        Process::munmap(self, addr, length)
+    }
+
+    fn close(&self, fd: RawFd) -> Result<c_int> {
+        Process::close(self, fd)
+    }
+}
+
 impl Drop for Process {
     fn drop(&mut self) {
         debug!("tracer cleanup started");
@@ -460,4 +601,46 @@ int main() {
             .stdout;
         assert_eq!(output, b"OK\n");
     }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_syscall_timeout() {
+        let dir = tempdir().expect("cannot create tempdir");
+        let binary = dir.path().join("main");
+        compile_executable(
+            r#"
+#include <unistd.h>
+int main() {
+  int a; a = read(0, &a, sizeof(a));
+  return 0;
+}
+"#,
+            &binary,
+        );
+        let (readfd, writefd) = pipe2(OFlag::O_CLOEXEC).expect("cannot create pipe");
+        let read_end = unsafe { Stdio::from_raw_fd(readfd) };
+        let write_end = unsafe { File::from_raw_fd(writefd) };
+        let mut child = Command::new(binary)
+            .stdin(read_end)
+            .spawn()
+            .expect("test program failed");
+        let pid = Pid::from_raw(child.id() as i32);
+        let mut proc = attach(pid).expect("cannot attach with ptrace");
+        proc.set_syscall_timeout(Duration::from_millis(100));
+
+        // nobody writes to stdin, so this read blocks until we give up. Use the red zone below
+        // the stack pointer as buffer.
+        let buf = proc.saved_regs.rsp - 64;
+        let args = syscall_args!(proc.saved_regs, libc::SYS_read as c_ulong, 0, buf, 4);
+        let start = Instant::now();
+        let err = proc.syscall(&args).expect_err("read should time out");
+        assert!(err.to_string().contains("did not finish"), "{}", err);
+        assert!(start.elapsed() < DEFAULT_SYSCALL_TIMEOUT);
+        // the read was interrupted, so the process can be used again
+        assert_eq!(proc.getpid().expect("getpid failed"), pid.as_raw());
+
+        drop(write_end);
+        child.kill().expect("cannot kill test program");
+        child.wait().expect("cannot wait for test program");
+    }
 }
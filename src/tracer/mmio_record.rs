@@ -0,0 +1,243 @@
+//! Recording of MMIO exits to a file for later analysis, i.e. to reverse-engineer what a driver
+//! does with a device.
+//!
+//! The file starts with `MAGIC` and a little endian u32 version, followed by records. Each record
+//! is prefixed by its length as u32 and consists of the host CLOCK_MONOTONIC timestamp (u64 ns),
+//! the guest physical address (u64), an is_write byte, the access length byte and the data. The
+//! data of reads is what the vcpu saw before the read was answered, usually zeros.
+
+use nix::time::{clock_gettime, ClockId};
+use simple_error::{bail, try_with};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::result::Result;
+use crate::tracer::wrap_syscall::{MmioRw, MMIO_RW_DATA_MAX};
+
+const MAGIC: &[u8; 8] = b"VMSHMMIO";
+const VERSION: u32 = 1;
+
+/// timestamp, address, is_write and len
+const RECORD_HEADER_LEN: usize = 8 + 8 + 1 + 1;
+
+/// Buffer size of the recorder. Exits are frequent, so we only write in big chunks to keep
+/// syscalls off the MMIO exit path.
+const BUFFER_SIZE: usize = 1 << 20;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MmioRecord {
+    /// host CLOCK_MONOTONIC in nanoseconds
+    pub monotonic_ns: u64,
+    pub addr: u64,
+    pub is_write: bool,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for MmioRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:09} {} {:#x}",
+            self.monotonic_ns / 1_000_000_000,
+            self.monotonic_ns % 1_000_000_000,
+            if self.is_write { "write" } else { "read " },
+            self.addr
+        )?;
+        if self.is_write {
+            write!(f, " ")?;
+            for b in &self.data {
+                write!(f, "{:02x}", b)?;
+            }
+        } else {
+            write!(f, " ({} bytes)", self.data.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends MMIO exits to a file, see the module documentation for the format.
+pub struct MmioRecorder<W: Write = BufWriter<File>> {
+    out: W,
+    count: u64,
+}
+
+impl MmioRecorder {
+    pub fn create(path: &Path) -> Result<MmioRecorder> {
+        let file = try_with!(File::create(path), "cannot create {}", path.display());
+        MmioRecorder::new(BufWriter::with_capacity(BUFFER_SIZE, file))
+    }
+}
+
+impl<W: Write> MmioRecorder<W> {
+    pub fn new(mut out: W) -> Result<MmioRecorder<W>> {
+        try_with!(out.write_all(MAGIC), "cannot write header");
+        try_with!(out.write_all(&VERSION.to_le_bytes()), "cannot write header");
+        Ok(MmioRecorder { out, count: 0 })
+    }
+
+    pub fn write_record(&mut self, record: &MmioRecord) -> Result<()> {
+        let mut buf = Vec::with_capacity(4 + RECORD_HEADER_LEN + record.data.len());
+        buf.extend_from_slice(&((RECORD_HEADER_LEN + record.data.len()) as u32).to_le_bytes());
+        buf.extend_from_slice(&record.monotonic_ns.to_le_bytes());
+        buf.extend_from_slice(&record.addr.to_le_bytes());
+        buf.push(record.is_write as u8);
+        buf.push(record.data.len() as u8);
+        buf.extend_from_slice(&record.data);
+        try_with!(self.out.write_all(&buf), "cannot write mmio record");
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Append `mmio` timestamped with the current time.
+    pub fn record(&mut self, mmio: &MmioRw) -> Result<()> {
+        let now = try_with!(
+            clock_gettime(ClockId::CLOCK_MONOTONIC),
+            "cannot read host clock"
+        );
+        self.write_record(&MmioRecord {
+            monotonic_ns: now.tv_sec() as u64 * 1_000_000_000 + now.tv_nsec() as u64,
+            addr: mmio.addr,
+            is_write: mmio.is_write,
+            data: mmio.data().to_vec(),
+        })
+    }
+
+    /// Number of records written so far
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Flush buffered records and return the writer.
+    pub fn finish(mut self) -> Result<W> {
+        try_with!(self.out.flush(), "cannot flush mmio records");
+        Ok(self.out)
+    }
+}
+
+/// Reads back files written by `MmioRecorder`.
+pub struct MmioReader<R: Read = BufReader<File>> {
+    input: R,
+}
+
+impl MmioReader {
+    pub fn open(path: &Path) -> Result<MmioReader> {
+        let file = try_with!(File::open(path), "cannot open {}", path.display());
+        MmioReader::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> MmioReader<R> {
+    pub fn new(mut input: R) -> Result<MmioReader<R>> {
+        let mut header = [0u8; 12];
+        try_with!(input.read_exact(&mut header), "cannot read header");
+        if &header[..8] != MAGIC {
+            bail!("not an mmio recording");
+        }
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&header[8..]);
+        let version = u32::from_le_bytes(version);
+        if version != VERSION {
+            bail!("unsupported mmio recording version {}", version);
+        }
+        Ok(MmioReader { input })
+    }
+
+    /// The next record or None at the end of the file.
+    pub fn read_record(&mut self) -> Result<Option<MmioRecord>> {
+        let mut len = [0u8; 4];
+        match self.input.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => bail!("cannot read mmio record: {}", e),
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if !(RECORD_HEADER_LEN..=RECORD_HEADER_LEN + MMIO_RW_DATA_MAX).contains(&len) {
+            bail!("mmio record has an invalid length of {}", len);
+        }
+        let mut buf = vec![0u8; len];
+        try_with!(self.input.read_exact(&mut buf), "mmio record is truncated");
+        let u64_at = |offset: usize| {
+            let mut val = [0u8; 8];
+            val.copy_from_slice(&buf[offset..offset + 8]);
+            u64::from_le_bytes(val)
+        };
+        let (monotonic_ns, addr) = (u64_at(0), u64_at(8));
+        let data_len = buf[17] as usize;
+        if data_len != len - RECORD_HEADER_LEN {
+            bail!(
+                "mmio record claims {} bytes of data but has {}",
+                data_len,
+                len - RECORD_HEADER_LEN
+            );
+        }
+        Ok(Some(MmioRecord {
+            monotonic_ns,
+            addr,
+            is_write: buf[16] != 0,
+            data: buf[RECORD_HEADER_LEN..].to_vec(),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for MmioReader<R> {
+    type Item = Result<MmioRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Print all records of the recording at `path`, one per line.
+#[allow(clippy::print_stdout)]
+pub fn print_records(path: &Path) -> Result<()> {
+    for record in MmioReader::open(path)? {
+        println!("{}", record?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MmioReader, MmioRecord, MmioRecorder};
+
+    #[test]
+    fn test_record_roundtrip() {
+        let records = vec![
+            MmioRecord {
+                monotonic_ns: 1_000,
+                addr: 0xd000_0050,
+                is_write: true,
+                data: vec![1, 0, 0, 0],
+            },
+            MmioRecord {
+                monotonic_ns: 2_000,
+                addr: 0xd000_0070,
+                is_write: false,
+                data: vec![0],
+            },
+        ];
+        let mut recorder = MmioRecorder::new(vec![]).expect("cannot write header");
+        for record in &records {
+            recorder.write_record(record).expect("cannot write record");
+        }
+        assert_eq!(recorder.count(), 2);
+        let buf = recorder.finish().expect("cannot flush");
+
+        let reader = MmioReader::new(&buf[..]).expect("cannot read header");
+        let read = reader
+            .collect::<Result<Vec<_>, _>>()
+            .expect("cannot read records");
+        assert_eq!(read, records);
+
+        // truncated record
+        let mut reader = MmioReader::new(&buf[..buf.len() - 1]).expect("cannot read header");
+        assert_eq!(
+            reader.read_record().expect("cannot read record"),
+            Some(records[0].clone())
+        );
+        assert!(reader.read_record().is_err());
+        assert!(MmioReader::new(&b"VMSHMMIX\x01\0\0\0"[..]).is_err());
+    }
+}
@@ -1,4 +1,6 @@
+pub mod attach_lock;
 pub mod inject_syscall;
+pub mod mmio_record;
 pub mod proc;
 pub mod ptrace;
 /// This module provides a safe wrapper for `ptrace(PTRACE_GET_SYSCALL_INFO)` but only for linux
@@ -10,6 +12,9 @@ pub mod ptrace;
 /// While `SyscallInfo` could provide amazing information in its `op` field, this field is (as of
 /// v5.4.106) always empty (`SyscallOp::None`) - which makes this function kind of useless.
 pub mod ptrace_syscall_info;
+pub mod syscalls;
+#[cfg(test)]
+pub mod testutils;
 pub mod wrap_syscall;
 
 use crate::kvm::hypervisor::VCPU;
@@ -1,11 +1,14 @@
 use libc::c_int;
+use log::warn;
+use nix::errno::Errno;
 use nix::fcntl::{self, OFlag};
 use nix::sys::mman::{MapFlags, ProtFlags};
 use nix::sys::stat;
 use nix::unistd::{getpid, Pid};
-use simple_error::{require_with, try_with};
-use std::fs::{read_dir, read_link, File};
-use std::io::{BufRead, BufReader};
+use simple_error::{bail, require_with, simple_error, try_with, SimpleError};
+use std::ffi::OsString;
+use std::fs::{self, read_dir, read_link, File};
+use std::io;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::prelude::RawFd;
 use std::path::PathBuf;
@@ -13,11 +16,17 @@ use std::path::PathBuf;
 use crate::page_math::compute_host_offset;
 use crate::result::Result;
 
+/// appended by the kernel to pathnames of files that were unlinked
+const DELETED_SUFFIX: &str = " (deleted)";
+
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Mapping {
     pub start: usize,
     pub end: usize,
+    #[cfg_attr(feature = "serde", serde(with = "prot_flags_bits"))]
     pub prot_flags: ProtFlags,
+    #[cfg_attr(feature = "serde", serde(with = "map_flags_bits"))]
     pub map_flags: MapFlags,
     pub offset: u64,
     pub major_dev: u64,
@@ -29,6 +38,35 @@ pub struct Mapping {
     pub phys_addr: usize,
 }
 
+/// (De)serialize mmap flags as the integers passed to mmap(2)
+#[cfg(feature = "serde")]
+macro_rules! flags_as_bits {
+    ($name:ident, $flags:ty) => {
+        mod $name {
+            use serde::{Deserialize, Deserializer, Serializer};
+
+            pub fn serialize<S: Serializer>(
+                flags: &$flags,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.serialize_i32(flags.bits())
+            }
+
+            pub fn deserialize<'de, D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> std::result::Result<$flags, D::Error> {
+                let bits = i32::deserialize(deserializer)?;
+                Ok(<$flags>::from_bits_truncate(bits))
+            }
+        }
+    };
+}
+
+#[cfg(feature = "serde")]
+flags_as_bits!(prot_flags_bits, nix::sys::mman::ProtFlags);
+#[cfg(feature = "serde")]
+flags_as_bits!(map_flags_bits, nix::sys::mman::MapFlags);
+
 impl Mapping {
     #[must_use]
     pub fn size(&self) -> usize {
@@ -42,6 +80,22 @@ impl Mapping {
     pub fn phys_to_host_offset(&self) -> isize {
         compute_host_offset(self.start, self.phys_addr)
     }
+
+    /// The mapped file was removed after it has been mapped.
+    #[must_use]
+    pub fn is_deleted(&self) -> bool {
+        self.inode != 0 && self.pathname.ends_with(DELETED_SUFFIX)
+    }
+
+    /// Pathname without the ` (deleted)` suffix
+    #[must_use]
+    pub fn path(&self) -> &str {
+        if self.is_deleted() {
+            &self.pathname[..self.pathname.len() - DELETED_SUFFIX.len()]
+        } else {
+            &self.pathname
+        }
+    }
 }
 
 #[must_use]
@@ -52,6 +106,28 @@ pub fn find_mapping(mappings: &[Mapping], ip: usize) -> Option<Mapping> {
         .cloned()
 }
 
+/// Merge VM mappings that are adjacent both in guest physical and in hypervisor memory and have
+/// the same protection. The result is sorted by guest physical address.
+#[must_use]
+pub fn coalesce_mappings(maps: &[Mapping]) -> Vec<Mapping> {
+    let mut sorted = maps.to_vec();
+    sorted.sort_by_key(|m| m.phys_addr);
+    let mut merged: Vec<Mapping> = Vec::with_capacity(sorted.len());
+    for m in sorted {
+        match merged.last_mut() {
+            Some(prev)
+                if prev.phys_end() == m.phys_addr
+                    && prev.end == m.start
+                    && prev.prot_flags == m.prot_flags =>
+            {
+                prev.end = m.end;
+            }
+            _ => merged.push(m),
+        }
+    }
+    merged
+}
+
 pub struct PidHandle {
     pub pid: Pid,
     file: File,
@@ -62,84 +138,260 @@ pub fn pid_path(pid: Pid) -> PathBuf {
     PathBuf::from("/proc").join(pid.as_raw().to_string())
 }
 
+/// Parse /proc/<pid>/task/<tid>/syscall: the syscall number followed by its six arguments,
+/// stack pointer and program counter. Returns None if the thread is running or not blocked in a
+/// syscall.
+fn parse_syscall(line: &str) -> Option<(i64, Vec<u64>)> {
+    let mut fields = line.split_whitespace();
+    let nr = fields.next()?.parse::<i64>().ok()?;
+    if nr < 0 {
+        return None;
+    }
+    let args = fields
+        .take(6)
+        .map(|arg| u64::from_str_radix(arg.trim_start_matches("0x"), 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    if args.len() != 6 {
+        return None;
+    }
+    Some((nr, args))
+}
+
+/// Syscall number and arguments thread `tid` of `pid` is currently blocked in, if any.
+pub fn thread_syscall(pid: Pid, tid: Pid) -> Result<Option<(i64, Vec<u64>)>> {
+    let path = pid_path(pid)
+        .join("task")
+        .join(tid.as_raw().to_string())
+        .join("syscall");
+    let line = try_with!(fs::read_to_string(&path), "cannot read {}", path.display());
+    Ok(parse_syscall(line.trim()))
+}
+
+/// Thread group id from the contents of /proc/<pid>/status
+fn parse_tgid(status: &str) -> Option<Pid> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))
+        .and_then(|tgid| tgid.trim().parse::<i32>().ok())
+        .map(Pid::from_raw)
+}
+
+/// The process `pid` belongs to. This is `pid` itself unless it is the id of a thread other
+/// than the main thread, which shows up in /proc as well even though `ps` hides it.
+pub fn thread_group_leader(pid: Pid) -> Result<Pid> {
+    let path = pid_path(pid).join("status");
+    let status = try_with!(fs::read_to_string(&path), "cannot read {}", path.display());
+    Ok(require_with!(
+        parse_tgid(&status),
+        "no Tgid in {}",
+        path.display()
+    ))
+}
+
+/// Seccomp mode of a process as shown in the Seccomp field of /proc/<pid>/status. Syscalls we
+/// inject are executed by the process itself and are subject to its seccomp policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompMode {
+    Disabled,
+    /// SECCOMP_MODE_STRICT: only read, write, _exit and sigreturn are allowed
+    Strict,
+    /// SECCOMP_MODE_FILTER: a BPF program decides, i.e. QEMU's -sandbox or firecracker's filters
+    Filter,
+}
+
+fn parse_seccomp(status: &str) -> Option<SeccompMode> {
+    let mode = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Seccomp:"))?;
+    match mode.trim() {
+        "0" => Some(SeccompMode::Disabled),
+        "1" => Some(SeccompMode::Strict),
+        "2" => Some(SeccompMode::Filter),
+        _ => None,
+    }
+}
+
+/// Seccomp mode of thread `tid` of the process behind `handle`. Filters are per thread, i.e.
+/// firecracker installs a different one for its vcpu, api and vmm threads, so ask for the thread
+/// syscalls are injected into. None if the status file cannot be read or the kernel was built
+/// without CONFIG_SECCOMP, which omits the field.
+pub fn seccomp_mode(handle: &impl ProcFiles, tid: Pid) -> Option<SeccompMode> {
+    let status = handle
+        .read_file(&format!("task/{}/status", tid.as_raw()))
+        .ok()?;
+    parse_seccomp(&String::from_utf8_lossy(&status))
+}
+
+/// Scheduling state and tracer of a thread as shown in /proc/<pid>/task/<tid>/status
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadStatus {
+    /// i.e. R (running), S (sleeping), T (stopped) or t (tracing stop)
+    pub state: char,
+    pub tracer: Option<Pid>,
+}
+
+impl ThreadStatus {
+    /// The thread does not run until it receives SIGCONT or its tracer continues it.
+    #[must_use]
+    pub fn is_stopped(&self) -> bool {
+        self.state == 'T' || self.state == 't'
+    }
+}
+
+fn parse_thread_status(status: &str) -> Option<ThreadStatus> {
+    let state = status
+        .lines()
+        .find_map(|line| line.strip_prefix("State:"))
+        .and_then(|state| state.trim().chars().next())?;
+    let tracer = status
+        .lines()
+        .find_map(|line| line.strip_prefix("TracerPid:"))
+        .and_then(|tracer| tracer.trim().parse::<i32>().ok())?;
+    Some(ThreadStatus {
+        state,
+        tracer: if tracer == 0 {
+            None
+        } else {
+            Some(Pid::from_raw(tracer))
+        },
+    })
+}
+
+/// Thread ids of process `pid`
+pub fn threads(pid: Pid) -> Result<Vec<Pid>> {
+    let dir = pid_path(pid).join("task");
+    let entries = try_with!(fs::read_dir(&dir), "cannot read {}", dir.display());
+    let mut tids = vec![];
+    for entry in entries {
+        let entry = try_with!(entry, "cannot read {}", dir.display());
+        let name = entry.file_name();
+        let name = require_with!(name.to_str(), "cannot convert filename to string");
+        let tid = try_with!(name.parse::<i32>(), "invalid tid {}", name);
+        tids.push(Pid::from_raw(tid));
+    }
+    Ok(tids)
+}
+
+pub fn thread_status(pid: Pid, tid: Pid) -> Result<ThreadStatus> {
+    let path = pid_path(pid)
+        .join("task")
+        .join(tid.as_raw().to_string())
+        .join("status");
+    let status = try_with!(fs::read_to_string(&path), "cannot read {}", path.display());
+    Ok(require_with!(
+        parse_thread_status(&status),
+        "no State or TracerPid in {}",
+        path.display()
+    ))
+}
+
+/// Files in /proc/<pid> we read. Used to report which ones are restricted.
+const PROC_FILES: &[&str] = &["maps", "fd", "environ", "status"];
+
+const PERMISSION_HINT: &str =
+    "run vmsh as the user of the hypervisor or with CAP_SYS_PTRACE, and check kernel.yama.ptrace_scope";
+
+fn is_permission_error(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EACCES) | Some(libc::EPERM))
+}
+
 pub fn openpid(pid: Pid) -> Result<PidHandle> {
     let path = pid_path(pid);
-    let fd = try_with!(
-        fcntl::open(
-            &path,
-            OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
-            stat::Mode::empty(),
-        ),
-        "failed to open: {}",
-        path.display()
-    );
+    let fd = match fcntl::open(
+        &path,
+        OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        stat::Mode::empty(),
+    ) {
+        Ok(fd) => fd,
+        Err(Errno::EACCES) | Err(Errno::EPERM) => {
+            bail!("{} is not accessible: {}", path.display(), PERMISSION_HINT)
+        }
+        Err(e) => bail!("failed to open: {}: {}", path.display(), e),
+    };
     let file = unsafe { File::from_raw_fd(fd) };
 
     Ok(PidHandle { pid, file })
 }
 
-fn parse_flags(fields: &[u8]) -> (ProtFlags, MapFlags) {
-    assert!(fields.len() == 4);
-    (
-        (if fields[0] == b'r' {
-            ProtFlags::PROT_READ
-        } else {
-            ProtFlags::empty()
-        }) | (if fields[1] == b'w' {
-            ProtFlags::PROT_WRITE
-        } else {
-            ProtFlags::empty()
-        }) | (if fields[2] == b'x' {
-            ProtFlags::PROT_EXEC
-        } else {
-            ProtFlags::empty()
-        }),
-        if fields[3] == b'p' {
-            MapFlags::MAP_PRIVATE
-        } else {
-            MapFlags::MAP_SHARED
-        },
-    )
+fn parse_flags(perms: &str) -> Result<(ProtFlags, MapFlags)> {
+    let fields = perms.as_bytes();
+    if fields.len() != 4 {
+        bail!("expected 4 permission characters, got '{}'", perms);
+    }
+    let flag = |i: usize, c: u8, flag: ProtFlags| -> Result<ProtFlags> {
+        match fields[i] {
+            b'-' => Ok(ProtFlags::empty()),
+            f if f == c => Ok(flag),
+            _ => bail!("invalid permissions '{}'", perms),
+        }
+    };
+    let prot_flags = flag(0, b'r', ProtFlags::PROT_READ)?
+        | flag(1, b'w', ProtFlags::PROT_WRITE)?
+        | flag(2, b'x', ProtFlags::PROT_EXEC)?;
+    let map_flags = match fields[3] {
+        b'p' => MapFlags::MAP_PRIVATE,
+        b's' => MapFlags::MAP_SHARED,
+        _ => bail!("invalid sharing flag in permissions '{}'", perms),
+    };
+    Ok((prot_flags, map_flags))
 }
 
+/// Split off the next space separated field of `rest`.
+fn next_field<'a>(rest: &mut &'a str, name: &str) -> Result<&'a str> {
+    let s = rest.trim_start_matches(' ');
+    if s.is_empty() {
+        bail!("{} is missing", name);
+    }
+    let (field, tail) = s.split_at(s.find(' ').unwrap_or(s.len()));
+    *rest = tail;
+    Ok(field)
+}
+
+/// Parses a line of /proc/<pid>/maps:
+/// `start-end perms offset major:minor inode [pathname]`
+/// The pathname is optional and may contain spaces, i.e. `[anon:foo bar]` or `/tmp/x (deleted)`.
 fn parse_line(line: &str) -> Result<Mapping> {
-    let fields = line.splitn(6, ' ').collect::<Vec<_>>();
-    let range = fields[0].splitn(2, '-').collect::<Vec<_>>();
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    let mut rest = line;
 
+    let range = next_field(&mut rest, "address range")?;
+    let (start, end) = require_with!(range.split_once('-'), "invalid address range: {}", range);
     let start = try_with!(
-        usize::from_str_radix(range[0], 16),
+        usize::from_str_radix(start, 16),
         "start address is not a number: {}",
-        range[0]
+        start
     );
     let end = try_with!(
-        usize::from_str_radix(range[1], 16),
+        usize::from_str_radix(end, 16),
         "end address is not a number: {}",
-        range[1]
+        end
     );
-    let (prot_flags, map_flags) = parse_flags(fields[1].as_bytes());
+    if end < start {
+        bail!("mapping ends before it starts: {}", range);
+    }
+    let (prot_flags, map_flags) = parse_flags(next_field(&mut rest, "permissions")?)?;
+    let offset = next_field(&mut rest, "offset")?;
     let offset = try_with!(
-        u64::from_str_radix(fields[2], 16),
+        u64::from_str_radix(offset, 16),
         "offset is not a number: {}",
-        range[2]
+        offset
     );
-    let dev = fields[3].splitn(2, ':').collect::<Vec<_>>();
+    let dev = next_field(&mut rest, "device")?;
+    let (major, minor) = require_with!(dev.split_once(':'), "invalid device: {}", dev);
     let major_dev = try_with!(
-        u64::from_str_radix(dev[0], 16),
+        u64::from_str_radix(major, 16),
         "major dev is not a number: {}",
-        dev[0]
+        major
     );
     let minor_dev = try_with!(
-        u64::from_str_radix(dev[1], 16),
+        u64::from_str_radix(minor, 16),
         "minor dev is not a number: {}",
-        dev[1]
+        minor
     );
-    let inode = try_with!(
-        fields[4].parse::<u64>(),
-        "inode is not a number: {}",
-        fields[4]
-    );
-    let stripped = fields[5].trim_start();
-    let pathname = stripped.strip_suffix('\n').unwrap_or(stripped).to_string();
+    let inode = next_field(&mut rest, "inode")?;
+    let inode = try_with!(inode.parse::<u64>(), "inode is not a number: {}", inode);
+    // the kernel pads the pathname with spaces for alignment
+    let pathname = rest.trim_start_matches(' ').to_string();
 
     Ok(Mapping {
         start,
@@ -160,27 +412,35 @@ pub struct ProcFd {
     pub path: PathBuf,
 }
 
-impl PidHandle {
-    #[must_use]
-    pub fn entry(&self, name: &str) -> PathBuf {
-        pid_path(getpid())
-            .join("fd")
-            .join(self.file.as_raw_fd().to_string())
-            .join(name)
-    }
+/// Raw reads of /proc/<pid>. `PidHandle` reads the files of a real process, tests can use
+/// `testutils::FakeProc` to feed canned, possibly malformed, content to the parsers below.
+pub trait ProcFiles {
+    fn pid(&self) -> Pid;
+
+    /// Content of /proc/<pid>/`name`
+    fn read_file(&self, name: &str) -> io::Result<Vec<u8>>;
+
+    /// Names of the entries in /proc/<pid>/fd and where they link to. Links that cannot be
+    /// read, i.e. because the fd was closed in the meantime, carry the error instead.
+    fn read_fd_links(&self) -> io::Result<Vec<(OsString, io::Result<PathBuf>)>>;
 
-    pub fn fds(&self) -> Result<Vec<ProcFd>> {
-        let path = self.entry("fd");
+    fn fds(&self) -> Result<Vec<ProcFd>> {
+        let entries = match self.read_fd_links() {
+            Ok(entries) => entries,
+            Err(e) => return Err(open_error(self.pid(), "fd", e)),
+        };
         let mut fds = vec![];
-        let entries = try_with!(read_dir(&path), "failed to read {}", path.display());
-        for maybe_entry in entries {
-            let entry = try_with!(maybe_entry, "failed to read {}", path.display());
-            let file_name = entry.file_name();
-            let target = if let Ok(res) = read_link(entry.path()) {
-                res
-            } else {
-                // file might be closed again
-                continue;
+        let mut restricted = 0;
+        for (file_name, target) in entries {
+            let target = match target {
+                Ok(res) => res,
+                Err(e) => {
+                    if is_permission_error(&e) {
+                        restricted += 1;
+                    }
+                    // file might be closed again
+                    continue;
+                }
             };
             let fd_num = try_with!(
                 require_with!(file_name.to_str(), "invalid filename encoding").parse::<c_int>(),
@@ -192,18 +452,278 @@ impl PidHandle {
                 path: target,
             });
         }
+        if restricted > 0 {
+            warn!(
+                "skipped {} file descriptors in {} we are not allowed to read",
+                restricted,
+                pid_path(self.pid()).join("fd").display()
+            );
+        }
         Ok(fds)
     }
 
-    pub fn maps(&self) -> Result<Vec<Mapping>> {
-        let path = self.entry("maps");
-        let f = try_with!(File::open(&path), "cannot open {}", path.display());
-        let buf = BufReader::new(f);
+    fn maps(&self) -> Result<Vec<Mapping>> {
+        let content = match self.read_file("maps") {
+            Ok(content) => content,
+            Err(e) => return Err(open_error(self.pid(), "maps", e)),
+        };
+        let content = try_with!(
+            String::from_utf8(content),
+            "cannot read from {}",
+            pid_path(self.pid()).join("maps").display()
+        );
         let mut maps = vec![];
-        for line in buf.lines() {
-            let line = try_with!(line, "cannot read from {}", path.display());
-            maps.push(try_with!(parse_line(&line), "cannot parse line {}", line));
+        for line in content.lines() {
+            maps.push(try_with!(parse_line(line), "cannot parse line {}", line));
         }
         Ok(maps)
     }
 }
+
+fn open_error(pid: Pid, name: &str, err: io::Error) -> SimpleError {
+    let path = pid_path(pid).join(name);
+    if is_permission_error(&err) {
+        simple_error!("{} is not accessible: {}", path.display(), PERMISSION_HINT)
+    } else {
+        simple_error!("failed to read {}: {}", path.display(), err)
+    }
+}
+
+impl PidHandle {
+    #[must_use]
+    pub fn entry(&self, name: &str) -> PathBuf {
+        pid_path(getpid())
+            .join("fd")
+            .join(self.file.as_raw_fd().to_string())
+            .join(name)
+    }
+
+    /// Files of `PROC_FILES` that we are not allowed to read.
+    #[must_use]
+    pub fn inaccessible(&self) -> Vec<&'static str> {
+        PROC_FILES
+            .iter()
+            .filter(|name| {
+                let path = self.entry(name);
+                // for directories, metadata() succeeds even if we cannot list them
+                let res = match fs::metadata(&path) {
+                    Ok(m) if m.is_dir() => read_dir(&path).map(|_| ()),
+                    Ok(_) => File::open(&path).map(|_| ()),
+                    Err(e) => Err(e),
+                };
+                matches!(res, Err(e) if is_permission_error(&e))
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Warn about every file in /proc/<pid> we cannot read.
+    pub fn report_inaccessible(&self) {
+        let files = self.inaccessible();
+        if !files.is_empty() {
+            warn!(
+                "cannot read {} in {}, operations that depend on them will fail: {}",
+                files.join(", "),
+                pid_path(self.pid).display(),
+                PERMISSION_HINT
+            );
+        }
+    }
+}
+
+impl ProcFiles for PidHandle {
+    fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn read_file(&self, name: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.entry(name))
+    }
+
+    fn read_fd_links(&self) -> io::Result<Vec<(OsString, io::Result<PathBuf>)>> {
+        let mut links = vec![];
+        for entry in read_dir(self.entry("fd"))? {
+            let entry = entry?;
+            links.push((entry.file_name(), read_link(entry.path())));
+        }
+        Ok(links)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        coalesce_mappings, parse_line, parse_seccomp, parse_syscall, parse_tgid,
+        parse_thread_status, seccomp_mode, ProcFiles, SeccompMode, ThreadStatus, PERMISSION_HINT,
+    };
+    use crate::tracer::testutils::FakeProc;
+    use nix::sys::mman::{MapFlags, ProtFlags};
+    use nix::unistd::Pid;
+
+    #[test]
+    fn test_parse_line_corpus() {
+        let corpus = [
+            // (line, start, pathname)
+            ("55d0d6a00000-55d0d6a2c000 r--p 00000000 fd:01 1835341                    /usr/bin/qemu-system-x86_64\n", 0x55d0d6a00000, "/usr/bin/qemu-system-x86_64"),
+            ("7f2a40000000-7f2ac0000000 rw-s 00000000 00:01 4097                       /memfd:pc.ram (deleted)", 0x7f2a40000000, "/memfd:pc.ram (deleted)"),
+            ("7f2ad8000000-7f2ad8021000 rw-p 00000000 00:00 0 ", 0x7f2ad8000000, ""),
+            ("7f2ad8000000-7f2ad8021000 rw-p 00000000 00:00 0", 0x7f2ad8000000, ""),
+            ("7f2ae7fff000-7f2ae8000000 ---p 00000000 00:00 0                          [anon:foo bar]", 0x7f2ae7fff000, "[anon:foo bar]"),
+            ("7f2aec000000-7f2aec001000 rw-s 00000000 00:0e 10296                      anon_inode:kvm-vcpu:0", 0x7f2aec000000, "anon_inode:kvm-vcpu:0"),
+            ("7ffd6a3b1000-7ffd6a3d2000 rw-p 00000000 00:00 0                          [stack]", 0x7ffd6a3b1000, "[stack]"),
+            ("7f2b00000000-7f2b00001000 r-xp 00001000 103:02 524455                    /home/user/my vm/lib with spaces.so", 0x7f2b00000000, "/home/user/my vm/lib with spaces.so"),
+            ("ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0                  [vsyscall]", 0xffffffffff600000, "[vsyscall]"),
+        ];
+        for (line, start, pathname) in corpus.iter() {
+            let m = parse_line(line).unwrap_or_else(|e| panic!("cannot parse '{}': {}", line, e));
+            assert_eq!(m.start, *start, "{}", line);
+            assert_eq!(m.pathname, *pathname, "{}", line);
+        }
+
+        let m = parse_line(corpus[1].0).expect("cannot parse memfd");
+        assert_eq!(m.prot_flags, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE);
+        assert_eq!(m.map_flags, MapFlags::MAP_SHARED);
+        assert_eq!((m.major_dev, m.minor_dev, m.inode), (0, 1, 4097));
+        assert!(m.is_deleted());
+        assert_eq!(m.path(), "/memfd:pc.ram");
+
+        let m = parse_line(corpus[7].0).expect("cannot parse path with spaces");
+        assert_eq!((m.offset, m.major_dev, m.minor_dev), (0x1000, 0x103, 2));
+        assert_eq!(m.prot_flags, ProtFlags::PROT_READ | ProtFlags::PROT_EXEC);
+        assert!(!m.is_deleted());
+
+        for invalid in [
+            "",
+            "7f2ad8000000 rw-p 00000000 00:00 0",
+            "7f2ad8000000-7f2ad8021000 rw-p 00000000 00:00",
+            "7f2ad8000000-7f2ad8021000 rwp 00000000 00:00 0",
+            "7f2ad8000000-7f2ad8021000 rw-q 00000000 00:00 0",
+            "7f2ad8000000-7f2ad8021000 rw-p 00000000 0000 0",
+            "7f2ad8021000-7f2ad8000000 rw-p 00000000 00:00 0",
+        ]
+        .iter()
+        {
+            assert!(
+                parse_line(invalid).is_err(),
+                "'{}' should not parse",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_coalesce_mappings() {
+        let line = |start: usize, end: usize, perms: &str, phys_addr: usize| {
+            let mut m = parse_line(&format!(
+                "{:x}-{:x} {} 00000000 00:01 4097 /memfd:pc.ram (deleted)",
+                start, end, perms
+            ))
+            .expect("cannot parse line");
+            m.phys_addr = phys_addr;
+            m
+        };
+        let maps = vec![
+            line(0x7f00_000c_0000, 0x7f00_8000_0000, "rw-s", 0xc0000),
+            line(0x7f00_0000_0000, 0x7f00_000a_0000, "rw-s", 0),
+            // not adjacent in guest physical memory
+            line(0x7f00_000a_0000, 0x7f00_000c_0000, "rw-s", 0xfeb0_0000),
+            // adjacent but read-only
+            line(0x7f00_8000_0000, 0x7f00_8000_1000, "r--s", 0x8000_0000),
+            line(0x7f00_8000_1000, 0x7f00_8000_2000, "r--s", 0x8000_1000),
+        ];
+        let merged = coalesce_mappings(&maps);
+        let ranges = merged
+            .iter()
+            .map(|m| (m.phys_addr, m.phys_end()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![
+                (0, 0xa0000),
+                (0xc0000, 0x8000_0000),
+                (0x8000_0000, 0x8000_2000),
+                (0xfeb0_0000, 0xfeb2_0000),
+            ]
+        );
+        assert_eq!(merged[2].start, 0x7f00_8000_0000);
+    }
+
+    #[test]
+    fn test_parse_syscall() {
+        assert_eq!(
+            parse_syscall("16 0x12 0xae80 0x0 0x0 0x0 0x0 0x7f3a1bffe6c8 0x7f3a2b5d7b5f"),
+            Some((16, vec![0x12, 0xae80, 0, 0, 0, 0]))
+        );
+        assert_eq!(parse_syscall("running"), None);
+        assert_eq!(parse_syscall("-1 0x7ffd4e0e8c28 0x55d3c1a3b2e0"), None);
+    }
+
+    #[test]
+    fn test_seccomp_mode() {
+        let status =
+            "Name:\tfirecracker\nTracerPid:\t0\nNoNewPrivs:\t1\nSeccomp:\t2\nSeccomp_filters:\t1\n";
+        let pid = Pid::from_raw(4242);
+        let filtered = FakeProc::new(pid)
+            .file("status", "Name:\tfirecracker\nSeccomp:\t0\n")
+            .file("task/4242/status", status);
+        assert_eq!(seccomp_mode(&filtered, pid), Some(SeccompMode::Filter));
+        assert_eq!(parse_seccomp("Seccomp:\t0\n"), Some(SeccompMode::Disabled));
+        assert_eq!(parse_seccomp("Seccomp:\t1\n"), Some(SeccompMode::Strict));
+        assert_eq!(parse_seccomp("Name:\tqemu\n"), None);
+        assert_eq!(seccomp_mode(&FakeProc::new(pid), pid), None);
+    }
+
+    #[test]
+    fn test_parse_tgid() {
+        let status = "Name:\tqemu-system-x86\nUmask:\t0022\nState:\tS (sleeping)\nTgid:\t4242\nNgid:\t0\nPid:\t4250\n";
+        assert_eq!(parse_tgid(status), Some(Pid::from_raw(4242)));
+        assert_eq!(parse_tgid("Name:\tfoo\n"), None);
+    }
+
+    #[test]
+    fn test_parse_thread_status() {
+        let status = "Name:\tqemu-system-x86\nState:\tt (tracing stop)\nTgid:\t4242\nPid:\t4250\nPPid:\t1\nTracerPid:\t4300\n";
+        let parsed = parse_thread_status(status).expect("valid status");
+        assert_eq!(
+            parsed,
+            ThreadStatus {
+                state: 't',
+                tracer: Some(Pid::from_raw(4300))
+            }
+        );
+        assert!(parsed.is_stopped());
+
+        let status = "State:\tS (sleeping)\nTracerPid:\t0\n";
+        let parsed = parse_thread_status(status).expect("valid status");
+        assert_eq!(parsed.tracer, None);
+        assert!(!parsed.is_stopped());
+        assert_eq!(parse_thread_status("Name:\tfoo\n"), None);
+    }
+
+    #[test]
+    fn test_proc_files() {
+        let pid = Pid::from_raw(42);
+        let proc = FakeProc::new(pid)
+            .file(
+                "maps",
+                "7f2ad8000000-7f2ad8021000 rw-p 00000000 00:00 0\nnot a mapping\n",
+            )
+            .fd(3, "/dev/kvm")
+            .fd(4, "anon_inode:kvm-vm");
+        let err = proc.maps().expect_err("malformed line");
+        assert!(err.to_string().contains("not a mapping"), "{}", err);
+        let fds = proc.fds().expect("valid fds");
+        assert_eq!(
+            fds.iter().map(|fd| fd.fd_num).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+
+        // restricted files are reported with a hint, missing ones with their path
+        let proc = FakeProc::new(pid).denied("maps");
+        let err = proc.maps().expect_err("maps is restricted");
+        assert!(err.to_string().contains(PERMISSION_HINT), "{}", err);
+        let proc = FakeProc::new(pid);
+        let err = proc.maps().expect_err("maps is missing");
+        assert!(err.to_string().contains("/proc/42/maps"), "{}", err);
+    }
+}
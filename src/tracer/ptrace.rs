@@ -5,7 +5,7 @@ use nix::sys::ptrace::{self, AddressType, Request, RequestType};
 use nix::sys::wait::waitpid;
 use nix::sys::wait::WaitPidFlag;
 use nix::unistd::Pid;
-use simple_error::{require_with, try_with};
+use simple_error::{bail, require_with, try_with, SimpleError};
 use std::fs;
 use std::{mem, ptr};
 
@@ -19,12 +19,12 @@ pub struct Thread {
 }
 
 /// Get user registers, as with `ptrace(PTRACE_GETREGS, ...)`
-fn getregs(pid: Pid) -> nix::Result<Regs> {
+pub(crate) fn getregs(pid: Pid) -> nix::Result<Regs> {
     ptrace_get_data::<Regs>(Request::PTRACE_GETREGS, pid)
 }
 
 /// Set user registers, as with `ptrace(PTRACE_SETREGS, ...)`
-fn setregs(pid: Pid, regs: &Regs) -> nix::Result<()> {
+pub(crate) fn setregs(pid: Pid, regs: &Regs) -> nix::Result<()> {
     let res = unsafe {
         libc::ptrace(
             Request::PTRACE_SETREGS as RequestType,
@@ -96,9 +96,11 @@ impl Thread {
         Ok(())
     }
 
-    pub fn syscall(&self) -> Result<()> {
+    /// Resume until the next syscall stop and deliver `sig`, i.e. a signal the thread was
+    /// stopped for.
+    pub fn syscall(&self, sig: Option<nix::sys::signal::Signal>) -> Result<()> {
         try_with!(
-            ptrace::syscall(self.tid, None),
+            ptrace::syscall(self.tid, sig),
             "cannot set break on syscall with ptrace"
         );
         Ok(())
@@ -139,6 +141,21 @@ impl Thread {
     }
 }
 
+/// Call `f` again as long as it fails with EINTR. Blocking syscalls like waitpid(2) return
+/// EINTR if one of our signal handlers runs in this thread and was not installed with
+/// SA_RESTART. That is no reason to give up on the tracee.
+pub fn retry_on_eintr<T, F>(mut f: F) -> nix::Result<T>
+where
+    F: FnMut() -> nix::Result<T>,
+{
+    loop {
+        match f() {
+            Err(Errno::EINTR) => continue,
+            res => return res,
+        }
+    }
+}
+
 pub fn attach_seize(tid: Pid) -> Result<()> {
     // seize seems to be more modern and versatile than `ptrace::attach()`: continue, stop and
     // detach from tracees at (almost) any time
@@ -146,14 +163,49 @@ pub fn attach_seize(tid: Pid) -> Result<()> {
         ptrace::seize(tid, ptrace::Options::PTRACE_O_TRACESYSGOOD),
         "cannot seize the process"
     );
-    try_with!(interrupt(tid), "cannot interrupt/stop the tracee");
-
-    try_with!(waitpid(tid, Some(WaitPidFlag::WSTOPPED)), "waitpid failed");
+    let stopped = interrupt(tid)
+        .map_err(|e| format!("cannot interrupt/stop the tracee: {}", e))
+        .and_then(|_| {
+            retry_on_eintr(|| waitpid(tid, Some(WaitPidFlag::WSTOPPED)))
+                .map_err(|e| format!("waitpid failed: {}", e))
+        });
+    if let Err(e) = stopped {
+        // do not leave the thread seized behind
+        let _ = ptrace::detach(tid, None);
+        bail!("{}", e);
+    }
 
     Ok(())
 }
 
+/// Turns errors collected for individual threads into a single error.
+fn aggregate_errors(action: &str, total: usize, errors: Vec<(Pid, SimpleError)>) -> Result<()> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let details = errors
+        .iter()
+        .map(|(tid, e)| format!("{}: {}", tid, e))
+        .collect::<Vec<_>>()
+        .join("; ");
+    bail!(
+        "cannot {} {} of {} threads: {}",
+        action,
+        errors.len(),
+        total,
+        details
+    )
+}
+
+/// Seize every thread of `pid`. If any thread cannot be stopped, the others are released again
+/// and an error listing all failed threads is returned.
 pub fn attach_all_threads(pid: Pid) -> Result<(Vec<Thread>, usize)> {
+    attach_threads(pid, |_| true)
+}
+
+/// Like `attach_all_threads`, but only seize threads for which `filter` returns true. The main
+/// thread is always seized, since we inject syscalls through it.
+pub fn attach_threads<F: Fn(Pid) -> bool>(pid: Pid, filter: F) -> Result<(Vec<Thread>, usize)> {
     let dir = proc::pid_path(pid).join("task");
     let threads_dir = try_with!(
         fs::read_dir(&dir),
@@ -163,23 +215,53 @@ pub fn attach_all_threads(pid: Pid) -> Result<(Vec<Thread>, usize)> {
     let mut process_idx = 0;
 
     let mut threads = vec![];
+    let mut errors = vec![];
+    let mut total = 0;
 
-    for (i, thread_name) in threads_dir.enumerate() {
+    for thread_name in threads_dir {
         let entry = try_with!(thread_name, "failed to read directory {}", dir.display());
         let file_name = entry.file_name();
         let file_name = require_with!(file_name.to_str(), "cannot convert filename to string");
         let raw_tid = try_with!(file_name.parse::<pid_t>(), "invalid tid {}", file_name);
         let tid = Pid::from_raw(raw_tid);
-        if tid == pid {
-            process_idx = i;
+        if tid != pid && !filter(tid) {
+            continue;
         }
-        if let Ok(t) = attach_seize(tid).map(|_| Thread { tid }) {
-            threads.push(t);
+        total += 1;
+        match attach_seize(tid) {
+            Ok(()) => {
+                if tid == pid {
+                    process_idx = threads.len();
+                }
+                threads.push(Thread { tid });
+            }
+            // thread exited in the meantime
+            Err(_) if !proc::pid_path(tid).exists() => {}
+            Err(e) => errors.push((tid, e)),
         }
     }
+    // on error, dropping `threads` detaches the threads we already stopped
+    aggregate_errors("attach to", total, errors)?;
     Ok((threads, process_idx))
 }
 
+/// Detach from all `threads`, even if detaching some of them fails. Dropping the threads
+/// afterwards is fine, since detaching twice is ignored.
+pub fn detach_all_threads(threads: &[Thread]) -> Result<()> {
+    let mut errors = vec![];
+    for thread in threads {
+        match ptrace::detach(thread.tid, None) {
+            // ESRCH == thread already terminated
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(e) => errors.push((
+                thread.tid,
+                SimpleError::new(format!("cannot detach process from ptrace: {}", e)),
+            )),
+        }
+    }
+    aggregate_errors("detach from", threads.len(), errors)
+}
+
 impl Drop for Thread {
     fn drop(&mut self) {
         match ptrace::detach(self.tid, None) {
@@ -189,3 +271,27 @@ impl Drop for Thread {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::retry_on_eintr;
+    use nix::errno::Errno;
+
+    #[test]
+    fn test_retry_on_eintr() {
+        let mut calls = 0;
+        let res = retry_on_eintr(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(Errno::EINTR)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(res, Ok(3));
+        assert_eq!(
+            retry_on_eintr::<(), _>(|| Err(Errno::EPERM)),
+            Err(Errno::EPERM)
+        );
+    }
+}
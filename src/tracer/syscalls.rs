@@ -0,0 +1,351 @@
+//! Decode syscall numbers and arguments into strace-like text, i.e.
+//! `openat(AT_FDCWD, "/etc/passwd", 0x80000, 0x0)`.
+//!
+//! The same table serves syscalls of host threads, i.e. the hypervisor's own syscalls seen at a
+//! ptrace syscall stop, and syscalls of guest processes seen when single-stepping a vcpu onto a
+//! syscall instruction. Only the reader for string arguments differs: host memory for the
+//! former, guest virtual memory for the latter.
+
+use nix::errno::Errno;
+use std::fmt::Write;
+
+use crate::cpu::Regs;
+use crate::inspect::read_cstr;
+use crate::result::Result;
+
+/// String arguments are cut after this many bytes, like `strace -s`
+const MAX_STR_LEN: usize = 64;
+
+/// How an argument is printed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgKind {
+    /// signed decimal
+    Int,
+    /// flags, masks and other values that are easier to read in hex
+    Hex,
+    /// file descriptor
+    Fd,
+    /// file descriptor or AT_FDCWD of the *at() syscalls
+    DirFd,
+    /// pointer to a null-terminated string, i.e. a path
+    Str,
+    /// pointer to anything else
+    Ptr,
+}
+
+pub struct SyscallDef {
+    pub nr: i64,
+    pub name: &'static str,
+    pub args: &'static [ArgKind],
+}
+
+use ArgKind::*;
+
+const fn def(nr: i64, name: &'static str, args: &'static [ArgKind]) -> SyscallDef {
+    SyscallDef { nr, name, args }
+}
+
+/// Syscalls we know the arguments of. Others are printed with the number and all six
+/// arguments in hex. Numbers and the set of syscalls differ between architectures (i.e. aarch64
+/// has no `open` or `stat`), the table is only filled in for x86_64.
+#[cfg(target_arch = "x86_64")]
+static SYSCALLS: &[SyscallDef] = &[
+    def(libc::SYS_read, "read", &[Fd, Ptr, Int]),
+    def(libc::SYS_write, "write", &[Fd, Ptr, Int]),
+    def(libc::SYS_open, "open", &[Str, Hex, Hex]),
+    def(libc::SYS_close, "close", &[Fd]),
+    def(libc::SYS_stat, "stat", &[Str, Ptr]),
+    def(libc::SYS_fstat, "fstat", &[Fd, Ptr]),
+    def(libc::SYS_lstat, "lstat", &[Str, Ptr]),
+    def(libc::SYS_poll, "poll", &[Ptr, Int, Int]),
+    def(libc::SYS_lseek, "lseek", &[Fd, Int, Int]),
+    def(libc::SYS_mmap, "mmap", &[Ptr, Hex, Hex, Hex, Fd, Hex]),
+    def(libc::SYS_mprotect, "mprotect", &[Ptr, Hex, Hex]),
+    def(libc::SYS_munmap, "munmap", &[Ptr, Hex]),
+    def(libc::SYS_brk, "brk", &[Ptr]),
+    def(
+        libc::SYS_rt_sigaction,
+        "rt_sigaction",
+        &[Int, Ptr, Ptr, Int],
+    ),
+    def(
+        libc::SYS_rt_sigprocmask,
+        "rt_sigprocmask",
+        &[Int, Ptr, Ptr, Int],
+    ),
+    def(libc::SYS_ioctl, "ioctl", &[Fd, Hex, Hex]),
+    def(libc::SYS_pread64, "pread64", &[Fd, Ptr, Int, Int]),
+    def(libc::SYS_pwrite64, "pwrite64", &[Fd, Ptr, Int, Int]),
+    def(libc::SYS_readv, "readv", &[Fd, Ptr, Int]),
+    def(libc::SYS_writev, "writev", &[Fd, Ptr, Int]),
+    def(libc::SYS_access, "access", &[Str, Hex]),
+    def(libc::SYS_pipe, "pipe", &[Ptr]),
+    def(libc::SYS_sched_yield, "sched_yield", &[]),
+    def(libc::SYS_madvise, "madvise", &[Ptr, Hex, Int]),
+    def(libc::SYS_dup, "dup", &[Fd]),
+    def(libc::SYS_dup2, "dup2", &[Fd, Fd]),
+    def(libc::SYS_nanosleep, "nanosleep", &[Ptr, Ptr]),
+    def(libc::SYS_getpid, "getpid", &[]),
+    def(libc::SYS_socket, "socket", &[Int, Hex, Int]),
+    def(libc::SYS_connect, "connect", &[Fd, Ptr, Int]),
+    def(libc::SYS_accept, "accept", &[Fd, Ptr, Ptr]),
+    def(libc::SYS_sendto, "sendto", &[Fd, Ptr, Int, Hex, Ptr, Int]),
+    def(
+        libc::SYS_recvfrom,
+        "recvfrom",
+        &[Fd, Ptr, Int, Hex, Ptr, Ptr],
+    ),
+    def(libc::SYS_sendmsg, "sendmsg", &[Fd, Ptr, Hex]),
+    def(libc::SYS_recvmsg, "recvmsg", &[Fd, Ptr, Hex]),
+    def(libc::SYS_bind, "bind", &[Fd, Ptr, Int]),
+    def(libc::SYS_listen, "listen", &[Fd, Int]),
+    def(libc::SYS_clone, "clone", &[Hex, Ptr, Ptr, Ptr, Hex]),
+    def(libc::SYS_fork, "fork", &[]),
+    def(libc::SYS_vfork, "vfork", &[]),
+    def(libc::SYS_execve, "execve", &[Str, Ptr, Ptr]),
+    def(libc::SYS_exit, "exit", &[Int]),
+    def(libc::SYS_wait4, "wait4", &[Int, Ptr, Hex, Ptr]),
+    def(libc::SYS_kill, "kill", &[Int, Int]),
+    def(libc::SYS_uname, "uname", &[Ptr]),
+    def(libc::SYS_fcntl, "fcntl", &[Fd, Int, Hex]),
+    def(libc::SYS_flock, "flock", &[Fd, Int]),
+    def(libc::SYS_fsync, "fsync", &[Fd]),
+    def(libc::SYS_truncate, "truncate", &[Str, Int]),
+    def(libc::SYS_ftruncate, "ftruncate", &[Fd, Int]),
+    def(libc::SYS_getcwd, "getcwd", &[Ptr, Int]),
+    def(libc::SYS_chdir, "chdir", &[Str]),
+    def(libc::SYS_rename, "rename", &[Str, Str]),
+    def(libc::SYS_mkdir, "mkdir", &[Str, Hex]),
+    def(libc::SYS_rmdir, "rmdir", &[Str]),
+    def(libc::SYS_unlink, "unlink", &[Str]),
+    def(libc::SYS_readlink, "readlink", &[Str, Ptr, Int]),
+    def(libc::SYS_chmod, "chmod", &[Str, Hex]),
+    def(libc::SYS_umask, "umask", &[Hex]),
+    def(libc::SYS_getuid, "getuid", &[]),
+    def(libc::SYS_getgid, "getgid", &[]),
+    def(libc::SYS_geteuid, "geteuid", &[]),
+    def(libc::SYS_getppid, "getppid", &[]),
+    def(libc::SYS_setsid, "setsid", &[]),
+    def(libc::SYS_prctl, "prctl", &[Int, Hex, Hex, Hex, Hex]),
+    def(libc::SYS_arch_prctl, "arch_prctl", &[Hex, Hex]),
+    def(libc::SYS_mount, "mount", &[Str, Str, Str, Hex, Ptr]),
+    def(libc::SYS_umount2, "umount2", &[Str, Hex]),
+    def(libc::SYS_gettid, "gettid", &[]),
+    def(libc::SYS_tkill, "tkill", &[Int, Int]),
+    def(libc::SYS_futex, "futex", &[Ptr, Int, Int, Ptr, Ptr, Int]),
+    def(libc::SYS_getdents64, "getdents64", &[Fd, Ptr, Int]),
+    def(libc::SYS_clock_gettime, "clock_gettime", &[Int, Ptr]),
+    def(libc::SYS_exit_group, "exit_group", &[Int]),
+    def(libc::SYS_epoll_wait, "epoll_wait", &[Fd, Ptr, Int, Int]),
+    def(libc::SYS_tgkill, "tgkill", &[Int, Int, Int]),
+    def(libc::SYS_openat, "openat", &[DirFd, Str, Hex, Hex]),
+    def(libc::SYS_mkdirat, "mkdirat", &[DirFd, Str, Hex]),
+    def(libc::SYS_newfstatat, "newfstatat", &[DirFd, Str, Ptr, Hex]),
+    def(libc::SYS_unlinkat, "unlinkat", &[DirFd, Str, Hex]),
+    def(libc::SYS_renameat, "renameat", &[DirFd, Str, DirFd, Str]),
+    def(libc::SYS_readlinkat, "readlinkat", &[DirFd, Str, Ptr, Int]),
+    def(libc::SYS_faccessat, "faccessat", &[DirFd, Str, Hex]),
+    def(libc::SYS_ppoll, "ppoll", &[Ptr, Int, Ptr, Ptr, Int]),
+    def(
+        libc::SYS_epoll_pwait,
+        "epoll_pwait",
+        &[Fd, Ptr, Int, Int, Ptr, Int],
+    ),
+    def(libc::SYS_eventfd2, "eventfd2", &[Int, Hex]),
+    def(libc::SYS_epoll_create1, "epoll_create1", &[Hex]),
+    def(libc::SYS_dup3, "dup3", &[Fd, Fd, Hex]),
+    def(libc::SYS_pipe2, "pipe2", &[Ptr, Hex]),
+    def(libc::SYS_prlimit64, "prlimit64", &[Int, Int, Ptr, Ptr]),
+    def(libc::SYS_getrandom, "getrandom", &[Ptr, Int, Hex]),
+    def(libc::SYS_memfd_create, "memfd_create", &[Str, Hex]),
+    def(libc::SYS_statx, "statx", &[DirFd, Str, Hex, Hex, Ptr]),
+    def(
+        libc::SYS_io_uring_enter,
+        "io_uring_enter",
+        &[Fd, Int, Int, Hex, Ptr, Int],
+    ),
+    def(libc::SYS_clone3, "clone3", &[Ptr, Int]),
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+static SYSCALLS: &[SyscallDef] = &[];
+
+/// Syscalls return `-errno` in this range, larger values are results, i.e. addresses from mmap
+const MAX_ERRNO: i64 = 4095;
+
+/// Format the return value of a syscall like strace does: `0`, or `-1 EINVAL (Invalid argument)`
+/// for an error.
+pub fn format_return(ret: i64) -> String {
+    if (-MAX_ERRNO..0).contains(&ret) {
+        let errno = Errno::from_i32(-ret as i32);
+        format!("-1 {:?} ({})", errno, errno.desc())
+    } else {
+        ret.to_string()
+    }
+}
+
+/// Look up the name and arguments of syscall `nr`.
+pub fn lookup(nr: u64) -> Option<&'static SyscallDef> {
+    SYSCALLS.iter().find(|def| def.nr as u64 == nr)
+}
+
+/// A syscall with its number and the raw values of all six argument registers
+#[derive(Clone, Debug, PartialEq)]
+pub struct Syscall {
+    pub nr: u64,
+    pub args: [u64; 6],
+}
+
+impl Syscall {
+    /// The syscall a thread of the hypervisor is in at a ptrace syscall-enter or syscall-exit
+    /// stop. The number is taken from `orig_rax`, since `rax` holds the return value.
+    #[cfg(target_arch = "x86_64")]
+    pub fn from_syscall_stop(regs: &Regs) -> Syscall {
+        let (nr, a1, a2, a3, a4, a5, a6) = regs.get_syscall_params();
+        Syscall {
+            nr,
+            args: [a1, a2, a3, a4, a5, a6],
+        }
+    }
+
+    /// The syscall a vcpu is about to make when its instruction pointer is on a syscall
+    /// instruction (`cpu::SYSCALL_TEXT`), i.e. while single-stepping the guest. Unlike at a
+    /// ptrace stop, the number is still in `rax`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn before_instruction(regs: &Regs) -> Syscall {
+        Syscall {
+            nr: regs.rax,
+            args: [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9],
+        }
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        lookup(self.nr).map(|def| def.name)
+    }
+
+    /// Format the syscall as `name(arg, ...)`. `read` fills a buffer from the address space the
+    /// syscall was made in and is used for string arguments. Strings that cannot be read are
+    /// printed as pointer.
+    pub fn decode<F>(&self, mut read: F) -> String
+    where
+        F: FnMut(usize, &mut [u8]) -> Result<()>,
+    {
+        let (name, kinds) = match lookup(self.nr) {
+            Some(def) => (def.name.to_string(), def.args),
+            None => (format!("syscall_{}", self.nr), &[Hex; 6][..]),
+        };
+        let mut out = name;
+        out.push('(');
+        for (i, (kind, val)) in kinds.iter().zip(self.args.iter()).enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            format_arg(&mut out, *kind, *val, &mut read);
+        }
+        out.push(')');
+        out
+    }
+}
+
+fn format_arg<F>(out: &mut String, kind: ArgKind, val: u64, read: &mut F)
+where
+    F: FnMut(usize, &mut [u8]) -> Result<()>,
+{
+    // writing to a String does not fail
+    let _ = match kind {
+        Int => write!(out, "{}", val as i64),
+        Hex => write!(out, "{:#x}", val),
+        Fd => write!(out, "{}", val as i32),
+        DirFd if val as i32 == libc::AT_FDCWD => write!(out, "AT_FDCWD"),
+        DirFd => write!(out, "{}", val as i32),
+        Ptr if val == 0 => write!(out, "NULL"),
+        Ptr => write!(out, "{:#x}", val),
+        Str if val == 0 => write!(out, "NULL"),
+        Str => match read_cstr(&mut *read, val as usize, MAX_STR_LEN) {
+            Ok((s, true)) => write!(out, "{:?}", s),
+            Ok((s, false)) => write!(out, "{:?}...", s),
+            Err(_) => write!(out, "{:#x}", val),
+        },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_return;
+    #[cfg(target_arch = "x86_64")]
+    use super::{lookup, Syscall};
+    #[cfg(target_arch = "x86_64")]
+    use simple_error::bail;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_decode() {
+        let mem = b"/etc/passwd\0";
+        let start = 0x7ffd_0000_1000;
+        let read = |addr: usize, buf: &mut [u8]| {
+            let off = addr.wrapping_sub(start);
+            if off + buf.len() > mem.len() {
+                bail!("unmapped {:#x}", addr);
+            }
+            buf.copy_from_slice(&mem[off..off + buf.len()]);
+            Ok(())
+        };
+
+        let openat = Syscall {
+            nr: libc::SYS_openat as u64,
+            args: [-100i64 as u64, start as u64, 0x80000, 0, 0, 0],
+        };
+        assert_eq!(openat.name(), Some("openat"));
+        assert_eq!(
+            openat.decode(read),
+            "openat(AT_FDCWD, \"/etc/passwd\", 0x80000, 0x0)"
+        );
+
+        // unreadable string
+        let unlink = Syscall {
+            nr: libc::SYS_unlink as u64,
+            args: [0x1000, 0, 0, 0, 0, 0],
+        };
+        assert_eq!(unlink.decode(read), "unlink(0x1000)");
+
+        let read_call = Syscall {
+            nr: libc::SYS_read as u64,
+            args: [3, 0, -1i64 as u64, 0, 0, 0],
+        };
+        assert_eq!(read_call.decode(read), "read(3, NULL, -1)");
+
+        let unknown = Syscall {
+            nr: 4242,
+            args: [1, 2, 3, 4, 5, 6],
+        };
+        assert_eq!(unknown.name(), None);
+        assert_eq!(
+            unknown.decode(read),
+            "syscall_4242(0x1, 0x2, 0x3, 0x4, 0x5, 0x6)"
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_lookup() {
+        assert_eq!(
+            lookup(libc::SYS_ioctl as u64).map(|def| def.name),
+            Some("ioctl")
+        );
+        // every name appears once
+        let mut names = super::SYSCALLS.iter().map(|d| d.name).collect::<Vec<_>>();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), super::SYSCALLS.len());
+    }
+
+    #[test]
+    fn test_format_return() {
+        assert_eq!(format_return(0), "0");
+        assert_eq!(format_return(42), "42");
+        assert_eq!(format_return(-22), "-1 EINVAL (Invalid argument)");
+        assert_eq!(format_return(-4), "-1 EINTR (Interrupted system call)");
+        // not an errno, i.e. an address in the upper half
+        assert_eq!(format_return(-4096), "-4096");
+    }
+}
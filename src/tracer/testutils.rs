@@ -0,0 +1,111 @@
+//! Helpers to exercise the tracer module without a real process.
+
+use nix::errno::Errno;
+use nix::sys::mman::{MapFlags, ProtFlags};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::prelude::RawFd;
+use std::path::PathBuf;
+
+use crate::tracer::proc::{Mapping, ProcFiles};
+
+/// Shared read-write mapping of `size` bytes at `start` in the hypervisor that backs guest
+/// physical memory at `phys_addr`. Override fields with struct update syntax where needed.
+pub fn mapping(start: usize, phys_addr: usize, size: usize) -> Mapping {
+    Mapping {
+        start,
+        end: start + size,
+        prot_flags: ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+        map_flags: MapFlags::MAP_SHARED,
+        offset: 0,
+        major_dev: 0,
+        minor_dev: 0,
+        inode: 0,
+        pathname: String::new(),
+        phys_addr,
+    }
+}
+
+/// In-memory /proc/<pid> with canned content. Files that were not added do not exist.
+pub struct FakeProc {
+    pid: Pid,
+    files: HashMap<String, std::result::Result<Vec<u8>, Errno>>,
+    fds: Option<std::result::Result<Vec<(RawFd, std::result::Result<PathBuf, Errno>)>, Errno>>,
+}
+
+impl FakeProc {
+    pub fn new(pid: Pid) -> FakeProc {
+        FakeProc {
+            pid,
+            files: HashMap::new(),
+            fds: None,
+        }
+    }
+
+    /// Add /proc/<pid>/`name` with `content`
+    pub fn file(mut self, name: &str, content: impl Into<Vec<u8>>) -> FakeProc {
+        self.files.insert(name.to_string(), Ok(content.into()));
+        self
+    }
+
+    /// Make reading /proc/<pid>/`name` fail with EACCES. Use "fd" for the fd directory.
+    pub fn denied(mut self, name: &str) -> FakeProc {
+        if name == "fd" {
+            self.fds = Some(Err(Errno::EACCES));
+        } else {
+            self.files.insert(name.to_string(), Err(Errno::EACCES));
+        }
+        self
+    }
+
+    /// Add an entry to /proc/<pid>/fd linking to `target`
+    pub fn fd(mut self, fd: RawFd, target: &str) -> FakeProc {
+        self.push_fd(fd, Ok(PathBuf::from(target)));
+        self
+    }
+
+    /// Add an entry to /proc/<pid>/fd whose link cannot be read, i.e. because it was closed
+    pub fn broken_fd(mut self, fd: RawFd, err: Errno) -> FakeProc {
+        self.push_fd(fd, Err(err));
+        self
+    }
+
+    fn push_fd(&mut self, fd: RawFd, target: std::result::Result<PathBuf, Errno>) {
+        match &mut self.fds {
+            Some(Ok(fds)) => fds.push((fd, target)),
+            _ => self.fds = Some(Ok(vec![(fd, target)])),
+        }
+    }
+}
+
+impl ProcFiles for FakeProc {
+    fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn read_file(&self, name: &str) -> io::Result<Vec<u8>> {
+        match self.files.get(name) {
+            Some(Ok(content)) => Ok(content.clone()),
+            Some(Err(errno)) => Err(io::Error::from(*errno)),
+            None => Err(io::Error::from(Errno::ENOENT)),
+        }
+    }
+
+    fn read_fd_links(&self) -> io::Result<Vec<(OsString, io::Result<PathBuf>)>> {
+        match &self.fds {
+            Some(Ok(fds)) => Ok(fds
+                .iter()
+                .map(|(fd, target)| {
+                    (
+                        OsString::from(fd.to_string()),
+                        target.clone().map_err(io::Error::from),
+                    )
+                })
+                .collect()),
+            Some(Err(errno)) => Err(io::Error::from(*errno)),
+            None => Err(io::Error::from(Errno::ENOENT)),
+        }
+    }
+}
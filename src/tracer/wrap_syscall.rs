@@ -1,25 +1,31 @@
 use crate::tracer::Tracer;
 use kvm_bindings as kvmb;
-use log::{debug, trace, warn};
+use log::{debug, info, trace, warn};
+use nix::errno::Errno;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
+use nix::sys::wait::{waitpid, WaitStatus};
 use nix::unistd::getpgid;
 use nix::unistd::Pid;
-use nix::{
-    errno::Errno,
-    sys::wait::{waitpid, WaitStatus},
-};
 use nix::{sys::signal::Signal, unistd::getpgrp};
 use simple_error::bail;
 use simple_error::try_with;
 use std::{
-    fmt,
+    fmt, fs,
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
     thread::{current, ThreadId},
 };
 
+use crate::fmt::{hexdump_with, HexdumpOptions};
 use crate::kvm::hypervisor::{self, VCPU};
 use crate::kvm::ioctls;
+use crate::kvm::memslots::get_vcpu_maps;
 use crate::result::Result;
-use crate::tracer::proc::Mapping;
-use crate::tracer::ptrace;
+use crate::tracer::mmio_record::MmioRecorder;
+use crate::tracer::proc::{self, Mapping};
+use crate::tracer::ptrace::{self, retry_on_eintr};
+use crate::tracer::syscalls;
 
 type MmioRwRaw = kvmb::kvm_run__bindgen_ty_1__bindgen_ty_6;
 pub const MMIO_RW_DATA_MAX: usize = 8;
@@ -72,6 +78,28 @@ impl MmioRw {
         &mut self.data[..self.len]
     }
 
+    /// The data as little endian integer, if the access has a size of 1, 2, 4 or 8 bytes.
+    #[must_use]
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.len {
+            1 | 2 | 4 | 8 => {
+                let mut buf = [0u8; 8];
+                buf[..self.len].copy_from_slice(self.data());
+                Some(u64::from_le_bytes(buf))
+            }
+            _ => None,
+        }
+    }
+
+    /// Like `as_u64` but only for accesses of 1, 2 or 4 bytes, i.e. virtio-mmio registers.
+    #[must_use]
+    pub fn as_u32(&self) -> Option<u32> {
+        match self.len {
+            1 | 2 | 4 => self.as_u64().map(|v| v as u32),
+            _ => None,
+        }
+    }
+
     /// # Safety of the tracee
     ///
     /// Do not run this function when the traced process has continued since
@@ -114,12 +142,22 @@ impl MmioRw {
 
 impl fmt::Display for MmioRw {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.is_write {
+        if let (true, Some(val)) = (self.is_write, self.as_u64()) {
+            write!(
+                f,
+                "MmioRw{{ write {}b {:#x} to guest phys @ {:#x} }}",
+                self.len, val, self.addr
+            )
+        } else if self.is_write {
+            let opts = HexdumpOptions {
+                width: MMIO_RW_DATA_MAX,
+                group: 0,
+            };
             write!(
                 f,
-                "MmioRw{{ write {:?} to guest phys @ {:#x} }}",
-                self.data(),
-                self.addr
+                "MmioRw{{ write {}b to guest phys: {} }}",
+                self.len,
+                hexdump_with(self.addr, self.data(), &opts).trim_end()
             )
         } else {
             write!(
@@ -131,6 +169,202 @@ impl fmt::Display for MmioRw {
     }
 }
 
+/// suberror of KVM_EXIT_INTERNAL_ERROR added in Linux 5.12, not in kvm-bindings yet
+const KVM_INTERNAL_ERROR_UNEXPECTED_EXIT_REASON: u32 = 4;
+
+/// KVM could not handle an exit of the guest, i.e. an instruction it cannot emulate. The
+/// hypervisor usually stops the vm afterwards, so this is often the only clue why a guest died.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InternalError {
+    pub suberror: u32,
+    /// suberror specific data, i.e. the instruction bytes for emulation failures
+    pub data: Vec<u64>,
+}
+
+impl InternalError {
+    /// `ndata` is reported by the kernel and clamped to the size of `data`.
+    #[must_use]
+    pub fn new(suberror: u32, ndata: u32, data: &[u64]) -> InternalError {
+        let ndata = (ndata as usize).min(data.len());
+        InternalError {
+            suberror,
+            data: data[..ndata].to_vec(),
+        }
+    }
+
+    #[must_use]
+    pub fn suberror_name(&self) -> &'static str {
+        match self.suberror {
+            kvmb::KVM_INTERNAL_ERROR_EMULATION => "EMULATION",
+            kvmb::KVM_INTERNAL_ERROR_SIMUL_EX => "SIMUL_EX",
+            kvmb::KVM_INTERNAL_ERROR_DELIVERY_EV => "DELIVERY_EV",
+            KVM_INTERNAL_ERROR_UNEXPECTED_EXIT_REASON => "UNEXPECTED_EXIT_REASON",
+            _ => "UNKNOWN",
+        }
+    }
+}
+
+impl fmt::Display for InternalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "kvm internal error {} ({})",
+            self.suberror_name(),
+            self.suberror
+        )?;
+        if !self.data.is_empty() {
+            let data = self
+                .data
+                .iter()
+                .map(|d| format!("{:#x}", d))
+                .collect::<Vec<_>>();
+            write!(f, ", data: [{}]", data.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A hypercall (vmcall/vmmcall) of the guest. KVM handles most hypercalls itself, only those
+/// the hypervisor asked for with KVM_CAP_EXIT_HYPERCALL (i.e. KVM_HC_MAP_GPA_RANGE for
+/// confidential guests) exit to userspace and show up here.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hypercall {
+    pub nr: u64,
+    pub args: [u64; 6],
+    /// the guest was in 64-bit mode, otherwise only the lower 32 bits of `args` are valid
+    pub longmode: bool,
+}
+
+impl Hypercall {
+    /// Names of the hypercalls in include/uapi/linux/kvm_para.h
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self.nr {
+            1 => "KVM_HC_VAPIC_POLL_IRQ",
+            2 => "KVM_HC_MMU_OP",
+            3 => "KVM_HC_FEATURES",
+            4 => "KVM_HC_PPC_MAP_MAGIC_PAGE",
+            5 => "KVM_HC_KICK_CPU",
+            6 => "KVM_HC_MIPS_GET_CLOCK_FREQ",
+            7 => "KVM_HC_MIPS_EXIT_VM",
+            8 => "KVM_HC_MIPS_CONSOLE_OUTPUT",
+            9 => "KVM_HC_CLOCK_PAIRING",
+            10 => "KVM_HC_SEND_IPI",
+            11 => "KVM_HC_SCHED_YIELD",
+            12 => "KVM_HC_MAP_GPA_RANGE",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Hypercall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let args = self
+            .args
+            .iter()
+            .map(|arg| format!("{:#x}", arg))
+            .collect::<Vec<_>>();
+        write!(
+            f,
+            "hypercall {} ({})({}){}",
+            self.name(),
+            self.nr,
+            args.join(", "),
+            if self.longmode { "" } else { " 32-bit" }
+        )
+    }
+}
+
+/// Decoded exit_reason of a `KvmExit`
+pub enum VmExit {
+    Mmio(MmioRw),
+    InternalError(InternalError),
+    Hypercall(Hypercall),
+    /// any other exit_reason
+    Other(u32),
+}
+
+/// A vcpu thread that has just returned from ioctl(KVM_RUN).
+pub struct KvmExit {
+    pub vcpu: VCPU,
+    pub tid: Pid,
+    pub kvm_run: kvmb::kvm_run,
+}
+
+impl KvmExit {
+    pub fn mmio(&self) -> Result<Option<MmioRw>> {
+        Ok(MmioRw::from(
+            &self.kvm_run,
+            self.tid,
+            self.vcpu.map()?.clone(),
+        ))
+    }
+
+    pub fn exit(&self) -> Result<VmExit> {
+        Ok(match self.kvm_run.exit_reason {
+            kvmb::KVM_EXIT_INTERNAL_ERROR => {
+                // Safe because the exit_reason told us which union field to use.
+                let internal = unsafe { &self.kvm_run.__bindgen_anon_1.internal };
+                VmExit::InternalError(InternalError::new(
+                    internal.suberror,
+                    internal.ndata,
+                    &internal.data,
+                ))
+            }
+            kvmb::KVM_EXIT_HYPERCALL => {
+                // Safe because the exit_reason told us which union field to use.
+                let hypercall = unsafe { &self.kvm_run.__bindgen_anon_1.hypercall };
+                VmExit::Hypercall(Hypercall {
+                    nr: hypercall.nr,
+                    args: hypercall.args,
+                    longmode: hypercall.longmode != 0,
+                })
+            }
+            reason => match self.mmio()? {
+                Some(mmio) => VmExit::Mmio(mmio),
+                None => VmExit::Other(reason),
+            },
+        })
+    }
+
+    /// Make the exit look like a KVM_RUN interrupted by a signal: the exit_reason becomes
+    /// KVM_EXIT_INTR and the ioctl returns -EINTR, so that the hypervisor silently re-enters the
+    /// guest instead of handling an exit it did not ask for (i.e. KVM_EXIT_DEBUG).
+    ///
+    /// Same preconditions as `MmioRw::answer_read()`.
+    pub fn hide(&self) -> Result<()> {
+        let kvm_run_ptr = self.vcpu.map()?.start as *mut kvm_bindings::kvm_run;
+        let reason_ptr: *mut u32 = unsafe { &mut ((*kvm_run_ptr).exit_reason) };
+        hypervisor::memory::process_write(
+            self.tid,
+            reason_ptr.cast::<libc::c_void>(),
+            &kvmb::KVM_EXIT_INTR,
+        )?;
+        let mut regs = try_with!(
+            ptrace::getregs(self.tid),
+            "cannot get registers of vcpu thread {}",
+            self.tid
+        );
+        regs.set_syscall_return(-(libc::EINTR as i64));
+        try_with!(
+            ptrace::setregs(self.tid, &regs),
+            "cannot set return value of KVM_RUN in vcpu thread {}",
+            self.tid
+        );
+        Ok(())
+    }
+
+    /// Guest instruction pointer of a KVM_EXIT_DEBUG exit
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn debug_pc(&self) -> Option<u64> {
+        match self.kvm_run.exit_reason {
+            // Safe because the exit_reason told us which union field to use.
+            kvmb::KVM_EXIT_DEBUG => Some(unsafe { self.kvm_run.__bindgen_anon_1.debug.arch.pc }),
+            _ => None,
+        }
+    }
+}
+
 /// Contains the state of the thread running a vcpu.
 /// TODO in theory vcpus could change threads which they are run on
 #[derive(Debug)]
@@ -138,6 +372,11 @@ struct Thread {
     ptthread: ptrace::Thread,
     is_running: bool,
     in_syscall: bool,
+    /// kept stopped by `KvmRunWrapper::cont_thread`
+    held: bool,
+    /// Signal the thread was stopped for. Under ptrace a signal is only delivered if the tracer
+    /// passes it on when resuming the thread, so it is reinjected on the next resume.
+    pending_signal: Option<Signal>,
 }
 
 impl Thread {
@@ -146,9 +385,21 @@ impl Thread {
             ptthread,
             is_running: false,
             in_syscall: false, // ptrace (in practice) never attaches to a process while it is in a syscall
+            held: false,
+            pending_signal: None,
         }
     }
 
+    /// Resume until the next syscall stop
+    fn syscall(&mut self) -> Result<()> {
+        try_with!(
+            self.ptthread.syscall(self.pending_signal.take()),
+            "ptrace.thread.syscall() failed"
+        );
+        self.is_running = true;
+        Ok(())
+    }
+
     pub fn toggle_in_syscall(&mut self) {
         self.in_syscall = !self.in_syscall;
     }
@@ -163,7 +414,7 @@ impl Thread {
         // wait for thread to actually be interrupted
         loop {
             let status = try_with!(
-                waitpid(self.ptthread.tid, None),
+                retry_on_eintr(|| waitpid(self.ptthread.tid, None)),
                 "failed to waitpid on thread {}",
                 self.ptthread.tid
             );
@@ -180,6 +431,79 @@ impl Thread {
     }
 }
 
+/// Signal `Interrupter::interrupt()` sends to the tracer thread, so that it returns from
+/// waitpid(2) with EINTR.
+const WAKE_SIGNAL: Signal = Signal::SIGUSR2;
+
+extern "C" fn ignore_wake_signal(_: libc::c_int) {}
+
+/// Restores the action `WAKE_SIGNAL` had before `Interrupter::new()` once the last clone of the
+/// interrupter is dropped.
+#[derive(Debug)]
+struct WakeHandler {
+    previous: SigAction,
+}
+
+impl Drop for WakeHandler {
+    fn drop(&mut self) {
+        // Safe because `previous` is what sigaction(2) returned for WAKE_SIGNAL before.
+        if let Err(e) = unsafe { sigaction(WAKE_SIGNAL, &self.previous) } {
+            warn!("cannot restore handler for {}: {}", WAKE_SIGNAL, e);
+        }
+    }
+}
+
+/// Makes a `KvmRunWrapper` on the thread that created it stop waiting for the hypervisor when
+/// triggered from another thread. The traced threads are interrupted with PTRACE_INTERRUPT,
+/// so `wait_for_kvm_exit()` returns even if an idle guest makes no syscalls.
+#[derive(Clone, Debug)]
+pub struct Interrupter {
+    tracer: libc::pthread_t,
+    requested: Arc<AtomicBool>,
+    _handler: Arc<WakeHandler>,
+}
+
+impl Interrupter {
+    /// Must be created on the tracer thread, which has to outlive every `interrupt()` call.
+    pub fn new() -> Result<Interrupter> {
+        // Without SA_RESTART, so that a blocking waitpid(2) fails with EINTR.
+        let action = SigAction::new(
+            SigHandler::Handler(ignore_wake_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        // Safe because the handler does nothing and WAKE_SIGNAL is not used otherwise.
+        let previous = try_with!(
+            unsafe { sigaction(WAKE_SIGNAL, &action) },
+            "cannot install handler for {}",
+            WAKE_SIGNAL
+        );
+        Ok(Interrupter {
+            // Safe because pthread_self(3) always succeeds.
+            tracer: unsafe { libc::pthread_self() },
+            requested: Arc::new(AtomicBool::new(false)),
+            _handler: Arc::new(WakeHandler { previous }),
+        })
+    }
+
+    /// Whether `interrupt()` was called
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// Wake the tracer thread. May be called repeatedly, i.e. if the tracer was just about to
+    /// block in waitpid(2) when the signal arrived.
+    pub fn interrupt(&self) -> Result<()> {
+        self.requested.store(true, Ordering::Release);
+        // Safe because the tracer thread is still alive, see `new()`.
+        let res = unsafe { libc::pthread_kill(self.tracer, WAKE_SIGNAL as libc::c_int) };
+        if res != 0 {
+            bail!("cannot wake tracer thread: {}", Errno::from_i32(res));
+        }
+        Ok(())
+    }
+}
+
 /// TODO respect and handle newly spawned threads as well
 pub struct KvmRunWrapper {
     process_idx: usize,
@@ -187,6 +511,19 @@ pub struct KvmRunWrapper {
     process_group: Pid,
     owner: Option<ThreadId>,
     vcpus: Vec<VCPU>,
+    /// guest physical ranges `wait_for_ioctl()` reports MMIO exits for, empty means all
+    mmio_filter: Vec<Range<u64>>,
+    /// records every MMIO exit, regardless of `mmio_filter`
+    mmio_recorder: Option<MmioRecorder>,
+    /// log every ioctl of the traced threads when it returns
+    log_ioctls: bool,
+    /// see `set_interrupter()`
+    interrupter: Option<Interrupter>,
+}
+
+/// True if `addr` is in one of `ranges` or if there are no ranges at all.
+fn in_ranges(ranges: &[Range<u64>], addr: u64) -> bool {
+    ranges.is_empty() || ranges.iter().any(|r| r.contains(&addr))
 }
 
 impl Drop for KvmRunWrapper {
@@ -208,11 +545,72 @@ fn get_process_group(pid: Pid) -> Result<Pid> {
     Ok(process_group)
 }
 
+/// Threads of `pid` that are blocked in ioctl(KVM_RUN) on the fd of one of `vcpus`.
+fn vcpu_threads(pid: Pid, vcpus: &[VCPU]) -> Result<Vec<Pid>> {
+    let dir = proc::pid_path(pid).join("task");
+    let entries = try_with!(fs::read_dir(&dir), "cannot read {}", dir.display());
+    let mut tids = vec![];
+    for entry in entries {
+        let entry = try_with!(entry, "cannot read {}", dir.display());
+        let tid = match entry.file_name().to_str().map(str::parse::<i32>) {
+            Some(Ok(tid)) => Pid::from_raw(tid),
+            _ => continue,
+        };
+        let (nr, args) = match proc::thread_syscall(pid, tid) {
+            Ok(Some(syscall)) => syscall,
+            // not in a syscall right now, or exited in the meantime
+            _ => continue,
+        };
+        if nr == libc::SYS_ioctl
+            && args[1] == ioctls::KVM_RUN()
+            && vcpus.iter().any(|v| v.fd_num as u64 == args[0])
+        {
+            tids.push(tid);
+        }
+    }
+    Ok(tids)
+}
+
 impl KvmRunWrapper {
     pub fn attach(pid: Pid, vcpus: &[VCPU]) -> Result<KvmRunWrapper> {
+        Self::attach_threads(pid, vcpus, None)
+    }
+
+    /// Like `attach()`, but leaves threads that do not run a vcpu (i.e. iothreads or the main
+    /// loop of QEMU) alone, which saves waitpid() calls on their syscalls. Vcpu threads are
+    /// recognized by being blocked in ioctl(KVM_RUN) on one of the vcpu fds we found mappings
+    /// for. Falls back to all threads if some vcpu is not in KVM_RUN at the moment.
+    pub fn attach_vcpu_threads(pid: Pid, vcpus: &[VCPU]) -> Result<KvmRunWrapper> {
+        let tids = vcpu_threads(pid, vcpus)?;
+        if tids.len() < vcpus.len() {
+            warn!(
+                "found only {} of {} vcpu threads, tracing all threads",
+                tids.len(),
+                vcpus.len()
+            );
+            return Self::attach(pid, vcpus);
+        }
+        debug!("tracing vcpu threads {:?}", tids);
+        Self::attach_threads(pid, vcpus, Some(&tids))
+    }
+
+    fn attach_threads(pid: Pid, vcpus: &[VCPU], only: Option<&[Pid]>) -> Result<KvmRunWrapper> {
+        // we read kvm_run from these mappings on every exit, so they better be current
+        let mut vcpus = vcpus.to_vec();
+        match get_vcpu_maps(pid) {
+            Ok(current) => {
+                for vcpu in &mut vcpus {
+                    try_with!(vcpu.refresh_map(&current), "cannot trace ioctl(KVM_RUN)");
+                }
+            }
+            Err(e) => warn!("cannot check whether vcpu mappings are up-to-date: {}", e),
+        }
+        for vcpu in &vcpus {
+            try_with!(vcpu.check_map(), "cannot trace ioctl(KVM_RUN)");
+        }
         let (threads, process_idx) = try_with!(
-            ptrace::attach_all_threads(pid),
-            "cannot attach KvmRunWrapper to all threads of {} via ptrace",
+            ptrace::attach_threads(pid, |tid| only.map_or(true, |tids| tids.contains(&tid))),
+            "cannot attach KvmRunWrapper to threads of {} via ptrace",
             pid
         );
         let threads: Vec<Thread> = threads.into_iter().map(Thread::new).collect();
@@ -222,13 +620,23 @@ impl KvmRunWrapper {
             threads,
             process_group: get_process_group(pid)?,
             owner: Some(current().id()),
-            vcpus: vcpus.to_vec(),
+            vcpus,
+            mmio_filter: vec![],
+            mmio_recorder: None,
+            log_ioctls: false,
+            interrupter: None,
         })
     }
 
     /// Should be called before or during dropping a `KvmRunWrapper`
     fn prepare_detach(&mut self) -> Result<()> {
         for thread in &self.threads {
+            if let Some(sig) = thread.pending_signal {
+                warn!(
+                    "thread {} is detached before {} was delivered to it",
+                    thread.ptthread.tid, sig
+                );
+            }
             try_with!(
                 thread.prepare_detach(),
                 "cannot prepare thread {} for detaching",
@@ -263,16 +671,49 @@ impl KvmRunWrapper {
             threads,
             owner: tracer.owner,
             vcpus: tracer.vcpus,
+            mmio_filter: vec![],
+            mmio_recorder: None,
+            log_ioctls: false,
+            interrupter: None,
         })
     }
 
-    pub fn cont(&self) -> Result<()> {
-        for thread in &self.threads {
-            thread.ptthread.cont(None)?;
+    pub fn cont(&mut self) -> Result<()> {
+        for thread in &mut self.threads {
+            if !thread.is_running {
+                thread.ptthread.cont(thread.pending_signal.take())?;
+                thread.is_running = true;
+            }
+            thread.held = false;
+        }
+        Ok(())
+    }
+
+    /// Resume only thread `tid` and keep all other threads stopped, i.e. to step one vcpu while
+    /// the others wait. Like `stop_on_syscall()` it stops at the syscalls of `tid`, so
+    /// `wait_for_ioctl()` and `wait_for_kvm_exit()` report exits of this thread only until
+    /// `release_threads()` or `cont()` is called. Threads that are running already keep
+    /// running.
+    pub fn cont_thread(&mut self, tid: Pid) -> Result<()> {
+        if !self.threads.iter().any(|t| t.ptthread.tid == tid) {
+            bail!("thread {} is not traced", tid);
+        }
+        for thread in &mut self.threads {
+            thread.held = thread.ptthread.tid != tid;
+            if !thread.held && !thread.is_running {
+                thread.syscall()?;
+            }
         }
         Ok(())
     }
 
+    /// Undo `cont_thread()`: all threads are resumed by the next `wait_for_ioctl()` again.
+    pub fn release_threads(&mut self) {
+        for thread in &mut self.threads {
+            thread.held = false;
+        }
+    }
+
     #[allow(dead_code)]
     fn main_thread(&self) -> &Thread {
         &self.threads[self.process_idx]
@@ -298,35 +739,128 @@ impl KvmRunWrapper {
         Ok(())
     }
 
+    /// Resume all stopped threads, except those held by `cont_thread()`, until their next
+    /// syscall.
     pub fn stop_on_syscall(&mut self) -> Result<()> {
         for thread in &mut self.threads {
-            if !thread.is_running {
-                try_with!(thread.ptthread.syscall(), "ptrace.thread.syscall() failed");
-                thread.is_running = true;
+            if !thread.is_running && !thread.held {
+                thread.syscall()?;
             }
         }
         Ok(())
     }
 
+    /// Only report MMIO exits whose address falls into one of the `[start, end)` `ranges`.
+    /// Other exits are passed on to the hypervisor as if we were not there. An empty list
+    /// removes the filter.
+    pub fn set_mmio_filter(&mut self, ranges: Vec<Range<u64>>) {
+        self.mmio_filter = ranges;
+    }
+
+    /// Record all MMIO exits `wait_for_ioctl()` sees with `recorder`. Returns the previous
+    /// recorder, which should be `MmioRecorder::finish`ed to flush it.
+    pub fn set_mmio_recorder(&mut self, recorder: Option<MmioRecorder>) -> Option<MmioRecorder> {
+        std::mem::replace(&mut self.mmio_recorder, recorder)
+    }
+
+    /// Log every ioctl the traced threads make, with the decoded request and its return value.
+    /// Only threads we trace are seen, so with `attach_vcpu_threads()` ioctls of other threads,
+    /// i.e. KVM_IRQ_LINE from an iothread, are missing.
+    pub fn set_log_ioctls(&mut self, enable: bool) {
+        self.log_ioctls = enable;
+    }
+
+    /// Once `interrupter` is triggered, `wait_for_ioctl()` and `wait_for_kvm_exit()` interrupt
+    /// all running threads and return `None` as soon as one of them stopped.
+    pub fn set_interrupter(&mut self, interrupter: Option<Interrupter>) {
+        self.interrupter = interrupter;
+    }
+
+    /// PTRACE_INTERRUPT all running threads if the interrupter was triggered
+    fn interrupt_if_requested(&self) -> Result<()> {
+        if !self
+            .interrupter
+            .as_ref()
+            .map_or(false, Interrupter::requested)
+        {
+            return Ok(());
+        }
+        for thread in self.threads.iter().filter(|t| t.is_running) {
+            thread.ptthread.interrupt()?;
+        }
+        Ok(())
+    }
+
     // TODO Err if third qemu thread terminates?
     pub fn wait_for_ioctl(&mut self) -> Result<Option<MmioRw>> {
+        let mmio = match self.wait_for_kvm_exit()? {
+            Some(exit) => match exit.exit()? {
+                VmExit::Mmio(mmio) => Some(mmio),
+                VmExit::InternalError(e) => {
+                    warn!("vcpu {}: {}", exit.vcpu.idx, e);
+                    None
+                }
+                VmExit::Hypercall(hypercall) => {
+                    debug!("vcpu {}: {}", exit.vcpu.idx, hypercall);
+                    None
+                }
+                VmExit::Other(_) => None,
+            },
+            None => return Ok(None),
+        };
+        if let (Some(recorder), Some(mmio)) = (self.mmio_recorder.as_mut(), mmio.as_ref()) {
+            if let Err(e) = recorder.record(mmio) {
+                warn!("{}, stop recording mmio exits", e);
+                self.mmio_recorder = None;
+            }
+        }
+        Ok(mmio.filter(|mmio| in_ranges(&self.mmio_filter, mmio.addr)))
+    }
+
+    /// Call `f` for every MMIO exit matching the filter set with `set_mmio_filter()` until it
+    /// returns false. `f` may answer reads with `MmioRw::answer_read()`.
+    pub fn trace_mmio<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut MmioRw) -> Result<bool>,
+    {
+        loop {
+            if let Some(mut mmio) = self.wait_for_ioctl()? {
+                if !f(&mut mmio)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Like `wait_for_ioctl()` but returns every exit of ioctl(KVM_RUN) regardless of its
+    /// exit_reason.
+    pub fn wait_for_kvm_exit(&mut self) -> Result<Option<KvmExit>> {
         self.check_owner()?;
         self.stop_on_syscall()?;
         let status = try_with!(self.waitpid(), "cannot waitpid");
-        let mmio = try_with!(self.process_status(status), "cannot process status");
+        let exit = try_with!(self.process_status(status), "cannot process status");
 
-        Ok(mmio)
+        Ok(exit)
     }
 
     fn waitpid(&mut self) -> Result<WaitStatus> {
+        if !self.threads.iter().any(|t| t.is_running) {
+            // i.e. the thread resumed by cont_thread() has exited
+            bail!("no traced thread is running, all of them are held or exited");
+        }
+        self.interrupt_if_requested()?;
         loop {
-            let status = try_with!(
-                waitpid(
-                    Some(Pid::from_raw(-self.process_group.as_raw())),
-                    Some(nix::sys::wait::WaitPidFlag::__WALL)
-                ),
-                "cannot wait for ioctl syscall"
-            );
+            let status = match waitpid(
+                Some(Pid::from_raw(-self.process_group.as_raw())),
+                Some(nix::sys::wait::WaitPidFlag::__WALL),
+            ) {
+                Ok(status) => status,
+                Err(Errno::EINTR) => {
+                    self.interrupt_if_requested()?;
+                    continue;
+                }
+                Err(e) => bail!("cannot wait for ioctl syscall: {}", e),
+            };
             if let Some(pid) = status.pid() {
                 let res = self
                     .threads
@@ -340,18 +874,24 @@ impl KvmRunWrapper {
         }
     }
 
-    fn process_status(&mut self, status: WaitStatus) -> Result<Option<MmioRw>> {
+    fn process_status(&mut self, status: WaitStatus) -> Result<Option<KvmExit>> {
         match status {
-            WaitStatus::PtraceSyscall(pid) => {
-                return self.stopped(pid);
-            }
+            WaitStatus::PtraceSyscall(pid) => self.stopped(pid),
             WaitStatus::Exited(tid, status) => {
                 warn!("thread {} exited with: {}", tid, status);
                 self.drop_thread(tid);
+                Ok(None)
+            }
+            // signal-delivery-stop, other than the SIGTRAPs of ptrace itself
+            WaitStatus::Stopped(tid, sig) if sig != Signal::SIGTRAP => {
+                debug!("thread {} received {}, reinject it", tid, sig);
+                if let Some(thread) = self.threads.iter_mut().find(|t| t.ptthread.tid == tid) {
+                    thread.pending_signal = Some(sig);
+                }
+                Ok(None)
             }
-            _ => {}
+            _ => Ok(None),
         }
-        Ok(None)
     }
 
     fn drop_thread(&mut self, tid: Pid) {
@@ -373,7 +913,10 @@ impl KvmRunWrapper {
         }
     }
 
-    fn stopped(&mut self, pid: Pid) -> Result<Option<MmioRw>> {
+    /// Handle a syscall stop of thread `pid`. Only the return of ioctl(KVM_RUN) is reported as
+    /// exit. Every other syscall, including other ioctls, is passed through to the kernel
+    /// unchanged and returns `None`; ioctls are logged if `set_log_ioctls()` is on.
+    fn stopped(&mut self, pid: Pid) -> Result<Option<KvmExit>> {
         let thread: &mut Thread = match self
             .threads
             .iter_mut()
@@ -384,13 +927,23 @@ impl KvmRunWrapper {
         };
 
         let regs = try_with!(thread.ptthread.getregs(), "cannot syscall results");
-        let (syscall_nr, ioctl_fd, ioctl_request, _, _, _, _) = regs.get_syscall_params();
+        let (syscall_nr, ioctl_fd, ioctl_request, ioctl_arg, _, _, _) = regs.get_syscall_params();
         // SYS_ioctl = 16
         if syscall_nr != libc::SYS_ioctl as u64 {
             return Ok(None);
         }
 
         thread.toggle_in_syscall();
+        if self.log_ioctls && !thread.in_syscall {
+            info!(
+                "thread {}: ioctl({}, {}, {:#x}) = {}",
+                pid,
+                ioctl_fd as i32,
+                ioctls::format_request(ioctl_request),
+                ioctl_arg,
+                syscalls::format_return(regs.syscall_return())
+            );
+        }
         // KVM_RUN = 0xae80 = ioctl_io_nr!(KVM_RUN, KVMIO, 0x80)
         if ioctl_request != ioctls::KVM_RUN() {
             return Ok(None);
@@ -399,19 +952,17 @@ impl KvmRunWrapper {
         if thread.in_syscall {
             trace!("kvm-run enter {}", pid);
             return Ok(None);
-        } else {
-            trace!("kvm-run exit {}", pid);
-            let ret = regs.syscall_ret();
-            if ret != 0 {
-                log::warn!(
-                    "wrap_syscall: ioctl(KVM_RUN) failed in thread: {}: {} ({})",
-                    pid,
-                    Errno::from_i32(-(ret as i32)),
-                    -(ret as i32)
-                );
-                // hope that hypervisor handles it correctly
-                return Ok(None);
-            }
+        }
+        trace!("kvm-run exit {}", pid);
+        let ret = regs.syscall_return();
+        if ret != 0 {
+            warn!(
+                "wrap_syscall: ioctl(KVM_RUN) failed in thread: {}: {}",
+                pid,
+                syscalls::format_return(ret)
+            );
+            // hope that hypervisor handles it correctly
+            return Ok(None);
         }
 
         // fulfilled precondition: ioctl(KVM_RUN) just returned
@@ -429,9 +980,12 @@ impl KvmRunWrapper {
         let map_ptr = vcpu.map()?.start as *const kvm_bindings::kvm_run;
         let kvm_run: kvm_bindings::kvm_run =
             hypervisor::memory::process_read(pid, map_ptr.cast::<libc::c_void>())?;
-        let mmio = MmioRw::from(&kvm_run, thread.ptthread.tid, vcpu.map()?.clone());
 
-        Ok(mmio)
+        Ok(Some(KvmExit {
+            vcpu: vcpu.clone(),
+            tid: thread.ptthread.tid,
+            kvm_run,
+        }))
     }
 
     fn _check_siginfo(thread: &Thread) -> Result<()> {
@@ -448,3 +1002,178 @@ impl KvmRunWrapper {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ignore_wake_signal, in_ranges, Hypercall, InternalError, Interrupter, KvmRunWrapper,
+        MmioRw, MmioRwRaw, Thread, WAKE_SIGNAL,
+    };
+    use crate::tracer::proc::Mapping;
+    use crate::tracer::ptrace;
+    use crate::tracer::testutils::mapping;
+    use nix::unistd::Pid;
+
+    fn mmio(is_write: bool, data: &[u8]) -> MmioRw {
+        let mut raw = MmioRwRaw {
+            phys_addr: 0xd000_0050,
+            len: data.len() as u32,
+            is_write: is_write as u8,
+            ..Default::default()
+        };
+        raw.data[..data.len()].copy_from_slice(data);
+        let map = Mapping {
+            pathname: "anon_inode:kvm-vcpu:0".into(),
+            ..mapping(0x7f00_0000_0000, 0, 0x3000)
+        };
+        MmioRw::new(&raw, Pid::from_raw(1), map)
+    }
+
+    #[test]
+    fn test_mmio_as_int() {
+        let write = mmio(true, &[1, 0, 0, 0]);
+        assert_eq!(write.as_u64(), Some(1));
+        assert_eq!(write.as_u32(), Some(1));
+        assert_eq!(
+            write.to_string(),
+            "MmioRw{ write 4b 0x1 to guest phys @ 0xd0000050 }"
+        );
+        let quad = mmio(true, &[0x10, 0x32, 0x54, 0x76, 0x98, 0xba, 0xdc, 0xfe]);
+        assert_eq!(quad.as_u64(), Some(0xfedc_ba98_7654_3210));
+        assert_eq!(quad.as_u32(), None);
+        assert_eq!(mmio(false, &[0xff, 0xff]).as_u32(), Some(0xffff));
+        assert_eq!(mmio(true, &[1, 2, 3]).as_u64(), None);
+    }
+
+    #[test]
+    fn test_internal_error() {
+        let mut data = [0u64; 16];
+        data[0] = 0x0f0b;
+        data[1] = 0xffff_ffff_8100_1042;
+        let e = InternalError::new(1, 2, &data);
+        assert_eq!(e.suberror_name(), "EMULATION");
+        assert_eq!(
+            e.to_string(),
+            "kvm internal error EMULATION (1), data: [0xf0b, 0xffffffff81001042]"
+        );
+        // bogus ndata from a corrupted kvm_run
+        assert_eq!(InternalError::new(3, 100, &data).data.len(), 16);
+        assert_eq!(
+            InternalError::new(42, 0, &data).to_string(),
+            "kvm internal error UNKNOWN (42)"
+        );
+    }
+
+    #[test]
+    fn test_hypercall() {
+        let hypercall = Hypercall {
+            nr: 12,
+            args: [0x1_0000, 4, 0x10, 0, 0, 0],
+            longmode: true,
+        };
+        assert_eq!(
+            hypercall.to_string(),
+            "hypercall KVM_HC_MAP_GPA_RANGE (12)(0x10000, 0x4, 0x10, 0x0, 0x0, 0x0)"
+        );
+        let hypercall = Hypercall {
+            nr: 99,
+            args: [0; 6],
+            longmode: false,
+        };
+        assert!(hypercall.to_string().starts_with("hypercall unknown (99)("));
+        assert!(hypercall.to_string().ends_with(" 32-bit"));
+    }
+
+    #[test]
+    fn test_in_ranges() {
+        let ranges = vec![0xd000_0000..0xd000_1000, 0xfee0_0000..0xfee0_1000];
+        assert!(in_ranges(&ranges, 0xd000_0000));
+        assert!(in_ranges(&ranges, 0xfee0_0fff));
+        assert!(!in_ranges(&ranges, 0xd000_1000));
+        assert!(!in_ranges(&ranges, 0));
+        assert!(in_ranges(&[], 0));
+    }
+
+    /// A wrapper around threads that are not traced. Threads that are marked as running are
+    /// not resumed again, so tests can use it as long as they do not stop them.
+    fn fake_wrapper(threads: &[(i32, bool)]) -> KvmRunWrapper {
+        let threads = threads
+            .iter()
+            .map(|(tid, running)| {
+                let mut thread = Thread::new(ptrace::Thread {
+                    tid: Pid::from_raw(*tid),
+                });
+                thread.is_running = *running;
+                thread
+            })
+            .collect();
+        KvmRunWrapper {
+            process_idx: 0,
+            threads,
+            process_group: Pid::from_raw(0),
+            owner: None,
+            vcpus: vec![],
+            mmio_filter: vec![],
+            mmio_recorder: None,
+            log_ioctls: false,
+            interrupter: None,
+        }
+    }
+
+    #[test]
+    fn test_cont_thread() {
+        // pids above pid_max never exist, detaching them on drop fails with ESRCH
+        let (a, b, c) = (0x7fff_fff0, 0x7fff_fff1, 0x7fff_fff2);
+        let mut wrapper = fake_wrapper(&[(a, true), (b, false), (c, true)]);
+        let held = |w: &KvmRunWrapper| w.threads.iter().map(|t| t.held).collect::<Vec<_>>();
+
+        wrapper
+            .cont_thread(Pid::from_raw(a))
+            .expect("cannot continue thread");
+        assert_eq!(held(&wrapper), vec![false, true, true]);
+        // threads that run already are not stopped
+        assert!(wrapper.threads[2].is_running);
+
+        assert!(wrapper.cont_thread(Pid::from_raw(0x7fff_fff3)).is_err());
+        // a failed call does not change which threads are held
+        assert_eq!(held(&wrapper), vec![false, true, true]);
+
+        wrapper.release_threads();
+        assert_eq!(held(&wrapper), vec![false, false, false]);
+
+        // nothing to interrupt on drop
+        for thread in &mut wrapper.threads {
+            thread.is_running = false;
+        }
+    }
+
+    /// Current handler of `WAKE_SIGNAL`
+    fn wake_handler() -> libc::sighandler_t {
+        let mut action = std::mem::MaybeUninit::<libc::sigaction>::zeroed();
+        // Safe because a null action only queries the current one.
+        let res = unsafe {
+            libc::sigaction(
+                WAKE_SIGNAL as libc::c_int,
+                std::ptr::null(),
+                action.as_mut_ptr(),
+            )
+        };
+        assert_eq!(res, 0);
+        // Safe because sigaction(2) succeeded and filled it in.
+        unsafe { action.assume_init() }.sa_sigaction
+    }
+
+    #[test]
+    fn test_interrupter_restores_handler() {
+        let before = wake_handler();
+        let ours = ignore_wake_signal as libc::sighandler_t;
+        assert_ne!(before, ours);
+        let interrupter = Interrupter::new().expect("cannot create interrupter");
+        assert_eq!(wake_handler(), ours);
+        let clone = interrupter.clone();
+        drop(interrupter);
+        assert_eq!(wake_handler(), ours);
+        drop(clone);
+        assert_eq!(wake_handler(), before);
+    }
+}
@@ -0,0 +1,107 @@
+use log::{info, warn};
+use simple_error::{bail, require_with, try_with};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use xmas_elf::sections::{SectionData, SHN_UNDEF};
+use xmas_elf::ElfFile;
+
+use crate::btf::Btf;
+use crate::kernel::Kernel;
+use crate::result::Result;
+use crate::try_core_res;
+
+/// Symbol table of the uncompressed guest kernel image (vmlinux) as found on the host.
+pub struct Vmlinux {
+    /// link-time addresses of all symbols in .symtab
+    symbols: HashMap<String, usize>,
+    /// KASLR offset between link-time and runtime addresses
+    slide: isize,
+    /// type information, if the kernel was built with CONFIG_DEBUG_INFO_BTF
+    pub btf: Option<Btf>,
+}
+
+impl Vmlinux {
+    pub fn open(path: &Path) -> Result<Vmlinux> {
+        let data = try_with!(fs::read(path), "cannot read {}", path.display());
+        let elf = try_core_res!(ElfFile::new(&data), "cannot parse vmlinux");
+
+        let symbol_section = require_with!(
+            elf.find_section_by_name(".symtab"),
+            "vmlinux has no .symtab section, is it stripped?"
+        );
+        let symbol_table = symbol_section.get_data(&elf)?;
+        let sym_entries = match symbol_table {
+            SectionData::SymbolTable64(entries) => entries,
+            _ => bail!(
+                "expected .symtab to be a SymbolTable64, got: {:?}",
+                symbol_table
+            ),
+        };
+        let mut symbols = HashMap::new();
+        for sym in sym_entries.iter().filter(|sym| sym.shndx() != SHN_UNDEF) {
+            let name = try_core_res!(sym.get_name(&elf), "cannot get name of symbol");
+            symbols.insert(name.to_owned(), sym.value() as usize);
+        }
+        info!("read {} symbols from {}", symbols.len(), path.display());
+
+        let btf = match elf.find_section_by_name(".BTF") {
+            Some(section) => Some(try_with!(
+                Btf::parse(section.raw_data(&elf)),
+                "cannot parse .BTF section"
+            )),
+            None => {
+                info!("{} has no .BTF section", path.display());
+                None
+            }
+        };
+
+        Ok(Vmlinux {
+            symbols,
+            slide: 0,
+            btf,
+        })
+    }
+
+    /// Compute the KASLR offset by comparing against the symbols exported by the running kernel.
+    pub fn relocate(&mut self, kernel: &Kernel) -> Result<()> {
+        // init_task is always exported, prefer it over an arbitrary symbol
+        let common = std::iter::once("init_task")
+            .chain(kernel.symbols.keys().map(String::as_str))
+            .find_map(|name| Some((*kernel.symbols.get(name)?, *self.symbols.get(name)?)));
+        let (runtime, linktime) = require_with!(
+            common,
+            "vmlinux shares no symbols with the running kernel, does it belong to the guest?"
+        );
+        self.slide = runtime as isize - linktime as isize;
+        info!("kaslr offset: {:#x}", self.slide);
+
+        if let Some(mismatch) = kernel
+            .symbols
+            .iter()
+            .find(|(name, addr)| self.symbol(name).map(|a| a != **addr).unwrap_or(false))
+        {
+            warn!(
+                "symbol {} is at {:#x} in the guest but vmlinux suggests {:#x}",
+                mismatch.0,
+                mismatch.1,
+                self.symbol(mismatch.0).unwrap_or(0)
+            );
+        }
+        Ok(())
+    }
+
+    /// Runtime address of symbol `name`
+    pub fn symbol(&self, name: &str) -> Option<usize> {
+        self.symbols
+            .get(name)
+            .map(|addr| (*addr as isize + self.slide) as usize)
+    }
+
+    /// All symbols with their runtime addresses, in no particular order
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.symbols
+            .iter()
+            .map(move |(name, addr)| (name.as_str(), (*addr as isize + self.slide) as usize))
+    }
+}
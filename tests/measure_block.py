@@ -4,6 +4,7 @@
 - qemu virtio blk (detached_qemublk, direct_detached_qemublk)
 - qemu virtio 9p (detached_qemu9p)
 - vmsh virtio blk ws (attached_ws_javdev, direct_ws_javdev)
+- vmsh virtio blk ws, queue notifications trapped instead of ioeventfd (direct_wstrap_javdev)
 - vmsh virtio blk ioregionfd (attached_iorefd_javdev, direct_iorefd_javdev)
 
 for each:
@@ -297,7 +298,7 @@ def fio_suite(
 
 def main() -> None:
     """
-    not quick: 11 * fio_suite(10min) = 2h
+    not quick: 12 * fio_suite(10min) = 2h
     """
     util.check_ssd()
     util.check_memory()
@@ -332,6 +333,14 @@ def main() -> None:
             fio_suite(vm, fio_stats, GUEST_QEMUBLK, "direct_ws_qemublk", file=False)
             fio_suite(vm, fio_stats, GUEST_JAVDEV, "direct_ws_javdev", file=False)
 
+    if "direct_wstrap_javdev" in fio_stats["system"]:
+        print("skip direct_wstrap_javdev")
+    else:
+        with util.testbench(
+            helpers, with_vmsh=True, ioregionfd=False, mounts=False, queue_notify="trap"
+        ) as vm:
+            fio_suite(vm, fio_stats, GUEST_JAVDEV, "direct_wstrap_javdev", file=False)
+
     if (
         "direct_iorefd_qemublk" in fio_stats["system"]
         and "direct_iorefd_javdev" in fio_stats["system"]
@@ -82,6 +82,7 @@ def testbench(
     mounts: bool = True,
     vcpus: int = 4,
     mem: int = 8000,
+    queue_notify: str = "ioeventfd",
 ) -> Iterator[QemuVm]:
     if ioregionfd:
         mmiomode = "ioregionfd"
@@ -139,6 +140,8 @@ def testbench(
                     str(vm.pid),
                     "--mmio",
                     mmiomode,
+                    "--queue-notify",
+                    queue_notify,
                     "--",
                     "/bin/sh",
                     "-c",
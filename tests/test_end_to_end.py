@@ -0,0 +1,70 @@
+import os
+from typing import List
+
+import conftest
+import pytest
+
+from vmsh import EOF, VmshPopen
+
+# Boots a real qemu-kvm guest, so it only runs when asked for explicitly.
+RUN_END_TO_END = os.environ.get("VMSH_END_TO_END") == "1"
+
+
+def collect_lines(proc: VmshPopen) -> List[str]:
+    # the process has exited, stdout and stderr each end with an EOF marker
+    lines = []
+    eofs = 0
+    while eofs < 2:
+        line = proc.lines.get()
+        if line == EOF:
+            eofs += 1
+        else:
+            lines.append(str(line))
+    return lines
+
+
+@pytest.mark.skipif(
+    not RUN_END_TO_END, reason="set VMSH_END_TO_END=1 to boot qemu with -enable-kvm"
+)
+def test_attach_steps(helpers: conftest.Helpers) -> None:
+    """
+    Walk the core attach path against a live qemu one step at a time, so a regression points
+    at the step that broke: find the vm, read its memory map and vcpu registers and finally
+    inject the block device.
+    """
+    with helpers.busybox_image() as img, helpers.spawn_qemu(
+        helpers.notos_image()
+    ) as vm:
+        vm.wait_for_ssh()
+
+        proc = helpers.run_vmsh_command(["inspect", str(vm.pid)])
+        lines = collect_lines(proc)
+        assert any("vm mem:" in line for line in lines), "no guest memory mappings"
+        assert any("vcpu 0:" in line for line in lines), "no registers of vcpu 0"
+        assert any("rip=0x" in line for line in lines), "no instruction pointer"
+        assert any("found kernel at" in line for line in lines), "no guest kernel"
+
+        vmsh = helpers.spawn_vmsh_command(
+            [
+                "attach",
+                "--backing-file",
+                str(img),
+                str(vm.pid),
+                "--",
+                "/bin/sh",
+                "-c",
+                "echo works",
+            ]
+        )
+        with vmsh:
+            vmsh.wait_until_line(
+                "stage1 driver started",
+                lambda line: "stage1 driver started" in line,
+            )
+            res = vm.ssh_cmd(["dmesg"], check=False)
+            assert res.returncode == 0
+            assert "EXT4-fs (vdb): mounted filesystem" in res.stdout, res.stdout
+
+        # the vm survives detaching
+        res = vm.ssh_cmd(["echo", "ping"], check=False)
+        assert res.stdout == "ping\n"
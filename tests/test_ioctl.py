@@ -19,6 +19,35 @@ def test_injection(helpers: conftest.Helpers) -> None:
         run_ioctl_test("inject", vm)
 
 
+def test_ioctl_throughput(helpers: conftest.Helpers) -> None:
+    with helpers.spawn_qemu(helpers.notos_image()) as vm:
+        run_ioctl_test("ioctl_throughput", vm)
+
+
+def test_scratch_bench(helpers: conftest.Helpers) -> None:
+    with helpers.spawn_qemu(helpers.notos_image()) as vm:
+        run_ioctl_test("scratch_bench", vm)
+
+
+def test_mem_bench(helpers: conftest.Helpers) -> None:
+    with helpers.spawn_qemu(helpers.notos_image()) as vm:
+        run_ioctl_test("mem_bench", vm)
+
+
+def test_irq_bench(helpers: conftest.Helpers) -> None:
+    with helpers.spawn_qemu(helpers.notos_image()) as vm:
+        run_ioctl_test("irq_bench", vm)
+
+
+def test_stop_resume(helpers: conftest.Helpers) -> None:
+    with helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        run_ioctl_test("stop_resume", vm)
+        # check that the vm is still responsive
+        res = vm.ssh_cmd(["ls"])
+        assert res.returncode == 0
+
+
 def test_alloc_mem(helpers: conftest.Helpers) -> None:
     with helpers.spawn_qemu(helpers.notos_image()) as vm:
         run_ioctl_test("alloc_mem", vm)